@@ -1,50 +1,28 @@
 use crate::inventory::Inventory;
-use crate::modules::{ModuleArgs, ModuleResult};
-use crate::playbook::{Play, Task};
-use crate::ssh::{Auth, CommandResult, LocalConnection, SshConnection};
-use crate::template;
+use crate::modules::{async_status, ModuleArgs, ModuleContext, ModuleRegistry, ModuleResult};
+use crate::playbook::{Play, Strategy, Task};
+use crate::ssh::auth::default_identities;
+use crate::ssh::config::expand_path;
+use crate::ssh::jump::parse_proxy_jump;
+use crate::ssh::{
+    Auth, ChrootConnection, Connection, ConnectionPool, Deadline, DockerConnection, HostConfig, HostKeyCheck, HostKeyPolicy,
+    JumpHost, KubectlConnection, LocalConnection, Login, PoolKey, Prompter, SshConfig, SshConnection,
+};
+use crate::template::{self, HostVars};
 use anyhow::Result;
 use rayon::prelude::*;
-use std::collections::{HashMap, HashSet};
-
-pub enum Connection {
-    Ssh(SshConnection),
-    Local(LocalConnection),
-}
-
-impl Connection {
-    pub fn exec(&self, command: &str) -> Result<CommandResult> {
-        match self {
-            Connection::Ssh(c) => c.exec(command),
-            Connection::Local(c) => c.exec(command),
-        }
-    }
-
-    pub fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
-        match self {
-            Connection::Ssh(c) => c.write_file(path, content, mode),
-            Connection::Local(c) => c.write_file(path, content, mode),
-        }
-    }
-
-    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
-        match self {
-            Connection::Ssh(c) => c.read_file(path),
-            Connection::Local(c) => c.read_file(path),
-        }
-    }
-
-    pub fn host(&self) -> &str {
-        match self {
-            Connection::Ssh(c) => c.host(),
-            Connection::Local(c) => c.host(),
-        }
-    }
-}
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct Executor {
-    inventory: Inventory,
+    inventory: RwLock<Arc<Inventory>>,
+    /// Where the inventory was loaded from, for `meta: refresh_inventory`.
+    inventory_file: Option<PathBuf>,
     extra_vars: HashMap<String, String>,
     check_mode: bool,
     diff_mode: bool,
@@ -52,6 +30,32 @@ pub struct Executor {
     tags: HashSet<String>,
     skip_tags: HashSet<String>,
     limit: Option<String>,
+    playbook_dir: PathBuf,
+    lenient_undefined: bool,
+    force_handlers: bool,
+    connect_timeout: Duration,
+    ssh_config: SshConfig,
+    host_keys: HostKeyCheck,
+    /// SSH logins for hosts that don't set their own.
+    private_key: Option<Auth>,
+    password: Option<Auth>,
+    prompter: Option<Arc<dyn Prompter>>,
+    on_retry: Option<RetryHook>,
+    /// SSH sessions kept open across plays.
+    connections: ConnectionPool,
+    lookups: Arc<template::LookupRegistry>,
+    modules: Arc<ModuleRegistry>,
+    /// Facts gathered per host, kept across plays.
+    facts: Mutex<HashMap<String, serde_json::Map<String, Value>>>,
+}
+
+/// Told about each retry of an `until` loop as it happens.
+struct RetryHook(Box<dyn Fn(&str) + Send + Sync>);
+
+impl std::fmt::Debug for RetryHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("RetryHook")
+    }
 }
 
 #[derive(Debug, Default)]
@@ -61,20 +65,193 @@ pub struct PlayResult {
     pub changed: usize,
     pub failed: usize,
     pub skipped: usize,
+    pub unreachable: usize,
+    /// Why the host could not be reached, if it was unreachable.
+    pub unreachable_msg: Option<String>,
     pub task_results: Vec<TaskResult>,
 }
 
-#[derive(Debug)]
+impl PlayResult {
+    fn record(&mut self, task_result: TaskResult) {
+        match &task_result.result {
+            r if r.failed => self.failed += 1,
+            r if r.changed => self.changed += 1,
+            _ => self.ok += 1,
+        }
+        self.task_results.push(task_result);
+    }
+
+    /// Whether the host failed a task or could not be reached.
+    pub fn is_failed(&self) -> bool {
+        self.failed > 0 || self.unreachable > 0
+    }
+}
+
+/// Per-host state carried from task to task within a play.
+struct HostState {
+    name: String,
+    conn: Option<Arc<dyn Connection>>,
+    /// Connections opened for tasks delegated to other hosts.
+    delegates: HashMap<String, Arc<dyn Connection>>,
+    vars: HostVars,
+    notified: HashSet<String>,
+    /// Indexes of handlers waiting for the next flush.
+    pending_handlers: BTreeSet<usize>,
+    /// Set by `meta: end_host`.
+    ended: bool,
+    result: PlayResult,
+}
+
+impl HostState {
+    /// Failed, unreachable and ended hosts drop out of the rest of the play.
+    fn active(&self) -> bool {
+        self.conn.is_some() && !self.ended && !self.result.is_failed()
+    }
+}
+
+/// Failure, throttle and early-exit bookkeeping shared by the hosts of one
+/// batch.
+struct BatchStatus {
+    size: usize,
+    failed: AtomicUsize,
+    aborted: AtomicBool,
+    /// Set by `meta: end_batch` and `meta: end_play`.
+    ended: AtomicBool,
+    /// Set by `meta: end_play`; later batches are skipped too.
+    play_ended: AtomicBool,
+    throttles: Mutex<HashMap<usize, Arc<Throttle>>>,
+    /// Results of `run_once` tasks under the free strategy, by task index.
+    once: Mutex<HashMap<usize, Arc<OnceLock<Option<TaskResult>>>>>,
+}
+
+impl BatchStatus {
+    fn new(size: usize) -> Self {
+        BatchStatus {
+            size,
+            failed: AtomicUsize::new(0),
+            aborted: AtomicBool::new(false),
+            ended: AtomicBool::new(false),
+            play_ended: AtomicBool::new(false),
+            throttles: Mutex::new(HashMap::new()),
+            once: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits for a slot to run task number `index` when the task is
+    /// throttled. The slot is released when the permit is dropped.
+    fn throttle(&self, index: usize, task: &Task) -> Option<ThrottlePermit> {
+        if task.throttle == 0 {
+            return None;
+        }
+        let throttle = self
+            .throttles
+            .lock()
+            .unwrap()
+            .entry(index)
+            .or_insert_with(|| Arc::new(Throttle::new(task.throttle)))
+            .clone();
+        Some(Throttle::acquire(throttle))
+    }
+
+    /// Counts a newly failed host and aborts the play when the failure is
+    /// fatal or pushes the batch over `max_fail_percentage`.
+    fn host_failed(&self, play: &Play, fatal: bool) {
+        let failed = self.failed.fetch_add(1, Ordering::SeqCst) + 1;
+        let over_limit = play
+            .max_fail_percentage
+            .is_some_and(|max| failed as f64 * 100.0 / self.size as f64 > max);
+        if fatal || over_limit {
+            self.aborted.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    fn aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Stops the batch without failing any host, and the play with it when
+    /// `whole_play` is set.
+    fn end(&self, whole_play: bool) {
+        self.ended.store(true, Ordering::SeqCst);
+        if whole_play {
+            self.play_ended.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn ended(&self) -> bool {
+        self.ended.load(Ordering::SeqCst)
+    }
+
+    /// Whether the batch should stop scheduling tasks.
+    fn stopped(&self) -> bool {
+        self.aborted() || self.ended()
+    }
+
+    fn play_ended(&self) -> bool {
+        self.play_ended.load(Ordering::SeqCst)
+    }
+
+    /// Where the one result of `run_once` task number `index` is kept for
+    /// the hosts that reach it after the first.
+    fn once(&self, index: usize) -> Arc<OnceLock<Option<TaskResult>>> {
+        self.once.lock().unwrap().entry(index).or_default().clone()
+    }
+}
+
+/// A counting semaphore limiting how many hosts run a task at once.
+struct Throttle {
+    limit: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+struct ThrottlePermit(Arc<Throttle>);
+
+impl Throttle {
+    fn new(limit: usize) -> Self {
+        Throttle {
+            limit,
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn acquire(throttle: Arc<Throttle>) -> ThrottlePermit {
+        let mut running = throttle.running.lock().unwrap();
+        while *running >= throttle.limit {
+            running = throttle.freed.wait(running).unwrap();
+        }
+        *running += 1;
+        drop(running);
+        ThrottlePermit(throttle)
+    }
+}
+
+impl Drop for ThrottlePermit {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct TaskResult {
     pub task_name: String,
     pub host: String,
     pub result: ModuleResult,
+    /// One "FAILED - RETRYING" line per attempt an `until` loop retried.
+    pub retries: Vec<String>,
 }
 
 impl Executor {
     pub fn new(inventory: Inventory) -> Self {
         Self {
-            inventory,
+            inventory: RwLock::new(Arc::new(inventory)),
+            inventory_file: None,
             extra_vars: HashMap::new(),
             check_mode: false,
             diff_mode: false,
@@ -82,6 +259,20 @@ impl Executor {
             tags: HashSet::new(),
             skip_tags: HashSet::new(),
             limit: None,
+            playbook_dir: PathBuf::from("."),
+            lenient_undefined: false,
+            force_handlers: false,
+            connect_timeout: Duration::from_secs(10),
+            ssh_config: SshConfig::default(),
+            host_keys: HostKeyCheck::default(),
+            private_key: None,
+            password: None,
+            prompter: None,
+            on_retry: None,
+            connections: ConnectionPool::default(),
+            lookups: Arc::new(template::LookupRegistry::default()),
+            modules: Arc::new(ModuleRegistry::default()),
+            facts: Mutex::new(HashMap::new()),
         }
     }
 
@@ -120,6 +311,91 @@ impl Executor {
         self
     }
 
+    pub fn playbook_dir(mut self, dir: PathBuf) -> Self {
+        self.playbook_dir = dir;
+        self
+    }
+
+    /// Render undefined variables as empty strings instead of failing.
+    pub fn lenient_undefined(mut self, enabled: bool) -> Self {
+        self.lenient_undefined = enabled;
+        self
+    }
+
+    /// The file `meta: refresh_inventory` reloads the inventory from.
+    pub fn inventory_file(mut self, path: PathBuf) -> Self {
+        self.inventory_file = Some(path);
+        self
+    }
+
+    /// How long to wait for a connection to a host to be established.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Host settings from an OpenSSH client configuration, which inventory
+    /// variables override.
+    pub fn ssh_config(mut self, config: SshConfig) -> Self {
+        self.ssh_config = config;
+        self
+    }
+
+    /// How SSH host keys are checked, unless a host's own settings say
+    /// otherwise.
+    pub fn host_key_checking(mut self, check: HostKeyCheck) -> Self {
+        self.host_keys = check;
+        self
+    }
+
+    /// The key to log in with where `ansible_ssh_private_key_file` isn't
+    /// set.
+    pub fn private_key(mut self, path: Option<PathBuf>) -> Self {
+        self.private_key = path.map(|path| Auth::key(&path.display().to_string()));
+        self
+    }
+
+    /// The password to log in with where `ansible_password` isn't set.
+    pub fn password(mut self, password: Option<String>) -> Self {
+        self.password = password.map(|password| Auth::password(&password));
+        self
+    }
+
+    /// Asks for key passphrases and keyboard-interactive answers.
+    pub fn prompter(mut self, prompter: Arc<dyn Prompter>) -> Self {
+        self.prompter = Some(prompter);
+        self
+    }
+
+    /// Calls `hook` with each "FAILED - RETRYING" line as soon as the
+    /// attempt fails, rather than once the task is done.
+    pub fn on_retry(mut self, hook: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.on_retry = Some(RetryHook(Box::new(hook)));
+        self
+    }
+
+    /// Run notified handlers even on hosts that failed.
+    pub fn force_handlers(mut self, enabled: bool) -> Self {
+        self.force_handlers = enabled;
+        self
+    }
+
+    /// The modules tasks can use, in place of the built-in ones.
+    pub fn modules(mut self, registry: Arc<ModuleRegistry>) -> Self {
+        self.modules = registry;
+        self
+    }
+
+    fn inventory(&self) -> Arc<Inventory> {
+        self.inventory.read().unwrap().clone()
+    }
+
+    fn template_env(&self, vars: &HostVars) -> template::Environment {
+        crate::modules::template::environment(vars)
+            .lenient(self.lenient_undefined)
+            .lookups(self.lookups.clone())
+    }
+
     fn should_run_task(&self, task: &Task) -> bool {
         // If skip_tags is set and task has any of those tags, skip it
         if !self.skip_tags.is_empty() {
@@ -150,8 +426,9 @@ impl Executor {
         true
     }
 
-    pub fn run_play(&self, play: &Play, auth: &Auth) -> Vec<PlayResult> {
-        let hosts = self.resolve_hosts(&play.hosts);
+    pub fn run_play(&self, play: &Play) -> Vec<PlayResult> {
+        let mut hosts = self.resolve_hosts(&play.hosts);
+        play.order.sort_hosts(&mut hosts);
 
         // Build a thread pool with forks threads
         let pool = rayon::ThreadPoolBuilder::new()
@@ -159,12 +436,279 @@ impl Executor {
             .build()
             .unwrap();
 
-        pool.install(|| {
-            hosts
-                .par_iter()
-                .map(|host_name| self.run_play_on_host(play, host_name, auth))
-                .collect()
-        })
+        let mut results = Vec::new();
+        for batch in play.batches(&hosts) {
+            let status = BatchStatus::new(batch.len());
+            let batch_results: Vec<PlayResult> = pool.install(|| match play.strategy {
+                Strategy::Linear => self.run_linear(play, batch, &status),
+                Strategy::Free => batch
+                    .par_iter()
+                    .map(|host_name| self.run_free(play, host_name, &status))
+                    .collect(),
+            });
+
+            results.extend(batch_results);
+            // A batch in which every host failed also ends the rollout.
+            if status.aborted() || status.play_ended() || status.failed() == batch.len() {
+                break;
+            }
+        }
+        results
+    }
+
+    /// Runs each task on every host before moving on to the next task, with
+    /// at most `forks` hosts working at once. Handlers run once all tasks
+    /// are done.
+    fn run_linear(&self, play: &Play, hosts: &[String], status: &BatchStatus) -> Vec<PlayResult> {
+        let mut states: Vec<HostState> = hosts
+            .par_iter()
+            .map(|host_name| self.start_host(play, host_name, status))
+            .collect();
+
+        for (index, task) in play.tasks.iter().enumerate() {
+            if status.stopped() {
+                break;
+            }
+            if meta_action(task).is_some() {
+                self.run_meta(play, &mut states, task, status);
+                continue;
+            }
+            if task.run_once {
+                self.run_once(play, &mut states, task, status);
+                continue;
+            }
+            states
+                .par_iter_mut()
+                .filter(|state| state.active())
+                .for_each(|state| {
+                    let _permit = status.throttle(index, task);
+                    self.run_host_task(play, state, task, status);
+                });
+        }
+
+        self.run_handlers(play, &mut states, status);
+        states.into_iter().map(|state| state.result).collect()
+    }
+
+    /// Applies a `meta:` task. Host actions apply to each active host whose
+    /// `when` holds; play and batch actions apply if it holds for any host.
+    fn run_meta(&self, play: &Play, states: &mut [HostState], task: &Task, status: &BatchStatus) {
+        let mut matched = Vec::new();
+        for (i, state) in states.iter_mut().enumerate().filter(|(_, state)| state.active()) {
+            let holds = match &task.when {
+                None => Ok(true),
+                Some(when) => eval_when(when, &self.template_env(&state.vars), &state.vars),
+            };
+            match holds {
+                Ok(true) => matched.push(i),
+                Ok(false) => {}
+                Err(e) => self.record_failure(play, state, task, &format!("error evaluating 'when': {}", e), status),
+            }
+        }
+        if matched.is_empty() {
+            return;
+        }
+
+        match meta_action(task).unwrap_or_default() {
+            "flush_handlers" => self.run_handlers(play, states, status),
+            "end_play" => status.end(true),
+            "end_batch" => status.end(false),
+            "end_host" => matched.iter().for_each(|&i| states[i].ended = true),
+            "clear_facts" => matched.iter().for_each(|&i| self.clear_facts(&mut states[i])),
+            "refresh_inventory" => match self.refresh_inventory() {
+                Ok(()) => {
+                    for state in states.iter_mut() {
+                        state.vars.insert("groups".to_string(), self.groups_var());
+                    }
+                }
+                Err(e) => {
+                    for &i in &matched {
+                        self.record_failure(play, &mut states[i], task, &e, status);
+                    }
+                }
+            },
+            "noop" => {}
+            action => {
+                let msg = format!("invalid meta action '{}'", action);
+                for &i in &matched {
+                    self.record_failure(play, &mut states[i], task, &msg, status);
+                }
+            }
+        }
+    }
+
+    /// Forgets the facts gathered for a host.
+    fn clear_facts(&self, state: &mut HostState) {
+        if let Some(facts) = self.facts.lock().unwrap().remove(&state.name) {
+            for key in facts.keys() {
+                state.vars.remove(key);
+            }
+        }
+    }
+
+    /// Reloads the inventory from the file it came from, so later plays see
+    /// hosts and groups added since the run started.
+    fn refresh_inventory(&self) -> Result<(), String> {
+        let Some(path) = &self.inventory_file else {
+            return Err("no inventory file to refresh from".to_string());
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read inventory {}: {}", path.display(), e))?;
+        *self.inventory.write().unwrap() = Arc::new(Inventory::from_ini(&content));
+        Ok(())
+    }
+
+    /// Runs a `run_once` task on the first active host of the batch and
+    /// hands its result, registered variable and notifications to the rest.
+    /// A task skipped by tags is skipped on every host.
+    fn run_once(&self, play: &Play, states: &mut [HostState], task: &Task, status: &BatchStatus) {
+        let mut active = states.iter_mut().filter(|state| state.active());
+        if !self.should_run_task(task) {
+            active.for_each(|state| self.run_host_task(play, state, task, status));
+            return;
+        }
+        let Some(first) = active.next() else {
+            return;
+        };
+        self.run_host_task(play, first, task, status);
+        let Some(task_result) = first.result.task_results.last().cloned() else {
+            return;
+        };
+        for state in active {
+            self.share_once(play, state, task, &task_result, status);
+        }
+    }
+
+    /// Runs a `run_once` task under the free strategy: the first host to
+    /// reach it runs it, and later hosts wait for and take its result.
+    fn run_once_free(&self, play: &Play, state: &mut HostState, index: usize, task: &Task, status: &BatchStatus) {
+        if !self.should_run_task(task) {
+            self.run_host_task(play, state, task, status);
+            return;
+        }
+        let once = status.once(index);
+        let mut ran = false;
+        let task_result = once.get_or_init(|| {
+            ran = true;
+            let _permit = status.throttle(index, task);
+            self.run_host_task(play, state, task, status);
+            state.result.task_results.last().cloned()
+        });
+        if let (false, Some(task_result)) = (ran, task_result) {
+            self.share_once(play, state, task, task_result, status);
+        }
+    }
+
+    /// Gives a host the result of a `run_once` task that ran on another.
+    fn share_once(&self, play: &Play, state: &mut HostState, task: &Task, task_result: &TaskResult, status: &BatchStatus) {
+        let result = &task_result.result;
+        if let Some(reg) = &task.register {
+            state.vars.insert_unsafe(reg.clone(), registered(result));
+        }
+        if result.changed {
+            if let Err(e) = notify(task, &self.template_env(&state.vars), &state.vars, &mut state.notified) {
+                self.record_failure(play, state, task, &e, status);
+                return;
+            }
+        }
+        if result.failed {
+            status.host_failed(play, play.any_errors_fatal || task.any_errors_fatal);
+        }
+        state.result.record(TaskResult {
+            host: state.name.clone(),
+            ..task_result.clone()
+        });
+    }
+
+    /// Runs the whole play on one host without waiting for the others.
+    fn run_free(&self, play: &Play, host_name: &str, status: &BatchStatus) -> PlayResult {
+        let mut state = self.start_host(play, host_name, status);
+        for (index, task) in play.tasks.iter().enumerate() {
+            if !state.active() || status.stopped() {
+                break;
+            }
+            if meta_action(task).is_some() {
+                self.run_meta(play, std::slice::from_mut(&mut state), task, status);
+                continue;
+            }
+            if task.run_once {
+                self.run_once_free(play, &mut state, index, task, status);
+                continue;
+            }
+            let _permit = status.throttle(index, task);
+            self.run_host_task(play, &mut state, task, status);
+        }
+
+        self.run_handlers(play, std::slice::from_mut(&mut state), status);
+        state.result
+    }
+
+    /// Runs notified handlers in the order they are defined in the play,
+    /// each across all hosts that notified it. Handlers may notify other
+    /// handlers: later ones run in the same pass, earlier ones in another
+    /// pass of the same flush. A handler runs at most once per host in a
+    /// flush, so handlers that notify each other can't loop. Failed hosts
+    /// and aborted plays skip their handlers unless handlers are forced.
+    fn run_handlers(&self, play: &Play, states: &mut [HostState], status: &BatchStatus) {
+        let force = self.force_handlers || play.force_handlers;
+        let can_run = |state: &HostState| state.conn.is_some() && !state.ended && (force || !state.result.is_failed());
+
+        // Handlers that already ran on each host in this flush.
+        let mut ran: Vec<HashSet<usize>> = vec![HashSet::new(); states.len()];
+        let mut cursor = 0;
+        while (force || !status.aborted()) && !status.ended() {
+            for (state, ran) in states.iter_mut().zip(&ran) {
+                self.queue_handlers(play, state, status);
+                state.pending_handlers.retain(|index| !ran.contains(index));
+            }
+            let pending: BTreeSet<usize> = states
+                .iter()
+                .filter(|state| can_run(state))
+                .flat_map(|state| state.pending_handlers.iter().copied())
+                .collect();
+            let Some(&index) = pending.range(cursor..).next().or(pending.first()) else {
+                break;
+            };
+            cursor = index + 1;
+
+            let handler = &play.handlers[index];
+            states.par_iter_mut().zip(&mut ran).for_each(|(state, ran)| {
+                if state.pending_handlers.remove(&index) && can_run(state) {
+                    ran.insert(index);
+                    self.run_and_record(play, state, handler, status);
+                }
+            });
+        }
+
+        for state in states.iter_mut() {
+            state.notified.clear();
+            state.pending_handlers.clear();
+        }
+    }
+
+    /// Turns a host's notifications into the handlers that answer them,
+    /// by templated name or `listen` topic. A handler name that fails to
+    /// template fails the host.
+    fn queue_handlers(&self, play: &Play, state: &mut HostState, status: &BatchStatus) {
+        if state.notified.is_empty() {
+            return;
+        }
+        let env = self.template_env(&state.vars);
+        for (index, handler) in play.handlers.iter().enumerate() {
+            let name = match handler.name.as_ref().map(|name| env.render_str(name, &state.vars)).transpose() {
+                Ok(name) => name,
+                Err(e) => {
+                    state.notified.clear();
+                    let msg = format!("error templating handler name: {}", e);
+                    self.record_failure(play, state, handler, &msg, status);
+                    return;
+                }
+            };
+            if name.iter().chain(&handler.listen).any(|topic| state.notified.contains(topic)) {
+                state.pending_handlers.insert(index);
+            }
+        }
+        state.notified.clear();
     }
 
     fn resolve_hosts(&self, pattern: &str) -> Vec<String> {
@@ -180,8 +724,9 @@ impl Executor {
     }
 
     fn resolve_pattern(&self, pattern: &str) -> Vec<String> {
+        let inventory = self.inventory();
         if pattern == "all" {
-            return self.inventory.hosts.keys().cloned().collect();
+            return inventory.hosts.keys().cloned().collect();
         }
 
         if pattern == "localhost" {
@@ -189,12 +734,12 @@ impl Executor {
         }
 
         // Check if it's a group
-        if let Some(group) = self.inventory.groups.get(pattern) {
+        if let Some(group) = inventory.groups.get(pattern) {
             return group.hosts.clone();
         }
 
         // Check if it's a host
-        if self.inventory.hosts.contains_key(pattern) {
+        if inventory.hosts.contains_key(pattern) {
             return vec![pattern.to_string()];
         }
 
@@ -202,11 +747,12 @@ impl Executor {
         pattern
             .split(',')
             .map(|s| s.trim().to_string())
-            .filter(|h| self.inventory.hosts.contains_key(h))
+            .filter(|h| inventory.hosts.contains_key(h))
             .collect()
     }
 
     fn resolve_limit(&self, limit: &str) -> HashSet<String> {
+        let inventory = self.inventory();
         let mut included: HashSet<String> = HashSet::new();
         let mut excluded: HashSet<String> = HashSet::new();
 
@@ -231,7 +777,7 @@ impl Executor {
 
         // If no inclusions specified, start with all hosts
         if included.is_empty() {
-            included = self.inventory.hosts.keys().cloned().collect();
+            included = inventory.hosts.keys().cloned().collect();
         }
 
         // Remove excluded hosts
@@ -243,8 +789,9 @@ impl Executor {
     }
 
     fn expand_limit_pattern(&self, pattern: &str) -> Vec<String> {
+        let inventory = self.inventory();
         // Check if it's a group
-        if let Some(group) = self.inventory.groups.get(pattern) {
+        if let Some(group) = inventory.groups.get(pattern) {
             return group.hosts.clone();
         }
 
@@ -252,8 +799,7 @@ impl Executor {
         if pattern.contains('*') {
             let regex_pattern = format!("^{}$", pattern.replace('*', ".*"));
             if let Ok(re) = regex::Regex::new(&regex_pattern) {
-                return self
-                    .inventory
+                return inventory
                     .hosts
                     .keys()
                     .filter(|h| re.is_match(h))
@@ -267,779 +813,668 @@ impl Executor {
             return pattern
                 .split(',')
                 .map(|s| s.trim().to_string())
-                .filter(|h| self.inventory.hosts.contains_key(h))
+                .filter(|h| inventory.hosts.contains_key(h))
                 .collect();
         }
 
         // Single host
-        if self.inventory.hosts.contains_key(pattern) {
+        if inventory.hosts.contains_key(pattern) {
             return vec![pattern.to_string()];
         }
 
         vec![]
     }
 
-    fn run_play_on_host(&self, play: &Play, host_name: &str, auth: &Auth) -> PlayResult {
-        let mut result = PlayResult {
-            host: host_name.to_string(),
-            ..Default::default()
+    /// Connects to a host and builds its variables. A host that cannot be
+    /// reached is returned without a connection and takes no further part
+    /// in the play.
+    fn start_host(&self, play: &Play, host_name: &str, status: &BatchStatus) -> HostState {
+        let inventory = self.inventory();
+        let mut state = HostState {
+            name: host_name.to_string(),
+            conn: None,
+            delegates: HashMap::new(),
+            vars: HostVars::default(),
+            notified: HashSet::new(),
+            pending_handlers: BTreeSet::new(),
+            ended: false,
+            result: PlayResult {
+                host: host_name.to_string(),
+                ..Default::default()
+            },
         };
 
         // Get host info
-        let host = match self.inventory.hosts.get(host_name) {
+        let host = match inventory.hosts.get(host_name) {
             Some(h) => h,
             None => {
-                result.failed = 1;
-                return result;
+                state.result.unreachable = 1;
+                state.result.unreachable_msg = Some(format!("host '{}' not found in inventory", host_name));
+                status.host_failed(play, play.any_errors_fatal);
+                return state;
             }
         };
 
-        // Build connection
-        let connection_type = host.vars.get("ansible_connection").map(|s| s.as_str());
-
-        let conn: Connection = if connection_type == Some("local") {
-            Connection::Local(LocalConnection::new())
-        } else {
-            let connect_host = host.vars.get("ansible_host").unwrap_or(&host.name);
-            let port: u16 = host
-                .vars
-                .get("ansible_port")
-                .and_then(|p| p.parse().ok())
-                .unwrap_or(22);
-            let user = host
-                .vars
-                .get("ansible_user")
-                .cloned()
-                .unwrap_or_else(|| "root".to_string());
-
-            match SshConnection::connect(connect_host, port, &user, auth.clone()) {
-                Ok(c) => Connection::Ssh(c),
-                Err(e) => {
-                    result.failed = 1;
-                    result.task_results.push(TaskResult {
-                        task_name: "CONNECT".to_string(),
-                        host: host_name.to_string(),
-                        result: ModuleResult::failed(&format!("connection failed: {}", e)),
-                    });
-                    return result;
-                }
+        let conn = match self.connect(play, host_name) {
+            Ok(c) => c,
+            Err(e) => {
+                state.result.unreachable = 1;
+                state.result.unreachable_msg = Some(format!("connection failed: {}", e));
+                status.host_failed(play, play.any_errors_fatal);
+                return state;
             }
         };
 
         // Build variables for this host (order matters for precedence)
-        let mut host_vars = HashMap::new();
+        // Values are stored unrendered; templates inside them are resolved
+        // lazily when a task or template uses them.
+        let mut host_vars = HostVars::default();
+
+        // 1. Group and host vars from inventory (lowest precedence)
+        let inventory_vars = inventory.get_host_vars(host_name);
+        host_vars.insert("inventory_hostname".to_string(), Value::from(host_name));
+        host_vars.insert(
+            "ansible_host".to_string(),
+            Value::from(inventory_vars.get("ansible_host").unwrap_or(&host.name).as_str()),
+        );
+        host_vars.insert("playbook_dir".to_string(), Value::from(self.playbook_dir.display().to_string()));
+        host_vars.insert("groups".to_string(), self.groups_var());
+        host_vars.insert(
+            "group_names".to_string(),
+            Value::from(inventory.get_host_groups(host_name)),
+        );
+        for (k, v) in &inventory_vars {
+            host_vars.insert(k.clone(), Value::from(v.as_str()));
+        }
 
-        // 1. Host vars from inventory (lowest precedence)
-        host_vars.insert("inventory_hostname".to_string(), host_name.to_string());
-        host_vars.insert("ansible_host".to_string(), host.vars.get("ansible_host").unwrap_or(&host.name).clone());
-        for (k, v) in &host.vars {
-            host_vars.insert(k.clone(), v.clone());
+        // 2. Facts from earlier tasks and plays
+        if let Some(facts) = self.facts.lock().unwrap().get(host_name) {
+            for (k, v) in facts {
+                host_vars.insert_unsafe(k.clone(), v.clone());
+            }
         }
 
-        // 2. Play vars
+        // 3. Play vars
         for (k, v) in &play.vars {
-            if let Some(s) = v.as_str() {
-                host_vars.insert(k.clone(), s.to_string());
-            }
+            host_vars.insert(k.clone(), serde_json::to_value(v).unwrap_or(Value::Null));
         }
 
-        // 3. Extra vars (highest precedence)
+        // 4. Extra vars (highest precedence)
         for (k, v) in &self.extra_vars {
-            host_vars.insert(k.clone(), v.clone());
+            host_vars.insert(k.clone(), Value::from(v.as_str()));
         }
 
-        // Track notified handlers
-        let mut notified_handlers: HashSet<String> = HashSet::new();
+        state.conn = Some(conn);
+        state.vars = host_vars;
+        state
+    }
+
+    /// Opens a connection to a host using its inventory variables, by the
+    /// transport `ansible_connection` names. Hosts outside the inventory are
+    /// reached over SSH by name, except for `localhost` which always runs
+    /// locally. SSH connections come from the pool.
+    fn connect(&self, play: &Play, host_name: &str) -> Result<Arc<dyn Connection>> {
+        let inventory = self.inventory();
+        let known = inventory.hosts.contains_key(host_name);
+        let vars = inventory.get_host_vars(host_name);
+        let var = |name: &str| vars.get(name);
+
+        let kind = match var("ansible_connection") {
+            Some(kind) => kind.as_str(),
+            None if !known && matches!(host_name, "localhost" | "127.0.0.1") => "local",
+            None => "ssh",
+        };
+        let remote_addr = var("ansible_host").map(String::as_str).unwrap_or(host_name);
+
+        match kind {
+            "local" => Ok(Arc::new(LocalConnection::new())),
+            "docker" | "podman" => {
+                let runtime = var(&format!("ansible_{}_executable", kind)).map(String::as_str).unwrap_or(kind);
+                let mut conn = DockerConnection::new(runtime, remote_addr);
+                if let Some(user) = var("ansible_user") {
+                    conn = conn.user(user);
+                }
+                if let Some(args) = var(&format!("ansible_{}_extra_args", kind)) {
+                    conn = conn.extra_args(args);
+                }
+                Ok(Arc::new(conn))
+            }
+            "chroot" | "nspawn" => {
+                let mut conn = match kind {
+                    "chroot" => ChrootConnection::chroot(remote_addr),
+                    _ => ChrootConnection::nspawn(remote_addr),
+                };
+                if let Some(exe) = var(&format!("ansible_{}_exe", kind)) {
+                    conn = conn.executable(exe);
+                }
+                Ok(Arc::new(conn))
+            }
+            "kubectl" => {
+                let pod = var("ansible_kubectl_pod").map(String::as_str).unwrap_or(remote_addr);
+                let mut conn = KubectlConnection::new(pod);
+                let kubectl_var = |name: &str| var(&format!("ansible_kubectl_{}", name));
+                if let Some(path) = kubectl_var("executable") {
+                    conn = conn.executable(path);
+                }
+                if let Some(namespace) = kubectl_var("namespace") {
+                    conn = conn.namespace(namespace);
+                }
+                if let Some(container) = kubectl_var("container") {
+                    conn = conn.container(container);
+                }
+                if let Some(context) = kubectl_var("context") {
+                    conn = conn.context(context);
+                }
+                if let Some(path) = kubectl_var("kubeconfig") {
+                    conn = conn.kubeconfig(path);
+                }
+                if let Some(args) = kubectl_var("extra_args") {
+                    conn = conn.extra_args(args);
+                }
+                Ok(Arc::new(conn))
+            }
+            "ssh" => self.connect_ssh(play, remote_addr, var),
+            other => Err(anyhow::anyhow!("unknown connection type '{}'", other)),
+        }
+    }
 
-        // Execute tasks
-        for task in &play.tasks {
-            // Check if task should run based on tags
-            if !self.should_run_task(task) {
-                let task_name = task.name.clone().unwrap_or_else(|| "unnamed".to_string());
-                result.skipped += 1;
-                result.task_results.push(TaskResult {
-                    task_name,
-                    host: conn.host().to_string(),
-                    result: ModuleResult::ok("skipped (tags)"),
-                });
-                continue;
+    /// An SSH connection to `remote_addr`, through any jump hosts set for
+    /// it. Options given for ssh's command line beat the config file, and
+    /// the inventory's own variables beat both.
+    fn connect_ssh<'a>(
+        &self,
+        play: &Play,
+        remote_addr: &str,
+        var: impl Fn(&str) -> Option<&'a String>,
+    ) -> Result<Arc<dyn Connection>> {
+        let mut config = self.ssh_config.lookup(remote_addr);
+        for name in ["ansible_ssh_common_args", "ansible_ssh_extra_args"] {
+            if let Some(args) = var(name) {
+                config.apply_args(args);
             }
+        }
+        let host = config.hostname.clone().unwrap_or_else(|| remote_addr.to_string());
+        let port: u16 = var("ansible_port").and_then(|p| p.parse().ok()).or(config.port).unwrap_or(22);
+        let user = var("ansible_user").or(config.user.as_ref()).cloned().unwrap_or_else(|| "root".to_string());
+        let timeout = config.connect_timeout.unwrap_or(self.connect_timeout);
+        let checking = var("ansible_host_key_checking");
+        let host_keys = self.host_key_check(&config, checking, &host, &user);
+        let key = var("ansible_ssh_private_key_file").map(|key| Auth::key(&expand_path(key, &host, &user).display().to_string()));
+        let password = var("ansible_password").or_else(|| var("ansible_ssh_pass")).map(|p| Auth::password(p));
+        let login = self.login(&user, key, config.identity_paths(remote_addr, &user), password);
+
+        // Each hop is set up from its own config entry, logging in as the
+        // target's user unless told otherwise.
+        let mut route = Vec::new();
+        let mut jump: Option<Arc<JumpHost>> = None;
+        for hop in config.proxy_jump.as_deref().map(parse_proxy_jump).unwrap_or_default() {
+            let hop_config = self.ssh_config.lookup(&hop.host);
+            let hop_host = hop_config.hostname.clone().unwrap_or_else(|| hop.host.clone());
+            let hop_port = hop.port.or(hop_config.port).unwrap_or(22);
+            let hop_user = hop.user.or_else(|| hop_config.user.clone()).unwrap_or_else(|| user.clone());
+            let hop_login = self.login(&hop_user, None, hop_config.identity_paths(&hop.host, &hop_user), None);
+            let hop_timeout = hop_config.connect_timeout.unwrap_or(self.connect_timeout);
+            let hop_keys = self.host_key_check(&hop_config, checking, &hop_host, &hop_user);
+            route.push(PoolKey {
+                host: hop_host.clone(),
+                port: hop_port,
+                user: hop_user.clone(),
+                become_user: None,
+            });
+            let via = jump.clone();
+            jump = Some(self.connections.jump(route.clone(), || {
+                JumpHost::connect(via.as_deref(), &hop_host, hop_port, &hop_login, hop_timeout, &hop_keys)
+                    .map_err(|e| anyhow::anyhow!("jump host {}: {}", hop.host, e))
+            })?);
+        }
 
-            let task_result = self.run_task(&conn, task, &mut host_vars, &mut notified_handlers);
+        let key = PoolKey {
+            host: host.clone(),
+            port,
+            user: user.clone(),
+            become_user: play.become_.then(|| play.become_user.clone().unwrap_or_else(|| "root".to_string())),
+        };
+        self.connections.get(key, || match &jump {
+            Some(jump) => SshConnection::connect_via(jump, &host, port, &login, timeout, &host_keys),
+            None => SshConnection::connect(&host, port, &login, timeout, &host_keys),
+        })
+    }
 
-            match &task_result.result {
-                r if r.failed => result.failed += 1,
-                r if r.changed => result.changed += 1,
-                _ => result.ok += 1,
-            }
+    /// How to log in as `user`: the host's own key and password, or else the
+    /// run's; its IdentityFile keys; the agent; ssh's default keys; and
+    /// last keyboard-interactive.
+    fn login(&self, user: &str, key: Option<Auth>, identities: Vec<PathBuf>, password: Option<Auth>) -> Login {
+        let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+        let key_file = |path: PathBuf| Auth::key(&path.display().to_string());
+        let methods = key
+            .or_else(|| self.private_key.clone())
+            .into_iter()
+            .chain(identities.into_iter().filter(|path| path.exists()).map(key_file))
+            .chain(password.or_else(|| self.password.clone()))
+            .chain([Auth::Agent])
+            .chain(default_identities(&home).into_iter().map(key_file))
+            .chain([Auth::KeyboardInteractive]);
+        let login = methods.fold(Login::new(user), Login::method);
+        match &self.prompter {
+            Some(prompter) => login.prompter(prompter.clone()),
+            None => login,
+        }
+    }
 
-            result.task_results.push(task_result);
+    /// Host key checking for one host: the run's settings, then its ssh
+    /// options, then `ansible_host_key_checking`.
+    fn host_key_check(&self, config: &HostConfig, checking: Option<&String>, host: &str, user: &str) -> HostKeyCheck {
+        let mut check = self.host_keys.clone();
+        if let Some(file) = &config.user_known_hosts_file {
+            check = check.user_file(expand_path(file, host, user));
+        }
+        if let Some(policy) = checking.and_then(|c| HostKeyPolicy::parse(c)).or(config.strict_host_key_checking) {
+            check.policy = policy;
         }
+        check
+    }
 
-        // Execute notified handlers
-        for handler in &play.handlers {
-            if let Some(name) = &handler.name {
-                if notified_handlers.contains(name) {
-                    let task_result = self.run_task(&conn, handler, &mut host_vars, &mut HashSet::new());
+    /// The `groups` magic variable: every group name mapped to its hosts.
+    fn groups_var(&self) -> Value {
+        let inventory = self.inventory();
+        let mut groups = serde_json::Map::new();
+        groups.insert("all".to_string(), Value::from(inventory.get_all_hosts()));
+        for name in inventory.groups.keys() {
+            groups.insert(name.clone(), Value::from(inventory.get_group_hosts(name)));
+        }
+        Value::Object(groups)
+    }
+
+    fn run_host_task(&self, play: &Play, state: &mut HostState, task: &Task, status: &BatchStatus) {
+        // Check if task should run based on tags
+        if !self.should_run_task(task) {
+            let task_name = task.name.clone().unwrap_or_else(|| "unnamed".to_string());
+            state.result.skipped += 1;
+            state.result.task_results.push(TaskResult {
+                task_name,
+                host: state.name.clone(),
+                result: ModuleResult::ok("skipped (tags)"),
+                retries: Vec::new(),
+            });
+            return;
+        }
 
-                    match &task_result.result {
-                        r if r.failed => result.failed += 1,
-                        r if r.changed => result.changed += 1,
-                        _ => result.ok += 1,
-                    }
+        self.run_and_record(play, state, task, status);
+    }
 
-                    result.task_results.push(task_result);
-                }
+    /// Runs a task or handler on a host, counting a failure against the batch.
+    fn run_and_record(&self, play: &Play, state: &mut HostState, task: &Task, status: &BatchStatus) {
+        let Some(own_conn) = &state.conn else {
+            return;
+        };
+
+        let delegate = match self.delegate_host(task, &state.vars) {
+            Ok(delegate) => delegate,
+            Err(e) => {
+                self.record_failure(play, state, task, &e, status);
+                return;
             }
+        };
+
+        // Delegated tasks keep this host's variables but run elsewhere.
+        let conn = match &delegate {
+            None => Ok(&**own_conn),
+            Some(target) => match state.delegates.entry(target.clone()) {
+                std::collections::hash_map::Entry::Occupied(entry) => Ok(&**entry.into_mut()),
+                std::collections::hash_map::Entry::Vacant(entry) => self
+                    .connect(play, target)
+                    .map(|c| &**entry.insert(c))
+                    .map_err(|e| format!("failed to connect to delegated host '{}': {}", target, e)),
+            },
+        };
+
+        let task_result = match conn {
+            Ok(conn) => self.run_task(conn, task, &mut state.vars, &mut state.notified),
+            Err(e) => TaskResult {
+                task_name: task.name.clone().unwrap_or_else(|| "unnamed".to_string()),
+                host: state.name.clone(),
+                result: ModuleResult::failed(&e),
+                retries: Vec::new(),
+            },
+        };
+
+        if let Some(facts) = task_result.result.extra.get("ansible_facts") {
+            let target = match (&delegate, task.delegate_facts) {
+                (Some(target), true) => target.clone(),
+                _ => state.name.clone(),
+            };
+            self.store_facts(&target, facts, &mut state.vars, &state.name);
         }
 
-        result
+        if task_result.result.failed {
+            status.host_failed(play, play.any_errors_fatal || task.any_errors_fatal);
+        }
+        state.result.record(task_result);
+    }
+
+    /// Records a task that failed before it could run.
+    fn record_failure(&self, play: &Play, state: &mut HostState, task: &Task, msg: &str, status: &BatchStatus) {
+        status.host_failed(play, play.any_errors_fatal || task.any_errors_fatal);
+        state.result.record(TaskResult {
+            task_name: task.name.clone().unwrap_or_else(|| "unnamed".to_string()),
+            host: state.name.clone(),
+            result: ModuleResult::failed(msg),
+            retries: Vec::new(),
+        });
+    }
+
+    /// Where a task should run instead of its own host: the templated
+    /// `delegate_to`, or `localhost` for `local_action`.
+    fn delegate_host(&self, task: &Task, vars: &HostVars) -> Result<Option<String>, String> {
+        if task.module.contains_key("local_action") {
+            return Ok(Some("localhost".to_string()));
+        }
+        let Some(delegate_to) = &task.delegate_to else {
+            return Ok(None);
+        };
+        self.template_env(vars)
+            .render_str(delegate_to, vars)
+            .map(|host| Some(host.trim().to_string()))
+            .map_err(|e| format!("error templating 'delegate_to': {}", e))
+    }
+
+    /// Saves facts for `target`. They are merged into `vars` right away when
+    /// `target` is the current host; other hosts pick them up in later plays.
+    fn store_facts(&self, target: &str, facts: &serde_yaml::Value, vars: &mut HostVars, host: &str) {
+        let Ok(Value::Object(facts)) = serde_json::to_value(facts) else {
+            return;
+        };
+        if target == host {
+            for (k, v) in &facts {
+                vars.insert_unsafe(k.clone(), v.clone());
+            }
+        }
+        self.facts
+            .lock()
+            .unwrap()
+            .entry(target.to_string())
+            .or_default()
+            .extend(facts);
     }
 
     fn run_task(
         &self,
-        conn: &Connection,
+        conn: &dyn Connection,
         task: &Task,
-        vars: &mut HashMap<String, String>,
+        vars: &mut HostVars,
         notified: &mut HashSet<String>,
     ) -> TaskResult {
         let task_name = task.name.clone().unwrap_or_else(|| "unnamed".to_string());
 
+        let env = self.template_env(vars);
+
         // Check 'when' condition
         if let Some(when) = &task.when {
-            let rendered = template::render(when, vars);
-            if !eval_when(&rendered, vars) {
-                return TaskResult {
-                    task_name,
-                    host: conn.host().to_string(),
-                    result: ModuleResult::ok("skipped"),
-                };
+            match eval_when(when, &env, vars) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return TaskResult {
+                        task_name,
+                        host: conn.host().to_string(),
+                        result: ModuleResult::ok("skipped"),
+                        retries: Vec::new(),
+                    };
+                }
+                Err(e) => {
+                    return TaskResult {
+                        task_name,
+                        host: conn.host().to_string(),
+                        result: ModuleResult::failed(&format!("error evaluating 'when': {}", e)),
+                        retries: Vec::new(),
+                    };
+                }
             }
         }
 
         // Find module and args
-        let (module_name, module_args) = match extract_module(task, vars) {
-            Some(m) => m,
-            None => {
+        let (module_name, module_args) = match extract_module(task, &self.modules, &env, vars) {
+            Ok(m) => m,
+            Err(e) => {
                 return TaskResult {
                     task_name,
                     host: conn.host().to_string(),
-                    result: ModuleResult::failed("no module found in task"),
+                    result: ModuleResult::failed(&e),
+                    retries: Vec::new(),
                 };
             }
         };
 
-        // Execute module
-        let result = if self.check_mode {
-            ModuleResult::ok("check mode")
-        } else {
-            run_module(conn, &module_name, &module_args, vars)
-        };
+        // Execute module, retrying until the `until` condition holds
+        let mut retries = Vec::new();
+        let result = match &task.until {
+            None => self.execute(conn, task, &module_name, &module_args, &env, vars),
+            Some(until) => {
+                // Like Ansible, `retries` counts the attempts after the first
+                // and defaults to 3.
+                let attempts = match task.retries.unwrap_or(3) {
+                    n if n <= 0 => 1,
+                    n => n as u64 + 1,
+                };
+                let host = vars
+                    .get("inventory_hostname")
+                    .and_then(Value::as_str)
+                    .unwrap_or(conn.host())
+                    .to_string();
+                let mut attempt = 1;
+                loop {
+                    let mut result = self.execute(conn, task, &module_name, &module_args, &env, vars);
+                    result.extra.insert("attempts".to_string(), attempt.into());
+                    if let Some(reg) = &task.register {
+                        vars.insert_unsafe(reg.clone(), registered(&result));
+                    }
+                    match eval_when(until, &env, vars) {
+                        Ok(true) => break result,
+                        Ok(false) if attempt < attempts => {
+                            let line = format!(
+                                "FAILED - RETRYING: [{}]: {} ({} retries left).",
+                                host,
+                                task_name,
+                                attempts - attempt
+                            );
+                            if let Some(RetryHook(hook)) = &self.on_retry {
+                                hook(&line);
+                            }
+                            retries.push(line);
+                            std::thread::sleep(Duration::from_secs(task.delay.unwrap_or(5)));
+                            attempt += 1;
+                        }
+                        Ok(false) => {
+                            result.failed = true;
+                            break result;
+                        }
+                        Err(e) => break ModuleResult::failed(&format!("error evaluating 'until': {}", e)),
+                    }
+                }
+            }
+        };
 
         // Handle register
         if let Some(reg) = &task.register {
-            vars.insert(format!("{}.stdout", reg), result.stdout.clone());
-            vars.insert(format!("{}.stderr", reg), result.stderr.clone());
-            vars.insert(format!("{}.rc", reg), result.rc.to_string());
-            vars.insert(format!("{}.changed", reg), result.changed.to_string());
-            vars.insert(format!("{}.failed", reg), result.failed.to_string());
+            vars.insert_unsafe(reg.clone(), registered(&result));
         }
 
         // Handle notify
-        if result.changed {
-            if let Some(notify_list) = &task.notify {
-                for handler_name in notify_list {
-                    notified.insert(handler_name.clone());
-                }
-            }
-        }
+        let result = match result.changed {
+            true => match notify(task, &env, vars, notified) {
+                Ok(()) => result,
+                Err(e) => ModuleResult::failed(&e).with_output(&result.stdout, &result.stderr, result.rc),
+            },
+            false => result,
+        };
 
         TaskResult {
             task_name,
             host: conn.host().to_string(),
             result,
-        }
-    }
-}
-
-fn extract_module(task: &Task, vars: &HashMap<String, String>) -> Option<(String, ModuleArgs)> {
-    let known_modules = [
-        "command", "shell", "copy", "file", "template",
-        "apt", "service", "lineinfile", "raw", "script",
-        "yum", "dnf", "pip", "user", "group",
-    ];
-
-    for (key, value) in &task.module {
-        if known_modules.contains(&key.as_str()) {
-            let mut args = ModuleArgs::new();
-
-            // Handle string arg (e.g., command: "echo hello")
-            if let Some(s) = value.as_str() {
-                args.insert("_raw", &template::render(s, vars));
-            }
-
-            // Handle map args (e.g., apt: { name: nginx, state: present })
-            if let Some(map) = value.as_mapping() {
-                for (k, v) in map {
-                    if let (Some(key_str), Some(val_str)) = (k.as_str(), v.as_str()) {
-                        args.insert(key_str, &template::render(val_str, vars));
-                    } else if let (Some(key_str), Some(val_bool)) = (k.as_str(), v.as_bool()) {
-                        args.insert(key_str, if val_bool { "true" } else { "false" });
-                    }
-                }
-            }
-
-            return Some((key.clone(), args));
-        }
-    }
-
-    None
-}
-
-fn run_module(
-    conn: &Connection,
-    module: &str,
-    args: &ModuleArgs,
-    vars: &HashMap<String, String>,
-) -> ModuleResult {
-    match module {
-        "command" => run_command(conn, args),
-        "shell" => run_shell(conn, args),
-        "raw" => run_raw(conn, args),
-        "script" => run_script(conn, args),
-        "copy" => run_copy(conn, args),
-        "file" => run_file(conn, args),
-        "template" => run_template(conn, args, vars),
-        "apt" => run_apt(conn, args),
-        "service" => run_service(conn, args),
-        "lineinfile" => run_lineinfile(conn, args),
-        "blockinfile" => run_blockinfile(conn, args),
-        _ => ModuleResult::failed(&format!("unknown module: {}", module)),
-    }
-}
-
-fn run_command(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let cmd = match args.get("_raw") {
-        Some(c) => c.clone(),
-        None => match args.require("cmd") {
-            Ok(c) => c.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
-    };
-
-    let chdir = args.get("chdir");
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && {}", dir, cmd)
-    } else {
-        cmd
-    };
-
-    match conn.exec(&full_cmd) {
-        Ok(result) => ModuleResult::changed("command executed")
-            .with_output(&result.stdout, &result.stderr, result.exit_code),
-        Err(e) => ModuleResult::failed(&format!("command failed: {}", e)),
-    }
-}
-
-fn run_shell(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let cmd = match args.get("_raw") {
-        Some(c) => c.clone(),
-        None => match args.require("cmd") {
-            Ok(c) => c.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
-    };
-
-    let chdir = args.get("chdir");
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && sh -c '{}'", dir, cmd.replace('\'', "'\\''"))
-    } else {
-        format!("sh -c '{}'", cmd.replace('\'', "'\\''"))
-    };
-
-    match conn.exec(&full_cmd) {
-        Ok(result) => ModuleResult::changed("shell executed")
-            .with_output(&result.stdout, &result.stderr, result.exit_code),
-        Err(e) => ModuleResult::failed(&format!("shell failed: {}", e)),
-    }
-}
-
-fn run_raw(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let cmd = match args.get("_raw") {
-        Some(c) => c.clone(),
-        None => match args.require("cmd") {
-            Ok(c) => c.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
-    };
-
-    let chdir = args.get("chdir");
-
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && {}", dir, cmd)
-    } else {
-        cmd
-    };
-
-    match conn.exec(&full_cmd) {
-        Ok(result) => ModuleResult::changed("raw command executed")
-            .with_output(&result.stdout, &result.stderr, result.exit_code),
-        Err(e) => ModuleResult::failed(&format!("raw command failed: {}", e)),
-    }
-}
-
-fn run_script(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let script_path = match args.get("_raw_params") {
-        Some(p) => p.clone(),
-        None => match args.require("cmd") {
-            Ok(p) => p.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
-    };
-
-    let chdir = args.get("chdir");
-    let creates = args.get("creates");
-    let removes = args.get("removes");
-
-    if !std::path::Path::new(&script_path).exists() {
-        return ModuleResult::failed(&format!("script not found: {}", script_path));
-    }
-
-    if let Some(path) = creates {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code == 0 => {
-                return ModuleResult::ok("skipped, creates exists");
-            }
-            _ => {}
-        }
-    }
-
-    if let Some(path) = removes {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code != 0 => {
-                return ModuleResult::ok("skipped, removes does not exist");
-            }
-            _ => {}
+            retries,
         }
     }
 
-    let script_content = match std::fs::read(&script_path) {
-        Ok(content) => content,
-        Err(e) => return ModuleResult::failed(&format!("failed to read script: {}", e)),
-    };
-
-    let remote_path = "/tmp/.ansible_script";
-
-    match conn.write_file(remote_path, &script_content, 0o700) {
-        Ok(_) => {}
-        Err(e) => return ModuleResult::failed(&format!("failed to upload script: {}", e)),
-    }
-
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && {}", dir, remote_path)
-    } else {
-        remote_path.to_string()
-    };
-
-    match conn.exec(&full_cmd) {
-        Ok(result) => ModuleResult::changed("script executed")
-            .with_output(&result.stdout, &result.stderr, result.exit_code),
-        Err(e) => ModuleResult::failed(&format!("script failed: {}", e)),
-    }
-}
-
-fn run_copy(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let dest = match args.require("dest") {
-        Ok(d) => d.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let mode = args.get_or("mode", "0644");
-    let mode_int = i32::from_str_radix(&mode, 8).unwrap_or(0o644);
-
-    if let Some(content) = args.get("content") {
-        match conn.read_file(&dest) {
-            Ok(existing) if existing == content.as_bytes() => {
-                return ModuleResult::ok("content unchanged");
-            }
-            _ => {}
+    /// Runs the task's module, detached when the task is `async` and
+    /// stopped once its `timeout` runs out.
+    fn execute(
+        &self,
+        conn: &dyn Connection,
+        task: &Task,
+        module_name: &str,
+        module_args: &ModuleArgs,
+        env: &template::Environment,
+        vars: &HostVars,
+    ) -> ModuleResult {
+        if self.check_mode {
+            return ModuleResult::ok("check mode");
         }
 
-        match conn.write_file(&dest, content.as_bytes(), mode_int) {
-            Ok(_) => ModuleResult::changed("content copied"),
-            Err(e) => ModuleResult::failed(&format!("failed to write: {}", e)),
-        }
-    } else if let Some(src) = args.get("src") {
-        let content = match std::fs::read(src) {
-            Ok(c) => c,
-            Err(e) => return ModuleResult::failed(&format!("failed to read source: {}", e)),
+        let Some(limit) = task.timeout.filter(|secs| *secs > 0) else {
+            return self.execute_module(conn, task, module_name, module_args, env, vars);
         };
+        let deadline = Instant::now() + Duration::from_secs(limit);
+        let result = self.execute_module(&Deadline::new(conn, deadline), task, module_name, module_args, env, vars);
 
-        match conn.read_file(&dest) {
-            Ok(existing) if existing == content => {
-                return ModuleResult::ok("file unchanged");
-            }
-            _ => {}
-        }
-
-        match conn.write_file(&dest, &content, mode_int) {
-            Ok(_) => ModuleResult::changed("file copied"),
-            Err(e) => ModuleResult::failed(&format!("failed to copy: {}", e)),
+        if Instant::now() >= deadline {
+            return ModuleResult::failed(&format!("timed out after {} seconds", limit))
+                .with_output(&result.stdout, &result.stderr, result.rc);
         }
-    } else {
-        ModuleResult::failed("either 'src' or 'content' required")
+        result
     }
-}
 
-fn run_file(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let path = match args.require("path") {
-        Ok(p) => p.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let state = args.get_or("state", "file");
-
-    match state.as_str() {
-        "directory" => {
-            match conn.exec(&format!("test -d {}", path)) {
-                Ok(r) if r.exit_code == 0 => ModuleResult::ok("directory exists"),
-                _ => match conn.exec(&format!("mkdir -p {}", path)) {
-                    Ok(r) if r.exit_code == 0 => ModuleResult::changed("directory created"),
-                    _ => ModuleResult::failed("failed to create directory"),
-                },
-            }
-        }
-        "absent" => {
-            match conn.exec(&format!("test -e {}", path)) {
-                Ok(r) if r.exit_code != 0 => ModuleResult::ok("already absent"),
-                _ => match conn.exec(&format!("rm -rf {}", path)) {
-                    Ok(r) if r.exit_code == 0 => ModuleResult::changed("removed"),
-                    _ => ModuleResult::failed("failed to remove"),
-                },
-            }
-        }
-        "touch" => {
-            match conn.exec(&format!("touch {}", path)) {
-                Ok(r) if r.exit_code == 0 => ModuleResult::changed("touched"),
-                _ => ModuleResult::failed("failed to touch"),
-            }
+    fn execute_module(
+        &self,
+        conn: &dyn Connection,
+        task: &Task,
+        module_name: &str,
+        module_args: &ModuleArgs,
+        env: &template::Environment,
+        vars: &HostVars,
+    ) -> ModuleResult {
+        let Some(module) = self.modules.get(module_name) else {
+            return ModuleResult::failed(&format!("unknown module: {}", module_name));
+        };
+        let ctx = ModuleContext { env, vars };
+        match task.async_ {
+            Some(limit) if limit > 0 => match module.command(module_args) {
+                Some(Ok(cmd)) => {
+                    let async_dir = async_status::async_dir(&ctx);
+                    async_status::run_job(conn, &async_dir, &cmd, limit, task.poll.unwrap_or(10))
+                }
+                Some(Err(e)) => ModuleResult::failed(&e),
+                None => ModuleResult::failed(&format!("the {} module does not support async", module_name)),
+            },
+            _ => module.run(conn, module_args, &ctx),
         }
-        _ => ModuleResult::ok("file exists"),
     }
 }
 
-fn run_template(conn: &Connection, args: &ModuleArgs, vars: &HashMap<String, String>) -> ModuleResult {
-    let src = match args.require("src") {
-        Ok(s) => s.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let dest = match args.require("dest") {
-        Ok(d) => d.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let mode = args.get_or("mode", "0644");
-    let mode_int = i32::from_str_radix(&mode, 8).unwrap_or(0o644);
-
-    let template_content = match std::fs::read_to_string(&src) {
-        Ok(c) => c,
-        Err(e) => return ModuleResult::failed(&format!("failed to read template: {}", e)),
-    };
-
-    let rendered = template::render(&template_content, vars);
-
-    match conn.read_file(&dest) {
-        Ok(existing) if existing == rendered.as_bytes() => {
-            return ModuleResult::ok("template unchanged");
-        }
-        _ => {}
-    }
-
-    match conn.write_file(&dest, rendered.as_bytes(), mode_int) {
-        Ok(_) => ModuleResult::changed("template rendered"),
-        Err(e) => ModuleResult::failed(&format!("failed to write: {}", e)),
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.connections.close_all();
     }
 }
 
-fn run_apt(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let name = match args.require("name") {
-        Ok(n) => n.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let state = args.get_or("state", "present");
-
-    let is_installed = conn
-        .exec(&format!("dpkg-query -W -f='${{Status}}' {} 2>/dev/null | grep -q 'ok installed'", name))
-        .map(|r| r.exit_code == 0)
-        .unwrap_or(false);
-
-    match state.as_str() {
-        "present" | "installed" => {
-            if is_installed {
-                ModuleResult::ok("already installed")
-            } else {
-                match conn.exec(&format!("DEBIAN_FRONTEND=noninteractive apt-get install -y -qq {}", name)) {
-                    Ok(r) if r.exit_code == 0 => ModuleResult::changed("installed"),
-                    Ok(r) => ModuleResult::failed(&r.stderr),
-                    Err(e) => ModuleResult::failed(&format!("{}", e)),
-                }
-            }
-        }
-        "absent" => {
-            if !is_installed {
-                ModuleResult::ok("already absent")
-            } else {
-                match conn.exec(&format!("DEBIAN_FRONTEND=noninteractive apt-get remove -y -qq {}", name)) {
-                    Ok(r) if r.exit_code == 0 => ModuleResult::changed("removed"),
-                    Ok(r) => ModuleResult::failed(&r.stderr),
-                    Err(e) => ModuleResult::failed(&format!("{}", e)),
-                }
-            }
-        }
-        _ => ModuleResult::failed(&format!("unknown state: {}", state)),
+/// Records the task's templated `notify` targets.
+fn notify(task: &Task, env: &template::Environment, vars: &HostVars, notified: &mut HashSet<String>) -> Result<(), String> {
+    for name in &task.notify {
+        let name = env
+            .render_str(name, vars)
+            .map_err(|e| format!("error templating 'notify': {}", e))?;
+        notified.insert(name);
     }
+    Ok(())
 }
 
-fn run_service(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let name = match args.require("name") {
-        Ok(n) => n.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let state = args.get("state");
-
-    if let Some(st) = state {
-        match st.as_str() {
-            "started" => {
-                match conn.exec(&format!("systemctl start {}", name)) {
-                    Ok(r) if r.exit_code == 0 => ModuleResult::changed("started"),
-                    Ok(r) => ModuleResult::failed(&r.stderr),
-                    Err(e) => ModuleResult::failed(&format!("{}", e)),
-                }
-            }
-            "stopped" => {
-                match conn.exec(&format!("systemctl stop {}", name)) {
-                    Ok(r) if r.exit_code == 0 => ModuleResult::changed("stopped"),
-                    Ok(r) => ModuleResult::failed(&r.stderr),
-                    Err(e) => ModuleResult::failed(&format!("{}", e)),
-                }
-            }
-            "restarted" => {
-                match conn.exec(&format!("systemctl restart {}", name)) {
-                    Ok(r) if r.exit_code == 0 => ModuleResult::changed("restarted"),
-                    Ok(r) => ModuleResult::failed(&r.stderr),
-                    Err(e) => ModuleResult::failed(&format!("{}", e)),
-                }
-            }
-            _ => ModuleResult::failed(&format!("unknown state: {}", st)),
-        }
-    } else {
-        ModuleResult::ok("no state specified")
-    }
+/// The action of a `meta:` task, if this is one.
+fn meta_action(task: &Task) -> Option<&str> {
+    task.module.get("meta").and_then(|action| action.as_str())
 }
 
-fn run_lineinfile(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let path = match args.require("path") {
-        Ok(p) => p.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let line = match args.require("line") {
-        Ok(l) => l.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let content = conn.read_file(&path).unwrap_or_default();
-    let content_str = String::from_utf8_lossy(&content);
-
-    if content_str.lines().any(|l| l == line) {
-        return ModuleResult::ok("line present");
-    }
-
-    let new_content = format!("{}\n{}", content_str.trim_end(), line);
-
-    match conn.write_file(&path, new_content.as_bytes(), 0o644) {
-        Ok(_) => ModuleResult::changed("line added"),
-        Err(e) => ModuleResult::failed(&format!("{}", e)),
+/// The value stored by `register`: the module result as a dictionary, with
+/// `stdout_lines` and `stderr_lines` added as Ansible does.
+fn registered(result: &ModuleResult) -> Value {
+    let mut value = serde_json::to_value(result).unwrap_or(Value::Null);
+    if let Value::Object(map) = &mut value {
+        let lines = |s: &str| Value::from(s.lines().collect::<Vec<_>>());
+        map.insert("stdout_lines".to_string(), lines(&result.stdout));
+        map.insert("stderr_lines".to_string(), lines(&result.stderr));
     }
+    value
 }
 
-fn run_blockinfile(conn: &Connection, args: &ModuleArgs) -> ModuleResult {
-    let path = match args.require("path") {
-        Ok(p) => p.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let block = match args.require("block") {
-        Ok(b) => b.clone(),
-        Err(e) => return ModuleResult::failed(&e),
-    };
-
-    let marker = args.get_or("marker", "# {mark} ANSIBLE MANAGED BLOCK");
-    let insertafter = args.get("insertafter");
-    let insertbefore = args.get("insertbefore");
-    let create = args.get_bool("create");
-
-    let content = match conn.read_file(&path) {
-        Ok(c) => String::from_utf8_lossy(&c).to_string(),
-        Err(_) => {
-            if create {
-                String::new()
-            } else {
-                return ModuleResult::ok("file not found");
-            }
-        }
-    };
-
-    let begin_marker = marker.replace("{mark}", "BEGIN");
-    let end_marker = marker.replace("{mark}", "END");
-
-    if content.contains(&begin_marker) && content.contains(&end_marker) {
-        let current_block = extract_block(&content, &begin_marker, &end_marker);
-
-        if current_block == block {
-            return ModuleResult::ok("block already present");
-        }
+fn extract_module(
+    task: &Task,
+    modules: &ModuleRegistry,
+    env: &template::Environment,
+    vars: &HostVars,
+) -> Result<(String, ModuleArgs), String> {
+    for (key, value) in &task.module {
+        let (key, value) = match key.as_str() {
+            "local_action" => local_action(value)?,
+            _ => (key.clone(), value.clone()),
+        };
+        if modules.get(&key).is_some() {
+            let mut args = ModuleArgs::new();
 
-        let new_content = replace_block(&content, &begin_marker, &end_marker, &block);
+            let render = |s: &str| {
+                env.render_str(s, vars)
+                    .map_err(|e| format!("error templating '{}' argument: {}", key, e))
+            };
 
-        match conn.write_file(&path, new_content.as_bytes(), 0o644) {
-            Ok(_) => {
-                let diff = compute_diff_block_helper(&content, &new_content);
-                ModuleResult::changed("block replaced").with_diff(diff)
+            // Handle string arg (e.g., command: "echo hello")
+            if let Some(s) = value.as_str() {
+                args.insert("_raw", &render(s)?);
             }
-            Err(e) => ModuleResult::failed(&format!("failed to write: {}", e)),
-        }
-    } else {
-        let new_content = insert_block(&content, &begin_marker, &end_marker, &block, insertafter, insertbefore);
 
-        match conn.write_file(&path, new_content.as_bytes(), 0o644) {
-            Ok(_) => {
-                let diff = compute_diff_block_helper(&content, &new_content);
-                ModuleResult::changed("block inserted").with_diff(diff)
+            // Handle map args (e.g., apt: { name: nginx, state: present })
+            if let Some(map) = value.as_mapping() {
+                for (k, v) in map {
+                    if let (Some(key_str), Some(val_str)) = (k.as_str(), v.as_str()) {
+                        args.insert(key_str, &render(val_str)?);
+                    } else if let (Some(key_str), Some(val_bool)) = (k.as_str(), v.as_bool()) {
+                        args.insert(key_str, if val_bool { "true" } else { "false" });
+                    }
+                }
             }
-            Err(e) => ModuleResult::failed(&format!("failed to write: {}", e)),
-        }
-    }
-}
 
-fn extract_block(content: &str, begin: &str, end: &str) -> String {
-    if let Some(start) = content.find(begin) {
-        if let Some(end_pos) = content[start..].find(end) {
-            let block_start = start + begin.len();
-            let block_end = start + end_pos;
-            return content[block_start..block_end].trim().to_string();
+            return Ok((key, args));
         }
     }
-    String::new()
-}
 
-fn replace_block(content: &str, begin: &str, end: &str, block: &str) -> String {
-    if let Some(start) = content.find(begin) {
-        if let Some(end_pos) = content[start..].find(end) {
-            let block_start = start;
-            let block_end = start + end_pos + end.len();
-            let new_block = format!("{}\n{}\n{}", begin, block.trim(), end);
-            return format!("{}{}{}", &content[..block_start], &new_block, &content[block_end..]);
-        }
-    }
-    content.to_string()
+    Err("no module found in task".to_string())
 }
 
-fn insert_block(
-    content: &str,
-    begin: &str,
-    end: &str,
-    block: &str,
-    insertafter: Option<&String>,
-    insertbefore: Option<&String>,
-) -> String {
-    let new_block = format!("\n{}\n{}\n{}\n", begin, block.trim(), end);
-
-    if let Some(pattern) = insertafter {
-        if let Some(pos) = content.find(pattern) {
-            if let Some(line_end) = content[pos..].find('\n') {
-                let insert_pos = pos + line_end + 1;
-                return format!("{}{}{}", &content[..insert_pos], &new_block, &content[insert_pos..]);
-            }
-        }
-    }
-
-    if let Some(pattern) = insertbefore {
-        if let Some(pos) = content.find(pattern) {
-            return format!("{}{}{}", &content[..pos], &new_block, &content[pos..]);
+/// Splits a `local_action` into the module it runs and that module's
+/// arguments, from either `module args...` or a map with a `module` key.
+fn local_action(value: &serde_yaml::Value) -> Result<(String, serde_yaml::Value), String> {
+    if let Some(s) = value.as_str() {
+        let (module, args) = s.trim().split_once(char::is_whitespace).unwrap_or((s.trim(), ""));
+        return Ok((module.to_string(), serde_yaml::Value::from(args.trim())));
+    }
+    if let Some(map) = value.as_mapping() {
+        if let Some(module) = map.get("module").and_then(|m| m.as_str()) {
+            let mut args = map.clone();
+            args.remove("module");
+            return Ok((module.to_string(), serde_yaml::Value::Mapping(args)));
         }
     }
-
-    if content.is_empty() {
-        format!("{}\n{}\n{}", begin, block.trim(), end)
-    } else {
-        format!("{}{}", content, &new_block)
-    }
-}
-
-fn compute_diff_block_helper(old: &str, new: &str) -> String {
-    let mut diff = String::new();
-
-    for line in old.lines() {
-        diff.push_str(&format!("-{}\n", line));
-    }
-
-    diff.push_str("---\n");
-
-    for line in new.lines() {
-        diff.push_str(&format!("+{}\n", line));
-    }
-
-    diff
+    Err("local_action requires a module name".to_string())
 }
 
-fn eval_when(condition: &str, vars: &HashMap<String, String>) -> bool {
-    let condition = condition.trim();
-
-    // Handle comparison operators
-    if let Some((left, right)) = condition.split_once("==") {
-        let left = eval_expr(left.trim(), vars);
-        let right = eval_expr(right.trim(), vars);
-        return left == right;
-    }
-
-    if let Some((left, right)) = condition.split_once("!=") {
-        let left = eval_expr(left.trim(), vars);
-        let right = eval_expr(right.trim(), vars);
-        return left != right;
-    }
-
-    if condition.starts_with("not ") {
-        let inner = condition.strip_prefix("not ").unwrap().trim();
-        return !eval_when(inner, vars);
-    }
-
-    // Check defined/undefined
-    if condition.ends_with(" is defined") {
-        let var = condition.strip_suffix(" is defined").unwrap().trim();
-        return vars.contains_key(var);
-    }
-
-    if condition.ends_with(" is undefined") {
-        let var = condition.strip_suffix(" is undefined").unwrap().trim();
-        return !vars.contains_key(var);
-    }
-
-    // Truthy check
-    let value = vars.get(condition).cloned().unwrap_or_default();
-    !value.is_empty() && value != "false" && value != "0"
-}
-
-fn eval_expr(expr: &str, vars: &HashMap<String, String>) -> String {
-    let expr = expr.trim();
-    if expr.starts_with('"') || expr.starts_with('\'') {
-        expr.trim_matches(|c| c == '"' || c == '\'').to_string()
-    } else {
-        vars.get(expr).cloned().unwrap_or_default()
-    }
+/// Evaluates a `when` condition as a Jinja expression. Conditions written
+/// with `{{ }}` are rendered first, as Ansible allows.
+fn eval_when(
+    condition: &str,
+    env: &template::Environment,
+    vars: &dyn template::Vars,
+) -> Result<bool, template::TemplateError> {
+    if condition.contains("{{") {
+        let rendered = env.render_str(condition, vars)?;
+        return env.eval_condition(&rendered, vars);
+    }
+    env.eval_condition(condition, vars)
 }
 
 #[cfg(test)]
@@ -1050,40 +1485,57 @@ mod tests {
         pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
     }
 
+    fn when(condition: &str, vars: &dyn template::Vars) -> bool {
+        eval_when(condition, &template::Environment::new(), vars).unwrap()
+    }
+
     #[test]
     fn eval_when_equals() {
-        assert!(eval_when("os == \"linux\"", &vars(&[("os", "linux")])));
-        assert!(!eval_when("os == \"linux\"", &vars(&[("os", "windows")])));
+        assert!(when("os == \"linux\"", &vars(&[("os", "linux")])));
+        assert!(!when("os == \"linux\"", &vars(&[("os", "windows")])));
     }
 
     #[test]
     fn eval_when_not_equals() {
-        assert!(eval_when("os != \"windows\"", &vars(&[("os", "linux")])));
+        assert!(when("os != \"windows\"", &vars(&[("os", "linux")])));
     }
 
     #[test]
     fn eval_when_defined() {
-        assert!(eval_when("myvar is defined", &vars(&[("myvar", "value")])));
-        assert!(!eval_when("myvar is defined", &vars(&[])));
+        assert!(when("myvar is defined", &vars(&[("myvar", "value")])));
+        assert!(!when("myvar is defined", &vars(&[])));
     }
 
     #[test]
     fn eval_when_undefined() {
-        assert!(eval_when("myvar is undefined", &vars(&[])));
-        assert!(!eval_when("myvar is undefined", &vars(&[("myvar", "value")])));
+        assert!(when("myvar is undefined", &vars(&[])));
+        assert!(!when("myvar is undefined", &vars(&[("myvar", "value")])));
     }
 
     #[test]
     fn eval_when_truthy() {
-        assert!(eval_when("enabled", &vars(&[("enabled", "true")])));
-        assert!(!eval_when("enabled", &vars(&[("enabled", "false")])));
-        assert!(!eval_when("enabled", &vars(&[])));
+        assert!(when("enabled", &vars(&[("enabled", "true")])));
+        assert!(!when("enabled", &vars(&[("enabled", "false")])));
+    }
+
+    #[test]
+    fn eval_when_undefined_is_error() {
+        let err = eval_when("enabld", &template::Environment::new(), &vars(&[])).unwrap_err();
+        assert_eq!(err.msg, "'enabld' is undefined");
+        let lenient = template::Environment::new().lenient(true);
+        assert!(!eval_when("enabld", &lenient, &vars(&[])).unwrap());
+    }
+
+    #[test]
+    fn eval_when_expression() {
+        assert!(when("port | int >= 1024 and os in ['linux', 'bsd']", &vars(&[("port", "8080"), ("os", "linux")])));
+        assert!(when("{{ enabled }}", &vars(&[("enabled", "true")])));
     }
 
     #[test]
     fn eval_when_not() {
-        assert!(eval_when("not disabled", &vars(&[("disabled", "false")])));
-        assert!(!eval_when("not enabled", &vars(&[("enabled", "true")])));
+        assert!(when("not disabled", &vars(&[("disabled", "false")])));
+        assert!(!when("not enabled", &vars(&[("enabled", "true")])));
     }
 
     #[test]
@@ -1222,4 +1674,871 @@ mod tests {
         hosts.sort();
         assert_eq!(hosts, vec!["web1".to_string(), "web2".to_string()]);
     }
+
+    #[test]
+    fn registered_result_is_structured() {
+        let result = ModuleResult::ok("done").with_output("a\nb", "", 0);
+        let value = registered(&result);
+        assert_eq!(value["rc"], 0);
+        assert_eq!(value["stdout_lines"], serde_json::json!(["a", "b"]));
+        assert!(when("reg.rc == 0 and reg.stdout_lines | length == 2", &HashMap::from([("reg".to_string(), value)])));
+    }
+
+    /// Runs a playbook against local-connection hosts and returns the
+    /// results with the lines the tasks appended to `{{ log }}`. Group
+    /// headers and entries with their own vars are used verbatim.
+    fn run_local(hosts: &[&str], playbook: &str, configure: impl FnOnce(Executor) -> Executor) -> (Vec<PlayResult>, Vec<String>) {
+        let ini: String = hosts
+            .iter()
+            .map(|h| match h.starts_with('[') || h.contains('=') {
+                true => format!("{}\n", h),
+                false => format!("{} ansible_connection=local\n", h),
+            })
+            .collect();
+        static RUNS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let run = RUNS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let log = std::env::temp_dir().join(format!("wand-exec-{}-{}.log", std::process::id(), run));
+        let _ = std::fs::remove_file(&log);
+        let mut extra = HashMap::new();
+        extra.insert("log".to_string(), log.display().to_string());
+        let exec = configure(Executor::new(Inventory::from_ini(&ini)).with_vars(extra));
+        let play = crate::playbook::parse_playbook(playbook).unwrap().remove(0);
+        let results = exec.run_play(&play);
+        let lines = std::fs::read_to_string(&log).unwrap_or_default().lines().map(String::from).collect();
+        let _ = std::fs::remove_file(&log);
+        (results, lines)
+    }
+
+    #[test]
+    fn linear_strategy_runs_each_task_on_all_hosts_first() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo {{ inventory_hostname }}-1 >> {{ log }}
+    - shell: echo {{ inventory_hostname }}-2 >> {{ log }}
+"#;
+        let (results, lines) = run_local(&["a", "b", "c"], playbook, |e| e.forks(3));
+        assert_eq!(results.len(), 3);
+        assert!(lines[..3].iter().all(|l| l.ends_with("-1")), "{:?}", lines);
+        assert!(lines[3..].iter().all(|l| l.ends_with("-2")), "{:?}", lines);
+    }
+
+    #[test]
+    fn handlers_run_after_all_hosts_finish_tasks() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo task >> {{ log }}
+      notify: [note]
+    - shell: echo task >> {{ log }}
+  handlers:
+    - name: note
+      shell: echo handler >> {{ log }}
+"#;
+        let (_, lines) = run_local(&["a", "b"], playbook, |e| e.forks(2));
+        assert_eq!(lines, vec!["task", "task", "task", "task", "handler", "handler"]);
+    }
+
+    #[test]
+    fn free_strategy_runs_every_task() {
+        let playbook = r#"
+- hosts: all
+  strategy: free
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+"#;
+        let (results, mut lines) = run_local(&["a", "b"], playbook, |e| e);
+        lines.sort();
+        assert_eq!(lines, vec!["a", "b"]);
+        assert!(results.iter().all(|r| r.changed == 1));
+    }
+
+    #[test]
+    fn serial_runs_batches_with_handlers_in_between() {
+        let playbook = r#"
+- hosts: web
+  serial: [1, 2]
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+      notify: [note]
+  handlers:
+    - name: note
+      shell: echo handler >> {{ log }}
+"#;
+        let hosts = ["[web]", "a", "b", "c"];
+        let (results, lines) = run_local(&hosts, playbook, |e| e.forks(2));
+        assert_eq!(results.len(), 3);
+        assert_eq!(lines[..2], ["a", "handler"]);
+        assert_eq!(lines[4..], ["handler", "handler"]);
+    }
+
+    #[test]
+    fn max_fail_percentage_aborts_remaining_batches() {
+        let playbook = r#"
+- hosts: web
+  serial: 2
+  max_fail_percentage: 40
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}; test {{ inventory_hostname }} != b
+"#;
+        let hosts = ["[web]", "a", "b", "c", "d"];
+        let (results, lines) = run_local(&hosts, playbook, |e| e);
+        assert_eq!(results.len(), 2);
+        assert_eq!(lines.len(), 2);
+
+        let tolerant = playbook.replace("40", "50");
+        let (results, _) = run_local(&hosts, &tolerant, |e| e);
+        assert_eq!(results.len(), 4);
+    }
+
+    #[test]
+    fn failed_hosts_drop_out_of_later_tasks() {
+        let playbook = r#"
+- hosts: web
+  tasks:
+    - shell: test {{ inventory_hostname }} != b
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+"#;
+        let (results, lines) = run_local(&["[web]", "a", "b", "c"], playbook, |e| e);
+        assert_eq!(lines.len(), 2);
+        assert!(!lines.contains(&"b".to_string()));
+        let b = results.iter().find(|r| r.host == "b").unwrap();
+        assert_eq!((b.failed, b.task_results.len()), (1, 1));
+    }
+
+    #[test]
+    fn any_errors_fatal_aborts_all_hosts_after_the_task() {
+        let playbook = r#"
+- hosts: web
+  any_errors_fatal: true
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}; test {{ inventory_hostname }} != b
+      notify: [note]
+    - shell: echo never >> {{ log }}
+  handlers:
+    - name: note
+      shell: echo handler >> {{ log }}
+"#;
+        let (results, mut lines) = run_local(&["[web]", "a", "b", "c"], playbook, |e| e.forks(3));
+        lines.sort();
+        assert_eq!(lines, vec!["a", "b", "c"]);
+        assert_eq!(results.iter().map(|r| r.task_results.len()).sum::<usize>(), 3);
+    }
+
+    #[test]
+    fn max_fail_percentage_stops_the_play() {
+        let playbook = r#"
+- hosts: web
+  max_fail_percentage: 20
+  tasks:
+    - shell: test {{ inventory_hostname }} != b
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+"#;
+        let hosts = ["[web]", "a", "b", "c", "d"];
+        let (_, lines) = run_local(&hosts, playbook, |e| e);
+        assert!(lines.is_empty());
+
+        let (_, lines) = run_local(&hosts, &playbook.replace("20", "25"), |e| e);
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn unreachable_hosts_are_not_failed() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+"#;
+        let hosts = ["down ansible_host=127.0.0.1 ansible_port=1"];
+        let (results, lines) = run_local(&hosts, playbook, |e| e);
+        assert!(lines.is_empty());
+        assert_eq!((results[0].unreachable, results[0].failed), (1, 0));
+        assert!(results[0].task_results.is_empty());
+        assert!(results[0].unreachable_msg.as_ref().unwrap().starts_with("connection failed"));
+    }
+
+    #[test]
+    fn ssh_targets_come_from_ssh_config_and_args() {
+        let playbook = "- hosts: all\n  tasks:\n    - ping:\n";
+        let config = SshConfig::parse("Host db\n  HostName 127.0.0.1\n  Port 1\n", std::path::Path::new("."));
+        let hosts = [
+            "db ansible_connection=ssh",
+            "web ansible_host=127.0.0.1 ansible_ssh_common_args='-o Port=2 -p 3' ansible_ssh_extra_args=-oPort=4",
+        ];
+        let (results, _) = run_local(&hosts, playbook, |e| e.ssh_config(config));
+        let msgs: Vec<_> = results.iter().map(|r| r.unreachable_msg.clone().unwrap()).collect();
+        assert!(msgs[0].contains("127.0.0.1:1:"), "{}", msgs[0]);
+        assert!(msgs[1].contains("127.0.0.1:4:"), "{}", msgs[1]);
+    }
+
+    #[test]
+    fn ssh_logins_prefer_the_hosts_own_credentials() {
+        let exec = Executor::new(Inventory::default())
+            .private_key(Some(PathBuf::from("/keys/default")))
+            .password(Some("asked".to_string()));
+        let own = exec.login(
+            "deploy",
+            Some(Auth::key("/keys/web")),
+            vec![PathBuf::from("/keys/missing")],
+            Some(Auth::password("secret")),
+        );
+        assert_eq!(own.methods[..3], [Auth::key("/keys/web"), Auth::password("secret"), Auth::Agent]);
+        assert_eq!(own.methods.last(), Some(&Auth::KeyboardInteractive));
+
+        let defaults = exec.login("deploy", None, Vec::new(), None);
+        assert_eq!(defaults.methods[..3], [Auth::key("/keys/default"), Auth::password("asked"), Auth::Agent]);
+        let bare = Executor::new(Inventory::default()).login("deploy", None, Vec::new(), None);
+        assert_eq!(bare.methods[0], Auth::Agent);
+    }
+
+    #[test]
+    fn ssh_targets_go_through_their_jump_hosts() {
+        let playbook = "- hosts: all\n  tasks:\n    - ping:\n";
+        let config = SshConfig::parse(
+            "Host db\n  ProxyJump bastion\nHost bastion\n  HostName 127.0.0.1\n  Port 1\n",
+            std::path::Path::new("."),
+        );
+        let hosts = [
+            "db ansible_connection=ssh",
+            "web ansible_host=10.0.0.6 ansible_ssh_common_args='-o ProxyJump=ops@127.0.0.1:2,inner'",
+        ];
+        let (results, _) = run_local(&hosts, playbook, |e| e.ssh_config(config));
+        let msgs: Vec<_> = results.iter().map(|r| r.unreachable_msg.clone().unwrap()).collect();
+        assert!(msgs[0].contains("jump host bastion: 127.0.0.1:1:"), "{}", msgs[0]);
+        assert!(msgs[1].contains("jump host 127.0.0.1: 127.0.0.1:2:"), "{}", msgs[1]);
+    }
+
+    #[test]
+    fn ssh_args_from_group_vars_reach_the_connection() {
+        let playbook = "- hosts: all\n  tasks:\n    - ping:\n";
+        let hosts = [
+            "[web]",
+            "app ansible_host=10.0.0.7",
+            "[web:vars]",
+            "ansible_ssh_common_args='-o ProxyJump=ops@127.0.0.1:2'",
+            "[db]",
+            "store ansible_host=127.0.0.1",
+            "[all:vars]",
+            "ansible_port=3",
+        ];
+        let (results, _) = run_local(&hosts, playbook, |e| e);
+        let msgs: Vec<_> = results.iter().map(|r| (r.host.clone(), r.unreachable_msg.clone().unwrap())).collect();
+        let msg = |host: &str| &msgs.iter().find(|(h, _)| h == host).unwrap().1;
+        assert!(msg("app").contains("jump host 127.0.0.1: 127.0.0.1:2:"), "{}", msg("app"));
+        assert!(msg("store").contains("127.0.0.1:3:"), "{}", msg("store"));
+    }
+
+    #[test]
+    fn run_once_broadcasts_result_to_batch() {
+        let playbook = r#"
+- hosts: web
+  tasks:
+    - shell: echo once >> {{ log }}
+      run_once: true
+      register: migrated
+    - shell: echo {{ inventory_hostname }}-{{ migrated.rc }} >> {{ log }}
+"#;
+        let (results, mut lines) = run_local(&["[web]", "a", "b", "c"], playbook, |e| e);
+        lines.sort();
+        assert_eq!(lines, vec!["a-0", "b-0", "c-0", "once"]);
+        assert!(results.iter().all(|r| r.task_results.len() == 2 && r.changed == 2));
+    }
+
+    #[test]
+    fn run_once_under_free_strategy() {
+        let playbook = r#"
+- hosts: web
+  strategy: free
+  tasks:
+    - shell: sleep 0.{{ inventory_hostname | length }}
+    - shell: echo once >> {{ log }}
+      run_once: true
+      register: migrated
+    - shell: echo {{ inventory_hostname }}-{{ migrated.rc }} >> {{ log }}
+"#;
+        let (results, mut lines) = run_local(&["[web]", "a", "bb", "ccc"], playbook, |e| e);
+        lines.sort();
+        assert_eq!(lines, vec!["a-0", "bb-0", "ccc-0", "once"]);
+        assert!(results.iter().all(|r| r.task_results.len() == 3 && r.changed == 3));
+    }
+
+    #[test]
+    fn run_once_skipped_by_tags_is_skipped_everywhere() {
+        let playbook = r#"
+- hosts: web
+  tasks:
+    - shell: echo once >> {{ log }}
+      run_once: true
+      tags: [migrate]
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+      tags: [deploy]
+"#;
+        for strategy in ["strategy: linear", "strategy: free"] {
+            let playbook = playbook.replace("  tasks:", &format!("  {}\n  tasks:", strategy));
+            let (results, lines) = run_local(&["[web]", "a", "b"], &playbook, |e| e.skip_tags(vec!["migrate".to_string()]));
+            assert!(!lines.contains(&"once".to_string()));
+            assert!(results.iter().all(|r| r.skipped == 1 && r.changed == 1 && r.ok == 0), "{:?}", results);
+        }
+    }
+
+    #[test]
+    fn delegate_to_keeps_host_vars() {
+        let playbook = r#"
+- hosts: web
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+      delegate_to: localhost
+    - local_action: shell echo local-{{ inventory_hostname }} >> {{ log }}
+    - local_action:
+        module: shell
+        cmd: echo map-{{ inventory_hostname }} >> {{ log }}
+"#;
+        let (results, lines) = run_local(&["[web]", "a"], playbook, |e| e);
+        assert_eq!(lines, vec!["a", "local-a", "map-a"]);
+        assert_eq!(results[0].changed, 3);
+    }
+
+    #[test]
+    fn delegate_to_templated_host() {
+        let playbook = r#"
+- hosts: web
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+      delegate_to: "{{ groups['db'][0] }}"
+"#;
+        let hosts = ["[web]", "a", "[db]", "db1 ansible_host=127.0.0.1 ansible_port=1"];
+        let (results, lines) = run_local(&hosts, playbook, |e| e);
+        assert!(lines.is_empty());
+        let msg = &results[0].task_results[0].result.msg;
+        assert!(msg.starts_with("failed to connect to delegated host 'db1'"), "{}", msg);
+    }
+
+    #[test]
+    fn delegate_facts_go_to_delegated_host() {
+        let exec = Executor::new(Inventory::default());
+        let facts: serde_yaml::Value = serde_yaml::from_str("role: primary").unwrap();
+        let mut vars = HostVars::default();
+
+        exec.store_facts("db1", &facts, &mut vars, "web1");
+        assert!(vars.is_empty());
+        exec.store_facts("web1", &facts, &mut vars, "web1");
+        assert_eq!(vars["role"], "primary");
+
+        let stored = exec.facts.lock().unwrap();
+        assert_eq!(stored["db1"]["role"], "primary");
+    }
+
+    #[test]
+    fn throttle_limits_concurrent_hosts() {
+        let playbook = r#"
+- hosts: web
+  STRATEGY
+  tasks:
+    - shell: echo start >> {{ log }}; sleep 0.05; echo end >> {{ log }}
+      throttle: 1
+"#;
+        let hosts = ["[web]", "a", "b", "c"];
+        for strategy in ["strategy: linear", "strategy: free"] {
+            let (_, lines) = run_local(&hosts, &playbook.replace("STRATEGY", strategy), |e| e.forks(3));
+            assert_eq!(lines, ["start", "end"].repeat(3), "{}", strategy);
+        }
+    }
+
+    #[test]
+    fn order_controls_host_sequence() {
+        let playbook = r#"
+- hosts: all
+  serial: 1
+  order: ORDER
+  tasks:
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+"#;
+        let hosts = ["b", "c", "a"];
+        let run = |order: &str| run_local(&hosts, &playbook.replace("ORDER", order), |e| e).1.concat();
+        assert_eq!(run("inventory"), "bca");
+        assert_eq!(run("reverse_inventory"), "acb");
+        assert_eq!(run("sorted"), "abc");
+        assert_eq!(run("reverse_sorted"), "cba");
+    }
+
+    #[test]
+    fn handlers_listen_and_templated_names() {
+        let playbook = r#"
+- hosts: all
+  vars:
+    svc: nginx
+  tasks:
+    - shell: echo task >> {{ log }}
+      notify: ["restart {{ svc }}", restart web]
+  handlers:
+    - name: restart nginx
+      shell: echo by-name >> {{ log }}
+    - name: other
+      listen: restart web
+      shell: echo listener >> {{ log }}
+    - name: unrelated
+      shell: echo never >> {{ log }}
+"#;
+        let (_, lines) = run_local(&["a"], playbook, |e| e);
+        assert_eq!(lines, vec!["task", "by-name", "listener"]);
+    }
+
+    #[test]
+    fn flush_handlers_mid_play() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo task >> {{ log }}
+      notify: note
+    - meta: flush_handlers
+    - shell: echo after >> {{ log }}
+  handlers:
+    - name: note
+      shell: echo handler >> {{ log }}
+"#;
+        let (_, lines) = run_local(&["a"], playbook, |e| e);
+        assert_eq!(lines, vec!["task", "handler", "after"]);
+    }
+
+    #[test]
+    fn handlers_notify_handlers() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo task >> {{ log }}
+      notify: second
+  handlers:
+    - name: first
+      shell: echo first >> {{ log }}
+    - name: second
+      shell: echo second >> {{ log }}
+      notify: [first, third]
+    - name: third
+      shell: echo third >> {{ log }}
+"#;
+        let (_, lines) = run_local(&["a"], playbook, |e| e);
+        assert_eq!(lines, vec!["task", "second", "third", "first"]);
+    }
+
+    #[test]
+    fn handlers_that_notify_each_other_run_once_per_flush() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo task >> {{ log }}
+      notify: a
+  handlers:
+    - name: a
+      shell: echo a >> {{ log }}
+      notify: [a, b]
+    - name: b
+      shell: echo b >> {{ log }}
+      notify: a
+"#;
+        let (results, lines) = run_local(&["x", "y"], playbook, |e| e);
+        assert!(results.iter().all(|r| !r.is_failed()));
+        assert_eq!(lines, vec!["task", "task", "a", "a", "b", "b"]);
+    }
+
+    #[test]
+    fn notify_template_errors_fail_the_task() {
+        let playbook = r#"
+- hosts: all
+  vars:
+    svc: nginx
+  tasks:
+    - shell: "true"
+      notify: "restart {{ sevice }}"
+  handlers:
+    - name: "restart {{ svc }}"
+      shell: echo handler >> {{ log }}
+"#;
+        let (results, lines) = run_local(&["a"], playbook, |e| e);
+        assert!(lines.is_empty());
+        let task = &results[0].task_results[0];
+        assert!(task.result.failed);
+        assert!(task.result.msg.starts_with("error templating 'notify': 'sevice' is undefined"), "{}", task.result.msg);
+
+        let playbook = playbook.replace("{{ sevice }}", "{{ svc }}").replace("{{ svc }}\"\n      shell", "{{ nope }}\"\n      shell");
+        let (results, lines) = run_local(&["a"], &playbook, |e| e);
+        assert!(lines.is_empty());
+        let failure = results[0].task_results.last().unwrap();
+        assert!(failure.result.msg.starts_with("error templating handler name"), "{}", failure.result.msg);
+        assert!(results[0].is_failed());
+    }
+
+    #[test]
+    fn force_handlers_run_after_failure() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo task >> {{ log }}
+      notify: note
+    - shell: "false"
+  handlers:
+    - name: note
+      shell: echo handler >> {{ log }}
+"#;
+        let (_, lines) = run_local(&["a"], playbook, |e| e);
+        assert_eq!(lines, vec!["task"]);
+        let (_, lines) = run_local(&["a"], playbook, |e| e.force_handlers(true));
+        assert_eq!(lines, vec!["task", "handler"]);
+        let forced = playbook.replace("- hosts: all", "- hosts: all\n  force_handlers: true");
+        let (_, lines) = run_local(&["a"], &forced, |e| e);
+        assert_eq!(lines, vec!["task", "handler"]);
+    }
+
+    #[test]
+    fn meta_end_host_is_not_a_failure() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - meta: end_host
+      when: inventory_hostname == 'a'
+    - shell: echo {{ inventory_hostname }} >> {{ log }}
+"#;
+        let (results, lines) = run_local(&["a", "b"], playbook, |e| e);
+        assert_eq!(lines, vec!["b"]);
+        assert!(results.iter().all(|r| !r.is_failed()));
+    }
+
+    #[test]
+    fn meta_end_play_and_end_batch() {
+        let playbook = r#"
+- hosts: all
+  serial: 1
+  tasks:
+    - shell: echo {{ inventory_hostname }}-1 >> {{ log }}
+    - meta: ACTION
+    - shell: echo {{ inventory_hostname }}-2 >> {{ log }}
+"#;
+        let hosts = ["a", "b"];
+        let (results, lines) = run_local(&hosts, &playbook.replace("ACTION", "end_play"), |e| e);
+        assert_eq!((results.len(), lines), (1, vec!["a-1".to_string()]));
+        let (results, lines) = run_local(&hosts, &playbook.replace("ACTION", "end_batch"), |e| e);
+        assert_eq!((results.len(), lines), (2, vec!["a-1".to_string(), "b-1".to_string()]));
+        let (_, lines) = run_local(&hosts, &playbook.replace("ACTION", "noop"), |e| e);
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn meta_clear_facts() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo {{ role | default('none') }} >> {{ log }}
+    - meta: clear_facts
+    - shell: echo {{ role | default('none') }} >> {{ log }}
+"#;
+        let facts: serde_yaml::Value = serde_yaml::from_str("role: primary").unwrap();
+        let (_, lines) = run_local(&["a"], playbook, |e| {
+            e.store_facts("a", &facts, &mut HostVars::default(), "controller");
+            e
+        });
+        assert_eq!(lines, vec!["primary", "none"]);
+    }
+
+    #[test]
+    fn meta_invalid_action_fails() {
+        let playbook = "- hosts: all\n  tasks:\n    - meta: bogus\n";
+        let (results, _) = run_local(&["a"], playbook, |e| e);
+        assert_eq!(results[0].task_results[0].result.msg, "invalid meta action 'bogus'");
+    }
+
+    #[test]
+    fn meta_refresh_inventory() {
+        let path = std::env::temp_dir().join(format!("wand-refresh-{}.ini", std::process::id()));
+        std::fs::write(&path, "a ansible_connection=local\n").unwrap();
+        let inventory = Inventory::from_ini(&std::fs::read_to_string(&path).unwrap());
+        let exec = Executor::new(inventory).inventory_file(path.clone());
+
+        let playbook = format!(
+            r#"
+- hosts: all
+  tasks:
+    - shell: echo b ansible_connection=local >> {}
+    - meta: refresh_inventory
+    - shell: echo "{{{{ groups['all'] | join(',') }}}}"
+      register: all_hosts
+"#,
+            path.display()
+        );
+        let play = crate::playbook::parse_playbook(&playbook).unwrap().remove(0);
+        let results = exec.run_play(&play);
+        assert_eq!(results[0].task_results[1].result.stdout.trim(), "a,b");
+        assert_eq!(exec.run_play(&play).len(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn meta_refresh_inventory_needs_an_inventory_file() {
+        let (results, _) = run_local(&["a"], "- hosts: all\n  tasks:\n    - meta: refresh_inventory\n", |e| e);
+        let task = &results[0].task_results[0];
+        assert!(task.result.failed);
+        assert_eq!(task.result.msg, "no inventory file to refresh from");
+    }
+
+    #[test]
+    fn registered_output_is_not_templated() {
+        // The shell prints `{{ x }}` and `{{ lookup(...) }}` without the
+        // task text containing them.
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: printf '\173\173 lookup("pipe", "echo ran") }} \173\173 x }}'
+      register: out
+    - shell: echo '{{ out.stdout }}' >> {{ log }}
+"#;
+        let (results, lines) = run_local(&["a"], playbook, |e| e);
+        assert!(!results[0].is_failed(), "{:?}", results[0]);
+        assert_eq!(lines, vec![r#"{{ lookup("pipe", "echo ran") }} {{ x }}"#]);
+    }
+
+    #[test]
+    fn until_retries_until_condition_holds() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo try >> {{ log }}; wc -l < {{ log }}
+      register: out
+      until: out.stdout | int >= 3
+      retries: 5
+      delay: 0
+    - shell: echo attempts={{ out.attempts }} >> {{ log }}
+"#;
+        let (results, lines) = run_local(&["a"], playbook, |e| e);
+        assert_eq!(lines, vec!["try", "try", "try", "attempts=3"]);
+        let retries = &results[0].task_results[0].retries;
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[0], "FAILED - RETRYING: [a]: unnamed (5 retries left).");
+    }
+
+    #[test]
+    fn until_fails_after_last_retry() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - name: wait
+      shell: echo try >> {{ log }}
+      register: out
+      until: false
+      retries: 2
+      delay: 0
+"#;
+        let (results, lines) = run_local(&["a"], playbook, |e| e);
+        assert_eq!(lines.len(), 3);
+        let task = &results[0].task_results[0];
+        assert!(task.result.failed);
+        assert_eq!(task.result.extra["attempts"], 3);
+        assert_eq!(task.retries.last().unwrap(), "FAILED - RETRYING: [a]: wait (1 retries left).");
+    }
+
+    #[test]
+    fn until_defaults_to_three_retries_reported_as_they_happen() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - name: wait
+      shell: echo try >> {{ log }}
+      until: false
+      delay: 0
+"#;
+        // Each retry is reported while the log still holds only the
+        // attempts made so far.
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let (results, lines) = run_local(&["a"], playbook, |e| {
+            let log = e.extra_vars["log"].clone();
+            e.on_retry(move |line| {
+                let tries = std::fs::read_to_string(&log).unwrap().lines().count();
+                hook_seen.lock().unwrap().push(format!("{} after {}", line, tries));
+            })
+        });
+        assert_eq!(lines.len(), 4);
+        assert_eq!(results[0].task_results[0].result.extra["attempts"], 4);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                "FAILED - RETRYING: [a]: wait (3 retries left). after 1",
+                "FAILED - RETRYING: [a]: wait (2 retries left). after 2",
+                "FAILED - RETRYING: [a]: wait (1 retries left). after 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn async_fire_and_forget_with_async_status() {
+        let jobs = std::env::temp_dir().join(format!("wand-jobs-{}", std::process::id()));
+        let script = std::env::temp_dir().join(format!("wand-async-script-{}.sh", std::process::id()));
+        let playbook = r#"
+- hosts: all
+  vars:
+    ansible_async_dir: JOBS
+  tasks:
+    - shell: sleep 1; echo done
+      async: 30
+      poll: 0
+      register: job
+    - async_status:
+        jid: "{{ job.ansible_job_id }}"
+      register: status
+      until: status.finished == 1
+      retries: 10
+      delay: 1
+    - shell: echo {{ job.finished }}-{{ status.stdout | trim }} >> {{ log }}
+    - async_status:
+        jid: "{{ job.ansible_job_id }}"
+        mode: cleanup
+    - command: echo x
+      async: 30
+      poll: 1
+      register: polled
+    - shell: echo {{ polled.stdout | trim }}-{{ polled.finished }} >> {{ log }}
+    - script: SCRIPT
+      async: 30
+      poll: 1
+    - copy:
+        content: x
+        dest: "{{ log }}.copy"
+      async: 30
+"#
+        .replace("JOBS", &jobs.display().to_string())
+        .replace("SCRIPT", &script.display().to_string());
+        std::fs::write(&script, "echo from script\n").unwrap();
+        let (results, lines) = run_local(&["a"], &playbook, |e| e);
+        let _ = std::fs::remove_dir_all(&jobs);
+        let _ = std::fs::remove_file(&script);
+        assert_eq!(lines, vec!["0-done", "x-1"]);
+        let tasks = &results[0].task_results;
+        let script = &tasks[tasks.len() - 2].result;
+        assert_eq!((script.stdout.as_str(), script.extra["finished"].as_u64()), ("from script\n", Some(1)));
+        let copy = results[0].task_results.last().unwrap();
+        assert_eq!(copy.result.msg, "the copy module does not support async");
+    }
+
+    #[test]
+    fn task_timeout_stops_the_command() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: sleep 5; echo late >> {{ log }}
+      timeout: 1
+"#;
+        let started = Instant::now();
+        let (results, lines) = run_local(&["a"], playbook, |e| e);
+        assert!(started.elapsed() < Duration::from_secs(4));
+        assert!(lines.is_empty());
+        assert_eq!(results[0].task_results[0].result.msg, "timed out after 1 seconds");
+        assert!(results[0].task_results[0].result.failed);
+    }
+
+    #[test]
+    fn modules_by_fqcn_and_custom_registry() {
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - ansible.builtin.shell: echo builtin >> {{ log }}
+    - acme.tools.note:
+        text: custom
+    - yum:
+        name: nginx
+"#;
+        let mut registry = ModuleRegistry::default();
+        registry.register("acme.tools.note", |conn: &dyn Connection, args: &ModuleArgs, ctx: &ModuleContext| {
+            let log = ctx.vars["log"].as_str().unwrap();
+            let _ = conn.exec(&format!("echo {} >> {}", args.get_or("text", ""), log));
+            ModuleResult::changed("noted")
+        });
+        let (results, lines) = run_local(&["a"], playbook, |e| e.modules(Arc::new(registry)));
+        assert_eq!(lines, vec!["builtin", "custom"]);
+        assert_eq!(results[0].task_results[2].result.msg, "no module found in task");
+    }
+
+    #[test]
+    fn docker_connection_from_inventory_vars() {
+        let dir = std::env::temp_dir().join(format!("wand-exec-docker-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let docker = dir.join("docker");
+        // Exposes the container name and global options, then runs the command here.
+        let shim = r#"#!/bin/sh
+opts=""
+while [ "$1" != exec ]; do opts="$opts $1"; shift; done; shift
+while [ "$1" = -u ]; do shift 2; done
+export CONTAINER="$1$opts"; shift
+exec "$@"
+"#;
+        std::fs::write(&docker, shim).unwrap();
+        std::process::Command::new("chmod").arg("+x").arg(&docker).status().unwrap();
+
+        let host = format!(
+            "web ansible_connection=docker ansible_host=app1 ansible_docker_executable={} ansible_docker_extra_args=--tls",
+            docker.display()
+        );
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo $CONTAINER >> {{ log }}
+"#;
+        let (results, lines) = run_local(&[&host], playbook, |e| e);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!results[0].is_failed());
+        assert_eq!(lines, vec!["app1 --tls"]);
+    }
+
+    #[test]
+    fn chroot_connection_writes_under_the_root() {
+        let root = std::env::temp_dir().join(format!("wand-exec-chroot-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        // Stands in for chroot(8): runs the command from inside the root.
+        let exe = root.join("fake-chroot");
+        std::fs::write(&exe, "#!/bin/sh\ncd \"$1\" && shift && exec \"$@\"\n").unwrap();
+        std::process::Command::new("chmod").arg("+x").arg(&exe).status().unwrap();
+
+        let host = format!("image ansible_connection=chroot ansible_host={} ansible_chroot_exe={}", root.display(), exe.display());
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - copy:
+        content: golden
+        dest: /etc/release
+    - shell: cat etc/release >> {{ log }}
+"#;
+        let (results, lines) = run_local(&[&host], playbook, |e| e);
+        let release = std::fs::read_to_string(root.join("etc/release"));
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(!results[0].is_failed());
+        assert_eq!(release.unwrap(), "golden");
+        assert_eq!(lines, vec!["golden"]);
+    }
+
+    #[test]
+    fn kubectl_connection_from_inventory_vars() {
+        let dir = std::env::temp_dir().join(format!("wand-exec-kubectl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let kubectl = dir.join("kubectl");
+        // Exposes the kubectl arguments, then runs the command here.
+        let stub = r#"#!/bin/sh
+args=""
+while [ "$1" != -- ]; do args="$args $1"; shift; done; shift
+export KUBECTL_ARGS="$args"
+exec "$@"
+"#;
+        std::fs::write(&kubectl, stub).unwrap();
+        std::process::Command::new("chmod").arg("+x").arg(&kubectl).status().unwrap();
+
+        let host = format!(
+            "web ansible_connection=kubectl ansible_kubectl_pod=web-0 ansible_kubectl_namespace=shop \
+             ansible_kubectl_container=app ansible_kubectl_executable={}",
+            kubectl.display()
+        );
+        let playbook = r#"
+- hosts: all
+  tasks:
+    - shell: echo $KUBECTL_ARGS >> {{ log }}
+"#;
+        let (results, lines) = run_local(&[&host], playbook, |e| e);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!results[0].is_failed());
+        assert_eq!(lines, vec!["exec -i -n shop web-0 -c app"]);
+    }
 }
@@ -1,3 +1,4 @@
+use indexmap::IndexMap;
 use std::collections::HashMap;
 
 fn expand_host_pattern(pattern: &str) -> Vec<String> {
@@ -26,9 +27,34 @@ fn expand_host_pattern(pattern: &str) -> Vec<String> {
     vec![pattern.to_string()]
 }
 
+/// Splits a host line on whitespace outside of quotes, dropping the quotes,
+/// so that `key='a b'` is one part.
+fn split_quoted(line: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if !part.is_empty() {
+                    parts.push(std::mem::take(&mut part));
+                }
+            }
+            (c, _) => part.push(c),
+        }
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
+
 fn parse_host_line(line: &str) -> (Vec<String>, HashMap<String, String>) {
-    let mut parts = line.split_whitespace();
-    let host_pattern = parts.next().unwrap_or("").to_string();
+    let parts = split_quoted(line);
+    let mut parts = parts.iter();
+    let host_pattern = parts.next().cloned().unwrap_or_default();
     let mut vars = HashMap::new();
 
     for part in parts {
@@ -43,7 +69,8 @@ fn parse_host_line(line: &str) -> (Vec<String>, HashMap<String, String>) {
 
 #[derive(Debug, Default, PartialEq)]
 pub struct Inventory {
-    pub hosts: HashMap<String, Host>,
+    /// Hosts in the order they first appear in the inventory.
+    pub hosts: IndexMap<String, Host>,
     pub groups: HashMap<String, Group>,
 }
 
@@ -94,7 +121,9 @@ impl Inventory {
                     let group_name = group.strip_suffix(":vars").unwrap();
                     if let Some((key, value)) = line.split_once('=') {
                         if let Some(g) = inventory.groups.get_mut(group_name) {
-                            g.vars.insert(key.trim().to_string(), value.trim().to_string());
+                            let value = value.trim();
+                            let unquoted = ['"', '\''].iter().find_map(|&q| value.strip_prefix(q)?.strip_suffix(q));
+                            g.vars.insert(key.trim().to_string(), unquoted.unwrap_or(value).to_string());
                         }
                     }
                 } else if group.ends_with(":children") {
@@ -220,6 +249,13 @@ impl Inventory {
             }
         }
 
+        // Every host belongs to `all`, whether or not it is listed there.
+        if let (true, Some(all)) = (self.hosts.contains_key(host_name), self.groups.get("all")) {
+            for (k, v) in &all.vars {
+                vars.entry(k.clone()).or_insert_with(|| v.clone());
+            }
+        }
+
         if let Some(host) = self.hosts.get(host_name) {
             for (k, v) in &host.vars {
                 vars.insert(k.clone(), v.clone());
@@ -305,6 +341,17 @@ mod tests {
         assert_eq!(group.vars.get("max_clients").unwrap(), "200");
     }
 
+    #[test]
+    fn quoted_values_keep_their_spaces() {
+        let inv = Inventory::from_ini(
+            "[web]\nweb1 ansible_ssh_common_args='-o ProxyJump=bastion' motd=\"hello there\"\n[web:vars]\nansible_ssh_extra_args=\"-o Port=2222\"",
+        );
+        let host = inv.hosts.get("web1").unwrap();
+        assert_eq!(host.vars.get("ansible_ssh_common_args").unwrap(), "-o ProxyJump=bastion");
+        assert_eq!(host.vars.get("motd").unwrap(), "hello there");
+        assert_eq!(inv.groups["web"].vars.get("ansible_ssh_extra_args").unwrap(), "-o Port=2222");
+    }
+
     #[test]
     fn parse_group_children() {
         let inv = Inventory::from_ini("[web]\nweb1\n\n[db]\ndb1\n\n[all:children]\nweb\ndb");
@@ -399,6 +446,15 @@ mod tests {
         assert_eq!(vars.get("ansible_host").unwrap(), "192.168.1.1");
     }
 
+    #[test]
+    fn get_host_vars_from_all_vars() {
+        let inv = Inventory::from_ini("[web]\nweb1\n[web:vars]\nhttp_port=80\n[all:vars]\nhttp_port=8080\nansible_user=deploy");
+        let vars = inv.get_host_vars("web1");
+        assert_eq!(vars.get("http_port").unwrap(), "80");
+        assert_eq!(vars.get("ansible_user").unwrap(), "deploy");
+        assert!(inv.get_host_vars("unknown").is_empty());
+    }
+
     #[test]
     fn get_all_hosts() {
         let inv = Inventory::from_ini("[web]\nweb1\nweb2\n[db]\ndb1");
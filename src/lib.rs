@@ -0,0 +1,6 @@
+pub mod executor;
+pub mod inventory;
+pub mod modules;
+pub mod playbook;
+pub mod ssh;
+pub mod template;
@@ -1,17 +1,13 @@
-mod executor;
-mod inventory;
-mod modules;
-mod playbook;
-mod ssh;
-mod template;
-
 use anyhow::{Context, Result};
 use clap::Parser;
 use colored::Colorize;
-use executor::Executor;
-use inventory::Inventory;
-use ssh::Auth;
+use wand::executor::Executor;
+use wand::inventory::Inventory;
+use wand::playbook;
+use wand::ssh::{HostKeyCheck, HostKeyPolicy, Prompter, SshConfig, TerminalPrompter};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "wand")]
@@ -37,14 +33,32 @@ struct Cli {
     #[arg(short, long)]
     limit: Option<String>,
 
-    /// SSH private key path
-    #[arg(long)]
+    /// SSH private key for hosts without ansible_ssh_private_key_file
+    #[arg(long, visible_alias = "key-file")]
     private_key: Option<PathBuf>,
 
+    /// Ask for the SSH password for hosts without ansible_password
+    #[arg(short = 'k', long)]
+    ask_pass: bool,
+
     /// Remote user
     #[arg(short, long)]
     user: Option<String>,
 
+    /// SSH host key checking: strict, accept-new or off (default: strict,
+    /// or ANSIBLE_HOST_KEY_CHECKING)
+    #[arg(long, value_parser = parse_host_key_policy)]
+    host_key_checking: Option<HostKeyPolicy>,
+
+    /// A known_hosts file trusted alongside ~/.ssh/known_hosts, where new
+    /// host keys are recorded
+    #[arg(long)]
+    known_hosts: Option<PathBuf>,
+
+    /// Connection timeout in seconds
+    #[arg(short = 'T', long, default_value = "10")]
+    timeout: u64,
+
     /// Number of parallel processes (default: 5)
     #[arg(short, long, default_value = "5")]
     forks: usize,
@@ -61,11 +75,23 @@ struct Cli {
     #[arg(long)]
     skip_tags: Vec<String>,
 
+    /// Run notified handlers even on hosts that failed
+    #[arg(long)]
+    force_handlers: bool,
+
+    /// Render undefined variables as empty strings instead of failing
+    #[arg(long)]
+    lenient_undefined: bool,
+
     /// Verbosity level
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 }
 
+fn parse_host_key_policy(s: &str) -> Result<HostKeyPolicy, String> {
+    HostKeyPolicy::parse(s).ok_or_else(|| format!("expected strict, accept-new or off, not '{}'", s))
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
@@ -80,13 +106,22 @@ fn main() -> Result<()> {
     let plays = playbook::parse_playbook(&playbook_content)
         .with_context(|| "failed to parse playbook")?;
 
-    // Setup auth
-    let auth = if let Some(key_path) = &cli.private_key {
-        Auth::key(key_path.to_str().unwrap_or(""))
-    } else {
-        Auth::agent()
+    // Passphrases, keyboard-interactive answers and --ask-pass are asked
+    // on the terminal
+    let prompter = Arc::new(TerminalPrompter::default());
+    let password = match cli.ask_pass {
+        true => Some(prompter.secret("SSH password: ").context("failed to read the SSH password")?),
+        false => None,
     };
 
+    let policy = cli.host_key_checking.or_else(|| {
+        std::env::var("ANSIBLE_HOST_KEY_CHECKING").ok().and_then(|v| HostKeyPolicy::parse(&v))
+    });
+    let mut host_keys = HostKeyCheck::new(policy.unwrap_or_default());
+    if let Some(path) = &cli.known_hosts {
+        host_keys = host_keys.extra_file(path);
+    }
+
     // Parse extra vars
     let mut extra_vars = std::collections::HashMap::new();
     for var in &cli.extra_vars {
@@ -95,15 +130,31 @@ fn main() -> Result<()> {
         }
     }
 
+    let playbook_dir = match cli.playbook.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
     // Create executor
     let executor = Executor::new(inventory)
+        .inventory_file(cli.inventory.clone())
         .with_vars(extra_vars)
         .check_mode(cli.check)
         .diff_mode(cli.diff)
         .forks(cli.forks)
         .tags(cli.tags)
         .skip_tags(cli.skip_tags)
-        .limit(cli.limit);
+        .limit(cli.limit)
+        .playbook_dir(playbook_dir)
+        .lenient_undefined(cli.lenient_undefined)
+        .force_handlers(cli.force_handlers)
+        .connect_timeout(Duration::from_secs(cli.timeout))
+        .ssh_config(SshConfig::load_default())
+        .host_key_checking(host_keys)
+        .private_key(cli.private_key)
+        .password(password)
+        .prompter(prompter)
+        .on_retry(|line| println!("{}", line.dimmed()));
 
     // Print header
     println!();
@@ -116,6 +167,7 @@ fn main() -> Result<()> {
     let mut total_changed = 0;
     let mut total_failed = 0;
     let mut total_skipped = 0;
+    let mut total_unreachable = 0;
 
     // Run plays
     for play in &plays {
@@ -127,9 +179,13 @@ fn main() -> Result<()> {
         );
         println!();
 
-        let results = executor.run_play(play, &auth);
+        let results = executor.run_play(play);
 
         for result in &results {
+            if let Some(msg) = &result.unreachable_msg {
+                println!("{}: [{}] => {}", "UNREACHABLE".red().bold(), result.host.cyan(), msg);
+            }
+
             for task_result in &result.task_results {
                 let is_skipped = task_result.result.msg.contains("skipped");
                 let (status, color_status) = if task_result.result.failed {
@@ -149,10 +205,10 @@ fn main() -> Result<()> {
                     task_result.task_name
                 );
 
-                if cli.diff && task_result.result.diff.is_some() {
+                if let Some(diff) = task_result.result.diff.as_ref().filter(|_| cli.diff) {
                     println!("--- before");
                     println!("+++ after");
-                    for line in task_result.result.diff.as_ref().unwrap().lines() {
+                    for line in diff.lines() {
                         if line.starts_with('-') {
                             println!("{}", line.red());
                         } else if line.starts_with('+') {
@@ -183,6 +239,7 @@ fn main() -> Result<()> {
             total_changed += result.changed;
             total_failed += result.failed;
             total_skipped += result.skipped;
+            total_unreachable += result.unreachable;
         }
 
         println!();
@@ -192,10 +249,11 @@ fn main() -> Result<()> {
     println!("{} {}", "PLAY RECAP".bold(), "*".repeat(50));
     print!("{}={} ", "ok".green(), total_ok);
     print!("{}={} ", "changed".yellow(), total_changed);
+    print!("{}={} ", "unreachable".red(), total_unreachable);
     print!("{}={} ", "skipped".blue(), total_skipped);
     println!("{}={}", "failed".red(), total_failed);
 
-    if total_failed > 0 {
+    if total_failed > 0 || total_unreachable > 0 {
         std::process::exit(1);
     }
 
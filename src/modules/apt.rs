@@ -1,7 +1,44 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::{Module, ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
+pub struct Apt;
+
+impl Module for Apt {
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
+        run(conn, args)
+    }
+
+    fn command(&self, args: &ModuleArgs) -> Option<Result<String, String>> {
+        Some(apt_line(args))
+    }
+}
+
+/// Shell test for whether package `name` is installed.
+fn installed(name: &str) -> String {
+    format!("dpkg-query -W -f='${{Status}}' {} 2>/dev/null | grep -q 'ok installed'", name)
+}
+
+fn apt_get(action: &str, name: &str) -> String {
+    format!("DEBIAN_FRONTEND=noninteractive apt-get {} -y -qq {}", action, name)
+}
+
+/// The whole task as one shell command, for running it as an `async` job.
+fn apt_line(args: &ModuleArgs) -> Result<String, String> {
+    let name = args.require("name")?;
+    let state = args.get_or("state", "present");
+    let line = match state.as_str() {
+        "present" | "installed" => format!("{} || {}", installed(name), apt_get("install", name)),
+        "absent" | "removed" => format!("! {} || {}", installed(name), apt_get("remove", name)),
+        "latest" => apt_get("install", name),
+        _ => return Err(format!("unknown state: {}", state)),
+    };
+    Ok(match args.get_bool("update_cache") {
+        true => format!("apt-get update -qq && {{ {}; }}", line),
+        false => line,
+    })
+}
+
+fn run(conn: &dyn Connection, args: &ModuleArgs) -> ModuleResult {
     let name = match args.require("name") {
         Ok(n) => n.clone(),
         Err(e) => return ModuleResult::failed(&e),
@@ -22,7 +59,7 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
 
     // Check current state
     let is_installed = conn
-        .exec(&format!("dpkg-query -W -f='${{Status}}' {} 2>/dev/null | grep -q 'ok installed'", name))
+        .exec(&installed(&name))
         .map(|r| r.exit_code == 0)
         .unwrap_or(false);
 
@@ -32,7 +69,7 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
                 return ModuleResult::ok("package already installed");
             }
 
-            match conn.exec(&format!("DEBIAN_FRONTEND=noninteractive apt-get install -y -qq {}", name)) {
+            match conn.exec(&apt_get("install", &name)) {
                 Ok(r) if r.exit_code == 0 => ModuleResult::changed("package installed"),
                 Ok(r) => ModuleResult::failed(&format!("apt install failed: {}", r.stderr)),
                 Err(e) => ModuleResult::failed(&format!("apt install failed: {}", e)),
@@ -43,7 +80,7 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
                 return ModuleResult::ok("package already absent");
             }
 
-            match conn.exec(&format!("DEBIAN_FRONTEND=noninteractive apt-get remove -y -qq {}", name)) {
+            match conn.exec(&apt_get("remove", &name)) {
                 Ok(r) if r.exit_code == 0 => ModuleResult::changed("package removed"),
                 Ok(r) => ModuleResult::failed(&format!("apt remove failed: {}", r.stderr)),
                 Err(e) => ModuleResult::failed(&format!("apt remove failed: {}", e)),
@@ -51,9 +88,9 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
         }
         "latest" => {
             let cmd = if is_installed {
-                format!("DEBIAN_FRONTEND=noninteractive apt-get install -y -qq --only-upgrade {}", name)
+                apt_get("install --only-upgrade", &name)
             } else {
-                format!("DEBIAN_FRONTEND=noninteractive apt-get install -y -qq {}", name)
+                apt_get("install", &name)
             };
 
             match conn.exec(&cmd) {
@@ -87,4 +124,24 @@ mod tests {
         let args = ModuleArgs::new();
         assert_eq!(args.get_or("state", "present"), "present");
     }
+
+    #[test]
+    fn async_command_checks_before_changing() {
+        let mut args = ModuleArgs::new();
+        args.insert("name", "nginx");
+        let check = "dpkg-query -W -f='${Status}' nginx 2>/dev/null | grep -q 'ok installed'";
+        let install = "DEBIAN_FRONTEND=noninteractive apt-get install -y -qq nginx";
+        assert_eq!(Apt.command(&args).unwrap().unwrap(), format!("{} || {}", check, install));
+
+        args.insert("state", "absent");
+        args.insert("update_cache", "yes");
+        let remove = "DEBIAN_FRONTEND=noninteractive apt-get remove -y -qq nginx";
+        assert_eq!(
+            Apt.command(&args).unwrap().unwrap(),
+            format!("apt-get update -qq && {{ ! {} || {}; }}", check, remove)
+        );
+
+        args.insert("state", "newest");
+        assert_eq!(Apt.command(&args).unwrap(), Err("unknown state: newest".to_string()));
+    }
 }
@@ -0,0 +1,204 @@
+//! Detached task execution for `async:` tasks and the `async_status` module.
+//!
+//! A job runs on the target under a small shell wrapper that records its
+//! output and exit code in a job directory, so the controller only needs
+//! short-lived commands to start it and check on it later. Everything goes
+//! through `Connection::exec` and works the same over SSH and locally.
+
+use super::{ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::{shell_quote, Connection};
+use serde_json::Value;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where job directories live unless `ansible_async_dir` says otherwise.
+const DEFAULT_ASYNC_DIR: &str = "~/.ansible_async";
+
+/// The state of a job as seen from its job directory.
+enum JobStatus {
+    Running,
+    Finished(ModuleResult),
+}
+
+/// Starts `command` in the background with a time limit of `limit` seconds
+/// and returns its job id.
+fn launch(conn: &dyn Connection, async_dir: &str, command: &str, limit: u64) -> Result<String, String> {
+    let jid = format!("j{}.{}", rand::random::<u32>(), std::process::id());
+    let dir = job_dir(async_dir, &jid);
+    let script = format!(
+        "d={dir}; mkdir -p \"$d\" && \
+         (timeout {limit} sh -c {cmd} >\"$d/stdout\" 2>\"$d/stderr\"; echo $? >\"$d/rc.tmp\"; mv \"$d/rc.tmp\" \"$d/rc\") \
+         </dev/null >/dev/null 2>&1 &",
+        dir = dir,
+        limit = limit,
+        cmd = shell_quote(command),
+    );
+
+    match conn.exec(&script) {
+        Ok(r) if r.exit_code == 0 => Ok(jid),
+        Ok(r) => Err(format!("failed to start async job: {}", r.stderr.trim())),
+        Err(e) => Err(format!("failed to start async job: {}", e)),
+    }
+}
+
+/// Where job directories live on the host the task runs on.
+pub fn async_dir(ctx: &ModuleContext) -> String {
+    match ctx.vars.get("ansible_async_dir").and_then(Value::as_str) {
+        Some(dir) => ctx.env.render_str(dir, ctx.vars).unwrap_or_else(|_| dir.to_string()),
+        None => DEFAULT_ASYNC_DIR.to_string(),
+    }
+}
+
+/// Runs `command` as a job and polls it every `poll` seconds until it
+/// finishes or runs past `limit` seconds. A `poll` of 0 returns as soon as
+/// the job has started.
+pub fn run_job(conn: &dyn Connection, async_dir: &str, command: &str, limit: u64, poll: u64) -> ModuleResult {
+    let jid = match launch(conn, async_dir, command, limit) {
+        Ok(jid) => jid,
+        Err(e) => return ModuleResult::failed(&e),
+    };
+
+    if poll == 0 {
+        return started(async_dir, &jid);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(limit);
+    loop {
+        match status(conn, async_dir, &jid) {
+            Ok(JobStatus::Finished(result)) => return finished(result, &jid, limit),
+            Ok(JobStatus::Running) if Instant::now() >= deadline => {
+                return ModuleResult::failed(&format!(
+                    "async task did not complete within the requested time - {}s",
+                    limit
+                ))
+                .with_extra("ansible_job_id", jid.as_str());
+            }
+            Ok(JobStatus::Running) => thread::sleep(Duration::from_secs(poll)),
+            Err(e) => return ModuleResult::failed(&e),
+        }
+    }
+}
+
+/// The `async_status` module: reports on (or with `mode=cleanup`, removes)
+/// the job named by `jid`.
+pub fn run(conn: &dyn Connection, args: &ModuleArgs, ctx: &ModuleContext) -> ModuleResult {
+    let async_dir = &async_dir(ctx);
+    let jid = match args.require("jid") {
+        Ok(jid) => jid.clone(),
+        Err(e) => return ModuleResult::failed(&e),
+    };
+
+    if args.get_or("mode", "status") == "cleanup" {
+        return match conn.exec(&format!("rm -rf {}", job_dir(async_dir, &jid))) {
+            Ok(_) => ModuleResult::ok("job cleaned up").with_extra("ansible_job_id", jid.as_str()),
+            Err(e) => ModuleResult::failed(&format!("failed to clean up job {}: {}", jid, e)),
+        };
+    }
+
+    match status(conn, async_dir, &jid) {
+        Ok(JobStatus::Running) => started(async_dir, &jid),
+        Ok(JobStatus::Finished(result)) => finished(result, &jid, 0),
+        Err(e) => ModuleResult::failed(&e),
+    }
+}
+
+fn status(conn: &dyn Connection, async_dir: &str, jid: &str) -> Result<JobStatus, String> {
+    let dir = job_dir(async_dir, jid);
+    let check = format!(
+        "d={}; test -d \"$d\" || exit 3; test -f \"$d/rc\" || exit 2; cat \"$d/rc\"",
+        dir
+    );
+    let r = conn
+        .exec(&check)
+        .map_err(|e| format!("failed to check async job {}: {}", jid, e))?;
+
+    match r.exit_code {
+        0 => {}
+        2 => return Ok(JobStatus::Running),
+        _ => return Err(format!("could not find job {}", jid)),
+    }
+
+    let rc: i32 = r.stdout.trim().parse().unwrap_or(-1);
+    let read = |name: &str| {
+        conn.exec(&format!("cat {}/{}", dir, name))
+            .map(|r| r.stdout)
+            .unwrap_or_default()
+    };
+    let result = ModuleResult::changed("async job finished").with_output(&read("stdout"), &read("stderr"), rc);
+    Ok(JobStatus::Finished(result))
+}
+
+fn started(async_dir: &str, jid: &str) -> ModuleResult {
+    ModuleResult::changed("async task started")
+        .with_extra("ansible_job_id", jid)
+        .with_extra("results_file", format!("{}/{}", async_dir, jid))
+        .with_extra("started", 1)
+        .with_extra("finished", 0)
+}
+
+fn finished(mut result: ModuleResult, jid: &str, limit: u64) -> ModuleResult {
+    // `timeout` exits with 124 when it had to stop the job.
+    if limit > 0 && result.rc == 124 {
+        result.msg = format!("async task did not complete within the requested time - {}s", limit);
+    }
+    result.with_extra("ansible_job_id", jid).with_extra("finished", 1)
+}
+
+/// Shell expression for a job's directory, expanding a leading `~/`.
+fn job_dir(async_dir: &str, jid: &str) -> String {
+    let path = format!("{}/{}", async_dir.trim_end_matches('/'), jid);
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("\"$HOME\"/{}", shell_quote(rest)),
+        None => shell_quote(&path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::LocalConnection;
+
+    fn async_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("wand-async-{}-{}", std::process::id(), name));
+        dir.display().to_string()
+    }
+
+    #[test]
+    fn job_dir_expands_home() {
+        assert_eq!(job_dir("~/.ansible_async", "j1"), "\"$HOME\"/'.ansible_async/j1'");
+        assert_eq!(job_dir("/tmp/jobs/", "j1"), "'/tmp/jobs/j1'");
+    }
+
+    #[test]
+    fn run_polls_until_finished() {
+        let conn = LocalConnection::new();
+        let dir = async_dir("poll");
+        let result = run_job(&conn, &dir, "echo 'it''s done'; exit 3", 30, 1);
+        assert!(result.failed);
+        assert_eq!((result.rc, result.stdout.trim()), (3, "its done"));
+        assert_eq!(result.extra["finished"], 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn run_times_out() {
+        let conn = LocalConnection::new();
+        let dir = async_dir("timeout");
+        let result = run_job(&conn, &dir, "sleep 5", 1, 1);
+        assert!(result.failed);
+        assert_eq!(result.msg, "async task did not complete within the requested time - 1s");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn async_status_of_unknown_job() {
+        let conn = LocalConnection::new();
+        let mut args = ModuleArgs::new();
+        args.insert("jid", "j0.0");
+        let env = crate::template::Environment::new();
+        let mut vars = crate::template::HostVars::default();
+        vars.insert("ansible_async_dir".to_string(), Value::from(async_dir("missing")));
+        let result = run(&conn, &args, &ModuleContext { env: &env, vars: &vars });
+        assert_eq!(result.msg, "could not find job j0.0");
+    }
+}
@@ -1,50 +1,77 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::{Module, ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
+pub struct Command;
+
+impl Module for Command {
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
+        run_command(conn, args, command_line(args, |cmd| cmd), "command")
+    }
+
+    fn command(&self, args: &ModuleArgs) -> Option<Result<String, String>> {
+        Some(command_line(args, |cmd| cmd))
+    }
+}
+
+/// The command from `_raw` or `cmd`, passed through `wrap` and run from
+/// `chdir` if given.
+pub(super) fn command_line(args: &ModuleArgs, wrap: impl FnOnce(String) -> String) -> Result<String, String> {
     let cmd = match args.get("_raw") {
         Some(c) => c.clone(),
-        None => match args.require("cmd") {
-            Ok(c) => c.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
+        None => args.require("cmd")?.clone(),
+    };
+
+    Ok(match args.get("chdir") {
+        Some(dir) => format!("cd {} && {}", dir, wrap(cmd)),
+        None => wrap(cmd),
+    })
+}
+
+/// Runs `cmd` unless `creates` or `removes` show there is nothing to do.
+pub(super) fn run_command(
+    conn: &dyn Connection,
+    args: &ModuleArgs,
+    cmd: Result<String, String>,
+    what: &str,
+) -> ModuleResult {
+    let cmd = match cmd {
+        Ok(c) => c,
+        Err(e) => return ModuleResult::failed(&e),
     };
 
-    let chdir = args.get("chdir");
-    let creates = args.get("creates");
-    let removes = args.get("removes");
+    if let Some(result) = already_done(conn, args) {
+        return result;
+    }
 
-    // Check creates condition
-    if let Some(path) = creates {
+    match conn.exec(&cmd) {
+        Ok(result) => ModuleResult::changed(&format!("{} executed", what))
+            .with_output(&result.stdout, &result.stderr, result.exit_code),
+        Err(e) => ModuleResult::failed(&format!("{} failed: {}", what, e)),
+    }
+}
+
+/// The result of skipping a command because the path in `creates` exists
+/// or the one in `removes` does not.
+pub(super) fn already_done(conn: &dyn Connection, args: &ModuleArgs) -> Option<ModuleResult> {
+    if let Some(path) = args.get("creates") {
         match conn.exec(&format!("test -e {}", path)) {
             Ok(result) if result.exit_code == 0 => {
-                return ModuleResult::ok("skipped, creates exists");
+                return Some(ModuleResult::ok("skipped, creates exists"));
             }
             _ => {}
         }
     }
 
-    // Check removes condition
-    if let Some(path) = removes {
+    if let Some(path) = args.get("removes") {
         match conn.exec(&format!("test -e {}", path)) {
             Ok(result) if result.exit_code != 0 => {
-                return ModuleResult::ok("skipped, removes does not exist");
+                return Some(ModuleResult::ok("skipped, removes does not exist"));
             }
             _ => {}
         }
     }
 
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && {}", dir, cmd)
-    } else {
-        cmd
-    };
-
-    match conn.exec(&full_cmd) {
-        Ok(result) => ModuleResult::changed("command executed")
-            .with_output(&result.stdout, &result.stderr, result.exit_code),
-        Err(e) => ModuleResult::failed(&format!("command failed: {}", e)),
-    }
+    None
 }
 
 #[cfg(test)]
@@ -61,7 +88,24 @@ mod tests {
     #[test]
     fn missing_command_fails() {
         let args = ModuleArgs::new();
-        // Can't test without SSH connection, but we can verify args validation
-        assert!(args.require("cmd").is_err());
+        assert!(Command.command(&args).unwrap().is_err());
+    }
+
+    #[test]
+    fn command_line_with_chdir() {
+        let mut args = ModuleArgs::new();
+        args.insert("cmd", "ls");
+        args.insert("chdir", "/tmp");
+        assert_eq!(Command.command(&args).unwrap().unwrap(), "cd /tmp && ls");
+    }
+
+    #[test]
+    fn creates_skips_command() {
+        let conn = crate::ssh::LocalConnection::new();
+        let mut args = ModuleArgs::new();
+        args.insert("creates", "/");
+        assert_eq!(already_done(&conn, &args).unwrap().msg, "skipped, creates exists");
+        args.insert("creates", "/nonexistent/wand");
+        assert!(already_done(&conn, &args).is_none());
     }
 }
@@ -1,8 +1,8 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::{ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 use std::path::Path;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
+pub fn run(conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
     let dest = match args.require("dest") {
         Ok(d) => d.clone(),
         Err(e) => return ModuleResult::failed(&e),
@@ -14,13 +14,10 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
     // Either src (file) or content (inline)
     if let Some(content) = args.get("content") {
         // Check if file exists and has same content
-        match conn.read_file(&dest) {
-            Ok(existing) => {
-                if existing == content.as_bytes() {
-                    return ModuleResult::ok("content unchanged");
-                }
+        if let Ok(existing) = conn.read_file(&dest) {
+            if existing == content.as_bytes() {
+                return ModuleResult::ok("content unchanged");
             }
-            Err(_) => {} // File doesn't exist, will create
         }
 
         match conn.write_file(&dest, content.as_bytes(), mode_int) {
@@ -67,7 +64,7 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
     }
 }
 
-fn compute_diff(old: &str, new: &str) -> String {
+pub(super) fn compute_diff(old: &str, new: &str) -> String {
     let mut diff = String::new();
 
     for line in old.lines() {
@@ -97,8 +94,10 @@ mod tests {
     fn requires_src_or_content() {
         let mut args = ModuleArgs::new();
         args.insert("dest", "/tmp/test");
-        // Would fail with "either 'src' or 'content' is required"
-        // Can't test without SSH connection
+        let env = crate::template::Environment::new();
+        let ctx = ModuleContext { env: &env, vars: &Default::default() };
+        let conn = crate::ssh::LocalConnection::new();
+        assert_eq!(run(&conn, &args, &ctx).msg, "either 'src' or 'content' is required");
     }
 
     #[test]
@@ -1,7 +1,7 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::{ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::{Connection, FileKind};
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
+pub fn run(conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
     let path = match args.require("path") {
         Ok(p) => p.clone(),
         Err(e) => return ModuleResult::failed(&e),
@@ -29,17 +29,13 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
 }
 
 fn ensure_file(
-    conn: &SshConnection,
+    conn: &dyn Connection,
     path: &str,
     mode: Option<&String>,
     owner: Option<&String>,
     group: Option<&String>,
 ) -> ModuleResult {
-    // Check if file exists
-    let exists = conn
-        .exec(&format!("test -f {}", path))
-        .map(|r| r.exit_code == 0)
-        .unwrap_or(false);
+    let exists = matches!(conn.stat(path), Ok(Some(_)));
 
     if !exists {
         return ModuleResult::failed(&format!("path does not exist: {}", path));
@@ -73,16 +69,13 @@ fn ensure_file(
 }
 
 fn ensure_directory(
-    conn: &SshConnection,
+    conn: &dyn Connection,
     path: &str,
     mode: Option<&String>,
     owner: Option<&String>,
     group: Option<&String>,
 ) -> ModuleResult {
-    let exists = conn
-        .exec(&format!("test -d {}", path))
-        .map(|r| r.exit_code == 0)
-        .unwrap_or(false);
+    let exists = matches!(conn.stat(path), Ok(Some(stat)) if stat.kind == FileKind::Directory);
 
     let mut changed = false;
 
@@ -118,11 +111,8 @@ fn ensure_directory(
     }
 }
 
-fn ensure_absent(conn: &SshConnection, path: &str) -> ModuleResult {
-    let exists = conn
-        .exec(&format!("test -e {}", path))
-        .map(|r| r.exit_code == 0)
-        .unwrap_or(false);
+fn ensure_absent(conn: &dyn Connection, path: &str) -> ModuleResult {
+    let exists = matches!(conn.stat(path), Ok(Some(_)));
 
     if !exists {
         return ModuleResult::ok("path already absent");
@@ -134,7 +124,7 @@ fn ensure_absent(conn: &SshConnection, path: &str) -> ModuleResult {
     }
 }
 
-fn ensure_link(conn: &SshConnection, path: &str, src: &str) -> ModuleResult {
+fn ensure_link(conn: &dyn Connection, path: &str, src: &str) -> ModuleResult {
     // Check if link exists and points to correct target
     let current_target = conn
         .exec(&format!("readlink {}", path))
@@ -156,7 +146,7 @@ fn ensure_link(conn: &SshConnection, path: &str, src: &str) -> ModuleResult {
 }
 
 fn ensure_touch(
-    conn: &SshConnection,
+    conn: &dyn Connection,
     path: &str,
     mode: Option<&String>,
     owner: Option<&String>,
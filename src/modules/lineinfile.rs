@@ -1,7 +1,7 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::{ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
+pub fn run(conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
     let path = match args.require("path") {
         Ok(p) => p.clone(),
         Err(e) => return ModuleResult::failed(&e),
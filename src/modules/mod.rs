@@ -1,7 +1,12 @@
+use crate::ssh::Connection;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
 pub mod apt;
+pub mod async_status;
+pub mod blockinfile;
 pub mod command;
 pub mod copy;
 pub mod file;
@@ -12,6 +17,87 @@ pub mod service;
 pub mod shell;
 pub mod template;
 
+/// What a module can see of the task it runs for besides its arguments.
+pub struct ModuleContext<'a> {
+    pub env: &'a crate::template::Environment,
+    pub vars: &'a crate::template::HostVars,
+}
+
+/// A task module: does its work on a host through the connection.
+pub trait Module: Send + Sync {
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, ctx: &ModuleContext) -> ModuleResult;
+
+    /// The shell command the module runs, for modules that can run as
+    /// `async` jobs.
+    fn command(&self, _args: &ModuleArgs) -> Option<Result<String, String>> {
+        None
+    }
+}
+
+impl<F> Module for F
+where
+    F: Fn(&dyn Connection, &ModuleArgs, &ModuleContext) -> ModuleResult + Send + Sync,
+{
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, ctx: &ModuleContext) -> ModuleResult {
+        self(conn, args, ctx)
+    }
+}
+
+/// Modules by name. The default registry holds the built-in modules, each
+/// also under its `ansible.builtin.` and `ansible.legacy.` name.
+#[derive(Clone)]
+pub struct ModuleRegistry {
+    modules: HashMap<String, Arc<dyn Module>>,
+}
+
+impl ModuleRegistry {
+    pub fn empty() -> Self {
+        Self { modules: HashMap::new() }
+    }
+
+    pub fn register<M: Module + 'static>(&mut self, name: &str, module: M) {
+        self.modules.insert(name.to_string(), Arc::new(module));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Module>> {
+        self.modules.get(name).cloned()
+    }
+
+    fn register_builtin<M: Module + 'static>(&mut self, name: &str, module: M) {
+        let module: Arc<dyn Module> = Arc::new(module);
+        for prefix in ["", "ansible.builtin.", "ansible.legacy."] {
+            self.modules.insert(format!("{}{}", prefix, name), module.clone());
+        }
+    }
+}
+
+impl Default for ModuleRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register_builtin("command", command::Command);
+        registry.register_builtin("shell", shell::Shell);
+        registry.register_builtin("raw", raw::Raw);
+        registry.register_builtin("script", script::Script);
+        registry.register_builtin("copy", copy::run);
+        registry.register_builtin("file", file::run);
+        registry.register_builtin("template", template::run);
+        registry.register_builtin("apt", apt::Apt);
+        registry.register_builtin("service", service::Service);
+        registry.register_builtin("lineinfile", lineinfile::run);
+        registry.register_builtin("blockinfile", blockinfile::run);
+        registry.register_builtin("async_status", async_status::run);
+        registry
+    }
+}
+
+impl fmt::Debug for ModuleRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.modules.keys().collect();
+        names.sort();
+        f.debug_struct("ModuleRegistry").field("modules", &names).finish()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ModuleResult {
     pub changed: bool,
@@ -83,6 +169,11 @@ impl ModuleResult {
         self.diff = Some(diff);
         self
     }
+
+    pub fn with_extra(mut self, key: &str, value: impl Into<serde_yaml::Value>) -> Self {
+        self.extra.insert(key.to_string(), value.into());
+        self
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -97,10 +188,6 @@ impl ModuleArgs {
         }
     }
 
-    pub fn from_map(map: HashMap<String, String>) -> Self {
-        Self { args: map }
-    }
-
     pub fn get(&self, key: &str) -> Option<&String> {
         self.args.get(key)
     }
@@ -199,6 +286,30 @@ mod tests {
         assert!(!args.get_bool("missing"));
     }
 
+    #[test]
+    fn builtin_modules_have_fqcn_aliases() {
+        let registry = ModuleRegistry::default();
+        assert!(registry.get("copy").is_some());
+        assert!(registry.get("ansible.builtin.copy").is_some());
+        assert!(registry.get("ansible.legacy.shell").is_some());
+        assert!(registry.get("yum").is_none());
+        assert!(registry.get("ansible.builtin.command").unwrap().command(&ModuleArgs::new()).is_some());
+        assert!(registry.get("copy").unwrap().command(&ModuleArgs::new()).is_none());
+    }
+
+    #[test]
+    fn custom_module() {
+        let mut registry = ModuleRegistry::empty();
+        registry.register("acme.tools.hello", |_: &dyn Connection, args: &ModuleArgs, _: &ModuleContext| {
+            ModuleResult::ok(&format!("hello {}", args.get_or("name", "world")))
+        });
+        let env = crate::template::Environment::new();
+        let ctx = ModuleContext { env: &env, vars: &Default::default() };
+        let conn = crate::ssh::LocalConnection::new();
+        let result = registry.get("acme.tools.hello").unwrap().run(&conn, &ModuleArgs::new(), &ctx);
+        assert_eq!(result.msg, "hello world");
+    }
+
     #[test]
     fn module_args_require() {
         let mut args = ModuleArgs::new();
@@ -1,47 +1,16 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::command::{command_line, run_command};
+use super::{Module, ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
-    let cmd = match args.get("_raw") {
-        Some(c) => c.clone(),
-        None => match args.require("cmd") {
-            Ok(c) => c.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
-    };
+pub struct Raw;
 
-    let chdir = args.get("chdir");
-    let creates = args.get("creates");
-    let removes = args.get("removes");
-
-    if let Some(path) = creates {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code == 0 => {
-                return ModuleResult::ok("skipped, creates exists");
-            }
-            _ => {}
-        }
-    }
-
-    if let Some(path) = removes {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code != 0 => {
-                return ModuleResult::ok("skipped, removes does not exist");
-            }
-            _ => {}
-        }
+impl Module for Raw {
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
+        run_command(conn, args, command_line(args, |cmd| cmd), "raw command")
     }
 
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && {}", dir, cmd)
-    } else {
-        cmd
-    };
-
-    match conn.exec(&full_cmd) {
-        Ok(result) => ModuleResult::changed("raw command executed")
-            .with_output(&result.stdout, &result.stderr, result.exit_code),
-        Err(e) => ModuleResult::failed(&format!("raw command failed: {}", e)),
+    fn command(&self, args: &ModuleArgs) -> Option<Result<String, String>> {
+        Some(command_line(args, |cmd| cmd))
     }
 }
 
@@ -53,12 +22,12 @@ mod tests {
     fn args_parsing() {
         let mut args = ModuleArgs::new();
         args.insert("_raw", "echo hello");
-        assert_eq!(args.get("_raw"), Some(&"echo hello".to_string()));
+        assert_eq!(Raw.command(&args).unwrap().unwrap(), "echo hello");
     }
 
     #[test]
     fn missing_command_fails() {
         let args = ModuleArgs::new();
-        assert!(args.require("cmd").is_err());
+        assert!(Raw.command(&args).unwrap().is_err());
     }
 }
@@ -1,61 +1,72 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::command::already_done;
+use super::{Module, ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::{shell_quote, Connection, ExecOptions};
 use std::path::Path;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
-    let script_path = match args.get("_raw_params") {
-        Some(p) => p.clone(),
-        None => match args.require("cmd") {
-            Ok(p) => p.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
+pub struct Script;
+
+impl Module for Script {
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
+        run(conn, args)
+    }
+
+    fn command(&self, args: &ModuleArgs) -> Option<Result<String, String>> {
+        Some(script_line(args))
+    }
+}
+
+fn script_path(args: &ModuleArgs) -> Result<String, String> {
+    match args.get("_raw").or_else(|| args.get("_raw_params")) {
+        Some(p) => Ok(p.clone()),
+        None => args.require("cmd").cloned(),
+    }
+}
+
+/// The whole task as one shell command, for running it as an `async` job:
+/// the script travels inside the command and is written to a temporary
+/// file on the host, run and removed.
+fn script_line(args: &ModuleArgs) -> Result<String, String> {
+    let script_path = script_path(args)?;
+    let script = std::fs::read_to_string(&script_path).map_err(|_| format!("script not found: {}", script_path))?;
+    let cd = match args.get("chdir") {
+        Some(dir) => format!("cd {} && ", dir),
+        None => String::new(),
+    };
+    Ok(format!(
+        "f=$(mktemp) && printf %s {} >\"$f\" && chmod 700 \"$f\" && ({}\"$f\"); rc=$?; rm -f \"$f\"; exit $rc",
+        shell_quote(&script),
+        cd
+    ))
+}
+
+fn run(conn: &dyn Connection, args: &ModuleArgs) -> ModuleResult {
+    let script_path = match script_path(args) {
+        Ok(p) => p,
+        Err(e) => return ModuleResult::failed(&e),
     };
 
     let chdir = args.get("chdir");
-    let creates = args.get("creates");
-    let removes = args.get("removes");
 
     if !Path::new(&script_path).exists() {
         return ModuleResult::failed(&format!("script not found: {}", script_path));
     }
 
-    if let Some(path) = creates {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code == 0 => {
-                return ModuleResult::ok("skipped, creates exists");
-            }
-            _ => {}
-        }
+    if let Some(result) = already_done(conn, args) {
+        return result;
     }
 
-    if let Some(path) = removes {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code != 0 => {
-                return ModuleResult::ok("skipped, removes does not exist");
-            }
-            _ => {}
-        }
-    }
-
-    let script_content = match std::fs::read(&script_path) {
-        Ok(content) => content,
-        Err(e) => return ModuleResult::failed(&format!("failed to read script: {}", e)),
-    };
-
     let remote_path = "/tmp/.ansible_script";
 
-    match conn.write_file(remote_path, &script_content, 0o700) {
-        Ok(_) => {}
-        Err(e) => return ModuleResult::failed(&format!("failed to upload script: {}", e)),
+    if let Err(e) = conn.put_file(Path::new(&script_path), remote_path, 0o700) {
+        return ModuleResult::failed(&format!("failed to upload script: {}", e));
     }
 
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && {}", dir, remote_path)
-    } else {
-        remote_path.to_string()
-    };
+    let mut opts = ExecOptions::default();
+    if let Some(dir) = chdir {
+        opts = opts.cwd(dir);
+    }
 
-    match conn.exec(&full_cmd) {
+    match conn.exec_with(remote_path, &opts) {
         Ok(result) => ModuleResult::changed("script executed")
             .with_output(&result.stdout, &result.stderr, result.exit_code),
         Err(e) => ModuleResult::failed(&format!("script failed: {}", e)),
@@ -78,4 +89,19 @@ mod tests {
         let args = ModuleArgs::new();
         assert!(args.require("cmd").is_err());
     }
+
+    #[test]
+    fn async_command_carries_the_script() {
+        let path = std::env::temp_dir().join(format!("wand-script-{}.sh", std::process::id()));
+        std::fs::write(&path, "#!/bin/sh\necho \"it's $(pwd)\"\nexit 3\n").unwrap();
+        let mut args = ModuleArgs::new();
+        args.insert("_raw_params", &path.display().to_string());
+        args.insert("chdir", "/");
+        let cmd = Script.command(&args).unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let result = crate::ssh::LocalConnection::new().exec(&cmd).unwrap();
+        assert_eq!((result.exit_code, result.stdout.as_str()), (3, "it's /\n"));
+        assert_eq!(Script.command(&args).unwrap(), Err(format!("script not found: {}", path.display())));
+    }
 }
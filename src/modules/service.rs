@@ -1,7 +1,48 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::{Module, ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
+pub struct Service;
+
+impl Module for Service {
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
+        run(conn, args)
+    }
+
+    fn command(&self, args: &ModuleArgs) -> Option<Result<String, String>> {
+        Some(service_line(args))
+    }
+}
+
+/// The whole task as one shell command, for running it as an `async` job.
+/// Each step only acts when the service is not already as asked.
+fn service_line(args: &ModuleArgs) -> Result<String, String> {
+    let name = args.require("name")?;
+    let mut steps = Vec::new();
+
+    if let Some(en) = args.get("enabled") {
+        steps.push(match en == "true" || en == "yes" {
+            true => format!("systemctl is-enabled -q {0} || systemctl enable {0}", name),
+            false => format!("! systemctl is-enabled -q {0} || systemctl disable {0}", name),
+        });
+    }
+
+    if let Some(st) = args.get("state") {
+        steps.push(match st.as_str() {
+            "started" => format!("systemctl is-active -q {0} || systemctl start {0}", name),
+            "stopped" => format!("! systemctl is-active -q {0} || systemctl stop {0}", name),
+            "restarted" => format!("systemctl restart {}", name),
+            "reloaded" => format!("systemctl reload {}", name),
+            _ => return Err(format!("unknown state: {}", st)),
+        });
+    }
+
+    Ok(match steps.is_empty() {
+        true => "true".to_string(),
+        false => steps.into_iter().map(|step| format!("{{ {}; }}", step)).collect::<Vec<_>>().join(" && "),
+    })
+}
+
+fn run(conn: &dyn Connection, args: &ModuleArgs) -> ModuleResult {
     let name = match args.require("name") {
         Ok(n) => n.clone(),
         Err(e) => return ModuleResult::failed(&e),
@@ -93,4 +134,19 @@ mod tests {
         let args = ModuleArgs::new();
         assert!(args.require("name").is_err());
     }
+
+    #[test]
+    fn async_command_runs_each_step() {
+        let mut args = ModuleArgs::new();
+        args.insert("name", "nginx");
+        assert_eq!(Service.command(&args).unwrap().unwrap(), "true");
+
+        args.insert("enabled", "yes");
+        args.insert("state", "stopped");
+        assert_eq!(
+            Service.command(&args).unwrap().unwrap(),
+            "{ systemctl is-enabled -q nginx || systemctl enable nginx; } && \
+             { ! systemctl is-active -q nginx || systemctl stop nginx; }"
+        );
+    }
 }
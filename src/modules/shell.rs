@@ -1,51 +1,23 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::command::{command_line, run_command};
+use super::{Module, ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs) -> ModuleResult {
-    let cmd = match args.get("_raw") {
-        Some(c) => c.clone(),
-        None => match args.require("cmd") {
-            Ok(c) => c.clone(),
-            Err(e) => return ModuleResult::failed(&e),
-        },
-    };
+pub struct Shell;
 
-    let chdir = args.get("chdir");
-    let creates = args.get("creates");
-    let removes = args.get("removes");
-    let executable = args.get_or("executable", "/bin/sh");
-
-    // Check creates condition
-    if let Some(path) = creates {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code == 0 => {
-                return ModuleResult::ok("skipped, creates exists");
-            }
-            _ => {}
-        }
+impl Module for Shell {
+    fn run(&self, conn: &dyn Connection, args: &ModuleArgs, _ctx: &ModuleContext) -> ModuleResult {
+        run_command(conn, args, shell_line(args), "shell command")
     }
 
-    // Check removes condition
-    if let Some(path) = removes {
-        match conn.exec(&format!("test -e {}", path)) {
-            Ok(result) if result.exit_code != 0 => {
-                return ModuleResult::ok("skipped, removes does not exist");
-            }
-            _ => {}
-        }
+    fn command(&self, args: &ModuleArgs) -> Option<Result<String, String>> {
+        Some(shell_line(args))
     }
+}
 
-    let full_cmd = if let Some(dir) = chdir {
-        format!("cd {} && {} -c '{}'", dir, executable, cmd.replace('\'', "'\\''"))
-    } else {
-        format!("{} -c '{}'", executable, cmd.replace('\'', "'\\''"))
-    };
-
-    match conn.exec(&full_cmd) {
-        Ok(result) => ModuleResult::changed("shell command executed")
-            .with_output(&result.stdout, &result.stderr, result.exit_code),
-        Err(e) => ModuleResult::failed(&format!("shell command failed: {}", e)),
-    }
+/// The command run through `executable`, `/bin/sh` by default.
+fn shell_line(args: &ModuleArgs) -> Result<String, String> {
+    let executable = args.get_or("executable", "/bin/sh");
+    command_line(args, |cmd| format!("{} -c '{}'", executable, cmd.replace('\'', "'\\''")))
 }
 
 #[cfg(test)]
@@ -54,7 +26,8 @@ mod tests {
 
     #[test]
     fn default_executable() {
-        let args = ModuleArgs::new();
-        assert_eq!(args.get_or("executable", "/bin/sh"), "/bin/sh");
+        let mut args = ModuleArgs::new();
+        args.insert("_raw", "echo 'hi'");
+        assert_eq!(Shell.command(&args).unwrap().unwrap(), r#"/bin/sh -c 'echo '\''hi'\'''"#);
     }
 }
@@ -1,10 +1,9 @@
-use super::{ModuleArgs, ModuleResult};
-use crate::ssh::SshConnection;
+use super::{ModuleArgs, ModuleContext, ModuleResult};
+use crate::ssh::Connection;
 use crate::template as tpl;
-use std::collections::HashMap;
-use std::path::Path;
+use std::path::PathBuf;
 
-pub fn run(conn: &SshConnection, args: &ModuleArgs, vars: &HashMap<String, String>) -> ModuleResult {
+pub fn run(conn: &dyn Connection, args: &ModuleArgs, ctx: &ModuleContext) -> ModuleResult {
     let src = match args.require("src") {
         Ok(s) => s.clone(),
         Err(e) => return ModuleResult::failed(&e),
@@ -18,24 +17,17 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs, vars: &HashMap<String, Strin
     let mode = args.get_or("mode", "0644");
     let mode_int = i32::from_str_radix(&mode, 8).unwrap_or(0o644);
 
-    // Read local template
-    let src_path = Path::new(&src);
-    let template_content = match std::fs::read_to_string(src_path) {
-        Ok(c) => c,
-        Err(e) => return ModuleResult::failed(&format!("failed to read template: {}", e)),
-    };
-
     // Render template
-    let rendered = tpl::render(&template_content, vars);
+    let rendered = match ctx.env.render_file(&src, ctx.vars) {
+        Ok(r) => r,
+        Err(e) => return ModuleResult::failed(&format!("failed to render template: {}", e)),
+    };
 
     // Check if remote file exists and has same content
-    match conn.read_file(&dest) {
-        Ok(existing) => {
-            if existing == rendered.as_bytes() {
-                return ModuleResult::ok("template unchanged");
-            }
+    if let Ok(existing) = conn.read_file(&dest) {
+        if existing == rendered.as_bytes() {
+            return ModuleResult::ok("template unchanged");
         }
-        Err(_) => {} // File doesn't exist, will create
     }
 
     // Write rendered content
@@ -45,9 +37,22 @@ pub fn run(conn: &SshConnection, args: &ModuleArgs, vars: &HashMap<String, Strin
     }
 }
 
+/// Template search path: the role's `templates/` directory, then the
+/// playbook's `templates/` directory and the playbook directory itself.
+pub fn environment(vars: &dyn tpl::Vars) -> tpl::Environment {
+    let var = |name: &str| vars.get_var(name).and_then(|v| v.as_str().map(PathBuf::from));
+    let mut env = tpl::Environment::new();
+    if let Some(role_path) = var("role_path") {
+        env = env.search_path(role_path.join("templates"));
+    }
+    let playbook_dir = var("playbook_dir").unwrap_or_else(|| PathBuf::from("."));
+    env.search_path(playbook_dir.join("templates")).search_path(playbook_dir)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn requires_src() {
@@ -61,4 +66,22 @@ mod tests {
         args.insert("src", "/tmp/template.j2");
         assert!(args.require("dest").is_err());
     }
+
+    #[test]
+    fn search_path_order() {
+        let dir = std::env::temp_dir().join(format!("wand-template-module-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("templates")).unwrap();
+        std::fs::create_dir_all(dir.join("role/templates")).unwrap();
+        std::fs::write(dir.join("templates/app.j2"), "playbook").unwrap();
+        std::fs::write(dir.join("role/templates/app.j2"), "role").unwrap();
+
+        let mut vars = HashMap::new();
+        vars.insert("playbook_dir".to_string(), dir.display().to_string());
+        assert_eq!(environment(&vars).render_file("app.j2", &vars).unwrap(), "playbook");
+
+        vars.insert("role_path".to_string(), dir.join("role").display().to_string());
+        assert_eq!(environment(&vars).render_file("app.j2", &vars).unwrap(), "role");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
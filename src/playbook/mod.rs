@@ -1,3 +1,4 @@
+use rand::seq::SliceRandom;
 use serde::Deserialize;
 use std::collections::HashMap;
 
@@ -18,6 +19,145 @@ pub struct Play {
     pub become_: bool,
     #[serde(default)]
     pub become_user: Option<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default, deserialize_with = "deserialize_serial")]
+    pub serial: Vec<BatchSize>,
+    #[serde(default)]
+    pub max_fail_percentage: Option<f64>,
+    #[serde(default)]
+    pub any_errors_fatal: bool,
+    #[serde(default)]
+    pub order: HostOrder,
+    #[serde(default)]
+    pub force_handlers: bool,
+}
+
+impl Play {
+    /// Splits the play's hosts into `serial` batches. The last batch size
+    /// repeats until every host is covered; without `serial` the whole host
+    /// list is a single batch.
+    pub fn batches<'a>(&self, hosts: &'a [String]) -> Vec<&'a [String]> {
+        let mut batches = Vec::new();
+        let mut rest = hosts;
+        let mut sizes = self.serial.iter();
+        let mut size = hosts.len();
+
+        while !rest.is_empty() {
+            if let Some(next) = sizes.next() {
+                size = next.hosts(hosts.len());
+            }
+            let (batch, tail) = rest.split_at(size.clamp(1, rest.len()));
+            batches.push(batch);
+            rest = tail;
+        }
+        batches
+    }
+}
+
+/// The order in which a play visits its hosts.
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HostOrder {
+    #[default]
+    Inventory,
+    ReverseInventory,
+    Sorted,
+    ReverseSorted,
+    Shuffle,
+}
+
+impl HostOrder {
+    /// Reorders hosts that are given in inventory order.
+    pub fn sort_hosts(&self, hosts: &mut [String]) {
+        match self {
+            HostOrder::Inventory => {}
+            HostOrder::ReverseInventory => hosts.reverse(),
+            HostOrder::Sorted => hosts.sort(),
+            HostOrder::ReverseSorted => hosts.sort_by(|a, b| b.cmp(a)),
+            HostOrder::Shuffle => hosts.shuffle(&mut rand::thread_rng()),
+        }
+    }
+}
+
+/// One entry of a play's `serial` keyword.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BatchSize {
+    Hosts(usize),
+    Percent(f64),
+}
+
+impl BatchSize {
+    /// Number of hosts in a batch out of `total`; percentages round down
+    /// but never below one host.
+    pub fn hosts(&self, total: usize) -> usize {
+        match *self {
+            BatchSize::Hosts(0) => total,
+            BatchSize::Hosts(n) => n,
+            BatchSize::Percent(pct) => ((total as f64 * pct / 100.0) as usize).max(1),
+        }
+    }
+}
+
+impl std::str::FromStr for BatchSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let parsed = match s.strip_suffix('%') {
+            Some(pct) => pct.trim().parse().ok().filter(|p: &f64| *p >= 0.0).map(BatchSize::Percent),
+            None => s.parse().ok().map(BatchSize::Hosts),
+        };
+        parsed.ok_or_else(|| format!("invalid serial value '{}'", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for BatchSize {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Int(usize),
+            Str(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Int(n) => Ok(BatchSize::Hosts(n)),
+            Raw::Str(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+fn deserialize_serial<'de, D>(deserializer: D) -> Result<Vec<BatchSize>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(BatchSize),
+        Many(Vec<BatchSize>),
+    }
+
+    Ok(match Option::<OneOrMany>::deserialize(deserializer)? {
+        Some(OneOrMany::One(size)) => vec![size],
+        Some(OneOrMany::Many(sizes)) => sizes,
+        None => Vec::new(),
+    })
+}
+
+/// How a play schedules tasks across hosts.
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Each task runs on all hosts before the next task starts.
+    #[default]
+    Linear,
+    /// Each host runs through the play as fast as it can.
+    Free,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
@@ -28,27 +168,57 @@ pub struct Task {
     pub when: Option<String>,
     #[serde(default)]
     pub register: Option<String>,
-    #[serde(default)]
-    pub notify: Option<Vec<String>>,
-    #[serde(default, deserialize_with = "deserialize_tags")]
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub notify: Vec<String>,
+    /// Notification topics a handler answers to besides its name.
+    #[serde(default, deserialize_with = "deserialize_string_list")]
+    pub listen: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_string_list")]
     pub tags: Vec<String>,
     #[serde(default)]
     pub with_items: Option<Vec<serde_yaml::Value>>,
     #[serde(default, rename = "loop")]
     pub loop_: Option<Vec<serde_yaml::Value>>,
+    #[serde(default)]
+    pub any_errors_fatal: bool,
+    #[serde(default)]
+    pub run_once: bool,
+    #[serde(default)]
+    pub delegate_to: Option<String>,
+    #[serde(default)]
+    pub delegate_facts: bool,
+    #[serde(default)]
+    pub until: Option<String>,
+    #[serde(default)]
+    pub retries: Option<i64>,
+    /// Seconds to wait between `until` attempts.
+    #[serde(default)]
+    pub delay: Option<u64>,
+    /// Seconds the task may run before it is stopped and failed.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Run the task detached, giving up after this many seconds.
+    #[serde(default, rename = "async")]
+    pub async_: Option<u64>,
+    /// Seconds between status checks of an `async` task; 0 doesn't wait.
+    #[serde(default)]
+    pub poll: Option<u64>,
+    /// Most hosts that may run this task at once; 0 means no limit.
+    #[serde(default)]
+    pub throttle: usize,
     #[serde(flatten)]
     pub module: HashMap<String, serde_yaml::Value>,
 }
 
-fn deserialize_tags<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+fn deserialize_string_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     use serde::de::{self, Visitor};
 
-    struct TagsVisitor;
+    struct StringListVisitor;
 
-    impl<'de> Visitor<'de> for TagsVisitor {
+    impl<'de> Visitor<'de> for StringListVisitor {
         type Value = Vec<String>;
 
         fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -66,11 +236,11 @@ where
         where
             A: de::SeqAccess<'de>,
         {
-            let mut tags = Vec::new();
-            while let Some(tag) = seq.next_element::<String>()? {
-                tags.push(tag);
+            let mut items = Vec::new();
+            while let Some(item) = seq.next_element::<String>()? {
+                items.push(item);
             }
-            Ok(tags)
+            Ok(items)
         }
 
         fn visit_none<E>(self) -> Result<Vec<String>, E>
@@ -88,7 +258,7 @@ where
         }
     }
 
-    deserializer.deserialize_any(TagsVisitor)
+    deserializer.deserialize_any(StringListVisitor)
 }
 
 pub fn parse_playbook(content: &str) -> Result<Vec<Play>, serde_yaml::Error> {
@@ -287,4 +457,92 @@ mod tests {
         let plays = parse_playbook(yaml).unwrap();
         assert!(plays[0].vars_files.is_empty());
     }
+
+    #[test]
+    fn parse_strategy() {
+        let yaml = r#"
+- hosts: all
+  tasks: []
+- hosts: all
+  strategy: free
+  tasks: []
+"#;
+        let plays = parse_playbook(yaml).unwrap();
+        assert_eq!(plays[0].strategy, Strategy::Linear);
+        assert_eq!(plays[1].strategy, Strategy::Free);
+        assert!(parse_playbook("- hosts: all\n  strategy: bogus\n").is_err());
+    }
+
+    #[test]
+    fn parse_serial() {
+        let yaml = r#"
+- hosts: all
+  serial: 2
+- hosts: all
+  serial: "30%"
+- hosts: all
+  serial: [1, 5, "25%"]
+  max_fail_percentage: 10
+"#;
+        let plays = parse_playbook(yaml).unwrap();
+        assert_eq!(plays[0].serial, vec![BatchSize::Hosts(2)]);
+        assert_eq!(plays[1].serial, vec![BatchSize::Percent(30.0)]);
+        assert_eq!(
+            plays[2].serial,
+            vec![BatchSize::Hosts(1), BatchSize::Hosts(5), BatchSize::Percent(25.0)]
+        );
+        assert_eq!(plays[2].max_fail_percentage, Some(10.0));
+        assert!(parse_playbook("- hosts: all\n  serial: lots\n").is_err());
+    }
+
+    #[test]
+    fn serial_batches() {
+        let hosts: Vec<String> = (1..=10).map(|i| format!("h{}", i)).collect();
+        let sizes = |yaml: &str| -> Vec<usize> {
+            let play = &parse_playbook(yaml).unwrap()[0];
+            play.batches(&hosts).iter().map(|b| b.len()).collect()
+        };
+        assert_eq!(sizes("- hosts: all\n"), vec![10]);
+        assert_eq!(sizes("- hosts: all\n  serial: 4\n"), vec![4, 4, 2]);
+        assert_eq!(sizes("- hosts: all\n  serial: '25%'\n"), vec![2, 2, 2, 2, 2]);
+        assert_eq!(sizes("- hosts: all\n  serial: [1, 3, '50%']\n"), vec![1, 3, 5, 1]);
+        assert_eq!(sizes("- hosts: all\n  serial: '1%'\n").len(), 10);
+    }
+
+    #[test]
+    fn host_order() {
+        let plays = parse_playbook("- hosts: all\n- hosts: all\n  order: reverse_sorted\n").unwrap();
+        assert_eq!(plays[0].order, HostOrder::Inventory);
+        assert_eq!(plays[1].order, HostOrder::ReverseSorted);
+
+        let order = |order: HostOrder| {
+            let mut hosts = vec!["b".to_string(), "c".to_string(), "a".to_string()];
+            order.sort_hosts(&mut hosts);
+            hosts.concat()
+        };
+        assert_eq!(order(HostOrder::Inventory), "bca");
+        assert_eq!(order(HostOrder::ReverseInventory), "acb");
+        assert_eq!(order(HostOrder::Sorted), "abc");
+        assert_eq!(order(HostOrder::ReverseSorted), "cba");
+        assert_eq!(order(HostOrder::Shuffle).len(), 3);
+    }
+
+    #[test]
+    fn parse_notify_and_listen() {
+        let yaml = r#"
+- hosts: all
+  force_handlers: true
+  tasks:
+    - command: echo
+      notify: restart web
+  handlers:
+    - name: restart nginx
+      listen: [restart web, reload all]
+      command: echo
+"#;
+        let plays = parse_playbook(yaml).unwrap();
+        assert!(plays[0].force_handlers);
+        assert_eq!(plays[0].tasks[0].notify, vec!["restart web"]);
+        assert_eq!(plays[0].handlers[0].listen, vec!["restart web", "reload all"]);
+    }
 }
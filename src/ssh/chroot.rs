@@ -0,0 +1,239 @@
+//! Directory trees on this machine entered with `chroot`, or booted as a
+//! lightweight container with `systemd-nspawn`. Commands run inside the
+//! tree; files are read and written directly under its root path.
+
+use anyhow::{anyhow, Result};
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use super::local::{output, stat_path, write_path};
+use super::{with_deadline, CommandResult, Connection, ExecOptions, FileStat};
+
+/// How many symlinks a path may go through, as with Linux's `MAXSYMLINKS`.
+const MAX_LINKS: usize = 40;
+
+pub struct ChrootConnection {
+    root: PathBuf,
+    host: String,
+    nspawn: bool,
+    exe: String,
+}
+
+impl ChrootConnection {
+    pub fn chroot(root: &str) -> Self {
+        Self::new(root, false, "chroot")
+    }
+
+    pub fn nspawn(root: &str) -> Self {
+        Self::new(root, true, "systemd-nspawn")
+    }
+
+    fn new(root: &str, nspawn: bool, exe: &str) -> Self {
+        Self {
+            root: PathBuf::from(root),
+            host: root.to_string(),
+            nspawn,
+            exe: exe.to_string(),
+        }
+    }
+
+    /// Uses `exe` in place of `chroot` or `systemd-nspawn`.
+    pub fn executable(mut self, exe: &str) -> Self {
+        self.exe = exe.to_string();
+        self
+    }
+
+    fn exec_args(&self, command: &str) -> Vec<String> {
+        let mut args = match self.nspawn {
+            true => vec![
+                "--quiet".to_string(),
+                "--pipe".to_string(),
+                format!("--directory={}", self.root.display()),
+            ],
+            false => vec![self.root.display().to_string()],
+        };
+        args.extend(["sh".to_string(), "-c".to_string(), command.to_string()]);
+        args
+    }
+
+    /// Where `path` inside the tree is on this machine. Symlinks resolve
+    /// against the root, as they would inside the chroot, and `..` stops at
+    /// it, so the result never lies outside the tree. A final symlink is
+    /// only followed with `follow`.
+    fn host_path(&self, path: &str, follow: bool) -> Result<PathBuf> {
+        // Components still to resolve, the next one last.
+        let mut pending = Vec::new();
+        push_components(&mut pending, Path::new(path));
+        let mut resolved = PathBuf::new();
+        let mut links = 0;
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                resolved.pop();
+                continue;
+            }
+            let candidate = resolved.join(&name);
+            if pending.is_empty() && !follow {
+                resolved = candidate;
+                break;
+            }
+            match std::fs::read_link(self.root.join(&candidate)) {
+                Ok(target) => {
+                    links += 1;
+                    if links > MAX_LINKS {
+                        return Err(anyhow!("{}: too many levels of symbolic links", path));
+                    }
+                    if target.is_absolute() {
+                        resolved.clear();
+                    }
+                    push_components(&mut pending, &target);
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::InvalidInput) => resolved = candidate,
+                Err(e) => return Err(anyhow!("{}: {}", path, e)),
+            }
+        }
+        Ok(self.root.join(resolved))
+    }
+}
+
+/// Queues the parts of `path` onto `pending` so that its first part is
+/// popped next.
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    let parts = path.components().filter_map(|c| match c {
+        Component::Normal(name) => Some(name.to_os_string()),
+        Component::ParentDir => Some(OsString::from("..")),
+        _ => None,
+    });
+    let start = pending.len();
+    pending.extend(parts);
+    pending[start..].reverse();
+}
+
+impl Connection for ChrootConnection {
+    fn host(&self) -> &str {
+        &self.host
+    }
+
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult> {
+        let command = with_deadline(&opts.command_line(command), opts.deadline)?;
+        let out = output(Command::new(&self.exe).args(self.exec_args(&command)), opts.stdin.as_deref())
+            .map_err(|e| anyhow!("{}: {}", self.exe, e))?;
+        Ok(out.into())
+    }
+
+    fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
+        write_path(&self.host_path(path, true)?, content, mode)
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        Ok(std::fs::read(self.host_path(path, true)?)?)
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+        stat_path(&self.host_path(path, false)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::FileKind;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wand-chroot-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(dir.join("etc")).unwrap();
+        dir
+    }
+
+    /// Copies `/bin/sh` and the libraries it links into `root`, or returns
+    /// false where that isn't possible.
+    fn install_shell(root: &Path) -> bool {
+        let Ok(ldd) = Command::new("ldd").arg("/bin/sh").output() else {
+            return false;
+        };
+        let libs = String::from_utf8_lossy(&ldd.stdout)
+            .split_whitespace()
+            .filter(|word| word.starts_with('/'))
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        for file in libs.iter().map(PathBuf::as_path).chain([Path::new("/bin/sh")]) {
+            let dest = root.join(file.strip_prefix("/").unwrap());
+            std::fs::create_dir_all(dest.parent().unwrap()).unwrap();
+            if std::fs::copy(file, &dest).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn exec_args() {
+        let conn = ChrootConnection::chroot("/srv/image");
+        assert_eq!(conn.exec_args("true").join(" "), "/srv/image sh -c true");
+        let conn = ChrootConnection::nspawn("/srv/image");
+        assert_eq!(conn.exec_args("true").join(" "), "--quiet --pipe --directory=/srv/image sh -c true");
+    }
+
+    #[test]
+    fn files_live_under_the_root() {
+        let root = temp_root("files");
+        let conn = ChrootConnection::chroot(root.to_str().unwrap());
+        conn.write_file("/etc/motd", b"golden\n", 0o644).unwrap();
+        assert_eq!(std::fs::read(root.join("etc/motd")).unwrap(), b"golden\n");
+        assert_eq!(conn.read_file("/etc/motd").unwrap(), b"golden\n");
+        assert_eq!(conn.stat("/etc").unwrap().unwrap().kind, FileKind::Directory);
+        assert_eq!(conn.stat("/etc/missing").unwrap(), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn symlinks_and_dot_dot_stay_inside_the_root() {
+        let root = temp_root("links");
+        let outside = std::env::temp_dir().join(format!("wand-chroot-{}-outside", std::process::id()));
+        std::fs::create_dir_all(&outside).unwrap();
+        let host_file = outside.join("stub-resolv.conf");
+        std::fs::write(&host_file, "host\n").unwrap();
+
+        // An absolute link means the same path inside the image.
+        let in_tree = root.join(host_file.strip_prefix("/").unwrap());
+        std::fs::create_dir_all(in_tree.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&host_file, root.join("etc/resolv.conf")).unwrap();
+        std::os::unix::fs::symlink("../../../../..", root.join("etc/up")).unwrap();
+
+        let conn = ChrootConnection::chroot(root.to_str().unwrap());
+        conn.write_file("/etc/resolv.conf", b"image\n", 0o644).unwrap();
+        assert_eq!(std::fs::read(&host_file).unwrap(), b"host\n");
+        assert_eq!(std::fs::read(&in_tree).unwrap(), b"image\n");
+        assert_eq!(conn.read_file("/etc/resolv.conf").unwrap(), b"image\n");
+        assert_eq!(conn.stat("/etc/resolv.conf").unwrap().unwrap().kind, FileKind::Symlink);
+
+        conn.write_file("/../../escaped", b"x", 0o644).unwrap();
+        conn.write_file("/etc/up/escaped-too", b"x", 0o644).unwrap();
+        assert!(root.join("escaped").exists() && root.join("escaped-too").exists());
+
+        std::os::unix::fs::symlink("loop", root.join("loop")).unwrap();
+        assert!(conn.read_file("/loop").is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn exec_inside_the_root() {
+        let root = temp_root("exec");
+        // Entering a chroot needs root and a shell we can copy in.
+        let is_root = Command::new("id").arg("-u").output().map(|o| o.stdout == b"0\n").unwrap_or(false);
+        if !is_root || !install_shell(&root) {
+            std::fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+        std::fs::write(root.join("marker"), "").unwrap();
+
+        let conn = ChrootConnection::chroot(root.to_str().unwrap());
+        let opts = ExecOptions::default().env("IMAGE", "golden").cwd("/etc");
+        let r = conn.exec_with("test -e /marker && echo $IMAGE $(pwd)", &opts).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!((r.exit_code, r.stdout.trim()), (0, "golden /etc"));
+    }
+}
@@ -0,0 +1,446 @@
+//! The OpenSSH client configuration, as far as it applies to how we
+//! connect: `Host` and `Match host` blocks, `Include`, and the HostName,
+//! User, Port, IdentityFile, ProxyJump, ConnectTimeout,
+//! StrictHostKeyChecking and UserKnownHostsFile options.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::HostKeyPolicy;
+
+/// How deep `Include` may nest before we assume a loop.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// The `ssh` flags that take a value, which may be the next word.
+const TAKES_VALUE: &str = "BbcDEeFIiJLlmOopQRSWw";
+
+/// A parsed `ssh_config` file.
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    blocks: Vec<Block>,
+}
+
+/// Options that apply when `condition` matches, in file order.
+#[derive(Debug, Clone)]
+struct Block {
+    condition: Condition,
+    options: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    /// Before the first `Host` or `Match` line, or `Match all`.
+    Always,
+    /// `Host` patterns, matched against the name given on the command line.
+    Host(Vec<String>),
+    /// `Match host` patterns, matched against the name after any HostName.
+    MatchHost(Vec<String>),
+    /// A `Match` we don't evaluate, which never applies.
+    Never,
+}
+
+/// The settings that apply to one host.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    pub strict_host_key_checking: Option<HostKeyPolicy>,
+    pub user_known_hosts_file: Option<String>,
+}
+
+impl SshConfig {
+    /// `~/.ssh/config`, or an empty configuration if there is none.
+    pub fn load_default() -> Self {
+        match home_dir() {
+            Some(home) => Self::load(&home.join(".ssh/config")).unwrap_or_default(),
+            None => Self::default(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(Self::parse(&text, path.parent().unwrap_or(Path::new("."))))
+    }
+
+    /// Parses a configuration whose relative `Include` paths are relative
+    /// to `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Self {
+        let mut config = Self {
+            blocks: vec![Block {
+                condition: Condition::Always,
+                options: Vec::new(),
+            }],
+        };
+        config.parse_into(text, dir, 0);
+        config
+    }
+
+    fn parse_into(&mut self, text: &str, dir: &Path, depth: usize) {
+        for line in text.lines() {
+            let Some((key, value)) = split_line(line) else {
+                continue;
+            };
+            match key.as_str() {
+                "host" => self.blocks.push(Block {
+                    condition: Condition::Host(words(&value)),
+                    options: Vec::new(),
+                }),
+                "match" => self.blocks.push(Block {
+                    condition: match_condition(&value),
+                    options: Vec::new(),
+                }),
+                // Included files share the block they are included from
+                // until they start their own.
+                "include" if depth < MAX_INCLUDE_DEPTH => {
+                    for pattern in words(&value) {
+                        for path in include_paths(&pattern, dir) {
+                            if let Ok(text) = std::fs::read_to_string(&path) {
+                                self.parse_into(&text, dir, depth + 1);
+                            }
+                        }
+                    }
+                }
+                _ => self.blocks.last_mut().unwrap().options.push((key, value)),
+            }
+        }
+    }
+
+    /// The settings for connecting to `host`. As in OpenSSH, the first
+    /// value found for an option wins, except that identity files add up.
+    pub fn lookup(&self, host: &str) -> HostConfig {
+        let mut config = HostConfig::default();
+        for block in &self.blocks {
+            let target = config.hostname.as_deref().unwrap_or(host);
+            let applies = match &block.condition {
+                Condition::Always => true,
+                Condition::Host(patterns) => matches_patterns(patterns, host),
+                Condition::MatchHost(patterns) => matches_patterns(patterns, target),
+                Condition::Never => false,
+            };
+            if applies {
+                for (key, value) in &block.options {
+                    config.set(key, value, false);
+                }
+            }
+        }
+        config
+    }
+}
+
+impl HostConfig {
+    /// Sets an option by its (lowercase) name. `force` overrides a value
+    /// that is already set, as command-line options do.
+    fn set(&mut self, key: &str, value: &str, force: bool) {
+        fn first<T>(slot: &mut Option<T>, value: Option<T>, force: bool) {
+            if force || slot.is_none() {
+                if let Some(value) = value {
+                    *slot = Some(value);
+                }
+            }
+        }
+        match key {
+            "hostname" => first(&mut self.hostname, Some(value.to_string()), force),
+            "user" => first(&mut self.user, Some(value.to_string()), force),
+            "port" => first(&mut self.port, value.parse().ok(), force),
+            "identityfile" if force => self.identity_files.insert(0, value.to_string()),
+            "identityfile" => self.identity_files.push(value.to_string()),
+            "proxyjump" if value.eq_ignore_ascii_case("none") => first(&mut self.proxy_jump, None, force),
+            "proxyjump" => first(&mut self.proxy_jump, Some(value.to_string()), force),
+            "connecttimeout" => first(&mut self.connect_timeout, value.parse().ok().map(Duration::from_secs), force),
+            "stricthostkeychecking" => first(&mut self.strict_host_key_checking, HostKeyPolicy::parse(value), force),
+            // Only the first of several files, where OpenSSH also records keys.
+            "userknownhostsfile" => first(&mut self.user_known_hosts_file, words(value).into_iter().next(), force),
+            _ => {}
+        }
+    }
+
+    /// Applies `ssh` command-line options such as `-o Port=2222`, `-p`,
+    /// `-l`, `-i` and `-J`, which take precedence over the config file.
+    pub fn apply_args(&mut self, args: &str) {
+        let words = words(args);
+        let mut words = words.iter();
+        while let Some(word) = words.next() {
+            let (flag, inline) = match word.strip_prefix('-') {
+                Some(rest) if !rest.is_empty() => rest.split_at(1),
+                _ => continue,
+            };
+            if !TAKES_VALUE.contains(flag) {
+                continue;
+            }
+            let value = match inline {
+                "" => match words.next() {
+                    Some(value) => value.as_str(),
+                    None => break,
+                },
+                inline => inline,
+            };
+            match flag {
+                "o" => {
+                    if let Some((key, value)) = split_line(value) {
+                        self.set(&key, &value, true);
+                    }
+                }
+                "p" => self.set("port", value, true),
+                "l" => self.set("user", value, true),
+                "i" => self.set("identityfile", value, true),
+                "J" => self.set("proxyjump", value, true),
+                _ => {}
+            }
+        }
+    }
+
+    /// The identity files, expanded with [`expand_path`].
+    pub fn identity_paths(&self, host: &str, user: &str) -> Vec<PathBuf> {
+        let host = self.hostname.as_deref().unwrap_or(host);
+        self.identity_files.iter().map(|file| expand_path(file, host, user)).collect()
+    }
+}
+
+/// Expands a leading `~` and the `%d` (home), `%h` (remote host), `%r`
+/// (remote user) and `%%` tokens in a path.
+pub fn expand_path(path: &str, host: &str, user: &str) -> PathBuf {
+    let home = home_dir().unwrap_or_default().display().to_string();
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", home, rest),
+        None => path.to_string(),
+    };
+    PathBuf::from(expand_tokens(&path, &[('d', &home), ('h', host), ('r', user)]))
+}
+
+/// Splits `Key value`, `Key=value` or `Key = "value"` into a lowercase key
+/// and its value, skipping blank lines and comments.
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let key = line[..end].to_ascii_lowercase();
+    let rest = line[end..].trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim();
+    Some((key, rest.trim_matches('"').to_string()))
+}
+
+/// Whitespace-separated words, honouring single and double quotes.
+fn words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut in_word = false;
+    for c in s.chars() {
+        match (c, quote) {
+            ('"' | '\'', None) => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (c, _) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+fn match_condition(value: &str) -> Condition {
+    let words = words(value);
+    match words.iter().map(|w| w.to_ascii_lowercase()).collect::<Vec<_>>().as_slice() {
+        [all] if all == "all" => Condition::Always,
+        [host, _] if host == "host" => Condition::MatchHost(words[1].split(',').map(String::from).collect()),
+        _ => Condition::Never,
+    }
+}
+
+/// Whether `host` matches a pattern list: some positive pattern matches
+/// and no `!`-negated one does.
+fn matches_patterns(patterns: &[String], host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.iter().flat_map(|p| p.split(',')) {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_match(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= glob_match(pattern, host),
+        }
+    }
+    matched
+}
+
+/// Matches `*` and `?` wildcards, case-insensitively like OpenSSH.
+pub(super) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_ascii_lowercase().chars().collect();
+    let text: Vec<char> = text.to_ascii_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The files an `Include` pattern names: relative to `dir` unless absolute
+/// or under `~`, with a `*` wildcard allowed in the file name.
+fn include_paths(pattern: &str, dir: &Path) -> Vec<PathBuf> {
+    let path = match pattern.strip_prefix("~/") {
+        Some(rest) => home_dir().unwrap_or_default().join(rest),
+        None => dir.join(pattern),
+    };
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return vec![path];
+    }
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(parent)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| glob_match(&name, &e.file_name().to_string_lossy()))
+                .map(|e| e.path())
+                .collect()
+        })
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+fn expand_tokens(s: &str, tokens: &[(char, &str)]) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some(t) => match tokens.iter().find(|(name, _)| *name == t) {
+                Some((_, value)) => out.push_str(value),
+                None => {
+                    out.push('%');
+                    out.push(t);
+                }
+            },
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_value_wins_and_identities_add_up() {
+        let config = SshConfig::parse(
+            r#"
+# bastion-fronted hosts
+Host web? !web9
+    HostName %h.internal
+    User deploy
+    IdentityFile ~/.ssh/web
+
+Host *
+    User nobody
+    Port=2222
+    IdentityFile "~/.ssh/id_ed25519"
+    ConnectTimeout 5
+"#,
+            Path::new("."),
+        );
+        let web = config.lookup("web1");
+        assert_eq!(web.user.as_deref(), Some("deploy"));
+        assert_eq!(web.port, Some(2222));
+        assert_eq!(web.identity_files, vec!["~/.ssh/web", "~/.ssh/id_ed25519"]);
+        assert_eq!(web.connect_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(config.lookup("web9").user.as_deref(), Some("nobody"));
+        assert_eq!(config.lookup("db1").hostname, None);
+    }
+
+    #[test]
+    fn match_host_sees_the_hostname() {
+        let config = SshConfig::parse(
+            "Host db\n  HostName db.prod.example.com\nMatch host *.prod.example.com\n  ProxyJump bastion\nMatch exec \"true\"\n  User ignored\n",
+            Path::new("."),
+        );
+        let db = config.lookup("db");
+        assert_eq!(db.proxy_jump.as_deref(), Some("bastion"));
+        assert_eq!(db.user, None);
+        assert_eq!(config.lookup("other").proxy_jump, None);
+    }
+
+    #[test]
+    fn include_files_relative_to_the_config() {
+        let dir = std::env::temp_dir().join(format!("wand-ssh-config-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("config.d")).unwrap();
+        std::fs::write(dir.join("config.d/10-app"), "Host app\n  Port 2200\n").unwrap();
+        std::fs::write(dir.join("config.d/20-all"), "Host *\n  User ops\n").unwrap();
+        std::fs::write(dir.join("config"), "Include config.d/*\n").unwrap();
+        let config = SshConfig::load(&dir.join("config")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let app = config.lookup("app");
+        assert_eq!((app.port, app.user.as_deref()), (Some(2200), Some("ops")));
+    }
+
+    #[test]
+    fn command_line_args_override_the_file() {
+        let mut config = SshConfig::parse("Host *\n  Port 2222\n  User deploy\n", Path::new(".")).lookup("web1");
+        config.apply_args(
+            "-o Port=22 -oUser=admin -i /keys/web -o 'ConnectTimeout 3' -J jump1,jump2 -C -4 -o StrictHostKeyChecking=accept-new",
+        );
+        assert_eq!(config.port, Some(22));
+        assert_eq!(config.user.as_deref(), Some("admin"));
+        assert_eq!(config.identity_files, vec!["/keys/web"]);
+        assert_eq!(config.proxy_jump.as_deref(), Some("jump1,jump2"));
+        assert_eq!(config.strict_host_key_checking, Some(HostKeyPolicy::AcceptNew));
+    }
+
+    #[test]
+    fn identity_paths_expand_tokens() {
+        let config = HostConfig {
+            hostname: Some("web1.internal".to_string()),
+            identity_files: vec!["/keys/%r@%h".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(config.identity_paths("web1", "deploy"), vec![PathBuf::from("/keys/deploy@web1.internal")]);
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*.example.com", "db.EXAMPLE.com"));
+        assert!(glob_match("web?", "web1"));
+        assert!(!glob_match("web?", "web10"));
+        assert!(matches_patterns(&["*".to_string(), "!bastion".to_string()], "web"));
+        assert!(!matches_patterns(&["*".to_string(), "!bastion".to_string()], "bastion"));
+    }
+}
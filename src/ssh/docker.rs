@@ -0,0 +1,163 @@
+//! Containers reached through `docker exec` or `podman exec`. Files move
+//! through the container's own shell on stdin, so they land with the
+//! exec user's ownership.
+
+use anyhow::{anyhow, Result};
+use std::process::{Command, Output};
+
+use super::local::output;
+use super::{shell_quote, stdin_to_file, with_deadline, CommandResult, Connection, ExecOptions};
+
+pub struct DockerConnection {
+    runtime: String,
+    container: String,
+    user: Option<String>,
+    extra_args: Vec<String>,
+}
+
+impl DockerConnection {
+    /// A connection to `container` using the `runtime` CLI, `docker` or
+    /// `podman` or a path to either.
+    pub fn new(runtime: &str, container: &str) -> Self {
+        Self {
+            runtime: runtime.to_string(),
+            container: container.to_string(),
+            user: None,
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// Runs commands as `user` instead of the container's default user.
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Global runtime options, such as `-H tcp://host:2376`, passed before
+    /// the `exec` subcommand.
+    pub fn extra_args(mut self, args: &str) -> Self {
+        self.extra_args = args.split_whitespace().map(String::from).collect();
+        self
+    }
+
+    /// The runtime's arguments for running `command` in the container.
+    fn exec_args(&self, command: &str, opts: &ExecOptions) -> Vec<String> {
+        let mut args = self.extra_args.clone();
+        args.push("exec".to_string());
+        if opts.stdin.is_some() {
+            args.push("-i".to_string());
+        }
+        if let Some(user) = &self.user {
+            args.extend(["-u".to_string(), user.clone()]);
+        }
+        for (key, value) in &opts.env {
+            args.extend(["-e".to_string(), format!("{}={}", key, value)]);
+        }
+        if let Some(dir) = &opts.cwd {
+            args.extend(["-w".to_string(), dir.clone()]);
+        }
+        args.extend([self.container.clone(), "sh".to_string(), "-c".to_string(), command.to_string()]);
+        args
+    }
+
+    fn run(&self, command: &str, opts: &ExecOptions) -> Result<Output> {
+        let command = with_deadline(command, opts.deadline)?;
+        output(
+            Command::new(&self.runtime).args(self.exec_args(&command, opts)),
+            opts.stdin.as_deref(),
+        )
+        .map_err(|e| anyhow!("{}: {}", self.runtime, e))
+    }
+}
+
+impl Connection for DockerConnection {
+    fn host(&self) -> &str {
+        &self.container
+    }
+
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult> {
+        Ok(self.run(command, opts)?.into())
+    }
+
+    fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
+        let r = self.exec_with(&stdin_to_file(path, mode), &ExecOptions::default().stdin(content))?;
+        match r.exit_code {
+            0 => Ok(()),
+            _ => Err(anyhow!("failed to write {}: {}", path, r.stderr.trim())),
+        }
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let out = self.run(&format!("cat {}", shell_quote(path)), &ExecOptions::default())?;
+        match out.status.success() {
+            true => Ok(out.stdout),
+            false => Err(anyhow!("failed to read {}: {}", path, String::from_utf8_lossy(&out.stderr).trim())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A fake `docker` that logs its arguments and runs the command on this
+    /// machine, honouring `-e` and `-w`.
+    fn shim(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("wand-docker-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("calls.log");
+        let script = format!(
+            r#"#!/bin/sh
+echo "$@" >> {log}
+while [ "$1" != exec ]; do shift; done; shift
+while :; do
+  case "$1" in
+    -i) shift ;;
+    -u) shift 2 ;;
+    -e) export "$2"; shift 2 ;;
+    -w) cd "$2"; shift 2 ;;
+    *) break ;;
+  esac
+done
+shift
+exec "$@"
+"#,
+            log = log.display()
+        );
+        let docker = dir.join("docker");
+        std::fs::write(&docker, script).unwrap();
+        std::process::Command::new("chmod").arg("+x").arg(&docker).status().unwrap();
+        (docker, log)
+    }
+
+    #[test]
+    fn exec_args_follow_the_runtime_cli() {
+        let conn = DockerConnection::new("podman", "web1").user("app").extra_args("--remote --url unix:///run/p.sock");
+        let opts = ExecOptions::default().stdin("x").env("A", "1").cwd("/srv");
+        assert_eq!(
+            conn.exec_args("id", &opts).join(" "),
+            "--remote --url unix:///run/p.sock exec -i -u app -e A=1 -w /srv web1 sh -c id"
+        );
+    }
+
+    #[test]
+    fn exec_and_files_through_the_runtime() {
+        let (docker, log) = shim("files");
+        let dir = docker.parent().unwrap().to_path_buf();
+        let conn = DockerConnection::new(docker.to_str().unwrap(), "web1").user("app");
+
+        let r = conn.exec_with("echo $A $(pwd)", &ExecOptions::default().env("A", "1").cwd("/")).unwrap();
+        assert_eq!(r.stdout.trim(), "1 /");
+
+        let path = dir.join("out.txt").display().to_string();
+        conn.write_file(&path, b"hello\n", 0o600).unwrap();
+        assert_eq!(conn.read_file(&path).unwrap(), b"hello\n");
+        assert!(conn.read_file(&dir.join("missing").display().to_string()).is_err());
+
+        let calls = std::fs::read_to_string(&log).unwrap();
+        assert!(calls.lines().all(|l| l.starts_with("exec ")), "{}", calls);
+        assert!(calls.contains("exec -i -u app web1 sh -c cat >"), "{}", calls);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,318 @@
+//! Jump hosts (bastions) that targets are reached through, as with
+//! OpenSSH's ProxyJump. Each tunnel is a `direct-tcpip` channel on the jump
+//! host's session, relayed to a local socket that the next session runs over.
+
+use anyhow::{anyhow, Result};
+use ssh2::{Channel, ErrorCode, Session};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use super::{connect_tcp, handshake, HostKeyCheck, Login};
+
+/// How long a relay first waits when neither side has anything to move.
+const IDLE: Duration = Duration::from_millis(1);
+
+/// The longest an idle relay waits without either socket waking it. Data
+/// that another relay on the same session read in for this channel doesn't
+/// wake it, so this bounds how late that data can be.
+const MAX_IDLE: Duration = Duration::from_millis(100);
+
+/// One `[user@]host[:port]` entry of a ProxyJump list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JumpSpec {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// Parses a ProxyJump value: comma-separated hops in the order they are
+/// connected, each `[user@]host[:port]` or `ssh://[user@]host[:port]`.
+pub fn parse_proxy_jump(value: &str) -> Vec<JumpSpec> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .map(|hop| {
+            let hop = hop.strip_prefix("ssh://").unwrap_or(hop);
+            let (user, rest) = match hop.rsplit_once('@') {
+                Some((user, rest)) => (Some(user.to_string()), rest),
+                None => (None, hop),
+            };
+            // `[::1]:2222` keeps the colons of an IPv6 address apart from
+            // the port.
+            let (host, port) = match rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => match rest.rsplit_once(':') {
+                    Some((host, port)) if !host.contains(':') => (host, Some(port)),
+                    _ => (rest, None),
+                },
+            };
+            JumpSpec {
+                host: host.to_string(),
+                user,
+                port: port.and_then(|p| p.parse().ok()),
+            }
+        })
+        .collect()
+}
+
+/// An authenticated session used only to open tunnels. It runs
+/// non-blocking so that the relays of every host behind it can share it.
+pub struct JumpHost {
+    session: Session,
+    name: String,
+}
+
+impl JumpHost {
+    /// Connects to `host`, through `via` when it is itself behind a jump
+    /// host.
+    pub fn connect(
+        via: Option<&JumpHost>,
+        host: &str,
+        port: u16,
+        login: &Login,
+        timeout: Duration,
+        host_keys: &HostKeyCheck,
+    ) -> Result<Self> {
+        let session = match via {
+            Some(jump) => handshake(jump.tunnel(host, port, timeout)?, host, port, login, timeout, host_keys)?,
+            None => handshake(connect_tcp(host, port, timeout)?, host, port, login, timeout, host_keys)?,
+        };
+        session.set_blocking(false);
+        Ok(Self {
+            session,
+            name: format!("{}@{}:{}", login.user, host, port),
+        })
+    }
+
+    /// Opens a tunnel from here to `host:port` and returns the local end
+    /// of it.
+    pub fn tunnel(&self, host: &str, port: u16, timeout: Duration) -> Result<UnixStream> {
+        let deadline = Instant::now() + timeout;
+        let mut channel = loop {
+            match self.session.channel_direct_tcpip(host, port, None) {
+                Ok(channel) => break channel,
+                Err(e) if again(&e) && Instant::now() < deadline => std::thread::sleep(IDLE),
+                Err(e) => return Err(anyhow!("{}:{} via {}: {}", host, port, self.name, e)),
+            }
+        };
+        let (ours, mut theirs) = UnixStream::pair()?;
+        let session_fd = self.session.as_raw_fd();
+        std::thread::spawn(move || {
+            let _ = relay(&mut channel, session_fd, &mut theirs, Channel::send_eof);
+        });
+        Ok(ours)
+    }
+
+    /// Whether the session still takes requests, checked before reuse.
+    pub fn is_alive(&self) -> bool {
+        match self.session.keepalive_send() {
+            Ok(_) => true,
+            Err(e) => again(&e),
+        }
+    }
+
+    pub fn close(&self) {
+        let _ = self.session.disconnect(None, "closing connection", None);
+    }
+}
+
+/// LIBSSH2_ERROR_EAGAIN: a non-blocking call that has to be retried.
+fn again(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(-37)
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Copies bytes both ways between a non-blocking `remote` and `local` until
+/// both sides have closed, passing end-of-file on in each direction. When
+/// there is nothing to move it waits for `remote_fd`, the socket beneath
+/// `remote`, or `local` to become ready.
+fn relay<R: Read + Write>(
+    remote: &mut R,
+    remote_fd: RawFd,
+    local: &mut UnixStream,
+    send_eof: impl Fn(&mut R) -> Result<(), ssh2::Error>,
+) -> io::Result<()> {
+    local.set_nonblocking(true)?;
+    let mut buf = vec![0; 32 * 1024];
+    // Bytes read from one side that the other hasn't taken yet.
+    let (mut up, mut down) = (Vec::new(), Vec::new());
+    let (mut local_eof, mut eof_sent, mut remote_eof) = (false, false, false);
+    let mut idle = IDLE;
+    loop {
+        let mut moved = false;
+
+        if up.is_empty() && !local_eof {
+            match local.read(&mut buf) {
+                Ok(0) => local_eof = true,
+                Ok(n) => up.extend_from_slice(&buf[..n]),
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !up.is_empty() {
+            match remote.write(&up) {
+                Ok(n) => {
+                    up.drain(..n);
+                    moved = n > 0;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        } else if local_eof && !eof_sent {
+            match send_eof(remote).map_err(io::Error::from) {
+                Ok(()) => eof_sent = true,
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if down.is_empty() && !remote_eof {
+            match remote.read(&mut buf) {
+                Ok(0) => remote_eof = true,
+                Ok(n) => down.extend_from_slice(&buf[..n]),
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !down.is_empty() {
+            match local.write(&down) {
+                Ok(n) => {
+                    down.drain(..n);
+                    moved = n > 0;
+                }
+                Err(e) if would_block(&e) => {}
+                Err(e) => return Err(e),
+            }
+        } else if remote_eof {
+            let _ = local.shutdown(Shutdown::Write);
+            if eof_sent {
+                return Ok(());
+            }
+        }
+
+        if moved {
+            idle = IDLE;
+            continue;
+        }
+        // Only ask about the local side while it has something to give or
+        // take, as a closed socket would otherwise wake the wait at once.
+        let local_events = match (up.is_empty() && !local_eof, !down.is_empty()) {
+            (true, true) => libc::POLLIN | libc::POLLOUT,
+            (true, false) => libc::POLLIN,
+            (false, true) => libc::POLLOUT,
+            (false, false) => 0,
+        };
+        let remote_events = match up.is_empty() {
+            true => libc::POLLIN,
+            false => libc::POLLIN | libc::POLLOUT,
+        };
+        wait(&[(local.as_raw_fd(), local_events), (remote_fd, remote_events)], idle);
+        idle = (idle * 2).min(MAX_IDLE);
+    }
+}
+
+/// Blocks until one of `fds` is ready for its events or `timeout` passes,
+/// returning whether one became ready. Entries with no events are left out.
+fn wait(fds: &[(RawFd, libc::c_short)], timeout: Duration) -> bool {
+    let mut fds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|&(fd, events)| libc::pollfd {
+            fd: if events == 0 { -1 } else { fd },
+            events,
+            revents: 0,
+        })
+        .collect();
+    // SAFETY: `fds` is a valid array of `fds.len()` pollfd entries.
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout.as_millis() as libc::c_int) };
+    ready > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(host: &str, user: Option<&str>, port: Option<u16>) -> JumpSpec {
+        JumpSpec {
+            host: host.to_string(),
+            user: user.map(String::from),
+            port,
+        }
+    }
+
+    #[test]
+    fn proxy_jump_hops() {
+        assert_eq!(
+            parse_proxy_jump("ops@bastion:2222, inner,ssh://root@[fd00::1]:22,fd00::2"),
+            vec![
+                spec("bastion", Some("ops"), Some(2222)),
+                spec("inner", None, None),
+                spec("fd00::1", Some("root"), Some(22)),
+                spec("fd00::2", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn relay_copies_both_ways_and_passes_eof_on() {
+        // A socket pair stands in for the channel: `far` is the host at the
+        // other end of the tunnel.
+        let (mut remote, mut far) = UnixStream::pair().unwrap();
+        remote.set_nonblocking(true).unwrap();
+        let (mut client, mut local) = UnixStream::pair().unwrap();
+        let relay = std::thread::spawn(move || {
+            let remote_fd = remote.as_raw_fd();
+            relay(&mut remote, remote_fd, &mut local, |r| {
+                r.shutdown(Shutdown::Write).unwrap();
+                Ok(())
+            })
+        });
+
+        // Larger than a socket buffer, so both sides have to wait on the
+        // other at some point.
+        let request: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        let writer = {
+            let request = request.clone();
+            let mut client = client.try_clone().unwrap();
+            std::thread::spawn(move || {
+                client.write_all(&request).unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+            })
+        };
+        let mut received = Vec::new();
+        far.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        assert_eq!(received, request);
+
+        far.write_all(b"pong").unwrap();
+        far.shutdown(Shutdown::Write).unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"pong");
+        relay.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn wait_returns_when_a_socket_is_ready() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let fd = a.as_raw_fd();
+        assert!(!wait(&[(fd, libc::POLLIN)], Duration::from_millis(10)));
+        // An entry with no events is left out of the wait.
+        assert!(!wait(&[(fd, 0)], Duration::from_millis(10)));
+
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            b.write_all(b"x").unwrap();
+        });
+        // Far longer than any test should take: returning true means the
+        // socket woke the wait, not the timeout.
+        assert!(wait(&[(-1, 0), (fd, libc::POLLIN)], Duration::from_secs(600)));
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+//! Checking the keys servers present against OpenSSH known_hosts files.
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use ssh2::{CheckResult, KnownHostFileKind, MethodType, Session};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::config::glob_match;
+
+/// Host key algorithms libssh2 may offer, tried after the ones a host
+/// already has keys on record for.
+const HOST_KEY_ALGORITHMS: &str = "ssh-ed25519,ecdsa-sha2-nistp256,ecdsa-sha2-nistp384,ecdsa-sha2-nistp521,\
+rsa-sha2-512,rsa-sha2-256,ssh-rsa,ssh-dss";
+
+/// What to do with a host key that isn't on record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Only connect to hosts whose key is on record.
+    #[default]
+    Strict,
+    /// Record the keys of new hosts, but refuse changed ones.
+    AcceptNew,
+    /// Don't check host keys at all.
+    Off,
+}
+
+impl HostKeyPolicy {
+    /// Reads a policy from `strict`, `accept-new` or `off`, or from the
+    /// boolean and `StrictHostKeyChecking` spellings of them.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" | "yes" | "true" | "1" => Some(Self::Strict),
+            "accept-new" => Some(Self::AcceptNew),
+            "off" | "no" | "false" | "0" => Some(Self::Off),
+            _ => None,
+        }
+    }
+}
+
+/// How host keys are checked: the policy, and the known_hosts files to
+/// look in.
+#[derive(Debug, Clone)]
+pub struct HostKeyCheck {
+    pub policy: HostKeyPolicy,
+    user_file: PathBuf,
+    extra_file: Option<PathBuf>,
+}
+
+impl Default for HostKeyCheck {
+    fn default() -> Self {
+        let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+        Self {
+            policy: HostKeyPolicy::default(),
+            user_file: home.join(".ssh/known_hosts"),
+            extra_file: None,
+        }
+    }
+}
+
+impl HostKeyCheck {
+    pub fn new(policy: HostKeyPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Uses `path` in place of `~/.ssh/known_hosts`, as `UserKnownHostsFile`
+    /// does.
+    pub fn user_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.user_file = path.into();
+        self
+    }
+
+    /// Also trusts the keys in `path`, and records new keys there.
+    pub fn extra_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.extra_file = Some(path.into());
+        self
+    }
+
+    fn files(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.user_file.as_path()).chain(self.extra_file.as_deref())
+    }
+
+    /// Asks the server for a kind of key on record for `host` first, so
+    /// that a host with several keys isn't taken for a changed one.
+    pub fn prefer_recorded(&self, session: &Session, host: &str, port: u16) {
+        if self.policy == HostKeyPolicy::Off {
+            return;
+        }
+        let prefs: Vec<String> = self
+            .recorded_keys(host, port)
+            .into_iter()
+            .map(|(kind, _)| match kind.as_str() {
+                // RSA keys are also used with SHA-2 signatures.
+                "ssh-rsa" => "rsa-sha2-512,rsa-sha2-256,ssh-rsa".to_string(),
+                _ => kind,
+            })
+            .collect();
+        if !prefs.is_empty() {
+            let _ = session.method_pref(MethodType::HostKey, &format!("{},{}", prefs.join(","), HOST_KEY_ALGORITHMS));
+        }
+    }
+
+    /// Checks the key `session` was handed during the handshake.
+    pub fn verify(&self, session: &Session, host: &str, port: u16) -> Result<()> {
+        if self.policy == HostKeyPolicy::Off {
+            return Ok(());
+        }
+        let (key, _) = session.host_key().ok_or_else(|| anyhow!("{} sent no host key", host))?;
+        self.check(host, port, key)
+    }
+
+    fn check(&self, host: &str, port: u16, key: &[u8]) -> Result<()> {
+        if self.policy == HostKeyPolicy::Off {
+            return Ok(());
+        }
+        let mut known = Session::new()?.known_hosts()?;
+        for file in self.files().filter(|file| file.exists()) {
+            known
+                .read_file(file, KnownHostFileKind::OpenSSH)
+                .map_err(|e| anyhow!("{}: {}", file.display(), e))?;
+        }
+        let name = entry_name(host, port);
+        match known.check_port(host, port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound if self.policy == HostKeyPolicy::AcceptNew => {
+                let file = self.extra_file.as_deref().unwrap_or(&self.user_file);
+                append(file, &name, key).map_err(|e| anyhow!("recording the host key of {}: {}", name, e))
+            }
+            CheckResult::NotFound => Err(anyhow!(
+                "no host key is known for {} (it offered {}); add it to {} or use host key checking accept-new",
+                name,
+                fingerprint(key),
+                self.user_file.display()
+            )),
+            CheckResult::Mismatch => {
+                let recorded: Vec<String> = self.recorded_keys(host, port).iter().map(|(_, k)| fingerprint(k)).collect();
+                Err(anyhow!(
+                    "HOST KEY MISMATCH for {}: it offered {} but {} is on record; if the host was \
+                     reinstalled, remove the old key from the known_hosts file",
+                    name,
+                    fingerprint(key),
+                    recorded.join(", ")
+                ))
+            }
+            CheckResult::Failure => Err(anyhow!("could not check the host key of {}", name)),
+        }
+    }
+
+    /// The type and blob of every key on record for `host`, including in
+    /// hashed entries. Like libssh2, entries for the bare host name count
+    /// for every port.
+    fn recorded_keys(&self, host: &str, port: u16) -> Vec<(String, Vec<u8>)> {
+        let names = [entry_name(host, port), host.to_string()];
+        let mut keys = Vec::new();
+        for file in self.files() {
+            let Ok(text) = std::fs::read_to_string(file) else {
+                continue;
+            };
+            for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                // `@cert-authority` and `@revoked` lines don't hold host keys.
+                if let [hosts, kind, key, ..] = fields[..] {
+                    if !hosts.starts_with('@') && names.iter().any(|name| entry_matches(hosts, name)) {
+                        if let Ok(key) = STANDARD.decode(key) {
+                            keys.push((kind.to_string(), key));
+                        }
+                    }
+                }
+            }
+        }
+        keys
+    }
+}
+
+/// How known_hosts names a host: bare on port 22, `[host]:port` otherwise.
+fn entry_name(host: &str, port: u16) -> String {
+    match port {
+        22 => host.to_string(),
+        port => format!("[{}]:{}", host, port),
+    }
+}
+
+/// Whether a known_hosts host field, either `|1|salt|hash` or a list of
+/// patterns, names `name`.
+fn entry_matches(hosts: &str, name: &str) -> bool {
+    if let Some(hashed) = hosts.strip_prefix("|1|") {
+        let Some((salt, hash)) = hashed.split_once('|') else {
+            return false;
+        };
+        return match (STANDARD.decode(salt), STANDARD.decode(hash)) {
+            (Ok(salt), Ok(hash)) => hmac_sha1(&salt, name.as_bytes()).as_slice() == hash,
+            _ => false,
+        };
+    }
+    let mut matched = false;
+    for pattern in hosts.split(',') {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob_match(negated, name) => return false,
+            Some(_) => {}
+            None => matched |= glob_match(pattern, name),
+        }
+    }
+    matched
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    let mut block = [0u8; 64];
+    match key.len() > block.len() {
+        true => block[..20].copy_from_slice(&Sha1::digest(key)),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha1::new().chain_update(pad(0x36)).chain_update(data).finalize();
+    Sha1::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+}
+
+/// The key's type, which its blob starts with as an SSH string.
+fn key_type(key: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    std::str::from_utf8(key.get(4..4 + len)?).ok()
+}
+
+/// A key's fingerprint the way `ssh-keygen -l` shows it.
+pub fn fingerprint(key: &[u8]) -> String {
+    format!("{} SHA256:{}", key_type(key).unwrap_or("key"), STANDARD_NO_PAD.encode(Sha256::digest(key)))
+}
+
+fn append(file: &Path, name: &str, key: &[u8]) -> Result<()> {
+    let kind = key_type(key).ok_or_else(|| anyhow!("unrecognised key"))?;
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Don't run the new entry into a last line without a newline.
+    let existing = std::fs::read(file).unwrap_or_default();
+    let separator = match existing.last() {
+        Some(b'\n') | None => "",
+        Some(_) => "\n",
+    };
+    let mut out = std::fs::OpenOptions::new().create(true).append(true).open(file)?;
+    writeln!(out, "{}{} {} {}", separator, name, kind, STANDARD.encode(key))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ed25519 public key blob with the given key bytes.
+    fn key(byte: u8) -> Vec<u8> {
+        let mut blob = Vec::new();
+        blob.extend(11u32.to_be_bytes());
+        blob.extend(b"ssh-ed25519");
+        blob.extend(32u32.to_be_bytes());
+        blob.extend([byte; 32]);
+        blob
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wand-known-hosts-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(HostKeyPolicy::parse("False"), Some(HostKeyPolicy::Off));
+        assert_eq!(HostKeyPolicy::parse("accept-new"), Some(HostKeyPolicy::AcceptNew));
+        assert_eq!(HostKeyPolicy::parse("yes"), Some(HostKeyPolicy::Strict));
+        assert_eq!(HostKeyPolicy::parse("ask"), None);
+    }
+
+    #[test]
+    fn strict_checking() {
+        let dir = temp_dir("strict");
+        let user_file = dir.join("known_hosts");
+        std::fs::write(&user_file, format!("web1,10.0.0.1 ssh-ed25519 {}\n", STANDARD.encode(key(1)))).unwrap();
+        let check = HostKeyCheck::new(HostKeyPolicy::Strict).user_file(&user_file);
+
+        assert!(check.check("10.0.0.1", 22, &key(1)).is_ok());
+        let err = check.check("web1", 22, &key(2)).unwrap_err().to_string();
+        assert!(err.contains("MISMATCH"), "{}", err);
+        assert!(err.contains(&fingerprint(&key(1))) && err.contains(&fingerprint(&key(2))), "{}", err);
+        let err = check.check("web2", 22, &key(1)).unwrap_err().to_string();
+        assert!(err.starts_with("no host key is known for web2"), "{}", err);
+        assert!(HostKeyCheck::new(HostKeyPolicy::Off).user_file(&user_file).check("web1", 22, &key(2)).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn accept_new_records_new_hosts_in_the_extra_file() {
+        let dir = temp_dir("accept-new");
+        let extra = dir.join("wand_known_hosts");
+        std::fs::write(&extra, format!("web1 ssh-ed25519 {}", STANDARD.encode(key(1)))).unwrap();
+        let check = HostKeyCheck::new(HostKeyPolicy::AcceptNew).user_file(dir.join("missing")).extra_file(&extra);
+
+        check.check("web2", 2222, &key(2)).unwrap();
+        let text = std::fs::read_to_string(&extra).unwrap();
+        assert_eq!(text.lines().nth(1).unwrap(), format!("[web2]:2222 ssh-ed25519 {}", STANDARD.encode(key(2))));
+        assert!(HostKeyCheck::new(HostKeyPolicy::Strict).extra_file(&extra).check("web2", 2222, &key(2)).is_ok());
+        assert!(check.check("web1", 22, &key(3)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hashed_entries() {
+        // What `ssh-keygen -H` writes for `web1`.
+        let salt = [7u8; 20];
+        let hosts = format!("|1|{}|{}", STANDARD.encode(salt), STANDARD.encode(hmac_sha1(&salt, b"web1")));
+        assert!(entry_matches(&hosts, "web1"));
+        assert!(!entry_matches(&hosts, "web2"));
+        assert!(entry_matches("*.example.com,!bad.example.com", "db.example.com"));
+        assert!(!entry_matches("*.example.com,!bad.example.com", "bad.example.com"));
+
+        let dir = temp_dir("hashed");
+        let file = dir.join("known_hosts");
+        std::fs::write(&file, format!("{} ssh-ed25519 {}\n", hosts, STANDARD.encode(key(1)))).unwrap();
+        let check = HostKeyCheck::new(HostKeyPolicy::Strict).user_file(&file);
+        assert!(check.check("web1", 22, &key(1)).is_ok());
+        let err = check.check("web1", 22, &key(2)).unwrap_err().to_string();
+        assert!(err.contains(&fingerprint(&key(1))), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hmac_sha1_matches_rfc_2202() {
+        let mac = hmac_sha1(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(STANDARD.encode(mac), "7/zfauXrL6LSdBbV8YTfnCWafHk=");
+    }
+}
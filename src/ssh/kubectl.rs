@@ -0,0 +1,164 @@
+//! Kubernetes pods reached through `kubectl exec`. kubectl has no options
+//! for the environment or working directory of a command, so those go into
+//! the command line, and files stream through stdin.
+
+use anyhow::{anyhow, Result};
+use std::process::{Command, Output};
+
+use super::local::output;
+use super::{shell_quote, stdin_to_file, with_deadline, CommandResult, Connection, ExecOptions};
+
+pub struct KubectlConnection {
+    kubectl: String,
+    pod: String,
+    namespace: Option<String>,
+    container: Option<String>,
+    context: Option<String>,
+    kubeconfig: Option<String>,
+    extra_args: Vec<String>,
+}
+
+impl KubectlConnection {
+    pub fn new(pod: &str) -> Self {
+        Self {
+            kubectl: "kubectl".to_string(),
+            pod: pod.to_string(),
+            namespace: None,
+            container: None,
+            context: None,
+            kubeconfig: None,
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// Uses `kubectl` at this path.
+    pub fn executable(mut self, path: &str) -> Self {
+        self.kubectl = path.to_string();
+        self
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// The container to run in, for pods with more than one.
+    pub fn container(mut self, container: &str) -> Self {
+        self.container = Some(container.to_string());
+        self
+    }
+
+    pub fn context(mut self, context: &str) -> Self {
+        self.context = Some(context.to_string());
+        self
+    }
+
+    pub fn kubeconfig(mut self, path: &str) -> Self {
+        self.kubeconfig = Some(path.to_string());
+        self
+    }
+
+    /// Global kubectl options passed before the `exec` subcommand.
+    pub fn extra_args(mut self, args: &str) -> Self {
+        self.extra_args = args.split_whitespace().map(String::from).collect();
+        self
+    }
+
+    fn exec_args(&self, command: &str) -> Vec<String> {
+        let mut args = self.extra_args.clone();
+        if let Some(path) = &self.kubeconfig {
+            args.push(format!("--kubeconfig={}", path));
+        }
+        if let Some(context) = &self.context {
+            args.push(format!("--context={}", context));
+        }
+        args.extend(["exec".to_string(), "-i".to_string()]);
+        if let Some(namespace) = &self.namespace {
+            args.extend(["-n".to_string(), namespace.clone()]);
+        }
+        args.push(self.pod.clone());
+        if let Some(container) = &self.container {
+            args.extend(["-c".to_string(), container.clone()]);
+        }
+        args.extend(["--".to_string(), "sh".to_string(), "-c".to_string(), command.to_string()]);
+        args
+    }
+
+    fn run(&self, command: &str, opts: &ExecOptions) -> Result<Output> {
+        let command = with_deadline(&opts.command_line(command), opts.deadline)?;
+        output(Command::new(&self.kubectl).args(self.exec_args(&command)), opts.stdin.as_deref())
+            .map_err(|e| anyhow!("{}: {}", self.kubectl, e))
+    }
+}
+
+impl Connection for KubectlConnection {
+    fn host(&self) -> &str {
+        &self.pod
+    }
+
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult> {
+        Ok(self.run(command, opts)?.into())
+    }
+
+    fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
+        let r = self.exec_with(&stdin_to_file(path, mode), &ExecOptions::default().stdin(content))?;
+        match r.exit_code {
+            0 => Ok(()),
+            _ => Err(anyhow!("failed to write {}: {}", path, r.stderr.trim())),
+        }
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let out = self.run(&format!("cat {}", shell_quote(path)), &ExecOptions::default())?;
+        match out.status.success() {
+            true => Ok(out.stdout),
+            false => Err(anyhow!("failed to read {}: {}", path, String::from_utf8_lossy(&out.stderr).trim())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_args_follow_kubectl_exec() {
+        let conn = KubectlConnection::new("web-0")
+            .namespace("shop")
+            .container("app")
+            .context("prod")
+            .extra_args("--request-timeout=5s");
+        assert_eq!(
+            conn.exec_args("id").join(" "),
+            "--request-timeout=5s --context=prod exec -i -n shop web-0 -c app -- sh -c id"
+        );
+        assert_eq!(KubectlConnection::new("web-0").exec_args("id").join(" "), "exec -i web-0 -- sh -c id");
+    }
+
+    #[test]
+    fn exec_and_files_through_a_stub_kubectl() {
+        let dir = std::env::temp_dir().join(format!("wand-kubectl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("calls.log");
+        // Logs everything before `--` and runs the rest here.
+        let stub = format!(
+            "#!/bin/sh\nwhile [ \"$1\" != -- ]; do printf '%s ' \"$1\" >> {log}; shift; done; shift\necho >> {log}\nexec \"$@\"\n",
+            log = log.display()
+        );
+        let kubectl = dir.join("kubectl");
+        std::fs::write(&kubectl, stub).unwrap();
+        std::process::Command::new("chmod").arg("+x").arg(&kubectl).status().unwrap();
+
+        let conn = KubectlConnection::new("web-0").executable(kubectl.to_str().unwrap()).namespace("shop");
+        let r = conn.exec_with("echo $A $(pwd)", &ExecOptions::default().env("A", "1").cwd("/")).unwrap();
+        assert_eq!(r.stdout.trim(), "1 /");
+
+        let path = dir.join("app.conf").display().to_string();
+        conn.write_file(&path, b"port=80\n", 0o640).unwrap();
+        assert_eq!(conn.read_file(&path).unwrap(), b"port=80\n");
+
+        let calls = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(calls.lines().collect::<Vec<_>>(), vec!["exec -i -n shop web-0 "; 3]);
+    }
+}
@@ -1,7 +1,9 @@
 use anyhow::Result;
-use std::process::{Command, Stdio};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
 
-use super::CommandResult;
+use super::{with_deadline, CommandResult, Connection, ExecOptions, FileKind, FileStat};
 
 pub struct LocalConnection {
     host: String,
@@ -13,42 +15,90 @@ impl LocalConnection {
             host: "localhost".to_string(),
         }
     }
+}
 
-    pub fn exec(&self, command: &str) -> Result<CommandResult> {
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
-
-        Ok(CommandResult {
-            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
-            exit_code: output.status.code().unwrap_or(-1),
-        })
+impl Connection for LocalConnection {
+    fn host(&self) -> &str {
+        &self.host
     }
 
-    pub fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
-        std::fs::write(path, content)?;
-
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let perms = std::fs::Permissions::from_mode(mode as u32);
-            std::fs::set_permissions(path, perms)?;
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult> {
+        let command = with_deadline(command, opts.deadline)?;
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command).envs(opts.env.iter().map(|(k, v)| (k, v)));
+        if let Some(dir) = &opts.cwd {
+            cmd.current_dir(dir);
         }
+        Ok(output(&mut cmd, opts.stdin.as_deref())?.into())
+    }
 
-        Ok(())
+    fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
+        write_path(Path::new(path), content, mode)
     }
 
-    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
         Ok(std::fs::read(path)?)
     }
 
-    pub fn host(&self) -> &str {
-        &self.host
+    fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+        stat_path(Path::new(path))
+    }
+}
+
+pub(super) fn write_path(path: &Path, content: &[u8], mode: i32) -> Result<()> {
+    std::fs::write(path, content)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let perms = std::fs::Permissions::from_mode(mode as u32);
+        std::fs::set_permissions(path, perms)?;
+    }
+
+    Ok(())
+}
+
+pub(super) fn stat_path(path: &Path) -> Result<Option<FileStat>> {
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let kind = match meta.file_type() {
+        t if t.is_symlink() => FileKind::Symlink,
+        t if t.is_dir() => FileKind::Directory,
+        t if t.is_file() => FileKind::File,
+        _ => FileKind::Other,
+    };
+    #[cfg(unix)]
+    let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777;
+    #[cfg(not(unix))]
+    let mode = 0;
+    Ok(Some(FileStat { kind, size: meta.len(), mode }))
+}
+
+/// Runs `cmd` to completion, feeding it `stdin` if given.
+pub(super) fn output(cmd: &mut Command, stdin: Option<&[u8]>) -> Result<Output> {
+    let mut child = cmd
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Feed stdin from another thread so a chatty command can't block on a
+    // full stdout pipe while we're still writing.
+    let writer = match (child.stdin.take(), stdin) {
+        (Some(mut pipe), Some(input)) => {
+            let input = input.to_vec();
+            Some(std::thread::spawn(move || pipe.write_all(&input)))
+        }
+        _ => None,
+    };
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        // The command may exit without reading all of its input.
+        let _ = writer.join();
     }
+    Ok(output)
 }
 
 impl Default for LocalConnection {
@@ -56,3 +106,53 @@ impl Default for LocalConnection {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_with_stdin_env_and_cwd() {
+        let conn = LocalConnection::new();
+        let opts = ExecOptions::default().stdin("piped").env("GREETING", "hi").cwd("/");
+        let r = conn.exec_with("echo $GREETING $(pwd) $(cat)", &opts).unwrap();
+        assert_eq!(r.stdout.trim(), "hi / piped");
+    }
+
+    #[test]
+    fn stat_matches_shell_stat() {
+        let conn = LocalConnection::new();
+        let dir = std::env::temp_dir().join(format!("wand-stat-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("f");
+        conn.write_file(file.to_str().unwrap(), b"abc", 0o640).unwrap();
+
+        let stat = conn.stat(file.to_str().unwrap()).unwrap().unwrap();
+        assert_eq!(stat, FileStat { kind: FileKind::File, size: 3, mode: 0o640 });
+        // The default implementation goes through `stat(1)` instead.
+        let via_shell = Connection::stat(&ShellStat(&conn), file.to_str().unwrap()).unwrap();
+        assert_eq!(via_shell, Some(stat));
+        assert_eq!(conn.stat(dir.to_str().unwrap()).unwrap().unwrap().kind, FileKind::Directory);
+        assert_eq!(conn.stat(dir.join("missing").to_str().unwrap()).unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A connection using the trait's default `stat`.
+    struct ShellStat<'a>(&'a LocalConnection);
+
+    impl Connection for ShellStat<'_> {
+        fn host(&self) -> &str {
+            self.0.host()
+        }
+        fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult> {
+            self.0.exec_with(command, opts)
+        }
+        fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
+            self.0.write_file(path, content, mode)
+        }
+        fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+            self.0.read_file(path)
+        }
+    }
+}
@@ -1,16 +1,224 @@
+pub mod auth;
+pub mod chroot;
+pub mod config;
+pub mod docker;
+pub mod jump;
+pub mod known_hosts;
+pub mod kubectl;
 pub mod local;
+pub mod pool;
 
 use anyhow::{anyhow, Result};
 use ssh2::Session;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::AsRawFd;
 use std::path::Path;
-
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub use auth::{Auth, Login, Prompter, TerminalPrompter};
+pub use chroot::ChrootConnection;
+pub use config::{HostConfig, SshConfig};
+pub use docker::DockerConnection;
+pub use jump::JumpHost;
+pub use known_hosts::{HostKeyCheck, HostKeyPolicy};
+pub use kubectl::KubectlConnection;
 pub use local::LocalConnection;
+pub use pool::{ConnectionPool, PoolKey};
 
 pub struct SshConnection {
     session: Session,
     host: String,
+    /// How long to wait on an unresponsive server outside of commands.
+    timeout: Duration,
+    /// Held while the session is in use, as its timeout applies to every
+    /// channel on it.
+    in_use: Mutex<()>,
+}
+
+/// A way to run commands and move files on a host. Transports implement
+/// `exec_with`, `write_file` and `read_file`; the rest have defaults built
+/// on those.
+pub trait Connection: Send + Sync {
+    fn host(&self) -> &str;
+
+    /// Runs `command` through the shell on the host.
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult>;
+
+    fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()>;
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>>;
+
+    fn exec(&self, command: &str) -> Result<CommandResult> {
+        self.exec_with(command, &ExecOptions::default())
+    }
+
+    /// Copies a file from the controller to `remote` on the host.
+    fn put_file(&self, local: &Path, remote: &str, mode: i32) -> Result<()> {
+        self.write_file(remote, &std::fs::read(local)?, mode)
+    }
+
+    /// Copies `remote` from the host to a file on the controller.
+    fn fetch_file(&self, remote: &str, local: &Path) -> Result<()> {
+        std::fs::write(local, self.read_file(remote)?)?;
+        Ok(())
+    }
+
+    /// What is at `path`, without following a final symlink, or `None` if
+    /// nothing is.
+    fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+        let r = self.exec(&format!("stat -c '%F:%s:%a' -- {}", shell_quote(path)))?;
+        if r.exit_code != 0 {
+            return Ok(None);
+        }
+        FileStat::parse(r.stdout.trim())
+            .map(Some)
+            .ok_or_else(|| anyhow!("unexpected stat output for {}: {}", path, r.stdout.trim()))
+    }
+
+    /// Whether the connection still works, checked before reusing it.
+    fn is_alive(&self) -> bool {
+        true
+    }
+
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Input, environment, working directory and time limit for a command.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    pub stdin: Option<Vec<u8>>,
+    pub env: Vec<(String, String)>,
+    pub cwd: Option<String>,
+    pub deadline: Option<Instant>,
+}
+
+impl ExecOptions {
+    pub fn stdin(mut self, input: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn cwd(mut self, dir: &str) -> Self {
+        self.cwd = Some(dir.to_string());
+        self
+    }
+
+    /// Stops the command on the target once `deadline` passes.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// `command` with the environment and directory set up in front, for
+    /// transports that can only pass a command line.
+    pub fn command_line(&self, command: &str) -> String {
+        let mut line = String::new();
+        for (key, value) in &self.env {
+            line.push_str(&format!("export {}={}; ", key, shell_quote(value)));
+        }
+        if let Some(dir) = &self.cwd {
+            line.push_str(&format!("cd {} && ", shell_quote(dir)));
+        }
+        line.push_str(command);
+        line
+    }
+}
+
+/// A connection whose commands all have to finish by a deadline, handed to
+/// the modules of one task. The deadline goes with each command rather than
+/// onto the connection, which other hosts may be using at the same time.
+pub struct Deadline<'a> {
+    conn: &'a dyn Connection,
+    deadline: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    pub fn new(conn: &'a dyn Connection, deadline: Instant) -> Self {
+        Self { conn, deadline }
+    }
+}
+
+impl Connection for Deadline<'_> {
+    fn host(&self) -> &str {
+        self.conn.host()
+    }
+
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult> {
+        let deadline = opts.deadline.map_or(self.deadline, |d| d.min(self.deadline));
+        self.conn.exec_with(command, &opts.clone().deadline(deadline))
+    }
+
+    fn write_file(&self, path: &str, content: &[u8], mode: i32) -> Result<()> {
+        self.conn.write_file(path, content, mode)
+    }
+
+    fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        self.conn.read_file(path)
+    }
+
+    fn put_file(&self, local: &Path, remote: &str, mode: i32) -> Result<()> {
+        self.conn.put_file(local, remote, mode)
+    }
+
+    fn fetch_file(&self, remote: &str, local: &Path) -> Result<()> {
+        self.conn.fetch_file(remote, local)
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+        self.conn.stat(path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    pub kind: FileKind,
+    pub size: u64,
+    pub mode: u32,
+}
+
+impl FileStat {
+    /// Parses `stat -c '%F:%s:%a'` output.
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.rsplitn(3, ':');
+        let mode = u32::from_str_radix(parts.next()?, 8).ok()?;
+        let size = parts.next()?.parse().ok()?;
+        let kind = match parts.next()? {
+            "regular file" | "regular empty file" => FileKind::File,
+            "directory" => FileKind::Directory,
+            "symbolic link" => FileKind::Symlink,
+            _ => FileKind::Other,
+        };
+        Some(Self { kind, size, mode })
+    }
+}
+
+/// A command that saves its stdin to `path` with `mode`, for transports
+/// that move files through `exec`.
+fn stdin_to_file(path: &str, mode: i32) -> String {
+    let path = shell_quote(path);
+    format!("cat > {} && chmod {:o} {}", path, mode, path)
+}
+
+/// Quotes `s` as a single shell word.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
 }
 
 #[derive(Debug, Clone)]
@@ -20,57 +228,89 @@ pub struct CommandResult {
     pub exit_code: i32,
 }
 
-impl SshConnection {
-    pub fn connect(host: &str, port: u16, user: &str, auth: Auth) -> Result<Self> {
-        let addr = format!("{}:{}", host, port);
-        let tcp = TcpStream::connect(&addr)?;
-
-        let mut session = Session::new()?;
-        session.set_tcp_stream(tcp);
-        session.handshake()?;
-
-        match auth {
-            Auth::Key { private_key, passphrase } => {
-                let passphrase = passphrase.as_deref();
-                session.userauth_pubkey_file(user, None, Path::new(&private_key), passphrase)?;
-            }
-            Auth::Password(password) => {
-                session.userauth_password(user, &password)?;
-            }
-            Auth::Agent => {
-                let mut agent = session.agent()?;
-                agent.connect()?;
-                agent.list_identities()?;
-
-                let identities = agent.identities()?;
-                let mut authenticated = false;
-
-                for identity in identities {
-                    if agent.userauth(user, &identity).is_ok() {
-                        authenticated = true;
-                        break;
-                    }
-                }
-
-                if !authenticated {
-                    return Err(anyhow!("SSH agent authentication failed"));
-                }
-            }
+impl From<std::process::Output> for CommandResult {
+    fn from(output: std::process::Output) -> Self {
+        Self {
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            exit_code: output.status.code().unwrap_or(-1),
         }
+    }
+}
 
-        if !session.authenticated() {
-            return Err(anyhow!("SSH authentication failed"));
-        }
+impl SshConnection {
+    /// Connects and authenticates, giving up on each step after `timeout`.
+    pub fn connect(host: &str, port: u16, login: &Login, timeout: Duration, host_keys: &HostKeyCheck) -> Result<Self> {
+        let session = handshake(connect_tcp(host, port, timeout)?, host, port, login, timeout, host_keys)?;
+        Ok(Self::new(session, host, timeout))
+    }
+
+    /// Connects through a tunnel opened by `jump`, which also resolves
+    /// `host`.
+    pub fn connect_via(
+        jump: &JumpHost,
+        host: &str,
+        port: u16,
+        login: &Login,
+        timeout: Duration,
+        host_keys: &HostKeyCheck,
+    ) -> Result<Self> {
+        let session = handshake(jump.tunnel(host, port, timeout)?, host, port, login, timeout, host_keys)?;
+        Ok(Self::new(session, host, timeout))
+    }
 
-        Ok(Self {
+    fn new(session: Session, host: &str, timeout: Duration) -> Self {
+        Self {
             session,
             host: host.to_string(),
-        })
+            timeout,
+            in_use: Mutex::new(()),
+        }
+    }
+}
+
+/// Starts an SSH session with `host` over `stream`, checks its host key
+/// and logs in. The session is left blocking with no timeout.
+fn handshake<S: AsRawFd + 'static>(
+    stream: S,
+    host: &str,
+    port: u16,
+    login: &Login,
+    timeout: Duration,
+    host_keys: &HostKeyCheck,
+) -> Result<Session> {
+    let mut session = Session::new()?;
+    session.set_timeout(timeout.as_millis() as u32);
+    session.set_tcp_stream(stream);
+    host_keys.prefer_recorded(&session, host, port);
+    session.handshake()?;
+    host_keys.verify(&session, host, port)?;
+    auth::authenticate(&session, login, host)?;
+    session.set_timeout(0);
+    Ok(session)
+}
+
+impl Connection for SshConnection {
+    fn host(&self) -> &str {
+        &self.host
     }
 
-    pub fn exec(&self, command: &str) -> Result<CommandResult> {
+    fn exec_with(&self, command: &str, opts: &ExecOptions) -> Result<CommandResult> {
+        let command = with_deadline(&opts.command_line(command), opts.deadline)?;
+        let _in_use = self.in_use.lock().unwrap();
+        // Give the remote `timeout` a moment to report before the session
+        // itself stops waiting.
+        let session_timeout = opts.deadline.map_or(0, |d| {
+            (d.saturating_duration_since(Instant::now()) + KILL_GRACE * 2).as_millis() as u32
+        });
+        self.session.set_timeout(session_timeout);
+
         let mut channel = self.session.channel_session()?;
-        channel.exec(command)?;
+        channel.exec(&command)?;
+        if let Some(input) = &opts.stdin {
+            channel.write_all(input)?;
+        }
+        channel.send_eof()?;
 
         let mut stdout = String::new();
         channel.read_to_string(&mut stdout)?;
@@ -88,41 +328,8 @@ impl SshConnection {
         })
     }
 
-    pub fn exec_sudo(&self, command: &str, password: &str) -> Result<CommandResult> {
-        let sudo_cmd = format!("echo '{}' | sudo -S {}", password, command);
-        self.exec(&sudo_cmd)
-    }
-
-    pub fn upload(&self, local_path: &Path, remote_path: &str, mode: i32) -> Result<()> {
-        let content = std::fs::read(local_path)?;
-        let mut remote_file = self.session.scp_send(
-            Path::new(remote_path),
-            mode,
-            content.len() as u64,
-            None,
-        )?;
-
-        remote_file.write_all(&content)?;
-        remote_file.send_eof()?;
-        remote_file.wait_eof()?;
-        remote_file.close()?;
-        remote_file.wait_close()?;
-
-        Ok(())
-    }
-
-    pub fn download(&self, remote_path: &str, local_path: &Path) -> Result<()> {
-        let (mut remote_file, _stat) = self.session.scp_recv(Path::new(remote_path))?;
-
-        let mut content = Vec::new();
-        remote_file.read_to_end(&mut content)?;
-
-        std::fs::write(local_path, content)?;
-
-        Ok(())
-    }
-
-    pub fn write_file(&self, remote_path: &str, content: &[u8], mode: i32) -> Result<()> {
+    fn write_file(&self, remote_path: &str, content: &[u8], mode: i32) -> Result<()> {
+        let _in_use = self.in_use.lock().unwrap();
         let mut remote_file = self.session.scp_send(
             Path::new(remote_path),
             mode,
@@ -139,7 +346,8 @@ impl SshConnection {
         Ok(())
     }
 
-    pub fn read_file(&self, remote_path: &str) -> Result<Vec<u8>> {
+    fn read_file(&self, remote_path: &str) -> Result<Vec<u8>> {
+        let _in_use = self.in_use.lock().unwrap();
         let (mut remote_file, _stat) = self.session.scp_recv(Path::new(remote_path))?;
 
         let mut content = Vec::new();
@@ -148,43 +356,60 @@ impl SshConnection {
         Ok(content)
     }
 
-    pub fn host(&self) -> &str {
-        &self.host
+    fn is_alive(&self) -> bool {
+        let _in_use = self.in_use.lock().unwrap();
+        self.session.set_timeout(self.timeout.as_millis() as u32);
+        let alive = self
+            .session
+            .channel_session()
+            .and_then(|mut channel| {
+                channel.exec("true")?;
+                channel.wait_close()?;
+                channel.exit_status()
+            })
+            .is_ok_and(|rc| rc == 0);
+        self.session.set_timeout(0);
+        alive
     }
-}
 
-#[derive(Clone)]
-pub enum Auth {
-    Key {
-        private_key: String,
-        passphrase: Option<String>,
-    },
-    Password(String),
-    Agent,
+    fn close(&self) -> Result<()> {
+        Ok(self.session.disconnect(None, "closing connection", None)?)
+    }
 }
 
-impl Auth {
-    pub fn key(path: &str) -> Self {
-        Auth::Key {
-            private_key: path.to_string(),
-            passphrase: None,
-        }
-    }
+/// How long a timed-out command gets to exit after `TERM` before `KILL`.
+const KILL_GRACE: Duration = Duration::from_secs(5);
 
-    pub fn key_with_passphrase(path: &str, passphrase: &str) -> Self {
-        Auth::Key {
-            private_key: path.to_string(),
-            passphrase: Some(passphrase.to_string()),
+fn connect_tcp(host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let mut last_err = None;
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_err = Some(e),
         }
     }
+    Err(match last_err {
+        Some(e) => anyhow!("{}:{}: {}", host, port, e),
+        None => anyhow!("{}: no addresses found", host),
+    })
+}
 
-    pub fn password(password: &str) -> Self {
-        Auth::Password(password.to_string())
-    }
-
-    pub fn agent() -> Self {
-        Auth::Agent
+/// Wraps `command` in `timeout` so that it is stopped on the target once
+/// `deadline` passes.
+fn with_deadline(command: &str, deadline: Option<Instant>) -> Result<String> {
+    let Some(deadline) = deadline else {
+        return Ok(command.to_string());
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(anyhow!("deadline exceeded"));
     }
+    Ok(format!(
+        "timeout -k {} {} sh -c {}",
+        KILL_GRACE.as_secs(),
+        remaining.as_secs_f64().ceil() as u64,
+        shell_quote(command)
+    ))
 }
 
 #[cfg(test)]
@@ -192,24 +417,47 @@ mod tests {
     use super::*;
 
     #[test]
-    fn auth_key_creation() {
-        let auth = Auth::key("/home/user/.ssh/id_rsa");
-        match auth {
-            Auth::Key { private_key, passphrase } => {
-                assert_eq!(private_key, "/home/user/.ssh/id_rsa");
-                assert!(passphrase.is_none());
-            }
-            _ => panic!("Expected Key auth"),
-        }
+    fn with_deadline_wraps_in_timeout() {
+        assert_eq!(with_deadline("echo hi", None).unwrap(), "echo hi");
+        let deadline = Instant::now() + Duration::from_millis(2500);
+        assert_eq!(
+            with_deadline("echo 'hi'", Some(deadline)).unwrap(),
+            r#"timeout -k 5 3 sh -c 'echo '\''hi'\'''"#
+        );
+        assert!(with_deadline("echo", Some(Instant::now())).is_err());
     }
 
     #[test]
-    fn auth_password_creation() {
-        let auth = Auth::password("secret");
-        match auth {
-            Auth::Password(p) => assert_eq!(p, "secret"),
-            _ => panic!("Expected Password auth"),
-        }
+    fn deadlines_stay_with_their_own_commands() {
+        let conn = LocalConnection::new();
+        std::thread::scope(|s| {
+            let bounded = s.spawn(|| {
+                let start = Instant::now();
+                let r = Deadline::new(&conn, start + Duration::from_secs(1)).exec("sleep 5").unwrap();
+                (r.exit_code, start.elapsed())
+            });
+            // Another host sharing the connection, with no limit of its own.
+            let r = conn.exec("sleep 2 && echo done").unwrap();
+            assert_eq!(r.stdout.trim(), "done");
+
+            let (rc, took) = bounded.join().unwrap();
+            assert_eq!(rc, 124);
+            assert!(took < Duration::from_secs(4), "took {:?}", took);
+        });
+    }
+
+    #[test]
+    fn exec_options_command_line() {
+        let opts = ExecOptions::default().env("A", "it's").cwd("/srv/app");
+        assert_eq!(opts.command_line("make"), r#"export A='it'\''s'; cd '/srv/app' && make"#);
+    }
+
+    #[test]
+    fn parse_stat_output() {
+        let stat = FileStat::parse("regular empty file:0:644").unwrap();
+        assert_eq!(stat, FileStat { kind: FileKind::File, size: 0, mode: 0o644 });
+        assert_eq!(FileStat::parse("directory:4096:1777").unwrap().kind, FileKind::Directory);
+        assert!(FileStat::parse("garbage").is_none());
     }
 
     #[test]
@@ -0,0 +1,175 @@
+//! Connections kept open for the whole run, so that later plays and tasks
+//! delegated to a host reuse its session instead of connecting again.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use super::{Connection, JumpHost};
+
+/// What makes two targets share a connection: where it goes, who logs in,
+/// and who tasks become once there.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub become_user: Option<String>,
+}
+
+type Slot<T> = Arc<Mutex<Option<Arc<T>>>>;
+
+/// Jump hosts are keyed by the whole route to them, first hop first.
+type Route = Vec<PoolKey>;
+
+#[derive(Default)]
+pub struct ConnectionPool {
+    slots: Mutex<HashMap<PoolKey, Slot<dyn Connection>>>,
+    jumps: Mutex<HashMap<Route, Slot<JumpHost>>>,
+}
+
+impl ConnectionPool {
+    /// The open connection for `key`, or a new one from `connect` if there
+    /// is none yet or the open one no longer answers. Different keys connect
+    /// in parallel; callers after the same key wait for the first one.
+    pub fn get<C: Connection + 'static>(
+        &self,
+        key: PoolKey,
+        connect: impl FnOnce() -> Result<C>,
+    ) -> Result<Arc<dyn Connection>> {
+        checkout(&self.slots, key, |conn| conn.is_alive(), || {
+            Ok(Arc::new(connect()?) as Arc<dyn Connection>)
+        })
+    }
+
+    /// The jump host at the end of `route`, shared by every target behind
+    /// it, connecting as for [`get`](Self::get).
+    pub fn jump(&self, route: Route, connect: impl FnOnce() -> Result<JumpHost>) -> Result<Arc<JumpHost>> {
+        checkout(&self.jumps, route, JumpHost::is_alive, || Ok(Arc::new(connect()?)))
+    }
+
+    /// Closes every pooled connection, then the jump hosts they went
+    /// through.
+    pub fn close_all(&self) {
+        for slot in self.slots.lock().unwrap().values() {
+            if let Some(conn) = slot.lock().unwrap().take() {
+                let _ = conn.close();
+            }
+        }
+        let jumps = self.jumps.lock().unwrap();
+        // Later hops first, as each depends on the one before.
+        let mut routes: Vec<&Route> = jumps.keys().collect();
+        routes.sort_by_key(|route| std::cmp::Reverse(route.len()));
+        for route in routes {
+            if let Some(jump) = jumps[route].lock().unwrap().take() {
+                jump.close();
+            }
+        }
+    }
+}
+
+fn checkout<K: Eq + std::hash::Hash, T: ?Sized>(
+    slots: &Mutex<HashMap<K, Slot<T>>>,
+    key: K,
+    is_alive: impl Fn(&T) -> bool,
+    connect: impl FnOnce() -> Result<Arc<T>>,
+) -> Result<Arc<T>> {
+    let slot = slots.lock().unwrap().entry(key).or_default().clone();
+    let mut slot = slot.lock().unwrap();
+    if let Some(conn) = slot.as_ref() {
+        if is_alive(conn) {
+            return Ok(conn.clone());
+        }
+    }
+    let conn = connect()?;
+    *slot = Some(conn.clone());
+    Ok(conn)
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |k: &PoolKey| format!("{}@{}:{}", k.user, k.host, k.port);
+        let mut keys: Vec<String> = self.slots.lock().unwrap().keys().map(name).collect();
+        keys.sort();
+        let mut jumps: Vec<String> = self.jumps.lock().unwrap().keys().filter_map(|r| r.last().map(name)).collect();
+        jumps.sort();
+        f.debug_struct("ConnectionPool")
+            .field("connections", &keys)
+            .field("jump_hosts", &jumps)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::{CommandResult, ExecOptions};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    struct Fake {
+        alive: Arc<AtomicBool>,
+    }
+
+    impl Connection for Fake {
+        fn host(&self) -> &str {
+            "fake"
+        }
+        fn exec_with(&self, _: &str, _: &ExecOptions) -> Result<CommandResult> {
+            Err(anyhow::anyhow!("not used"))
+        }
+        fn write_file(&self, _: &str, _: &[u8], _: i32) -> Result<()> {
+            Err(anyhow::anyhow!("not used"))
+        }
+        fn read_file(&self, _: &str) -> Result<Vec<u8>> {
+            Err(anyhow::anyhow!("not used"))
+        }
+        fn is_alive(&self) -> bool {
+            self.alive.load(Ordering::SeqCst)
+        }
+    }
+
+    fn key(host: &str, become_user: Option<&str>) -> PoolKey {
+        PoolKey {
+            host: host.to_string(),
+            port: 22,
+            user: "deploy".to_string(),
+            become_user: become_user.map(String::from),
+        }
+    }
+
+    #[test]
+    fn reuses_live_connections_and_replaces_dead_ones() {
+        let pool = ConnectionPool::default();
+        let connects = AtomicUsize::new(0);
+        let alive = Arc::new(AtomicBool::new(true));
+        let get = |key: PoolKey| {
+            pool.get(key, || {
+                connects.fetch_add(1, Ordering::SeqCst);
+                Ok(Fake { alive: alive.clone() })
+            })
+            .unwrap()
+        };
+
+        let first = get(key("web1", None));
+        assert!(Arc::ptr_eq(&first, &get(key("web1", None))));
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+
+        get(key("web1", Some("root")));
+        get(key("web2", None));
+        assert_eq!(connects.load(Ordering::SeqCst), 3);
+
+        alive.store(false, Ordering::SeqCst);
+        let again = get(key("web1", None));
+        assert!(!Arc::ptr_eq(&first, &again));
+        assert_eq!(connects.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn failed_connects_are_retried() {
+        let pool = ConnectionPool::default();
+        assert!(pool.get(key("web1", None), || Err::<Fake, _>(anyhow::anyhow!("refused"))).is_err());
+        let alive = Arc::new(AtomicBool::new(true));
+        assert!(pool.get(key("web1", None), || Ok(Fake { alive })).is_ok());
+    }
+}
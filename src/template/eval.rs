@@ -0,0 +1,1155 @@
+use super::filters;
+use super::parser::{self, Args, BinOp, Expr, ForLoop, Macro, Node, Pos};
+use super::value::{self, as_int, as_number, is_truthy, to_output};
+use super::{Environment, LookupContext, TemplateError, Vars};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A value during evaluation. Besides plain data this carries undefined
+/// values (so `default` and `is defined` can see them) and the callables
+/// and namespaces created by macros and imports.
+#[derive(Debug, Clone)]
+pub enum Val {
+    Undefined(String, Pos),
+    Data(Value),
+    Macro(Rc<MacroRef>),
+    Namespace(Rc<HashMap<String, Val>>),
+}
+
+#[derive(Debug)]
+pub struct MacroRef {
+    def: Rc<Macro>,
+    siblings: Rc<Vec<Rc<Macro>>>,
+    with_context: bool,
+    dir: Option<PathBuf>,
+    /// Scopes captured at a `{% call %}` site; set only for `caller`.
+    captured: Option<Vec<Scope>>,
+}
+
+type Scope = HashMap<String, Val>;
+
+struct NoVars;
+
+impl Vars for NoVars {
+    fn get_var(&self, _name: &str) -> Option<Value> {
+        None
+    }
+}
+
+static NO_VARS: NoVars = NoVars;
+
+pub struct Renderer<'a> {
+    env: &'a Environment,
+    vars: &'a dyn Vars,
+    scopes: Vec<Scope>,
+    /// Templates currently being rendered, for include cycle detection.
+    stack: Vec<PathBuf>,
+    dir: Option<PathBuf>,
+    macros: Rc<Vec<Rc<Macro>>>,
+    blocks: HashMap<String, Vec<Rc<Vec<Node>>>>,
+    /// Override chains of the blocks being rendered, for `super()`.
+    block_stack: Vec<(Vec<Rc<Vec<Node>>>, usize)>,
+    with_context: bool,
+    /// Fail on undefined values instead of treating them as empty.
+    strict: bool,
+    /// Variables whose templated values are being rendered, and the cache
+    /// of those already rendered.
+    resolving: Vec<String>,
+    resolved: HashMap<String, Value>,
+}
+
+/// Positional and keyword arguments of a call or filter.
+type EvaluatedArgs = (Vec<Val>, Vec<(String, Val)>);
+
+impl<'a> Renderer<'a> {
+    pub fn new(env: &'a Environment, vars: &'a dyn Vars) -> Self {
+        Self {
+            env,
+            vars,
+            scopes: vec![Scope::new()],
+            stack: Vec::new(),
+            dir: None,
+            macros: Rc::new(Vec::new()),
+            blocks: HashMap::new(),
+            block_stack: Vec::new(),
+            with_context: true,
+            strict: !env.lenient,
+            resolving: Vec::new(),
+            resolved: HashMap::new(),
+        }
+    }
+
+    pub fn render_source(&mut self, source: &str) -> Result<String, TemplateError> {
+        let nodes = parser::parse(source)?;
+        self.render_template(&nodes)
+    }
+
+    pub fn eval_condition(&mut self, source: &str) -> Result<bool, TemplateError> {
+        let expr = parser::parse_expr(source)?;
+        self.truthy(&expr)
+    }
+
+    pub fn render_path(&mut self, path: &Path) -> Result<String, TemplateError> {
+        let nodes = self.load(path)?;
+        self.enter(path, |r| r.render_template(&nodes))
+    }
+
+    fn load(&self, path: &Path) -> Result<Vec<Node>, TemplateError> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|e| TemplateError::new(&format!("failed to read template {}: {}", path.display(), e)))?;
+        parser::parse(&source).map_err(|e| e.in_template(path))
+    }
+
+    /// Runs `f` with `path` pushed onto the include stack, failing if the
+    /// template is already being rendered.
+    fn enter<T>(
+        &mut self,
+        path: &Path,
+        f: impl FnOnce(&mut Self) -> Result<T, TemplateError>,
+    ) -> Result<T, TemplateError> {
+        if self.stack.iter().any(|p| p == path) {
+            let chain: Vec<String> = self
+                .stack
+                .iter()
+                .chain(std::iter::once(&path.to_path_buf()))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(TemplateError::new(&format!("include cycle detected: {}", chain.join(" -> "))));
+        }
+
+        let saved_dir = std::mem::replace(&mut self.dir, path.parent().map(|p| p.to_path_buf()));
+        self.stack.push(path.to_path_buf());
+        let result = f(self).map_err(|e| e.in_template(path));
+        self.stack.pop();
+        self.dir = saved_dir;
+        result
+    }
+
+    fn find(&self, name: &str, pos: Pos) -> Result<PathBuf, TemplateError> {
+        self.env
+            .resolve_from(name, self.dir.as_deref())
+            .ok_or_else(|| TemplateError::new(&format!("template not found: {}", name)).at(pos.line, pos.col))
+    }
+
+    fn render_template(&mut self, nodes: &[Node]) -> Result<String, TemplateError> {
+        let macros: Vec<Rc<Macro>> = nodes
+            .iter()
+            .filter_map(|n| match n {
+                Node::Macro(m) => Some(m.clone()),
+                _ => None,
+            })
+            .collect();
+        let saved_macros = std::mem::replace(&mut self.macros, Rc::new(macros));
+
+        let result = match nodes.iter().find_map(|n| match n {
+            Node::Extends(parent, pos) => Some((parent, *pos)),
+            _ => None,
+        }) {
+            Some((parent, pos)) => self.render_child(nodes, parent, pos),
+            None => self.render_nodes(nodes),
+        };
+
+        self.macros = saved_macros;
+        result
+    }
+
+    /// Renders a template that extends another: its blocks are registered as
+    /// overrides and the parent template produces the output.
+    fn render_child(&mut self, nodes: &[Node], parent: &Expr, pos: Pos) -> Result<String, TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Block(name, body) => {
+                    self.blocks.entry(name.clone()).or_default().push(body.clone());
+                }
+                Node::Macro(_) | Node::Set(..) | Node::SetBlock(..) | Node::Import { .. } | Node::FromImport { .. } => {
+                    self.render_node(node)?;
+                }
+                _ => {}
+            }
+        }
+
+        let name = self.eval_data(parent)?;
+        let path = self.find(&to_output(&name), pos)?;
+        let parent_nodes = self.load(&path)?;
+        self.enter(&path, |r| r.render_template(&parent_nodes))
+    }
+
+    fn render_nodes(&mut self, nodes: &[Node]) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        for node in nodes {
+            out.push_str(&self.render_node(node)?);
+        }
+        Ok(out)
+    }
+
+    fn render_node(&mut self, node: &Node) -> Result<String, TemplateError> {
+        match node {
+            Node::Text(text) => Ok(text.clone()),
+            Node::Output(expr) => {
+                let val = self.eval(expr)?;
+                self.output(val)
+            }
+            Node::If(branches, else_body) => {
+                for (cond, body) in branches {
+                    if self.truthy(cond)? {
+                        return self.render_nodes(body);
+                    }
+                }
+                self.render_nodes(else_body)
+            }
+            Node::For(for_loop) => self.render_for(for_loop),
+            Node::Set(targets, expr) => {
+                let val = self.eval(expr)?;
+                self.assign(targets, val)?;
+                Ok(String::new())
+            }
+            Node::SetBlock(name, body) => {
+                let text = self.render_nodes(body)?;
+                self.set(name, Val::Data(Value::String(text)));
+                Ok(String::new())
+            }
+            Node::Include {
+                template,
+                ignore_missing,
+                with_context,
+                pos,
+            } => {
+                let names = match self.eval_data(template)? {
+                    Value::Array(items) => items.iter().map(to_output).collect(),
+                    other => vec![to_output(&other)],
+                };
+                let Some(path) = names.iter().find_map(|n| self.env.resolve_from(n, self.dir.as_deref())) else {
+                    if *ignore_missing {
+                        return Ok(String::new());
+                    }
+                    return Err(TemplateError::new(&format!("template not found: {}", names.join(", ")))
+                        .at(pos.line, pos.col));
+                };
+                let nodes = self.load(&path)?;
+                let saved_blocks = std::mem::take(&mut self.blocks);
+                let result = if *with_context {
+                    self.scopes.push(Scope::new());
+                    let result = self.enter(&path, |r| r.render_template(&nodes));
+                    self.scopes.pop();
+                    result
+                } else {
+                    self.isolated(false, |r| r.enter(&path, |r| r.render_template(&nodes)))
+                };
+                self.blocks = saved_blocks;
+                result
+            }
+            Node::Import {
+                template,
+                alias,
+                with_context,
+                pos,
+            } => {
+                let namespace = self.import(template, *with_context, *pos)?;
+                self.set(alias, Val::Namespace(Rc::new(namespace)));
+                Ok(String::new())
+            }
+            Node::FromImport {
+                template,
+                names,
+                with_context,
+                pos,
+            } => {
+                let namespace = self.import(template, *with_context, *pos)?;
+                for (name, alias) in names {
+                    let Some(val) = namespace.get(name) else {
+                        return Err(TemplateError::new(&format!("cannot import '{}'", name)).at(pos.line, pos.col));
+                    };
+                    self.set(alias, val.clone());
+                }
+                Ok(String::new())
+            }
+            Node::Macro(def) => {
+                let macro_ref = self.macro_ref(def.clone());
+                self.set(&def.name, macro_ref);
+                Ok(String::new())
+            }
+            Node::Call(call, caller_def) => {
+                let Expr::Call(callee, args, pos) = call else {
+                    return Ok(String::new());
+                };
+                let caller = Val::Macro(Rc::new(MacroRef {
+                    def: caller_def.clone(),
+                    siblings: self.macros.clone(),
+                    with_context: true,
+                    dir: self.dir.clone(),
+                    captured: Some(self.scopes.clone()),
+                }));
+                let target = self.eval(callee)?;
+                let (positional, keyword) = self.eval_args(args)?;
+                let val = self.call_value(target, positional, keyword, Some(caller), *pos)?;
+                self.output(val)
+            }
+            Node::Block(name, body) => {
+                let mut chain = self.blocks.get(name).cloned().unwrap_or_default();
+                chain.push(body.clone());
+                self.render_block(chain, 0)
+            }
+            Node::FilterBlock(name, args, body, pos) => {
+                let text = self.render_nodes(body)?;
+                let (positional, keyword) = self.eval_args(args)?;
+                let val = self.apply_filter(name, Val::Data(Value::String(text)), positional, keyword, *pos)?;
+                self.output(val)
+            }
+            Node::Extends(..) => Ok(String::new()),
+        }
+    }
+
+    fn render_block(&mut self, chain: Vec<Rc<Vec<Node>>>, index: usize) -> Result<String, TemplateError> {
+        let body = chain[index].clone();
+        self.block_stack.push((chain, index));
+        let result = self.render_nodes(&body);
+        self.block_stack.pop();
+        result
+    }
+
+    fn render_for(&mut self, for_loop: &ForLoop) -> Result<String, TemplateError> {
+        let iterable = self.eval(&for_loop.iter)?;
+        let items = match iterable {
+            Val::Data(v) => value::iterate(&v),
+            Val::Undefined(msg, pos) => {
+                self.undefined(msg, pos)?;
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+
+        let mut selected = Vec::new();
+        for item in items {
+            if let Some(filter) = &for_loop.filter {
+                self.scopes.push(Scope::new());
+                self.bind_targets(&for_loop.targets, &item);
+                let keep = self.truthy(filter);
+                self.scopes.pop();
+                if !keep? {
+                    continue;
+                }
+            }
+            selected.push(item);
+        }
+
+        if selected.is_empty() {
+            return self.render_nodes(&for_loop.else_body);
+        }
+
+        let length = selected.len();
+        let mut out = String::new();
+        for (i, item) in selected.iter().enumerate() {
+            self.scopes.push(Scope::new());
+            self.bind_targets(&for_loop.targets, item);
+            let loop_info = value::object(vec![
+                ("index".to_string(), Value::from(i + 1)),
+                ("index0".to_string(), Value::from(i)),
+                ("revindex".to_string(), Value::from(length - i)),
+                ("revindex0".to_string(), Value::from(length - i - 1)),
+                ("first".to_string(), Value::Bool(i == 0)),
+                ("last".to_string(), Value::Bool(i + 1 == length)),
+                ("length".to_string(), Value::from(length)),
+                (
+                    "previtem".to_string(),
+                    if i > 0 { selected[i - 1].clone() } else { Value::Null },
+                ),
+                ("nextitem".to_string(), selected.get(i + 1).cloned().unwrap_or(Value::Null)),
+            ]);
+            self.set("loop", Val::Data(loop_info));
+            let result = self.render_nodes(&for_loop.body);
+            self.scopes.pop();
+            out.push_str(&result?);
+        }
+
+        Ok(out)
+    }
+
+    fn bind_targets(&mut self, targets: &[String], item: &Value) {
+        if targets.len() == 1 {
+            self.set(&targets[0], Val::Data(item.clone()));
+            return;
+        }
+        let parts = match item {
+            Value::Array(parts) => parts.clone(),
+            other => vec![other.clone()],
+        };
+        for (i, target) in targets.iter().enumerate() {
+            self.set(target, Val::Data(parts.get(i).cloned().unwrap_or(Value::Null)));
+        }
+    }
+
+    fn assign(&mut self, targets: &[String], val: Val) -> Result<(), TemplateError> {
+        if targets.len() == 1 {
+            self.set(&targets[0], val);
+            return Ok(());
+        }
+        let Val::Data(Value::Array(parts)) = val else {
+            return Err(TemplateError::new("cannot unpack non-sequence"));
+        };
+        for (i, target) in targets.iter().enumerate() {
+            self.set(target, Val::Data(parts.get(i).cloned().unwrap_or(Value::Null)));
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, val: Val) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), val);
+        }
+    }
+
+    fn macro_ref(&self, def: Rc<Macro>) -> Val {
+        Val::Macro(Rc::new(MacroRef {
+            def,
+            siblings: self.macros.clone(),
+            with_context: self.with_context,
+            dir: self.dir.clone(),
+            captured: None,
+        }))
+    }
+
+    /// Runs `f` with fresh scopes, and without the template variables unless
+    /// `with_context` is set, restoring the current state afterwards.
+    fn isolated<T>(&mut self, with_context: bool, f: impl FnOnce(&mut Self) -> T) -> T {
+        let vars: &'a dyn Vars = if with_context { self.vars } else { &NO_VARS };
+        let saved_scopes = std::mem::replace(&mut self.scopes, vec![Scope::new()]);
+        let saved_vars = std::mem::replace(&mut self.vars, vars);
+        let saved_context = std::mem::replace(&mut self.with_context, with_context);
+        let result = f(self);
+        self.scopes = saved_scopes;
+        self.vars = saved_vars;
+        self.with_context = saved_context;
+        result
+    }
+
+    fn import(&mut self, template: &Expr, with_context: bool, pos: Pos) -> Result<HashMap<String, Val>, TemplateError> {
+        let name = to_output(&self.eval_data(template)?);
+        let path = self.find(&name, pos)?;
+        let nodes = self.load(&path)?;
+
+        self.isolated(with_context, |r| {
+            r.enter(&path, |r| {
+                r.render_template(&nodes)?;
+                // render_template restores the caller's macro list, so rebuild
+                // the namespace from this template's definitions.
+                let macros: Vec<Rc<Macro>> = nodes
+                    .iter()
+                    .filter_map(|n| match n {
+                        Node::Macro(m) => Some(m.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                let saved = std::mem::replace(&mut r.macros, Rc::new(macros));
+                let mut namespace = r.scopes[0].clone();
+                for node in &nodes {
+                    if let Node::Macro(def) = node {
+                        namespace.insert(def.name.clone(), r.macro_ref(def.clone()));
+                    }
+                }
+                r.macros = saved;
+                Ok(namespace)
+            })
+        })
+    }
+
+    fn output(&mut self, val: Val) -> Result<String, TemplateError> {
+        Ok(match val {
+            Val::Data(v) => to_output(&v),
+            Val::Undefined(msg, pos) => {
+                self.undefined(msg, pos)?;
+                String::new()
+            }
+            Val::Macro(m) => format!("<macro {}>", m.def.name),
+            Val::Namespace(_) => "<module>".to_string(),
+        })
+    }
+
+    fn truthy(&mut self, expr: &Expr) -> Result<bool, TemplateError> {
+        let val = self.eval(expr)?;
+        self.val_truthy(&val)
+    }
+
+    /// Reports use of an undefined value: an error in strict mode, nothing
+    /// in lenient mode.
+    fn undefined(&self, msg: String, pos: Pos) -> Result<(), TemplateError> {
+        if self.strict {
+            return Err(TemplateError::new(&msg).at(pos.line, pos.col));
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression to plain data. Undefined values are an error
+    /// in strict mode and empty otherwise.
+    fn eval_data(&mut self, expr: &Expr) -> Result<Value, TemplateError> {
+        let val = self.eval(expr)?;
+        self.data(val)
+    }
+
+    fn data(&self, val: Val) -> Result<Value, TemplateError> {
+        Ok(match val {
+            Val::Data(v) => v,
+            Val::Undefined(msg, pos) => {
+                self.undefined(msg, pos)?;
+                Value::String(String::new())
+            }
+            Val::Macro(m) => Value::String(format!("<macro {}>", m.def.name)),
+            Val::Namespace(_) => Value::String("<module>".to_string()),
+        })
+    }
+
+    fn lookup_name(&mut self, name: &str) -> Result<Option<Val>, TemplateError> {
+        for scope in self.scopes.iter().rev() {
+            if let Some(val) = scope.get(name) {
+                return Ok(Some(val.clone()));
+            }
+        }
+        match self.vars.get_var(name) {
+            Some(value) => self.resolve_var(name, value).map(|v| Some(Val::Data(v))),
+            None => Ok(None),
+        }
+    }
+
+    /// Variables may themselves contain templates (`app_dir: /opt/{{ app }}`).
+    /// They are rendered lazily when first used, recursively and with cycle
+    /// detection, and the result is cached for the rest of this render.
+    /// Unsafe variables are data and are never rendered.
+    fn resolve_var(&mut self, name: &str, value: Value) -> Result<Value, TemplateError> {
+        if self.vars.is_unsafe(name) || !needs_templating(&value) {
+            return Ok(value);
+        }
+        if let Some(resolved) = self.resolved.get(name) {
+            return Ok(resolved.clone());
+        }
+        if self.resolving.iter().any(|n| n == name) {
+            return Err(TemplateError::new(&format!(
+                "recursive loop detected in template string: {}",
+                to_output(&value)
+            )));
+        }
+
+        self.resolving.push(name.to_string());
+        let result = self.isolated(true, |r| r.template_value(&value));
+        self.resolving.pop();
+
+        // Positions inside the variable's own text would be misleading in
+        // the template that used it, so only the message is kept.
+        let resolved = result.map_err(|e| {
+            if e.msg.starts_with("recursive loop detected") {
+                TemplateError::new(&e.msg)
+            } else {
+                TemplateError::new(&format!("{} (in variable '{}')", e.msg, name))
+            }
+        })?;
+        self.resolved.insert(name.to_string(), resolved.clone());
+        Ok(resolved)
+    }
+
+    /// Renders every template string inside a value. A string that is a
+    /// single `{{ expression }}` keeps the expression's type, so lists and
+    /// dictionaries survive templating.
+    fn template_value(&mut self, value: &Value) -> Result<Value, TemplateError> {
+        match value {
+            Value::String(s) if needs_templating(value) => {
+                let nodes = parser::parse(s)?;
+                if let [Node::Output(expr)] = nodes.as_slice() {
+                    return self.eval_data(expr);
+                }
+                self.render_nodes(&nodes).map(Value::String)
+            }
+            Value::Array(items) => items
+                .iter()
+                .map(|item| self.template_value(item))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::Array),
+            Value::Object(map) => {
+                let mut entries = Vec::new();
+                for (k, v) in map {
+                    entries.push((k.clone(), self.template_value(v)?));
+                }
+                Ok(value::object(entries))
+            }
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Val, TemplateError> {
+        match expr {
+            Expr::Const(v) => Ok(Val::Data(v.clone())),
+            Expr::Name(name, pos) => Ok(self
+                .lookup_name(name)
+                .map_err(|e| e.at(pos.line, pos.col))?
+                .unwrap_or_else(|| Val::Undefined(format!("'{}' is undefined", name), *pos))),
+            Expr::List(items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    values.push(self.eval_data(item)?);
+                }
+                Ok(Val::Data(Value::Array(values)))
+            }
+            Expr::Dict(pairs) => {
+                let mut entries = Vec::new();
+                for (k, v) in pairs {
+                    let key = to_output(&self.eval_data(k)?);
+                    entries.push((key, self.eval_data(v)?));
+                }
+                Ok(Val::Data(value::object(entries)))
+            }
+            Expr::Attr(obj, attr) => {
+                let base = self.eval(obj)?;
+                Ok(self.get_attr(base, &Value::String(attr.clone()), expr_pos(obj)))
+            }
+            Expr::Index(obj, index) => {
+                let base = self.eval(obj)?;
+                let key = self.eval_data(index)?;
+                Ok(self.get_attr(base, &key, expr_pos(obj)))
+            }
+            Expr::Slice(obj, start, end) => {
+                let base = self.eval_data(obj)?;
+                let start = match start {
+                    Some(e) => as_int(&self.eval_data(e)?),
+                    None => None,
+                };
+                let end = match end {
+                    Some(e) => as_int(&self.eval_data(e)?),
+                    None => None,
+                };
+                Ok(Val::Data(value::slice(&base, start, end)))
+            }
+            Expr::Call(callee, args, pos) => self.eval_call(callee, args, *pos),
+            Expr::Filter(input, name, args, pos) => {
+                let input = self.eval(input)?;
+                let (positional, keyword) = self.eval_args(args)?;
+                self.apply_filter(name, input, positional, keyword, *pos)
+            }
+            Expr::Test(input, name, args, negated, pos) => {
+                let input = self.eval(input)?;
+                let (positional, _) = self.eval_args(args)?;
+                let positional = positional.into_iter().map(|v| self.data(v)).collect::<Result<Vec<_>, _>>()?;
+                let input = match input {
+                    Val::Data(v) => Some(v),
+                    Val::Undefined(..) => None,
+                    other => Some(self.data(other)?),
+                };
+                let result = match filters::test(name, input.as_ref(), &positional) {
+                    Some(Ok(b)) => b,
+                    Some(Err(msg)) => return Err(TemplateError::new(&msg).at(pos.line, pos.col)),
+                    None => {
+                        return Err(TemplateError::new(&format!("unknown test '{}'", name)).at(pos.line, pos.col))
+                    }
+                };
+                Ok(Val::Data(Value::Bool(result != *negated)))
+            }
+            Expr::Not(inner) => Ok(Val::Data(Value::Bool(!self.truthy(inner)?))),
+            Expr::Neg(inner) => {
+                let v = self.eval_data(inner)?;
+                match as_number(&v) {
+                    Some(n) if v.is_number() || v.is_string() => Ok(Val::Data(value::number(-n))),
+                    _ => Err(TemplateError::new(&format!("bad operand type for unary -: '{}'", value::type_name(&v)))),
+                }
+            }
+            Expr::Bin(BinOp::And, left, right) => {
+                let l = self.eval(left)?;
+                if !self.val_truthy(&l)? {
+                    return Ok(l);
+                }
+                self.eval(right)
+            }
+            Expr::Bin(BinOp::Or, left, right) => {
+                let l = self.eval(left)?;
+                if self.val_truthy(&l)? {
+                    return Ok(l);
+                }
+                self.eval(right)
+            }
+            Expr::Bin(op, left, right) => {
+                let l = self.eval_data(left)?;
+                let r = self.eval_data(right)?;
+                binary(op, &l, &r).map(Val::Data)
+            }
+            Expr::Cond(then, cond, otherwise) => {
+                if self.truthy(cond)? {
+                    self.eval(then)
+                } else {
+                    match otherwise {
+                        Some(e) => self.eval(e),
+                        None => Ok(Val::Data(Value::String(String::new()))),
+                    }
+                }
+            }
+        }
+    }
+
+    fn val_truthy(&self, val: &Val) -> Result<bool, TemplateError> {
+        Ok(match val {
+            Val::Data(v) => is_truthy(v),
+            Val::Undefined(msg, pos) => {
+                self.undefined(msg.clone(), *pos)?;
+                false
+            }
+            _ => true,
+        })
+    }
+
+    fn get_attr(&self, base: Val, key: &Value, pos: Pos) -> Val {
+        match base {
+            Val::Data(v) => match value::get_item(&v, key) {
+                Some(found) => Val::Data(found),
+                None => Val::Undefined(
+                    format!("'{}' object has no attribute '{}'", value::type_name(&v), to_output(key)),
+                    pos,
+                ),
+            },
+            Val::Namespace(ns) => ns
+                .get(&to_output(key))
+                .cloned()
+                .unwrap_or_else(|| Val::Undefined(format!("module has no attribute '{}'", to_output(key)), pos)),
+            Val::Undefined(msg, pos) => Val::Undefined(msg, pos),
+            Val::Macro(_) => Val::Undefined(format!("macro has no attribute '{}'", to_output(key)), pos),
+        }
+    }
+
+    fn eval_args(&mut self, args: &Args) -> Result<EvaluatedArgs, TemplateError> {
+        let mut positional = Vec::new();
+        for arg in &args.positional {
+            positional.push(self.eval(arg)?);
+        }
+        let mut keyword = Vec::new();
+        for (name, arg) in &args.keyword {
+            keyword.push((name.clone(), self.eval(arg)?));
+        }
+        Ok((positional, keyword))
+    }
+
+    fn apply_filter(
+        &mut self,
+        name: &str,
+        input: Val,
+        positional: Vec<Val>,
+        keyword: Vec<(String, Val)>,
+        pos: Pos,
+    ) -> Result<Val, TemplateError> {
+        let input = match input {
+            Val::Undefined(..) if filters::UNDEFINED_AWARE.contains(&name) => None,
+            other => Some(self.data(other)?),
+        };
+        let args = positional.into_iter().map(|v| self.data(v)).collect::<Result<Vec<_>, _>>()?;
+        let kwargs = keyword
+            .into_iter()
+            .map(|(k, v)| self.data(v).map(|v| (k, v)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        match filters::apply(name, input.as_ref(), &args, &kwargs) {
+            Some(Ok(v)) => Ok(Val::Data(v)),
+            Some(Err(msg)) => Err(TemplateError::new(&msg).at(pos.line, pos.col)),
+            None => Err(TemplateError::new(&format!("no filter named '{}'", name)).at(pos.line, pos.col)),
+        }
+    }
+
+    fn eval_call(&mut self, callee: &Expr, args: &Args, pos: Pos) -> Result<Val, TemplateError> {
+        // Python-style method calls on data, e.g. `name.upper()` or `d.items()`.
+        if let Expr::Attr(obj, method) = callee {
+            let base = self.eval(obj)?;
+            if let Val::Data(data) = &base {
+                if !matches!(data, Value::Object(map) if map.contains_key(method)) {
+                    let (positional, _) = self.eval_args(args)?;
+                    let positional = positional.into_iter().map(|v| self.data(v)).collect::<Result<Vec<_>, _>>()?;
+                    return call_method(data, method, &positional)
+                        .map(Val::Data)
+                        .map_err(|msg| TemplateError::new(&msg).at(pos.line, pos.col));
+                }
+            }
+            let target = self.get_attr(base, &Value::String(method.clone()), pos);
+            let (positional, keyword) = self.eval_args(args)?;
+            return self.call_value(target, positional, keyword, None, pos);
+        }
+
+        if let Expr::Name(name, _) = callee {
+            if self.lookup_name(name)?.is_none() {
+                let (positional, keyword) = self.eval_args(args)?;
+                return self.call_global(name, positional, keyword, pos);
+            }
+        }
+
+        let target = self.eval(callee)?;
+        let (positional, keyword) = self.eval_args(args)?;
+        self.call_value(target, positional, keyword, None, pos)
+    }
+
+    fn call_global(
+        &mut self,
+        name: &str,
+        positional: Vec<Val>,
+        keyword: Vec<(String, Val)>,
+        pos: Pos,
+    ) -> Result<Val, TemplateError> {
+        let args = positional.into_iter().map(|v| self.data(v)).collect::<Result<Vec<_>, _>>()?;
+        match name {
+            "range" => {
+                let nums: Vec<i64> = args.iter().filter_map(as_int).collect();
+                let (start, end, step) = match nums.as_slice() {
+                    [end] => (0, *end, 1),
+                    [start, end] => (*start, *end, 1),
+                    [start, end, step] if *step != 0 => (*start, *end, *step),
+                    _ => return Err(TemplateError::new("invalid arguments to range()").at(pos.line, pos.col)),
+                };
+                let mut items = Vec::new();
+                let mut i = start;
+                while (step > 0 && i < end) || (step < 0 && i > end) {
+                    items.push(Value::from(i));
+                    i += step;
+                }
+                Ok(Val::Data(Value::Array(items)))
+            }
+            "dict" => {
+                let mut entries = Vec::new();
+                for (k, v) in keyword {
+                    entries.push((k, self.data(v)?));
+                }
+                Ok(Val::Data(value::object(entries)))
+            }
+            "super" => {
+                let Some((chain, index)) = self.block_stack.last().cloned() else {
+                    return Err(TemplateError::new("super() used outside of a block").at(pos.line, pos.col));
+                };
+                if index + 1 >= chain.len() {
+                    return Ok(Val::Data(Value::String(String::new())));
+                }
+                self.render_block(chain, index + 1).map(|s| Val::Data(Value::String(s)))
+            }
+            "lookup" | "query" | "q" => {
+                let mut options = Vec::new();
+                for (k, v) in keyword {
+                    options.push((k, self.data(v)?));
+                }
+                self.lookup(name != "lookup", args, options)
+                    .map(Val::Data)
+                    .map_err(|e| e.at(pos.line, pos.col))
+            }
+            _ => Err(TemplateError::new(&format!("'{}' is undefined", name)).at(pos.line, pos.col)),
+        }
+    }
+
+    /// Runs a lookup plugin. `lookup()` joins string results with commas
+    /// unless `wantlist=True`; `query()` always returns a list.
+    fn lookup(&self, mut wantlist: bool, args: Vec<Value>, options: Vec<(String, Value)>) -> Result<Value, TemplateError> {
+        let Some((plugin, terms)) = args.split_first() else {
+            return Err(TemplateError::new("lookup requires a plugin name"));
+        };
+        let name = to_output(plugin);
+        let mut errors = "strict".to_string();
+        let mut plugin_options = Vec::new();
+        for (k, v) in options {
+            match k.as_str() {
+                "wantlist" => wantlist |= is_truthy(&v),
+                "errors" => errors = to_output(&v),
+                _ => plugin_options.push((k, v)),
+            }
+        }
+
+        let Some(plugin) = self.env.lookups.get(&name) else {
+            return Err(TemplateError::new(&format!("lookup plugin '{}' not found", name)));
+        };
+        let ctx = LookupContext { env: self.env, vars: self.vars };
+        let results = match plugin.run(terms, &plugin_options, &ctx) {
+            Ok(results) => results,
+            Err(_) if errors == "ignore" || errors == "warn" => Vec::new(),
+            Err(msg) => return Err(TemplateError::new(&format!("lookup plugin '{}' failed: {}", name, msg))),
+        };
+
+        if wantlist {
+            return Ok(Value::Array(results));
+        }
+        if results.iter().all(|v| v.is_string()) {
+            return Ok(Value::String(results.iter().map(to_output).collect::<Vec<_>>().join(",")));
+        }
+        Ok(match results.len() {
+            1 => results.into_iter().next().unwrap_or(Value::Null),
+            _ => Value::Array(results),
+        })
+    }
+
+    fn call_value(
+        &mut self,
+        target: Val,
+        positional: Vec<Val>,
+        keyword: Vec<(String, Val)>,
+        caller: Option<Val>,
+        pos: Pos,
+    ) -> Result<Val, TemplateError> {
+        match target {
+            Val::Macro(m) => self.call_macro(&m, positional, keyword, caller),
+            Val::Undefined(msg, upos) => Err(TemplateError::new(&msg).at(upos.line, upos.col)),
+            _ => Err(TemplateError::new("object is not callable").at(pos.line, pos.col)),
+        }
+    }
+
+    fn call_macro(
+        &mut self,
+        m: &MacroRef,
+        positional: Vec<Val>,
+        keyword: Vec<(String, Val)>,
+        caller: Option<Val>,
+    ) -> Result<Val, TemplateError> {
+        let mut scopes = match &m.captured {
+            Some(captured) => captured.clone(),
+            None => {
+                let mut globals = Scope::new();
+                for sibling in m.siblings.iter() {
+                    globals.insert(
+                        sibling.name.clone(),
+                        Val::Macro(Rc::new(MacroRef {
+                            def: sibling.clone(),
+                            siblings: m.siblings.clone(),
+                            with_context: m.with_context,
+                            dir: m.dir.clone(),
+                            captured: None,
+                        })),
+                    );
+                }
+                vec![globals]
+            }
+        };
+        scopes.push(Scope::new());
+
+        let vars: &'a dyn Vars = if m.with_context { self.vars } else { &NO_VARS };
+        let saved_scopes = std::mem::replace(&mut self.scopes, scopes);
+        let saved_vars = std::mem::replace(&mut self.vars, vars);
+        let saved_context = std::mem::replace(&mut self.with_context, m.with_context);
+        let saved_dir = std::mem::replace(&mut self.dir, m.dir.clone());
+        let saved_macros = std::mem::replace(&mut self.macros, m.siblings.clone());
+
+        let result = (|| {
+            let mut positional = positional.into_iter();
+            let mut keyword = keyword;
+            for (name, default) in &m.def.params {
+                let val = if let Some(v) = positional.next() {
+                    v
+                } else if let Some(i) = keyword.iter().position(|(k, _)| k == name) {
+                    keyword.remove(i).1
+                } else if let Some(default) = default {
+                    self.eval(default)?
+                } else {
+                    Val::Undefined(format!("'{}' is undefined", name), Pos::default())
+                };
+                self.set(name, val);
+            }
+            let varargs: Vec<Value> = positional.map(|v| self.data(v)).collect::<Result<_, _>>()?;
+            self.set("varargs", Val::Data(Value::Array(varargs)));
+            let mut kwargs = Vec::new();
+            for (k, v) in keyword {
+                kwargs.push((k, self.data(v)?));
+            }
+            self.set("kwargs", Val::Data(value::object(kwargs)));
+            if let Some(caller) = caller {
+                self.set("caller", caller);
+            }
+            self.render_nodes(&m.def.body)
+        })();
+
+        self.scopes = saved_scopes;
+        self.vars = saved_vars;
+        self.with_context = saved_context;
+        self.dir = saved_dir;
+        self.macros = saved_macros;
+
+        result.map(|s| Val::Data(Value::String(s)))
+    }
+}
+
+fn expr_pos(expr: &Expr) -> Pos {
+    match expr {
+        Expr::Name(_, pos) | Expr::Call(_, _, pos) | Expr::Filter(_, _, _, pos) | Expr::Test(_, _, _, _, pos) => *pos,
+        Expr::Attr(inner, _) | Expr::Index(inner, _) | Expr::Slice(inner, _, _) | Expr::Not(inner) | Expr::Neg(inner) => {
+            expr_pos(inner)
+        }
+        Expr::Bin(_, left, _) | Expr::Cond(left, _, _) => expr_pos(left),
+        _ => Pos::default(),
+    }
+}
+
+/// Whether a value contains any template markup.
+fn needs_templating(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.contains("{{") || s.contains("{%"),
+        Value::Array(items) => items.iter().any(needs_templating),
+        Value::Object(map) => map.values().any(needs_templating),
+        _ => false,
+    }
+}
+
+fn binary(op: &BinOp, l: &Value, r: &Value) -> Result<Value, TemplateError> {
+    let type_error = |symbol: &str| {
+        TemplateError::new(&format!(
+            "unsupported operand types for {}: '{}' and '{}'",
+            symbol,
+            value::type_name(l),
+            value::type_name(r)
+        ))
+    };
+    let numbers = || match (l, r) {
+        (Value::Number(_), _) | (_, Value::Number(_)) => as_number(l).zip(as_number(r)),
+        _ => None,
+    };
+
+    Ok(match op {
+        BinOp::Eq => Value::Bool(value::loose_eq(l, r)),
+        BinOp::Ne => Value::Bool(!value::loose_eq(l, r)),
+        BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            let ord = value::compare(l, r).ok_or_else(|| type_error("comparison"))?;
+            Value::Bool(match op {
+                BinOp::Lt => ord.is_lt(),
+                BinOp::Le => ord.is_le(),
+                BinOp::Gt => ord.is_gt(),
+                _ => ord.is_ge(),
+            })
+        }
+        BinOp::In => Value::Bool(value::contains(r, l)),
+        BinOp::NotIn => Value::Bool(!value::contains(r, l)),
+        BinOp::Concat => Value::String(format!("{}{}", to_output(l), to_output(r))),
+        BinOp::Add => match (l, r) {
+            (Value::Array(a), Value::Array(b)) => Value::Array(a.iter().chain(b).cloned().collect()),
+            (Value::String(a), Value::String(b)) => Value::String(format!("{}{}", a, b)),
+            _ => {
+                let (a, b) = numbers().ok_or_else(|| type_error("+"))?;
+                value::number(a + b)
+            }
+        },
+        BinOp::Mul => match (l, r) {
+            (Value::String(s), Value::Number(n)) | (Value::Number(n), Value::String(s)) => {
+                Value::String(s.repeat(n.as_u64().unwrap_or(0) as usize))
+            }
+            _ => {
+                let (a, b) = numbers().ok_or_else(|| type_error("*"))?;
+                value::number(a * b)
+            }
+        },
+        BinOp::Sub | BinOp::Div | BinOp::FloorDiv | BinOp::Mod | BinOp::Pow => {
+            let symbol = match op {
+                BinOp::Sub => "-",
+                BinOp::Div => "/",
+                BinOp::FloorDiv => "//",
+                BinOp::Mod => "%",
+                _ => "**",
+            };
+            let (a, b) = as_number(l).zip(as_number(r)).ok_or_else(|| type_error(symbol))?;
+            if b == 0.0 && matches!(op, BinOp::Div | BinOp::FloorDiv | BinOp::Mod) {
+                return Err(TemplateError::new("division by zero"));
+            }
+            match op {
+                BinOp::Sub => value::number(a - b),
+                BinOp::Div => Value::from(a / b),
+                BinOp::FloorDiv => value::number((a / b).floor()),
+                BinOp::Mod => value::number(a.rem_euclid(b)),
+                _ => value::number(a.powf(b)),
+            }
+        }
+        BinOp::And | BinOp::Or => unreachable!("short-circuit operators are evaluated lazily"),
+    })
+}
+
+/// The subset of Python's `str`, `dict` and `list` methods templates commonly
+/// call.
+fn call_method(data: &Value, method: &str, args: &[Value]) -> Result<Value, String> {
+    let text = || to_output(data);
+    let arg_str = |i: usize| args.get(i).map(to_output);
+
+    let result = match (data, method) {
+        (Value::Object(map), "items") => Value::Array(
+            map.iter()
+                .map(|(k, v)| Value::Array(vec![Value::String(k.clone()), v.clone()]))
+                .collect(),
+        ),
+        (Value::Object(map), "keys") => Value::Array(map.keys().map(|k| Value::String(k.clone())).collect()),
+        (Value::Object(map), "values") => Value::Array(map.values().cloned().collect()),
+        (Value::Object(map), "get") => {
+            let key = arg_str(0).unwrap_or_default();
+            map.get(&key).cloned().or_else(|| args.get(1).cloned()).unwrap_or(Value::Null)
+        }
+        (Value::Array(items), "index") => {
+            let needle = args.first().cloned().unwrap_or(Value::Null);
+            let i = items
+                .iter()
+                .position(|v| value::loose_eq(v, &needle))
+                .ok_or_else(|| format!("{} is not in list", to_output(&needle)))?;
+            Value::from(i)
+        }
+        (Value::Array(items), "count") => {
+            let needle = args.first().cloned().unwrap_or(Value::Null);
+            Value::from(items.iter().filter(|v| value::loose_eq(v, &needle)).count())
+        }
+        (_, "upper") => Value::String(text().to_uppercase()),
+        (_, "lower") => Value::String(text().to_lowercase()),
+        (_, "strip") => match arg_str(0) {
+            Some(chars) => Value::String(text().trim_matches(|c| chars.contains(c)).to_string()),
+            None => Value::String(text().trim().to_string()),
+        },
+        (_, "lstrip") => Value::String(text().trim_start().to_string()),
+        (_, "rstrip") => Value::String(text().trim_end().to_string()),
+        (_, "startswith") => Value::Bool(text().starts_with(&arg_str(0).unwrap_or_default())),
+        (_, "endswith") => Value::Bool(text().ends_with(&arg_str(0).unwrap_or_default())),
+        (_, "replace") => Value::String(text().replace(&arg_str(0).unwrap_or_default(), &arg_str(1).unwrap_or_default())),
+        (_, "split") => {
+            let text = text();
+            Value::Array(match arg_str(0) {
+                Some(sep) => text.split(sep.as_str()).map(|s| Value::String(s.to_string())).collect(),
+                None => text.split_whitespace().map(|s| Value::String(s.to_string())).collect(),
+            })
+        }
+        (_, "join") => Value::String(
+            args.first()
+                .map(value::iterate)
+                .unwrap_or_default()
+                .iter()
+                .map(to_output)
+                .collect::<Vec<_>>()
+                .join(&text()),
+        ),
+        _ => return Err(format!("'{}' object has no attribute '{}'", value::type_name(data), method)),
+    };
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str) -> String {
+        let env = Environment::new();
+        let vars: HashMap<String, String> = HashMap::new();
+        Renderer::new(&env, &vars).render_source(source).unwrap()
+    }
+
+    #[test]
+    fn arithmetic_and_precedence() {
+        assert_eq!(render("{{ 1 + 2 * 3 }}"), "7");
+        assert_eq!(render("{{ 7 // 2 }}"), "3");
+        assert_eq!(render("{{ 'a' ~ 1 }}"), "a1");
+    }
+
+    #[test]
+    fn conditional_expression() {
+        assert_eq!(render("{{ 'yes' if 1 > 0 else 'no' }}"), "yes");
+    }
+
+    #[test]
+    fn dict_methods() {
+        assert_eq!(render("{{ {'a': 1}.get('b', 2) }}"), "2");
+        assert_eq!(render("{% for k, v in {'a': 1}.items() %}{{ k }}={{ v }}{% endfor %}"), "a=1");
+    }
+
+    #[test]
+    fn range_builtin() {
+        assert_eq!(render("{% for i in range(3) %}{{ i }}{% endfor %}"), "012");
+    }
+
+    #[test]
+    fn loop_variable() {
+        assert_eq!(
+            render("{% for x in ['a', 'b'] %}{{ loop.index }}{{ x }}{% if not loop.last %},{% endif %}{% endfor %}"),
+            "1a,2b"
+        );
+    }
+}
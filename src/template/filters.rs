@@ -0,0 +1,894 @@
+use super::query;
+use super::value::{self, as_int, as_number, iterate, to_output};
+use base64::Engine;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::net::IpAddr;
+
+/// Filters that receive undefined input instead of failing on it.
+pub const UNDEFINED_AWARE: [&str; 3] = ["default", "d", "mandatory"];
+
+fn arg<'a>(args: &'a [Value], kwargs: &'a [(String, Value)], index: usize, name: &str) -> Option<&'a Value> {
+    kwargs.iter().find(|(k, _)| k == name).map(|(_, v)| v).or_else(|| args.get(index))
+}
+
+fn str_arg(args: &[Value], kwargs: &[(String, Value)], index: usize, name: &str, default: &str) -> String {
+    arg(args, kwargs, index, name).map(to_output).unwrap_or_else(|| default.to_string())
+}
+
+/// Applies the named filter. `input` is `None` when the filtered value is
+/// undefined. Returns `None` for filters this engine does not know.
+pub fn apply(
+    name: &str,
+    input: Option<&Value>,
+    args: &[Value],
+    kwargs: &[(String, Value)],
+) -> Option<Result<Value, String>> {
+    let empty = Value::String(String::new());
+    let value = input.unwrap_or(&empty);
+    let text = || to_output(value);
+
+    let result = match name {
+        "default" | "d" => {
+            let fallback = arg(args, kwargs, 0, "default_value").cloned().unwrap_or(empty.clone());
+            let boolean = arg(args, kwargs, 1, "boolean").map(value::is_truthy).unwrap_or(false);
+            match input {
+                Some(v) if !boolean || value::is_truthy(v) => Ok(v.clone()),
+                _ => Ok(fallback),
+            }
+        }
+        "lower" => Ok(Value::String(text().to_lowercase())),
+        "upper" => Ok(Value::String(text().to_uppercase())),
+        "capitalize" => {
+            let text = text();
+            let mut chars = text.chars();
+            Ok(Value::String(match chars.next() {
+                None => String::new(),
+                Some(c) => c.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect(),
+            }))
+        }
+        "trim" => Ok(Value::String(text().trim().to_string())),
+        "string" => Ok(Value::String(text())),
+        "length" => Ok(Value::from(match value {
+            Value::Array(items) => items.len(),
+            Value::Object(map) => map.len(),
+            other => to_output(other).chars().count(),
+        })),
+        "replace" => {
+            let old = str_arg(args, kwargs, 0, "old", "");
+            let new = str_arg(args, kwargs, 1, "new", "");
+            Ok(Value::String(text().replace(&old, &new)))
+        }
+        "regex_replace" => {
+            let pattern = str_arg(args, kwargs, 0, "pattern", "");
+            let replacement = python_replacement(&str_arg(args, kwargs, 1, "replacement", ""));
+            regex::Regex::new(&pattern)
+                .map(|re| Value::String(re.replace_all(&text(), replacement.as_str()).to_string()))
+                .map_err(|e| format!("invalid regular expression '{}': {}", pattern, e))
+        }
+        "join" => {
+            let sep = str_arg(args, kwargs, 0, "d", "");
+            Ok(Value::String(
+                iterate(value).iter().map(to_output).collect::<Vec<_>>().join(&sep),
+            ))
+        }
+        "split" => {
+            let text = text();
+            let parts: Vec<Value> = match arg(args, kwargs, 0, "sep") {
+                Some(sep) => text.split(to_output(sep).as_str()).map(|s| Value::String(s.to_string())).collect(),
+                None => text.split_whitespace().map(|s| Value::String(s.to_string())).collect(),
+            };
+            Ok(Value::Array(parts))
+        }
+        "basename" => Ok(Value::String(
+            std::path::Path::new(&text())
+                .file_name()
+                .and_then(|s| s.to_str())
+                .map(|s| s.to_string())
+                .unwrap_or_else(text),
+        )),
+        "dirname" => Ok(Value::String(
+            std::path::Path::new(&text())
+                .parent()
+                .and_then(|s| s.to_str())
+                .map(|s| s.to_string())
+                .unwrap_or_else(text),
+        )),
+        "to_json" => serde_json::to_string(value).map(Value::String).map_err(|e| e.to_string()),
+        "to_yaml" => serde_yaml::to_string(value).map(Value::String).map_err(|e| e.to_string()),
+        "to_nice_json" => {
+            let width = arg(args, kwargs, 0, "indent").and_then(as_int).unwrap_or(4).max(0) as usize;
+            let indent = " ".repeat(width);
+            let mut out = Vec::new();
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+            let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+            serde::Serialize::serialize(value, &mut serializer)
+                .map(|_| Value::String(String::from_utf8_lossy(&out).into_owned()))
+                .map_err(|e| e.to_string())
+        }
+        "to_nice_yaml" => serde_yaml::to_string(value).map(Value::String).map_err(|e| e.to_string()),
+        "from_json" => serde_json::from_str(&text()).map_err(|e| format!("from_json: {}", e)),
+        "from_yaml" => serde_yaml::from_str(&text()).map_err(|e| format!("from_yaml: {}", e)),
+        "combine" => combine(value, args, kwargs),
+        "dict2items" => {
+            let key_name = str_arg(args, kwargs, 0, "key_name", "key");
+            let value_name = str_arg(args, kwargs, 1, "value_name", "value");
+            match value {
+                Value::Object(map) => Ok(Value::Array(
+                    map.iter()
+                        .map(|(k, v)| {
+                            value::object(vec![(key_name.clone(), Value::String(k.clone())), (value_name.clone(), v.clone())])
+                        })
+                        .collect(),
+                )),
+                other => Err(format!("dict2items requires a dictionary, got {}", value::type_name(other))),
+            }
+        }
+        "items2dict" => {
+            let key_name = str_arg(args, kwargs, 0, "key_name", "key");
+            let value_name = str_arg(args, kwargs, 1, "value_name", "value");
+            let mut map = Map::new();
+            for item in iterate(value) {
+                let (Some(k), Some(v)) = (item.get(&key_name), item.get(&value_name)) else {
+                    return Some(Err(format!("items2dict requires '{}' and '{}' in every item", key_name, value_name)));
+                };
+                map.insert(to_output(k), v.clone());
+            }
+            Ok(Value::Object(map))
+        }
+        "map" => map_filter(value, args, kwargs),
+        "select" | "reject" => {
+            let keep = name == "select";
+            let test_name = args.first().map(to_output);
+            let test_args = args.get(1..).unwrap_or_default();
+            filter_items(value, keep, |item| match &test_name {
+                Some(t) => run_test(t, Some(item), test_args),
+                None => Ok(value::is_truthy(item)),
+            })
+        }
+        "selectattr" | "rejectattr" => {
+            let keep = name == "selectattr";
+            let Some(attr) = args.first().map(to_output) else {
+                return Some(Err(format!("{} requires an attribute name", name)));
+            };
+            let test_name = args.get(1).map(to_output);
+            let test_args = args.get(2..).unwrap_or_default();
+            filter_items(value, keep, |item| {
+                let found = attribute(item, &attr);
+                match &test_name {
+                    Some(t) => run_test(t, found.as_ref(), test_args),
+                    None => Ok(found.as_ref().map(value::is_truthy).unwrap_or(false)),
+                }
+            })
+        }
+        "unique" => {
+            let mut seen: Vec<Value> = Vec::new();
+            for item in iterate(value) {
+                if !seen.contains(&item) {
+                    seen.push(item);
+                }
+            }
+            Ok(Value::Array(seen))
+        }
+        "union" | "intersect" | "difference" | "symmetric_difference" => {
+            let left = iterate(value);
+            let right = args.first().map(iterate).unwrap_or_default();
+            let mut out: Vec<Value> = Vec::new();
+            let mut push = |item: &Value| {
+                if !out.contains(item) {
+                    out.push(item.clone());
+                }
+            };
+            match name {
+                "union" => left.iter().chain(right.iter()).for_each(&mut push),
+                "intersect" => left.iter().filter(|i| right.contains(i)).for_each(&mut push),
+                "difference" => left.iter().filter(|i| !right.contains(i)).for_each(&mut push),
+                _ => left
+                    .iter()
+                    .filter(|i| !right.contains(i))
+                    .chain(right.iter().filter(|i| !left.contains(i)))
+                    .for_each(&mut push),
+            }
+            Ok(Value::Array(out))
+        }
+        "flatten" => {
+            let levels = arg(args, kwargs, 0, "levels").and_then(as_int);
+            let mut out = Vec::new();
+            flatten(&iterate(value), levels, &mut out);
+            Ok(Value::Array(out))
+        }
+        "sort" => {
+            let reverse = arg(args, kwargs, 0, "reverse").map(value::is_truthy).unwrap_or(false);
+            let case_sensitive = arg(args, kwargs, 1, "case_sensitive").map(value::is_truthy).unwrap_or(false);
+            let attr = arg(args, kwargs, 2, "attribute").map(to_output);
+            let mut items = iterate(value);
+            items.sort_by(|a, b| {
+                let key = |v: &Value| {
+                    let v = match &attr {
+                        Some(attr) => attribute(v, attr).unwrap_or(Value::Null),
+                        None => v.clone(),
+                    };
+                    match v {
+                        Value::String(s) if !case_sensitive => Value::String(s.to_lowercase()),
+                        other => other,
+                    }
+                };
+                value::compare(&key(a), &key(b)).unwrap_or(Ordering::Equal)
+            });
+            if reverse {
+                items.reverse();
+            }
+            Ok(Value::Array(items))
+        }
+        "first" | "last" => {
+            let index = Value::from(if name == "first" { 0 } else { -1 });
+            let target = match value {
+                Value::String(_) => value.clone(),
+                other => Value::Array(iterate(other)),
+            };
+            Ok(value::get_item(&target, &index).unwrap_or(Value::Null))
+        }
+        "min" | "max" => {
+            let attr = kwargs.iter().find(|(k, _)| k == "attribute").map(|(_, v)| to_output(v));
+            let key = |v: &Value| match &attr {
+                Some(attr) => attribute(v, attr).unwrap_or(Value::Null),
+                None => v.clone(),
+            };
+            let items = iterate(value);
+            let pick = items.iter().cloned().reduce(|best, item| {
+                let ord = value::compare(&key(&item), &key(&best)).unwrap_or(Ordering::Equal);
+                match (name, ord) {
+                    ("min", Ordering::Less) | ("max", Ordering::Greater) => item,
+                    _ => best,
+                }
+            });
+            Ok(pick.unwrap_or(Value::Null))
+        }
+        "int" => {
+            let default = arg(args, kwargs, 0, "default").and_then(as_int).unwrap_or(0);
+            let base = arg(args, kwargs, 1, "base").and_then(as_int).unwrap_or(10);
+            let parsed = match (value, base) {
+                (_, 10) => as_int(value),
+                (Value::String(s), base) => {
+                    let s = s.trim().to_lowercase();
+                    let digits = ["0x", "0o", "0b"].iter().fold(s.as_str(), |s, p| s.strip_prefix(p).unwrap_or(s));
+                    i64::from_str_radix(digits, base as u32).ok()
+                }
+                _ => as_int(value),
+            };
+            Ok(Value::from(parsed.unwrap_or(default)))
+        }
+        "float" => {
+            let default = arg(args, kwargs, 0, "default").and_then(as_number).unwrap_or(0.0);
+            Ok(Value::from(as_number(value).unwrap_or(default)))
+        }
+        "bool" => Ok(Value::Bool(match value {
+            Value::Bool(b) => *b,
+            Value::Number(n) => n.as_f64() == Some(1.0),
+            Value::String(s) => matches!(s.trim().to_lowercase().as_str(), "yes" | "on" | "1" | "true" | "y" | "t"),
+            _ => false,
+        })),
+        "abs" => match as_number(value) {
+            Some(n) => Ok(value::number(n.abs())),
+            None => Err(format!("abs expects a number, got {}", value::type_name(value))),
+        },
+        "round" => {
+            let precision = arg(args, kwargs, 0, "precision").and_then(as_int).unwrap_or(0) as i32;
+            let method = str_arg(args, kwargs, 1, "method", "common");
+            let factor = 10f64.powi(precision);
+            let n = as_number(value).unwrap_or(0.0) * factor;
+            let rounded = match method.as_str() {
+                "ceil" => n.ceil(),
+                "floor" => n.floor(),
+                _ => n.round(),
+            } / factor;
+            Ok(if precision == 0 { Value::from(rounded) } else { value::number(rounded) })
+        }
+        "sum" => {
+            let start = arg(args, kwargs, 1, "start").and_then(as_number).unwrap_or(0.0);
+            let attr = arg(args, kwargs, 0, "attribute").map(to_output);
+            let total = iterate(value)
+                .iter()
+                .map(|v| match &attr {
+                    Some(attr) => attribute(v, attr).and_then(|v| as_number(&v)).unwrap_or(0.0),
+                    None => as_number(v).unwrap_or(0.0),
+                })
+                .sum::<f64>();
+            Ok(value::number(start + total))
+        }
+        "list" => Ok(Value::Array(match value {
+            Value::String(s) => s.chars().map(|c| Value::String(c.to_string())).collect(),
+            other => iterate(other),
+        })),
+        "reverse" => Ok(match value {
+            Value::String(s) => Value::String(s.chars().rev().collect()),
+            other => Value::Array(iterate(other).into_iter().rev().collect()),
+        }),
+        "title" => Ok(Value::String(
+            text()
+                .split(' ')
+                .map(|word| apply("capitalize", Some(&Value::String(word.to_string())), &[], &[]))
+                .map(|w| w.and_then(Result::ok).map(|w| to_output(&w)).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(" "),
+        )),
+        "count" => return apply("length", input, args, kwargs),
+        "b64encode" => Ok(Value::String(base64::engine::general_purpose::STANDARD.encode(text()))),
+        "b64decode" => base64::engine::general_purpose::STANDARD
+            .decode(text().trim())
+            .map(|bytes| Value::String(String::from_utf8_lossy(&bytes).into_owned()))
+            .map_err(|e| format!("b64decode: {}", e)),
+        "hash" => digest(&str_arg(args, kwargs, 0, "hashtype", "sha1"), &text()),
+        "checksum" | "sha1" => digest("sha1", &text()),
+        "md5" => digest("md5", &text()),
+        "password_hash" => password_hash(
+            &text(),
+            &str_arg(args, kwargs, 0, "hashtype", "sha512"),
+            arg(args, kwargs, 1, "salt").map(to_output),
+            arg(args, kwargs, 2, "rounds").and_then(as_int),
+        ),
+        "ipaddr" | "ipv4" | "ipv6" => {
+            let query = arg(args, kwargs, 0, "query").map(to_output).unwrap_or_default();
+            let version = match name {
+                "ipv4" => Some(4),
+                "ipv6" => Some(6),
+                _ => None,
+            };
+            match value {
+                Value::Array(items) => Ok(Value::Array(
+                    items
+                        .iter()
+                        .filter_map(|item| ipaddr(&to_output(item), &query, version))
+                        .collect(),
+                )),
+                other => Ok(ipaddr(&to_output(other), &query, version).unwrap_or(Value::Bool(false))),
+            }
+        }
+        "regex_search" => {
+            let pattern = str_arg(args, kwargs, 0, "pattern", "");
+            regex_with_flags(&pattern, kwargs).map(|re| {
+                let text = text();
+                let Some(caps) = re.captures(&text) else {
+                    return Value::Null;
+                };
+                let groups = args.get(1..).unwrap_or_default();
+                if groups.is_empty() {
+                    return Value::String(caps[0].to_string());
+                }
+                Value::Array(
+                    groups
+                        .iter()
+                        .map(|g| {
+                            let g = to_output(g);
+                            let g = g.trim_start_matches('\\');
+                            let g = g.strip_prefix("g<").and_then(|g| g.strip_suffix('>')).unwrap_or(g);
+                            let found = match g.parse::<usize>() {
+                                Ok(i) => caps.get(i),
+                                Err(_) => caps.name(g),
+                            };
+                            found.map(|m| Value::String(m.as_str().to_string())).unwrap_or(Value::Null)
+                        })
+                        .collect(),
+                )
+            })
+        }
+        "regex_findall" => {
+            let pattern = str_arg(args, kwargs, 0, "pattern", "");
+            regex_with_flags(&pattern, kwargs).map(|re| {
+                let text = text();
+                let group_text = |caps: &regex::Captures, i: usize| {
+                    Value::String(caps.get(i).map(|m| m.as_str().to_string()).unwrap_or_default())
+                };
+                Value::Array(
+                    re.captures_iter(&text)
+                        .map(|caps| match re.captures_len() {
+                            1 => group_text(&caps, 0),
+                            2 => group_text(&caps, 1),
+                            n => Value::Array((1..n).map(|i| group_text(&caps, i)).collect()),
+                        })
+                        .collect(),
+                )
+            })
+        }
+        "regex_escape" => Ok(Value::String(regex::escape(&text()))),
+        "quote" => Ok(Value::String(shell_quote(&text()))),
+        "mandatory" => match input {
+            Some(v) => Ok(v.clone()),
+            None => Err(arg(args, kwargs, 0, "msg")
+                .map(to_output)
+                .unwrap_or_else(|| "Mandatory variable has not been defined".to_string())),
+        },
+        "ternary" => {
+            let none_val = arg(args, kwargs, 2, "none_val");
+            Ok(match none_val {
+                Some(v) if value.is_null() => v.clone(),
+                _ if value::is_truthy(value) => arg(args, kwargs, 0, "true_val").cloned().unwrap_or(Value::Null),
+                _ => arg(args, kwargs, 1, "false_val").cloned().unwrap_or(Value::Null),
+            })
+        }
+        "zip" | "zip_longest" => {
+            let mut lists = vec![iterate(value)];
+            lists.extend(args.iter().map(iterate));
+            let len = if name == "zip" {
+                lists.iter().map(Vec::len).min().unwrap_or(0)
+            } else {
+                lists.iter().map(Vec::len).max().unwrap_or(0)
+            };
+            let fill = kwargs.iter().find(|(k, _)| k == "fillvalue").map(|(_, v)| v.clone()).unwrap_or(Value::Null);
+            Ok(Value::Array(
+                (0..len)
+                    .map(|i| Value::Array(lists.iter().map(|l| l.get(i).cloned().unwrap_or(fill.clone())).collect()))
+                    .collect(),
+            ))
+        }
+        "json_query" => query::query(value, &str_arg(args, kwargs, 0, "expr", "")),
+        "type_debug" => Ok(Value::String(value::type_name(value).to_string())),
+        "indent" => {
+            let width = arg(args, kwargs, 0, "width").and_then(as_int).unwrap_or(4).max(0) as usize;
+            let first = arg(args, kwargs, 1, "first").map(value::is_truthy).unwrap_or(false);
+            let pad = " ".repeat(width);
+            let indented: Vec<String> = text()
+                .split('\n')
+                .enumerate()
+                .map(|(i, line)| {
+                    if (i > 0 || first) && !line.is_empty() {
+                        format!("{}{}", pad, line)
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .collect();
+            Ok(Value::String(indented.join("\n")))
+        }
+        _ => return None,
+    };
+
+    Some(result)
+}
+
+/// Merges dictionaries left to right. With `recursive=true` nested
+/// dictionaries are merged instead of replaced; `list_merge` controls how
+/// lists found under the same key are combined.
+fn combine(value: &Value, args: &[Value], kwargs: &[(String, Value)]) -> Result<Value, String> {
+    let recursive = kwargs.iter().any(|(k, v)| k == "recursive" && value::is_truthy(v));
+    let list_merge = kwargs
+        .iter()
+        .find(|(k, _)| k == "list_merge")
+        .map(|(_, v)| to_output(v))
+        .unwrap_or_else(|| "replace".to_string());
+
+    fn merge(base: &mut Value, other: &Value, recursive: bool, list_merge: &str) {
+        match (base, other) {
+            (Value::Object(base), Value::Object(other)) => {
+                for (k, v) in other {
+                    match base.get_mut(k) {
+                        Some(existing @ Value::Object(_)) if recursive && v.is_object() => {
+                            merge(existing, v, recursive, list_merge)
+                        }
+                        Some(Value::Array(existing)) if v.is_array() && list_merge != "replace" => {
+                            let new = v.as_array().cloned().unwrap_or_default();
+                            match list_merge {
+                                "keep" => {}
+                                "append" => existing.extend(new),
+                                "prepend" => {
+                                    let old = std::mem::replace(existing, new);
+                                    existing.extend(old);
+                                }
+                                "append_rp" => {
+                                    existing.retain(|item| !new.contains(item));
+                                    existing.extend(new);
+                                }
+                                _ => {
+                                    existing.retain(|item| !new.contains(item));
+                                    let old = std::mem::replace(existing, new);
+                                    existing.extend(old);
+                                }
+                            }
+                        }
+                        _ => {
+                            base.insert(k.clone(), v.clone());
+                        }
+                    }
+                }
+            }
+            (base, other) => *base = other.clone(),
+        }
+    }
+
+    if !value.is_object() {
+        return Err(format!("combine expects dictionaries, got {}", value::type_name(value)));
+    }
+    let mut result = value.clone();
+    for other in args.iter().flat_map(|a| match a {
+        Value::Array(items) => items.clone(),
+        other => vec![other.clone()],
+    }) {
+        if !other.is_object() {
+            return Err(format!("combine expects dictionaries, got {}", value::type_name(&other)));
+        }
+        merge(&mut result, &other, recursive, &list_merge);
+    }
+    Ok(result)
+}
+
+/// `map('filter', args...)` applies a filter to every item and
+/// `map(attribute='a.b')` extracts an attribute from each.
+fn map_filter(value: &Value, args: &[Value], kwargs: &[(String, Value)]) -> Result<Value, String> {
+    let items = iterate(value);
+    if let Some((_, attr)) = kwargs.iter().find(|(k, _)| k == "attribute") {
+        let attr = to_output(attr);
+        let default = kwargs.iter().find(|(k, _)| k == "default").map(|(_, v)| v.clone());
+        return items
+            .iter()
+            .map(|item| {
+                attribute(item, &attr)
+                    .or_else(|| default.clone())
+                    .ok_or_else(|| format!("'{}' object has no attribute '{}'", value::type_name(item), attr))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array);
+    }
+
+    let Some(filter) = args.first().map(to_output) else {
+        return Ok(Value::Array(items));
+    };
+    items
+        .iter()
+        .map(|item| apply(&filter, Some(item), &args[1..], kwargs).unwrap_or_else(|| Err(format!("no filter named '{}'", filter))))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Value::Array)
+}
+
+fn filter_items(value: &Value, keep: bool, mut pred: impl FnMut(&Value) -> Result<bool, String>) -> Result<Value, String> {
+    let mut out = Vec::new();
+    for item in iterate(value) {
+        if pred(&item)? == keep {
+            out.push(item);
+        }
+    }
+    Ok(Value::Array(out))
+}
+
+fn run_test(name: &str, input: Option<&Value>, args: &[Value]) -> Result<bool, String> {
+    test(name, input, args).unwrap_or_else(|| Err(format!("no test named '{}'", name)))
+}
+
+/// Resolves a dotted attribute path such as `a.b.0`.
+fn attribute(value: &Value, path: &str) -> Option<Value> {
+    path.split('.')
+        .try_fold(value.clone(), |current, key| value::get_item(&current, &Value::String(key.to_string())))
+}
+
+fn flatten(items: &[Value], levels: Option<i64>, out: &mut Vec<Value>) {
+    for item in items {
+        match item {
+            Value::Array(inner) if levels != Some(0) => flatten(inner, levels.map(|l| l - 1), out),
+            Value::Null => {}
+            other => out.push(other.clone()),
+        }
+    }
+}
+
+fn regex_with_flags(pattern: &str, kwargs: &[(String, Value)]) -> Result<regex::Regex, String> {
+    let flag = |name: &str| kwargs.iter().any(|(k, v)| k == name && value::is_truthy(v));
+    let mut flags = String::new();
+    if flag("ignorecase") {
+        flags.push('i');
+    }
+    if flag("multiline") {
+        flags.push('m');
+    }
+    let full = if flags.is_empty() { pattern.to_string() } else { format!("(?{}){}", flags, pattern) };
+    regex::Regex::new(&full).map_err(|e| format!("invalid regular expression '{}': {}", pattern, e))
+}
+
+fn digest(hashtype: &str, text: &str) -> Result<Value, String> {
+    use sha2::Digest;
+    let bytes = text.as_bytes();
+    let hash: Vec<u8> = match hashtype {
+        "md5" => md5::Md5::digest(bytes).to_vec(),
+        "sha1" => sha1::Sha1::digest(bytes).to_vec(),
+        "sha224" => sha2::Sha224::digest(bytes).to_vec(),
+        "sha256" => sha2::Sha256::digest(bytes).to_vec(),
+        "sha384" => sha2::Sha384::digest(bytes).to_vec(),
+        "sha512" => sha2::Sha512::digest(bytes).to_vec(),
+        other => return Err(format!("unsupported hash type '{}'", other)),
+    };
+    Ok(Value::String(hash.iter().map(|b| format!("{:02x}", b)).collect()))
+}
+
+// sha256_crypt and md5_crypt are deprecated upstream but still requested by
+// playbooks that target older systems.
+#[allow(deprecated)]
+fn password_hash(password: &str, hashtype: &str, salt: Option<String>, rounds: Option<i64>) -> Result<Value, String> {
+    let setup = |prefix: &str| match (&salt, rounds) {
+        (Some(salt), Some(rounds)) => Some(format!("${}$rounds={}${}", prefix, rounds, salt)),
+        (Some(salt), None) => Some(format!("${}${}", prefix, salt)),
+        _ => None,
+    };
+    let result = match hashtype {
+        "sha512" | "sha512_crypt" => match setup("6") {
+            Some(param) => pwhash::sha512_crypt::hash_with(param.as_str(), password),
+            None => pwhash::sha512_crypt::hash(password),
+        },
+        "sha256" | "sha256_crypt" => match setup("5") {
+            Some(param) => pwhash::sha256_crypt::hash_with(param.as_str(), password),
+            None => pwhash::sha256_crypt::hash(password),
+        },
+        "md5" | "md5_crypt" => match setup("1") {
+            Some(param) => pwhash::md5_crypt::hash_with(param.as_str(), password),
+            None => pwhash::md5_crypt::hash(password),
+        },
+        other => return Err(format!("unsupported password hash type '{}'", other)),
+    };
+    result.map(Value::String).map_err(|e| format!("password_hash: {}", e))
+}
+
+/// A lightweight `ipaddr`: validates addresses and CIDR networks and answers
+/// the common queries. Returns `None` when the value does not qualify.
+fn ipaddr(text: &str, query: &str, version: Option<u8>) -> Option<Value> {
+    let (addr, prefix) = match text.split_once('/') {
+        Some((a, p)) => (a.parse::<IpAddr>().ok()?, Some(p.parse::<u8>().ok()?)),
+        None => (text.parse::<IpAddr>().ok()?, None),
+    };
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix.is_some_and(|p| p > bits) {
+        return None;
+    }
+    match version {
+        Some(4) if !addr.is_ipv4() => return None,
+        Some(6) if !addr.is_ipv6() => return None,
+        _ => {}
+    }
+
+    let prefix_len = prefix.unwrap_or(bits);
+    let to_bits = |ip: IpAddr| match ip {
+        IpAddr::V4(v4) => u32::from(v4) as u128,
+        IpAddr::V6(v6) => u128::from(v6),
+    };
+    let from_bits = |n: u128| -> IpAddr {
+        if addr.is_ipv4() {
+            IpAddr::V4((n as u32).into())
+        } else {
+            IpAddr::V6(n.into())
+        }
+    };
+    let mask: u128 = if prefix_len == 0 {
+        0
+    } else {
+        (u128::MAX << (128 - prefix_len as u32)) >> (128 - bits as u32)
+    };
+    let host_mask = !mask & (u128::MAX >> (128 - bits as u32));
+    let network = to_bits(addr) & mask;
+    let is_private = match addr {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        IpAddr::V6(v6) => v6.is_loopback() || (v6.segments()[0] & 0xfe00) == 0xfc00,
+    };
+
+    let text_value = |s: String| Some(Value::String(s));
+    match query {
+        "" => text_value(text.to_string()),
+        "address" => text_value(addr.to_string()),
+        "network" => text_value(from_bits(network).to_string()),
+        "netmask" => text_value(from_bits(mask).to_string()),
+        "hostmask" => text_value(from_bits(host_mask).to_string()),
+        "broadcast" => text_value(from_bits(network | host_mask).to_string()),
+        "prefix" => Some(Value::from(prefix_len)),
+        "host" => text_value(format!("{}/{}", addr, prefix_len)),
+        "net" | "subnet" => text_value(format!("{}/{}", from_bits(network), prefix_len)),
+        "version" => Some(Value::from(if addr.is_ipv4() { 4 } else { 6 })),
+        "private" => is_private.then(|| Value::String(text.to_string())),
+        "public" => (!is_private).then(|| Value::String(text.to_string())),
+        _ => None,
+    }
+}
+
+/// Quotes a string for safe use in a POSIX shell command line.
+pub fn shell_quote(text: &str) -> String {
+    if text.is_empty() {
+        return "''".to_string();
+    }
+    let safe = text.chars().all(|c| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c));
+    if safe {
+        text.to_string()
+    } else {
+        format!("'{}'", text.replace('\'', "'\"'\"'"))
+    }
+}
+
+/// Converts Python-style `\1` group references to the `${1}` form used by
+/// the regex crate.
+pub fn python_replacement(replacement: &str) -> String {
+    let re = regex::Regex::new(r"\\(\d+)|\\g<(\w+)>").unwrap();
+    let escaped = replacement.replace('$', "$$");
+    re.replace_all(&escaped, |caps: &regex::Captures| {
+        let group = caps.get(1).or_else(|| caps.get(2)).unwrap().as_str();
+        format!("${{{}}}", group)
+    })
+    .to_string()
+}
+
+/// Evaluates a Jinja test (`value is name(args)`). `input` is `None` when the
+/// value is undefined. Returns `None` for unknown tests.
+pub fn test(name: &str, input: Option<&Value>, args: &[Value]) -> Option<Result<bool, String>> {
+    let null = Value::Null;
+    let value = input.unwrap_or(&null);
+
+    let result = match name {
+        "defined" => input.is_some(),
+        "undefined" => input.is_none(),
+        "none" => input.is_some() && value.is_null(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "float" => value.is_f64(),
+        "boolean" => value.is_boolean(),
+        "true" => value == &Value::Bool(true),
+        "false" => value == &Value::Bool(false),
+        "mapping" => value.is_object(),
+        "sequence" | "iterable" => value.is_array() || value.is_string() || value.is_object(),
+        "lower" => to_output(value) == to_output(value).to_lowercase(),
+        "upper" => to_output(value) == to_output(value).to_uppercase(),
+        "even" | "odd" | "divisibleby" => {
+            let Some(n) = as_int(value) else {
+                return Some(Err(format!("'{}' test expects a number", name)));
+            };
+            match name {
+                "even" => n % 2 == 0,
+                "odd" => n % 2 != 0,
+                _ => match args.first().and_then(as_int) {
+                    Some(0) | None => return Some(Err("divisibleby expects a non-zero number".to_string())),
+                    Some(d) => n % d == 0,
+                },
+            }
+        }
+        "eq" | "equalto" | "==" => args.first().map(|a| value::loose_eq(value, a)).unwrap_or(false),
+        "ne" | "!=" => !args.first().map(|a| value::loose_eq(value, a)).unwrap_or(false),
+        "sameas" => args.first().map(|a| a == value).unwrap_or(false),
+        "in" => args.first().map(|c| value::contains(c, value)).unwrap_or(false),
+        "gt" | "ge" | "lt" | "le" => {
+            let ord = args.first().and_then(|a| value::compare(value, a));
+            match (name, ord) {
+                (_, None) => false,
+                ("gt", Some(o)) => o.is_gt(),
+                ("ge", Some(o)) => o.is_ge(),
+                ("lt", Some(o)) => o.is_lt(),
+                (_, Some(o)) => o.is_le(),
+            }
+        }
+        "match" | "search" | "regex" => {
+            let pattern = args.first().map(to_output).unwrap_or_default();
+            let pattern = if name == "match" { format!("^(?:{})", pattern) } else { pattern };
+            match regex::Regex::new(&pattern) {
+                Ok(re) => re.is_match(&to_output(value)),
+                Err(e) => return Some(Err(format!("invalid regular expression: {}", e))),
+            }
+        }
+        "failed" | "failure" => result_flag(value, "failed"),
+        "succeeded" | "success" => !result_flag(value, "failed"),
+        "changed" | "change" => result_flag(value, "changed"),
+        "skipped" | "skip" => result_flag(value, "skipped"),
+        _ => return None,
+    };
+
+    Some(Ok(result))
+}
+
+fn result_flag(value: &Value, flag: &str) -> bool {
+    value.get(flag).map(value::is_truthy).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn run(name: &str, input: Value, args: &[Value]) -> Value {
+        apply(name, Some(&input), args, &[]).unwrap().unwrap()
+    }
+
+    #[test]
+    fn default_only_replaces_undefined() {
+        let fallback = [json!("x")];
+        assert_eq!(apply("default", None, &fallback, &[]).unwrap().unwrap(), json!("x"));
+        assert_eq!(run("default", json!(""), &fallback), json!(""));
+        assert_eq!(run("default", json!(""), &[json!("x"), json!(true)]), json!("x"));
+    }
+
+    #[test]
+    fn regex_replace_group_references() {
+        assert_eq!(
+            run("regex_replace", json!("host-01"), &[json!("(\\w+)-(\\d+)"), json!("\\2-\\1")]),
+            json!("01-host")
+        );
+    }
+
+    #[test]
+    fn indent_skips_first_line() {
+        assert_eq!(run("indent", json!("a\nb"), &[json!(2)]), json!("a\n  b"));
+    }
+
+    #[test]
+    fn result_tests() {
+        assert_eq!(test("failed", Some(&json!({"failed": true})), &[]), Some(Ok(true)));
+        assert_eq!(test("succeeded", Some(&json!({"failed": false})), &[]), Some(Ok(true)));
+    }
+
+    #[test]
+    fn combine_recursive() {
+        let base = json!({"a": {"x": 1, "y": 2}, "l": [1]});
+        let other = json!({"a": {"y": 3}, "l": [2]});
+        let kwargs = [("recursive".to_string(), json!(true))];
+        assert_eq!(
+            apply("combine", Some(&base), std::slice::from_ref(&other), &kwargs).unwrap().unwrap(),
+            json!({"a": {"x": 1, "y": 3}, "l": [2]})
+        );
+        assert_eq!(run("combine", base, &[other]), json!({"a": {"y": 3}, "l": [2]}));
+    }
+
+    #[test]
+    fn dict_items_round_trip() {
+        let items = run("dict2items", json!({"a": 1}), &[]);
+        assert_eq!(items, json!([{"key": "a", "value": 1}]));
+        assert_eq!(run("items2dict", items, &[]), json!({"a": 1}));
+    }
+
+    #[test]
+    fn map_and_selectattr() {
+        let users = json!([{"name": "a", "admin": true}, {"name": "b", "admin": false}]);
+        let kwargs = [("attribute".to_string(), json!("name"))];
+        assert_eq!(apply("map", Some(&users), &[], &kwargs).unwrap().unwrap(), json!(["a", "b"]));
+        assert_eq!(run("map", json!(["a"]), &[json!("upper")]), json!(["A"]));
+        assert_eq!(run("selectattr", users.clone(), &[json!("admin")]), json!([users[0]]));
+        assert_eq!(run("reject", json!([1, 2, 3]), &[json!("odd")]), json!([2]));
+    }
+
+    #[test]
+    fn set_operations() {
+        assert_eq!(run("unique", json!([1, 2, 1]), &[]), json!([1, 2]));
+        assert_eq!(run("union", json!([1, 2]), &[json!([2, 3])]), json!([1, 2, 3]));
+        assert_eq!(run("difference", json!([1, 2]), &[json!([2])]), json!([1]));
+        assert_eq!(run("flatten", json!([1, [2, [3]]]), &[json!(1)]), json!([1, 2, [3]]));
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(run("int", json!("0x1f"), &[json!(0), json!(16)]), json!(31));
+        assert_eq!(run("bool", json!("yes"), &[]), json!(true));
+        assert_eq!(run("b64decode", run("b64encode", json!("hi"), &[]), &[]), json!("hi"));
+        assert_eq!(run("hash", json!("a"), &[json!("md5")]), json!("0cc175b9c0f1b6a831c399e269772661"));
+        assert_eq!(run("quote", json!("it's"), &[]), json!("'it'\"'\"'s'"));
+    }
+
+    #[test]
+    fn password_hash_with_salt() {
+        let hashed = run("password_hash", json!("secret"), &[json!("sha512"), json!("saltsalt")]);
+        assert!(to_output(&hashed).starts_with("$6$saltsalt$"));
+    }
+
+    #[test]
+    fn ipaddr_queries() {
+        assert_eq!(run("ipaddr", json!("192.168.1.10/24"), &[json!("network")]), json!("192.168.1.0"));
+        assert_eq!(run("ipaddr", json!("192.168.1.10/24"), &[json!("netmask")]), json!("255.255.255.0"));
+        assert_eq!(run("ipaddr", json!("not-an-ip"), &[]), json!(false));
+        assert_eq!(run("ipv4", json!(["10.0.0.1", "::1", "x"]), &[]), json!(["10.0.0.1"]));
+    }
+
+    #[test]
+    fn regex_search_groups() {
+        assert_eq!(run("regex_search", json!("v1.2"), &[json!("(\\d)\\.(\\d)"), json!("\\2")]), json!(["2"]));
+        assert_eq!(run("regex_findall", json!("a1 b2"), &[json!("[a-z](\\d)")]), json!(["1", "2"]));
+    }
+
+    #[test]
+    fn mandatory_rejects_undefined() {
+        assert!(apply("mandatory", None, &[], &[]).unwrap().is_err());
+        assert_eq!(run("ternary", json!(false), &[json!("y"), json!("n")]), json!("n"));
+        assert_eq!(run("zip", json!([1, 2]), &[json!(["a"])]), json!([[1, "a"]]));
+    }
+
+    #[test]
+    fn unknown_filter_is_none() {
+        assert!(apply("no_such_filter", Some(&json!(1)), &[], &[]).is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use super::TemplateError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Text(String),
+    Var(String),
+    Block(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned {
+    pub token: Token,
+    pub line: usize,
+    pub col: usize,
+}
+
+/// Splits a template into text, `{{ }}` and `{% %}` tokens. Comments are
+/// dropped, `{% raw %}` sections become text and `-` whitespace control is
+/// applied to the neighbouring text.
+pub fn tokenize(source: &str) -> Result<Vec<Spanned>, TemplateError> {
+    let mut tokens: Vec<Spanned> = Vec::new();
+    let mut pos = 0;
+    let mut trim_next = false;
+
+    while pos < source.len() {
+        let rest = &source[pos..];
+        let next_tag = ["{{", "{%", "{#"]
+            .iter()
+            .filter_map(|open| rest.find(open))
+            .min();
+
+        let Some(offset) = next_tag else {
+            push_text(&mut tokens, source, pos, rest, trim_next);
+            break;
+        };
+
+        if offset > 0 {
+            push_text(&mut tokens, source, pos, &rest[..offset], trim_next);
+        }
+        trim_next = false;
+
+        let tag_start = pos + offset;
+        let (line, col) = line_col(source, tag_start);
+        let open = &source[tag_start..tag_start + 2];
+        let mut inner_start = tag_start + 2;
+
+        if source[inner_start..].starts_with('-') {
+            inner_start += 1;
+            trim_trailing(&mut tokens);
+        }
+
+        let close = match open {
+            "{{" => "}}",
+            "{%" => "%}",
+            _ => "#}",
+        };
+
+        let close_at = if open == "{#" {
+            source[inner_start..].find(close).map(|i| inner_start + i)
+        } else {
+            find_close(source, inner_start, close)
+        };
+
+        let Some(close_at) = close_at else {
+            return Err(TemplateError::new(&format!("unclosed tag '{}'", open)).at(line, col));
+        };
+
+        let mut inner_end = close_at;
+        if inner_end > inner_start && source[..inner_end].ends_with('-') {
+            inner_end -= 1;
+            trim_next = true;
+        }
+        pos = close_at + 2;
+
+        let raw = &source[inner_start..inner_end];
+        let content = raw.trim().to_string();
+        let lead = raw.len() - raw.trim_start().len();
+        let (line, col) = line_col(source, inner_start + lead);
+        match open {
+            "{{" => tokens.push(Spanned { token: Token::Var(content), line, col }),
+            "{%" if content == "raw" => {
+                let Some(end) = find_endraw(source, pos) else {
+                    return Err(TemplateError::new("unclosed 'raw' block").at(line, col));
+                };
+                let (raw_end, after) = end;
+                push_text(&mut tokens, source, pos, &source[pos..raw_end], false);
+                pos = after;
+            }
+            "{%" => tokens.push(Spanned { token: Token::Block(content), line, col }),
+            _ => {}
+        }
+    }
+
+    Ok(tokens)
+}
+
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rfind('\n').map(|nl| offset - nl).unwrap_or(offset + 1);
+    (line, col)
+}
+
+fn push_text(tokens: &mut Vec<Spanned>, source: &str, start: usize, text: &str, trim_start: bool) {
+    let text = if trim_start { text.trim_start() } else { text };
+    if text.is_empty() {
+        return;
+    }
+    let (line, col) = line_col(source, start);
+    tokens.push(Spanned {
+        token: Token::Text(text.to_string()),
+        line,
+        col,
+    });
+}
+
+fn trim_trailing(tokens: &mut Vec<Spanned>) {
+    if let Some(Spanned { token: Token::Text(text), .. }) = tokens.last_mut() {
+        let trimmed = text.trim_end().len();
+        text.truncate(trimmed);
+        if text.is_empty() {
+            tokens.pop();
+        }
+    }
+}
+
+/// Finds the closing delimiter of a tag, ignoring delimiters that appear
+/// inside quoted string literals.
+fn find_close(source: &str, from: usize, close: &str) -> Option<usize> {
+    let bytes = source.as_bytes();
+    let mut quote: Option<u8> = None;
+    let mut i = from;
+
+    while i < bytes.len() {
+        let b = bytes[i];
+        match quote {
+            Some(q) => {
+                if b == b'\\' {
+                    i += 1;
+                } else if b == q {
+                    quote = None;
+                }
+            }
+            None => {
+                if b == b'"' || b == b'\'' {
+                    quote = Some(b);
+                } else if source[i..].starts_with(close) {
+                    return Some(i);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    None
+}
+
+fn find_endraw(source: &str, from: usize) -> Option<(usize, usize)> {
+    let mut pos = from;
+    while let Some(offset) = source[pos..].find("{%") {
+        let tag_start = pos + offset;
+        let close = source[tag_start..].find("%}")? + tag_start;
+        let content = source[tag_start + 2..close].trim_matches(|c: char| c == '-' || c.is_whitespace());
+        if content == "endraw" {
+            return Some((tag_start, close + 2));
+        }
+        pos = close + 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<Token> {
+        tokenize(source).unwrap().into_iter().map(|t| t.token).collect()
+    }
+
+    #[test]
+    fn splits_text_and_tags() {
+        assert_eq!(
+            kinds("a {{ x }} b {% if y %}"),
+            vec![
+                Token::Text("a ".to_string()),
+                Token::Var("x".to_string()),
+                Token::Text(" b ".to_string()),
+                Token::Block("if y".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn whitespace_control() {
+        assert_eq!(
+            kinds("a  \n{%- if y -%}\n  b"),
+            vec![
+                Token::Text("a".to_string()),
+                Token::Block("if y".to_string()),
+                Token::Text("b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_block_is_text() {
+        assert_eq!(kinds("{% raw %}{{ x }}{% endraw %}"), vec![Token::Text("{{ x }}".to_string())]);
+    }
+
+    #[test]
+    fn close_inside_string_ignored() {
+        assert_eq!(kinds("{{ '}}' }}"), vec![Token::Var("'}}'".to_string())]);
+    }
+
+    #[test]
+    fn unclosed_tag_reports_position() {
+        let err = tokenize("line one\n  {{ oops").unwrap_err();
+        assert_eq!((err.line, err.col), (2, 3));
+    }
+}
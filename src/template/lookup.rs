@@ -0,0 +1,713 @@
+use super::value::{as_int, is_truthy, iterate, to_output};
+use super::{Environment, Vars};
+use rand::Rng;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+
+/// What a lookup plugin can see. Lookups always run on the controller, so
+/// they get the template environment and variables but no connection.
+pub struct LookupContext<'a> {
+    pub env: &'a Environment,
+    pub vars: &'a dyn Vars,
+}
+
+impl LookupContext<'_> {
+    /// Directories searched for relative paths: the role first, then the
+    /// playbook directory, each with its `files/` subdirectory first.
+    fn search_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        let var = |name: &str| self.vars.get_var(name).map(|v| PathBuf::from(to_output(&v)));
+        if let Some(role_path) = var("role_path") {
+            dirs.push(role_path.join("files"));
+            dirs.push(role_path);
+        }
+        let playbook_dir = var("playbook_dir").unwrap_or_else(|| PathBuf::from("."));
+        dirs.push(playbook_dir.join("files"));
+        dirs.push(playbook_dir);
+        dirs
+    }
+
+    fn find_file(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return path.exists().then(|| path.to_path_buf());
+        }
+        self.search_dirs().into_iter().map(|d| d.join(path)).find(|p| p.exists())
+    }
+
+    /// Where a file that may not exist yet should live, relative to the
+    /// playbook directory.
+    fn playbook_path(&self, name: &str) -> PathBuf {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return path.to_path_buf();
+        }
+        let dir = self.vars.get_var("playbook_dir").map(|v| to_output(&v)).unwrap_or_else(|| ".".to_string());
+        Path::new(&dir).join(path)
+    }
+}
+
+/// A lookup plugin: turns terms and keyword options into a list of values.
+pub trait Lookup: Send + Sync {
+    fn run(&self, terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String>;
+}
+
+impl<F> Lookup for F
+where
+    F: Fn(&[Value], &[(String, Value)], &LookupContext) -> Result<Vec<Value>, String> + Send + Sync,
+{
+    fn run(&self, terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+        self(terms, options, ctx)
+    }
+}
+
+/// Lookup plugins by name. The default registry holds the built-in plugins.
+#[derive(Clone)]
+pub struct LookupRegistry {
+    plugins: HashMap<String, Arc<dyn Lookup>>,
+}
+
+impl LookupRegistry {
+    pub fn empty() -> Self {
+        Self { plugins: HashMap::new() }
+    }
+
+    pub fn register<L: Lookup + 'static>(&mut self, name: &str, plugin: L) {
+        self.plugins.insert(name.to_string(), Arc::new(plugin));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Lookup>> {
+        self.plugins.get(name).cloned()
+    }
+}
+
+impl Default for LookupRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("file", file);
+        registry.register("env", env);
+        registry.register("pipe", pipe);
+        registry.register("template", template);
+        registry.register("vars", vars);
+        registry.register("fileglob", fileglob);
+        registry.register("first_found", first_found);
+        registry.register("lines", lines);
+        registry.register("csvfile", csvfile);
+        registry.register("ini", ini);
+        registry.register("dict", dict);
+        registry.register("sequence", sequence);
+        registry.register("password", password);
+        registry.register("together", together);
+        registry
+    }
+}
+
+impl fmt::Debug for LookupRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&String> = self.plugins.keys().collect();
+        names.sort();
+        f.debug_struct("LookupRegistry").field("plugins", &names).finish()
+    }
+}
+
+fn option<'a>(options: &'a [(String, Value)], name: &str) -> Option<&'a Value> {
+    options.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+}
+
+fn flag(options: &[(String, Value)], name: &str, default: bool) -> bool {
+    option(options, name).map(is_truthy).unwrap_or(default)
+}
+
+/// Splits a term like `key file=data.csv col=2` into the key and its inline
+/// parameters. Keyword options fill in parameters the term leaves out.
+fn term_params(term: &Value, options: &[(String, Value)]) -> (String, HashMap<String, String>) {
+    let mut key = Vec::new();
+    let mut params = HashMap::new();
+    for word in shell_words(&to_output(term)) {
+        match word.split_once('=') {
+            Some((k, v)) if !k.is_empty() && k.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                params.insert(k.to_string(), v.to_string());
+            }
+            _ => key.push(word),
+        }
+    }
+    for (k, v) in options {
+        params.entry(k.clone()).or_insert_with(|| to_output(v));
+    }
+    (key.join(" "), params)
+}
+
+fn shell_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut in_word = false;
+    for c in text.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    words
+}
+
+fn run_command(command: &str) -> Result<String, String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|e| format!("failed to run '{}': {}", command, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "command '{}' returned {}",
+            command,
+            output.status.code().unwrap_or(-1)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn file(terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    terms
+        .iter()
+        .map(|term| {
+            let name = to_output(term);
+            let path = ctx.find_file(&name).ok_or_else(|| format!("could not locate file in lookup: {}", name))?;
+            let mut content = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            if flag(options, "rstrip", true) {
+                content.truncate(content.trim_end().len());
+            }
+            if flag(options, "lstrip", false) {
+                content = content.trim_start().to_string();
+            }
+            Ok(Value::String(content))
+        })
+        .collect()
+}
+
+fn env(terms: &[Value], options: &[(String, Value)], _ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let default = option(options, "default").map(to_output).unwrap_or_default();
+    Ok(terms
+        .iter()
+        .map(|term| Value::String(std::env::var(to_output(term)).unwrap_or_else(|_| default.clone())))
+        .collect())
+}
+
+fn pipe(terms: &[Value], _options: &[(String, Value)], _ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    terms
+        .iter()
+        .map(|term| run_command(&to_output(term)).map(|out| Value::String(out.trim_end().to_string())))
+        .collect()
+}
+
+fn lines(terms: &[Value], _options: &[(String, Value)], _ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let mut result = Vec::new();
+    for term in terms {
+        let out = run_command(&to_output(term))?;
+        result.extend(out.lines().map(|l| Value::String(l.to_string())));
+    }
+    Ok(result)
+}
+
+fn template(terms: &[Value], _options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    terms
+        .iter()
+        .map(|term| {
+            ctx.env
+                .render_file(&to_output(term), ctx.vars)
+                .map(Value::String)
+                .map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+fn vars(terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    terms
+        .iter()
+        .map(|term| {
+            let name = to_output(term);
+            ctx.vars
+                .get_var(&name)
+                .or_else(|| option(options, "default").cloned())
+                .ok_or_else(|| format!("No variable found with this name: {}", name))
+        })
+        .collect()
+}
+
+/// Converts a shell glob to an anchored regular expression matching a single
+/// path component.
+fn glob_regex(pattern: &str) -> Result<regex::Regex, String> {
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' | ']' => re.push(c),
+            other => re.push_str(&regex::escape(&other.to_string())),
+        }
+    }
+    re.push('$');
+    regex::Regex::new(&re).map_err(|e| format!("invalid glob '{}': {}", pattern, e))
+}
+
+fn fileglob(terms: &[Value], _options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let mut result = Vec::new();
+    for term in terms {
+        let pattern = to_output(term);
+        let path = Path::new(&pattern);
+        let file_pattern = path.file_name().and_then(|f| f.to_str()).unwrap_or("").to_string();
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let re = glob_regex(&file_pattern)?;
+
+        let dirs = if path.is_absolute() {
+            vec![parent.to_path_buf()]
+        } else {
+            ctx.search_dirs().into_iter().map(|d| d.join(parent)).collect()
+        };
+        let Some(dir) = dirs.into_iter().find(|d| d.is_dir()) else {
+            continue;
+        };
+
+        let mut matches: Vec<String> = std::fs::read_dir(&dir)
+            .map_err(|e| format!("{}: {}", dir.display(), e))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .filter(|entry| entry.file_name().to_str().is_some_and(|n| re.is_match(n)))
+            .map(|entry| entry.path().display().to_string())
+            .collect();
+        matches.sort();
+        result.extend(matches.into_iter().map(Value::String));
+    }
+    Ok(result)
+}
+
+fn first_found(terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let split = |v: &Value| -> Vec<String> {
+        match v {
+            Value::String(s) => s.split([',', ';', ':']).map(|p| p.trim().to_string()).collect(),
+            other => iterate(other).iter().map(to_output).collect(),
+        }
+    };
+
+    let mut files = Vec::new();
+    let mut paths: Vec<String> = option(options, "paths").map(split).unwrap_or_default();
+    let mut skip = flag(options, "skip", false);
+    if let Some(f) = option(options, "files") {
+        files.extend(split(f));
+    }
+    for term in terms {
+        match term {
+            Value::Object(map) => {
+                files.extend(map.get("files").map(split).unwrap_or_default());
+                paths.extend(map.get("paths").map(split).unwrap_or_default());
+                skip |= map.get("skip").map(is_truthy).unwrap_or(false);
+            }
+            Value::Array(items) => files.extend(items.iter().map(to_output)),
+            other => files.push(to_output(other)),
+        }
+    }
+
+    for name in files.iter().filter(|f| !f.is_empty()) {
+        let found = if paths.is_empty() {
+            ctx.find_file(name)
+        } else {
+            paths
+                .iter()
+                .map(|dir| ctx.playbook_path(dir).join(name))
+                .find(|p| p.exists())
+        };
+        if let Some(path) = found {
+            return Ok(vec![Value::String(path.display().to_string())]);
+        }
+    }
+
+    if skip {
+        Ok(Vec::new())
+    } else {
+        Err("No file was found when using first_found.".to_string())
+    }
+}
+
+/// Splits one CSV record, honouring double-quoted fields.
+fn csv_fields(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                current.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    fields.push(current);
+    fields
+}
+
+fn csvfile(terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    terms
+        .iter()
+        .map(|term| {
+            let (key, params) = term_params(term, options);
+            let name = params.get("file").cloned().unwrap_or_else(|| "ansible.csv".to_string());
+            let delimiter = match params.get("delimiter").map(|d| d.as_str()) {
+                None | Some("TAB") | Some("\\t") => '\t',
+                Some(d) => d.chars().next().unwrap_or('\t'),
+            };
+            let col = params.get("col").and_then(|c| c.parse::<usize>().ok()).unwrap_or(1);
+            let path = ctx.find_file(&name).ok_or_else(|| format!("csvfile: could not locate {}", name))?;
+            let content = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+            for line in content.lines() {
+                let fields = csv_fields(line, delimiter);
+                if fields.first().map(|f| f == &key).unwrap_or(false) {
+                    return fields
+                        .get(col)
+                        .map(|v| Value::String(v.clone()))
+                        .ok_or_else(|| format!("csvfile: no column {} for key '{}'", col, key));
+                }
+            }
+            Ok(params.get("default").map(|d| Value::String(d.clone())).unwrap_or(Value::Null))
+        })
+        .collect()
+}
+
+fn ini(terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let mut result = Vec::new();
+    for term in terms {
+        let (key, params) = term_params(term, options);
+        let properties = params.get("type").map(|t| t == "properties").unwrap_or(false);
+        let default_file = if properties { "ansible.properties" } else { "ansible.ini" };
+        let name = params.get("file").cloned().unwrap_or_else(|| default_file.to_string());
+        let section = params.get("section").cloned().unwrap_or_else(|| "global".to_string());
+        let use_regex = params.get("re").map(|r| is_truthy(&Value::String(r.to_lowercase()))).unwrap_or(false);
+        let path = ctx.find_file(&name).ok_or_else(|| format!("ini: could not locate {}", name))?;
+        let content = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let key_re = if use_regex {
+            Some(regex::Regex::new(&key).map_err(|e| format!("ini: invalid regular expression '{}': {}", key, e))?)
+        } else {
+            None
+        };
+
+        let mut current = if properties { Some(section.clone()) } else { None };
+        let mut found = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                current = Some(name.trim().to_string());
+                continue;
+            }
+            if current.as_deref() != Some(section.as_str()) {
+                continue;
+            }
+            let Some((k, v)) = line.split_once(['=', ':']) else {
+                continue;
+            };
+            let k = k.trim();
+            let matched = match &key_re {
+                Some(re) => re.is_match(k),
+                None => k == key,
+            };
+            if matched {
+                found.push(Value::String(v.trim().to_string()));
+            }
+        }
+
+        if found.is_empty() {
+            result.push(params.get("default").map(|d| Value::String(d.clone())).unwrap_or(Value::String(String::new())));
+        } else {
+            result.extend(found);
+        }
+    }
+    Ok(result)
+}
+
+fn dict(terms: &[Value], _options: &[(String, Value)], _ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let mut result = Vec::new();
+    for term in terms {
+        let Value::Object(map) = term else {
+            return Err(format!("dict lookup expects a dictionary, got '{}'", to_output(term)));
+        };
+        for (k, v) in map {
+            result.push(serde_json::json!({"key": k, "value": v}));
+        }
+    }
+    Ok(result)
+}
+
+/// Formats an integer with a printf-style pattern such as `host%02d`.
+fn format_number(format: &str, n: i64) -> String {
+    let re = regex::Regex::new(r"%(0?)(\d*)([dixXos])").unwrap();
+    re.replace(format, |caps: &regex::Captures| {
+        let zero = !caps[1].is_empty();
+        let width: usize = caps[2].parse().unwrap_or(0);
+        let text = match &caps[3] {
+            "x" => format!("{:x}", n),
+            "X" => format!("{:X}", n),
+            "o" => format!("{:o}", n),
+            _ => n.to_string(),
+        };
+        if zero {
+            format!("{:0>width$}", text, width = width)
+        } else {
+            format!("{:>width$}", text, width = width)
+        }
+    })
+    .to_string()
+}
+
+fn sequence(terms: &[Value], options: &[(String, Value)], _ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let shortcut = regex::Regex::new(r"^(?:(-?\d+)-)?(-?\d+)(?:/(-?\d+))?(?::(.+))?$").unwrap();
+    let terms: Vec<Value> = if terms.is_empty() { vec![Value::String(String::new())] } else { terms.to_vec() };
+    let mut result = Vec::new();
+
+    for term in &terms {
+        let (rest, mut params) = term_params(term, options);
+        if let Some(caps) = shortcut.captures(rest.trim()) {
+            let mut set = |k: &str, v: Option<regex::Match>| {
+                if let Some(v) = v {
+                    params.insert(k.to_string(), v.as_str().to_string());
+                }
+            };
+            set("start", caps.get(1));
+            set("end", caps.get(2));
+            set("stride", caps.get(3));
+            set("format", caps.get(4));
+        } else if !rest.trim().is_empty() {
+            return Err(format!("sequence: can't parse arg '{}'", rest));
+        }
+
+        let num = |k: &str| -> Result<Option<i64>, String> {
+            params
+                .get(k)
+                .map(|v| as_int(&Value::String(v.clone())).ok_or_else(|| format!("sequence: invalid {} '{}'", k, v)))
+                .transpose()
+        };
+        let start = num("start")?.unwrap_or(1);
+        let stride = num("stride")?.unwrap_or(1);
+        let end = match (num("end")?, num("count")?) {
+            (Some(_), Some(_)) => return Err("sequence: can't specify both count and end".to_string()),
+            (Some(end), None) => end,
+            (None, Some(count)) => start + (count - 1) * stride,
+            (None, None) => return Err("sequence: must specify count or end".to_string()),
+        };
+        if stride == 0 {
+            return Err("sequence: stride must not be zero".to_string());
+        }
+        let format = params.get("format").cloned().unwrap_or_else(|| "%d".to_string());
+
+        let mut i = start;
+        while (stride > 0 && i <= end) || (stride < 0 && i >= end) {
+            result.push(Value::String(format_number(&format, i)));
+            i += stride;
+        }
+    }
+    Ok(result)
+}
+
+fn password_chars(spec: &str) -> Vec<char> {
+    let mut chars = String::new();
+    for set in spec.split(',') {
+        match set {
+            "ascii_letters" | "letters" => chars.push_str("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ"),
+            "ascii_lowercase" => chars.push_str("abcdefghijklmnopqrstuvwxyz"),
+            "ascii_uppercase" => chars.push_str("ABCDEFGHIJKLMNOPQRSTUVWXYZ"),
+            "digits" => chars.push_str("0123456789"),
+            "hexdigits" => chars.push_str("0123456789abcdefABCDEF"),
+            "octdigits" => chars.push_str("01234567"),
+            "punctuation" => chars.push_str("!\"#$%&'()*+,-./:;<=>?@[\\]^_`{|}~"),
+            literal => chars.push_str(literal),
+        }
+    }
+    let mut unique: Vec<char> = Vec::new();
+    for c in chars.chars() {
+        if !unique.contains(&c) {
+            unique.push(c);
+        }
+    }
+    unique
+}
+
+/// Held while a password file is checked and created, so that hosts
+/// rendering in parallel all get the one password that ends up saved.
+static PASSWORD_FILES: Mutex<()> = Mutex::new(());
+
+/// The password saved in a password file, without its salt.
+fn stored_password(path: &Path) -> Option<String> {
+    let existing = std::fs::read_to_string(path).ok()?;
+    let first = existing.lines().next().unwrap_or("");
+    Some(first.split(" salt=").next().unwrap_or("").to_string())
+}
+
+/// Returns the password stored at the given path, generating and saving a
+/// new random one on first use. `/dev/null` generates without saving.
+fn password(terms: &[Value], options: &[(String, Value)], ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    terms
+        .iter()
+        .map(|term| {
+            let (name, params) = term_params(term, options);
+            let length = params.get("length").and_then(|l| l.parse::<usize>().ok()).unwrap_or(20);
+            let chars = password_chars(params.get("chars").map(|c| c.as_str()).unwrap_or("ascii_letters,digits,.,:-_"));
+            if chars.is_empty() {
+                return Err("password: no characters to choose from".to_string());
+            }
+            let path = ctx.playbook_path(&name);
+            let persist = name != "/dev/null";
+
+            let _lock = PASSWORD_FILES.lock().unwrap_or_else(|e| e.into_inner());
+            if persist {
+                if let Some(stored) = stored_password(&path) {
+                    return Ok(Value::String(stored));
+                }
+            }
+
+            let mut rng = rand::thread_rng();
+            let generated: String = (0..length).map(|_| chars[rng.gen_range(0..chars.len())]).collect();
+            if persist {
+                let fail = |e: std::io::Error| format!("{}: {}", path.display(), e);
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).map_err(|e| format!("{}: {}", parent.display(), e))?;
+                }
+                // Created private from the start, and never over a file that
+                // another process saved in the meantime.
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                match options.open(&path) {
+                    Ok(mut file) => file.write_all(format!("{}\n", generated).as_bytes()).map_err(fail)?,
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                        return stored_password(&path).map(Value::String).ok_or_else(|| fail(e));
+                    }
+                    Err(e) => return Err(fail(e)),
+                }
+            }
+            Ok(Value::String(generated))
+        })
+        .collect()
+}
+
+fn together(terms: &[Value], _options: &[(String, Value)], _ctx: &LookupContext) -> Result<Vec<Value>, String> {
+    let lists: Vec<Vec<Value>> = terms.iter().map(iterate).collect();
+    let len = lists.iter().map(Vec::len).max().unwrap_or(0);
+    Ok((0..len)
+        .map(|i| Value::Array(lists.iter().map(|l| l.get(i).cloned().unwrap_or(Value::Null)).collect()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn run(name: &str, terms: &[Value], vars: &HashMap<String, String>) -> Result<Vec<Value>, String> {
+        let env = Environment::new();
+        let ctx = LookupContext { env: &env, vars };
+        LookupRegistry::default().get(name).unwrap().run(terms, &[], &ctx)
+    }
+
+    #[test]
+    fn sequence_forms() {
+        let none = HashMap::new();
+        assert_eq!(run("sequence", &[json!("start=1 end=3")], &none).unwrap(), vec![json!("1"), json!("2"), json!("3")]);
+        assert_eq!(run("sequence", &[json!("2-6/2:web%02d")], &none).unwrap(), vec![json!("web02"), json!("web04"), json!("web06")]);
+        assert_eq!(run("sequence", &[json!("count=2 start=5")], &none).unwrap(), vec![json!("5"), json!("6")]);
+    }
+
+    #[test]
+    fn together_pads_shorter_lists() {
+        let none = HashMap::new();
+        assert_eq!(
+            run("together", &[json!([1, 2]), json!(["a"])], &none).unwrap(),
+            vec![json!([1, "a"]), json!([2, null])]
+        );
+    }
+
+    #[test]
+    fn files_are_read_from_playbook_dir() {
+        let dir = std::env::temp_dir().join(format!("wand-lookup-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        std::fs::write(dir.join("files/motd"), "hello\n").unwrap();
+        std::fs::write(dir.join("files/users.csv"), "alice,1000\nbob,1001\n").unwrap();
+        std::fs::write(dir.join("app.ini"), "[db]\nhost = db1\nport=5432\n").unwrap();
+        let vars: HashMap<String, String> =
+            HashMap::from([("playbook_dir".to_string(), dir.display().to_string())]);
+
+        assert_eq!(run("file", &[json!("motd")], &vars).unwrap(), vec![json!("hello")]);
+        assert_eq!(run("csvfile", &[json!("bob file=users.csv delimiter=,")], &vars).unwrap(), vec![json!("1001")]);
+        assert_eq!(run("ini", &[json!("port section=db file=app.ini")], &vars).unwrap(), vec![json!("5432")]);
+        assert_eq!(run("fileglob", &[json!("*.csv")], &vars).unwrap().len(), 1);
+        assert!(run("first_found", &[json!(["missing", "motd"])], &vars).unwrap()[0]
+            .as_str()
+            .unwrap()
+            .ends_with("files/motd"));
+        assert!(run("file", &[json!("missing")], &vars).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn password_is_persisted() {
+        let dir = std::env::temp_dir().join(format!("wand-password-{}", std::process::id()));
+        let vars: HashMap<String, String> =
+            HashMap::from([("playbook_dir".to_string(), dir.display().to_string())]);
+        let first = run("password", &[json!("creds/db length=12 chars=digits")], &vars).unwrap();
+        assert_eq!(to_output(&first[0]).len(), 12);
+        assert!(to_output(&first[0]).chars().all(|c| c.is_ascii_digit()));
+        let second = run("password", &[json!("creds/db length=12")], &vars).unwrap();
+        assert_eq!(first, second);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join("creds/db")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn password_is_shared_by_parallel_renders() {
+        let dir = std::env::temp_dir().join(format!("wand-password-race-{}", std::process::id()));
+        let vars: HashMap<String, String> =
+            HashMap::from([("playbook_dir".to_string(), dir.display().to_string())]);
+        let passwords: Vec<Vec<Value>> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| run("password", &[json!("creds/shared")], &vars).unwrap()))
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert!(passwords.iter().all(|p| *p == passwords[0]));
+        let saved = std::fs::read_to_string(dir.join("creds/shared")).unwrap();
+        assert_eq!(saved.trim(), to_output(&passwords[0][0]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
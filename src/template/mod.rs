@@ -1,379 +1,214 @@
-use std::collections::HashMap;
-
-pub fn render(template: &str, vars: &HashMap<String, String>) -> String {
-    let result = process_comments(template);
-    let result = process_conditionals(&result, vars);
-    let result = process_loops(&result, vars);
-    process_variables(&result, vars)
+mod eval;
+mod filters;
+mod lexer;
+mod lookup;
+mod parser;
+mod query;
+mod value;
+
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use eval::Renderer;
+pub use lookup::{LookupContext, LookupRegistry};
+
+/// Source of template variables.
+pub trait Vars {
+    fn get_var(&self, name: &str) -> Option<Value>;
+
+    /// Whether `name` holds data that must never be rendered as a template.
+    fn is_unsafe(&self, _name: &str) -> bool {
+        false
+    }
 }
 
-fn process_comments(template: &str) -> String {
-    let mut result = template.to_string();
+impl Vars for HashMap<String, String> {
+    fn get_var(&self, name: &str) -> Option<Value> {
+        self.get(name).map(|v| Value::String(v.clone()))
+    }
+}
 
-    while let Some(start) = result.find("{#") {
-        if let Some(end) = result[start..].find("#}") {
-            let end = start + end + 2;
-            result = format!("{}{}", &result[..start], &result[end..]);
-        } else {
-            break;
-        }
+impl Vars for HashMap<String, Value> {
+    fn get_var(&self, name: &str) -> Option<Value> {
+        self.get(name).cloned()
     }
+}
 
-    result
+/// A host's variables, remembering which ones hold data that came back
+/// from the host: registered results and facts. As with Ansible's unsafe
+/// values, those are used as they are and never rendered, so output that
+/// happens to contain `{{` stays data.
+#[derive(Debug, Clone, Default)]
+pub struct HostVars {
+    values: HashMap<String, Value>,
+    unsafe_names: HashSet<String>,
 }
 
-fn process_conditionals(template: &str, vars: &HashMap<String, String>) -> String {
-    let mut result = template.to_string();
-
-    while let Some(if_start) = result.find("{%") {
-        let after_tag = &result[if_start + 2..];
-        if let Some(tag_end) = after_tag.find("%}") {
-            let tag_content = after_tag[..tag_end].trim();
-
-            if tag_content.starts_with("if ") {
-                let condition = tag_content.strip_prefix("if ").unwrap().trim();
-                let if_end = if_start + 2 + tag_end + 2;
-
-                if let Some(endif_pos) = find_endif(&result[if_end..]) {
-                    let block_content = &result[if_end..if_end + endif_pos];
-                    let endif_end = if_end + endif_pos + find_tag_len(&result[if_end + endif_pos..], "endif");
-
-                    let (if_block, else_block) = split_if_else(block_content);
-
-                    let output = if eval_condition(condition, vars) {
-                        process_conditionals(if_block, vars)
-                    } else {
-                        process_conditionals(else_block, vars)
-                    };
-
-                    result = format!("{}{}{}", &result[..if_start], output, &result[endif_end..]);
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        } else {
-            break;
-        }
+impl HostVars {
+    pub fn insert(&mut self, name: String, value: Value) {
+        self.unsafe_names.remove(&name);
+        self.values.insert(name, value);
     }
 
-    result
-}
+    pub fn insert_unsafe(&mut self, name: String, value: Value) {
+        self.unsafe_names.insert(name.clone());
+        self.values.insert(name, value);
+    }
 
-fn find_endif(s: &str) -> Option<usize> {
-    let mut depth = 1;
-    let mut pos = 0;
-
-    while pos < s.len() {
-        if let Some(tag_start) = s[pos..].find("{%") {
-            let tag_start = pos + tag_start;
-            if let Some(tag_end) = s[tag_start + 2..].find("%}") {
-                let tag_content = s[tag_start + 2..tag_start + 2 + tag_end].trim();
-                if tag_content.starts_with("if ") {
-                    depth += 1;
-                } else if tag_content == "endif" {
-                    depth -= 1;
-                    if depth == 0 {
-                        return Some(tag_start);
-                    }
-                }
-                pos = tag_start + 2 + tag_end + 2;
-            } else {
-                break;
-            }
-        } else {
-            break;
-        }
+    pub fn remove(&mut self, name: &str) -> Option<Value> {
+        self.unsafe_names.remove(name);
+        self.values.remove(name)
     }
-    None
 }
 
-fn find_tag_len(s: &str, tag: &str) -> usize {
-    if let Some(start) = s.find("{%") {
-        if let Some(end) = s[start + 2..].find("%}") {
-            let content = s[start + 2..start + 2 + end].trim();
-            if content == tag {
-                return start + 2 + end + 2;
-            }
-        }
+impl Deref for HostVars {
+    type Target = HashMap<String, Value>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.values
     }
-    0
 }
 
-fn split_if_else(block: &str) -> (&str, &str) {
-    let mut depth = 0;
-    let mut pos = 0;
-
-    while pos < block.len() {
-        if let Some(tag_start) = block[pos..].find("{%") {
-            let tag_start = pos + tag_start;
-            if let Some(tag_end) = block[tag_start + 2..].find("%}") {
-                let tag_content = block[tag_start + 2..tag_start + 2 + tag_end].trim();
-                if tag_content.starts_with("if ") {
-                    depth += 1;
-                } else if tag_content == "endif" {
-                    depth -= 1;
-                } else if tag_content == "else" && depth == 0 {
-                    let else_end = tag_start + 2 + tag_end + 2;
-                    return (&block[..tag_start], &block[else_end..]);
-                }
-                pos = tag_start + 2 + tag_end + 2;
-            } else {
-                break;
-            }
-        } else {
-            break;
+impl From<HashMap<String, Value>> for HostVars {
+    fn from(values: HashMap<String, Value>) -> Self {
+        Self {
+            values,
+            unsafe_names: HashSet::new(),
         }
     }
-
-    (block, "")
 }
 
-fn eval_condition(condition: &str, vars: &HashMap<String, String>) -> bool {
-    let condition = condition.trim();
-
-    if let Some((left, right)) = condition.split_once("==") {
-        let left = eval_expr(left.trim(), vars);
-        let right = eval_expr(right.trim(), vars);
-        return left == right;
-    }
-
-    if let Some((left, right)) = condition.split_once("!=") {
-        let left = eval_expr(left.trim(), vars);
-        let right = eval_expr(right.trim(), vars);
-        return left != right;
+impl Vars for HostVars {
+    fn get_var(&self, name: &str) -> Option<Value> {
+        self.values.get(name).cloned()
     }
 
-    if condition.starts_with("not ") {
-        let inner = condition.strip_prefix("not ").unwrap().trim();
-        return !eval_condition(inner, vars);
+    fn is_unsafe(&self, name: &str) -> bool {
+        self.unsafe_names.contains(name)
     }
+}
 
-    // Simple truthy check
-    let value = vars.get(condition).cloned().unwrap_or_default();
-    !value.is_empty() && value != "false" && value != "0"
+#[derive(Debug, Clone, PartialEq)]
+pub struct TemplateError {
+    pub msg: String,
+    pub template: Option<PathBuf>,
+    pub line: usize,
+    pub col: usize,
 }
 
-fn eval_expr(expr: &str, vars: &HashMap<String, String>) -> String {
-    let expr = expr.trim();
-    if expr.starts_with('"') || expr.starts_with('\'') {
-        expr.trim_matches(|c| c == '"' || c == '\'').to_string()
-    } else {
-        vars.get(expr).cloned().unwrap_or_default()
+impl TemplateError {
+    pub fn new(msg: &str) -> Self {
+        Self {
+            msg: msg.to_string(),
+            template: None,
+            line: 0,
+            col: 0,
+        }
     }
-}
 
-fn process_loops(template: &str, vars: &HashMap<String, String>) -> String {
-    let mut result = template.to_string();
-
-    while let Some(for_start) = result.find("{% for ") {
-        if let Some(tag_end) = result[for_start + 2..].find("%}") {
-            let tag_end = for_start + 2 + tag_end + 2;
-            let tag_content = &result[for_start + 7..tag_end - 2].trim();
-
-            if let Some((var_name, items_expr)) = tag_content.split_once(" in ") {
-                let var_name = var_name.trim();
-                let items_expr = items_expr.trim();
-
-                if let Some(endfor_pos) = find_endfor(&result[tag_end..]) {
-                    let block = &result[tag_end..tag_end + endfor_pos];
-                    let endfor_end = tag_end + endfor_pos + find_tag_len(&result[tag_end + endfor_pos..], "endfor");
-
-                    let items = get_list_items(items_expr, vars);
-                    let mut output = String::new();
-
-                    for item in items {
-                        let mut loop_vars = vars.clone();
-                        loop_vars.insert(var_name.to_string(), item);
-                        output.push_str(&process_variables(block, &loop_vars));
-                    }
-
-                    result = format!("{}{}{}", &result[..for_start], output, &result[endfor_end..]);
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        } else {
-            break;
+    pub fn at(mut self, line: usize, col: usize) -> Self {
+        if self.line == 0 {
+            self.line = line;
+            self.col = col;
         }
+        self
     }
 
-    result
+    /// Records the template the error occurred in, unless an inner template
+    /// has already claimed it.
+    pub fn in_template(mut self, path: &Path) -> Self {
+        if self.template.is_none() {
+            self.template = Some(path.to_path_buf());
+        }
+        self
+    }
 }
 
-fn find_endfor(s: &str) -> Option<usize> {
-    let mut depth = 1;
-    let mut pos = 0;
-
-    while pos < s.len() {
-        if let Some(tag_start) = s[pos..].find("{%") {
-            let tag_start = pos + tag_start;
-            if let Some(tag_end) = s[tag_start + 2..].find("%}") {
-                let tag_content = s[tag_start + 2..tag_start + 2 + tag_end].trim();
-                if tag_content.starts_with("for ") {
-                    depth += 1;
-                } else if tag_content == "endfor" {
-                    depth -= 1;
-                    if depth == 0 {
-                        return Some(tag_start);
-                    }
-                }
-                pos = tag_start + 2 + tag_end + 2;
-            } else {
-                break;
-            }
-        } else {
-            break;
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)?;
+        if let Some(template) = &self.template {
+            write!(f, " in {}", template.display())?;
+        }
+        if self.line > 0 {
+            write!(f, " at line {}, column {}", self.line, self.col)?;
         }
+        Ok(())
     }
-    None
 }
 
-fn get_list_items(expr: &str, vars: &HashMap<String, String>) -> Vec<String> {
-    if let Some(items_str) = vars.get(expr) {
-        items_str.split(',').map(|s| s.trim().to_string()).collect()
-    } else {
-        vec![]
-    }
+impl std::error::Error for TemplateError {}
+
+/// Template loading configuration. Names used by `include`, `import`,
+/// `from` and `extends` are looked up next to the including template first
+/// and then in each search path directory in order.
+///
+/// Undefined variables are an error unless the environment is lenient, in
+/// which case they render as empty strings and are falsy.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    search_path: Vec<PathBuf>,
+    lenient: bool,
+    lookups: Arc<LookupRegistry>,
 }
 
-fn process_variables(template: &str, vars: &HashMap<String, String>) -> String {
-    let mut result = template.to_string();
-
-    while let Some(start) = result.find("{{") {
-        if let Some(end) = result[start..].find("}}") {
-            let end = start + end + 2;
-            let expr = &result[start + 2..end - 2].trim();
-
-            let value = if let Some((var_name, filter_chain)) = expr.split_once('|') {
-                let var_name = var_name.trim();
-                let base_value = vars.get(var_name).cloned().unwrap_or_default();
-                apply_filters(&base_value, filter_chain, vars)
-            } else {
-                vars.get(*expr).cloned().unwrap_or_default()
-            };
-
-            result = format!("{}{}{}", &result[..start], value, &result[end..]);
-        } else {
-            break;
-        }
+impl Environment {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    result
-}
+    pub fn search_path<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.search_path.push(dir.into());
+        self
+    }
 
-fn apply_filters(value: &str, filter_chain: &str, vars: &HashMap<String, String>) -> String {
-    let mut result = value.to_string();
-
-    for filter in filter_chain.split('|') {
-        let filter = filter.trim();
-
-        if let Some((name, args)) = filter.split_once('(') {
-            let args = args.trim_end_matches(')');
-            result = apply_filter_with_args(&result, name.trim(), args, vars);
-        } else {
-            if matches!(filter, "basename" | "dirname" | "to_json" | "to_yaml") {
-                result = apply_filter_no_args(&result, filter);
-            } else {
-                result = apply_filter(&result, filter);
-            }
-        }
+    pub fn lenient(mut self, enabled: bool) -> Self {
+        self.lenient = enabled;
+        self
     }
 
-    result
-}
+    /// Replaces the lookup plugins available to `lookup()` and `query()`.
+    pub fn lookups(mut self, registry: Arc<LookupRegistry>) -> Self {
+        self.lookups = registry;
+        self
+    }
 
-fn apply_filter(value: &str, filter: &str) -> String {
-    match filter {
-        "lower" => value.to_lowercase(),
-        "upper" => value.to_uppercase(),
-        "capitalize" => {
-            let mut chars = value.chars();
-            match chars.next() {
-                None => String::new(),
-                Some(c) => c.to_uppercase().chain(chars).collect(),
-            }
-        }
-        "trim" => value.trim().to_string(),
-        "length" => value.len().to_string(),
-        _ => value.to_string(),
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        self.resolve_from(name, None)
     }
-}
 
-fn apply_filter_with_args(value: &str, filter: &str, args: &str, vars: &HashMap<String, String>) -> String {
-    let args = args.trim();
-    let arg = if args.starts_with('"') || args.starts_with('\'') {
-        args.trim_matches(|c| c == '"' || c == '\'').to_string()
-    } else {
-        vars.get(args).cloned().unwrap_or(args.to_string())
-    };
-
-    match filter {
-        "default" => {
-            if value.is_empty() {
-                arg
-            } else {
-                value.to_string()
-            }
-        }
-        "replace" => {
-            if let Some((old, new)) = arg.split_once(',') {
-                let old = old.trim().trim_matches(|c| c == '"' || c == '\'');
-                let new = new.trim().trim_matches(|c| c == '"' || c == '\'');
-                value.replace(old, new)
-            } else {
-                value.to_string()
-            }
+    fn resolve_from(&self, name: &str, current_dir: Option<&Path>) -> Option<PathBuf> {
+        let path = Path::new(name);
+        if path.is_absolute() {
+            return path.is_file().then(|| path.to_path_buf());
         }
-        "regex_replace" => {
-            if let Some((pattern, replacement)) = arg.split_once(',') {
-                let pattern = pattern.trim().trim_matches(|c| c == '"' || c == '\'');
-                let replacement = replacement.trim().trim_matches(|c| c == '"' || c == '\'');
-                if let Ok(re) = regex::Regex::new(pattern) {
-                    re.replace_all(value, replacement).to_string()
-                } else {
-                    value.to_string()
-                }
-            } else {
-                value.to_string()
-            }
-        }
-        "join" => {
-            value.split(',').map(|s| s.trim()).collect::<Vec<_>>().join(&arg)
-        }
-        "split" => {
-            value.split(&arg).map(|s| s.to_string()).collect::<Vec<_>>().join(", ")
-        }
-        _ => value.to_string(),
+
+        current_dir
+            .into_iter()
+            .chain(self.search_path.iter().map(|p| p.as_path()))
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
     }
-}
 
-fn apply_filter_no_args(value: &str, filter: &str) -> String {
-    match filter {
-        "basename" => {
-            std::path::Path::new(value)
-                .file_name()
-                .and_then(|s| s.to_str())
-                .unwrap_or(value)
-                .to_string()
-        }
-        "dirname" => {
-            std::path::Path::new(value)
-                .parent()
-                .and_then(|s| s.to_str())
-                .unwrap_or(value)
-                .to_string()
-        }
-        "to_json" => {
-            serde_json::to_string(value).unwrap_or_else(|_| value.to_string())
-        }
-        "to_yaml" => {
-            serde_yaml::to_string(value).unwrap_or_else(|_| value.to_string())
-        }
-        _ => value.to_string(),
+    pub fn render_str(&self, source: &str, vars: &dyn Vars) -> Result<String, TemplateError> {
+        Renderer::new(self, vars).render_source(source)
+    }
+
+    /// Evaluates a bare Jinja expression such as a `when` condition.
+    pub fn eval_condition(&self, expr: &str, vars: &dyn Vars) -> Result<bool, TemplateError> {
+        Renderer::new(self, vars).eval_condition(expr)
+    }
+
+    /// Renders the template `name`, resolved through the search path.
+    pub fn render_file(&self, name: &str, vars: &dyn Vars) -> Result<String, TemplateError> {
+        let path = self
+            .resolve(name)
+            .ok_or_else(|| TemplateError::new(&format!("template not found: {}", name)))?;
+        Renderer::new(self, vars).render_path(&path)
     }
 }
 
@@ -385,6 +220,10 @@ mod tests {
         pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
     }
 
+    fn render(template: &str, vars: &dyn Vars) -> String {
+        Environment::new().render_str(template, vars).unwrap()
+    }
+
     #[test]
     fn simple_substitution() {
         let result = render("Hello {{ name }}!", &vars(&[("name", "World")]));
@@ -398,9 +237,40 @@ mod tests {
     }
 
     #[test]
-    fn missing_var_empty() {
-        let result = render("Hello {{ name }}!", &vars(&[]));
-        assert_eq!(result, "Hello !");
+    fn missing_var_is_error() {
+        let err = Environment::new().render_str("Hello\n {{ name }}!", &vars(&[])).unwrap_err();
+        assert_eq!(err.to_string(), "'name' is undefined at line 2, column 5");
+    }
+
+    #[test]
+    fn missing_var_empty_when_lenient() {
+        let result = Environment::new().lenient(true).render_str("Hello {{ name }}!", &vars(&[]));
+        assert_eq!(result.unwrap(), "Hello !");
+    }
+
+    #[test]
+    fn missing_var_in_template_file() {
+        let dir = template_dir("strict", &[("app.conf.j2", "port={{ port }}\nhost={{ hots }}\n")]);
+        let env = Environment::new().search_path(&dir);
+        let err = env.render_file("app.conf.j2", &vars(&[("port", "80"), ("host", "h")])).unwrap_err();
+        assert_eq!(err.msg, "'hots' is undefined");
+        assert_eq!(err.template, Some(dir.join("app.conf.j2")));
+        assert_eq!((err.line, err.col), (2, 9));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn defined_test_and_default_allow_undefined() {
+        assert_eq!(render("{{ x is defined }} {{ x.y | default('d') }}", &vars(&[])), "False d");
+        assert_eq!(render("{% if x is defined and x %}yes{% endif %}", &vars(&[])), "");
+    }
+
+    #[test]
+    fn condition_evaluation() {
+        let env = Environment::new();
+        assert!(env.eval_condition("port | int > 80", &vars(&[("port", "8080")])).unwrap());
+        assert!(env.eval_condition("enabled", &vars(&[])).is_err());
+        assert!(!Environment::new().lenient(true).eval_condition("enabled", &vars(&[])).unwrap());
     }
 
     #[test]
@@ -483,7 +353,7 @@ mod tests {
 
     #[test]
     fn if_else_false() {
-        let result = render("{% if enabled %}yes{% else %}no{% endif %}", &vars(&[]));
+        let result = render("{% if enabled %}yes{% else %}no{% endif %}", &vars(&[("enabled", "0")]));
         assert_eq!(result, "no");
     }
 
@@ -507,7 +377,7 @@ mod tests {
 
     #[test]
     fn for_loop_empty() {
-        let result = render("{% for item in items %}{{ item }}{% endfor %}", &vars(&[]));
+        let result = render("{% for item in items %}{{ item }}{% endfor %}", &vars(&[("items", "")]));
         assert_eq!(result, "");
     }
 
@@ -571,4 +441,222 @@ mod tests {
         let result = render("{{ name }}{# comment #} {{ value }}", &vars(&[("name", "foo"), ("value", "bar")]));
         assert_eq!(result, "foo bar");
     }
+
+    fn template_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wand-template-{}-{}", name, std::process::id()));
+        for (file, content) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn include_uses_context() {
+        let dir = template_dir("include", &[("main.j2", "[{% include 'part.j2' %}]"), ("part.j2", "{{ name }}")]);
+        let env = Environment::new().search_path(&dir);
+        assert_eq!(env.render_file("main.j2", &vars(&[("name", "web")])).unwrap(), "[web]");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn include_ignore_missing() {
+        let env = Environment::new();
+        assert_eq!(env.render_str("a{% include 'nope.j2' ignore missing %}b", &vars(&[])).unwrap(), "ab");
+        assert!(env.render_str("{% include 'nope.j2' %}", &vars(&[])).is_err());
+    }
+
+    #[test]
+    fn include_cycle_is_error() {
+        let dir = template_dir("cycle", &[("a.j2", "{% include 'b.j2' %}"), ("b.j2", "{% include 'a.j2' %}")]);
+        let err = Environment::new().search_path(&dir).render_file("a.j2", &vars(&[])).unwrap_err();
+        assert!(err.msg.contains("include cycle detected"), "{}", err);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn macro_with_defaults() {
+        let tpl = "{% macro greet(who, greeting='Hello') %}{{ greeting }} {{ who }}{% endmacro %}{{ greet('a') }}, {{ greet('b', greeting='Hi') }}";
+        assert_eq!(render(tpl, &vars(&[])), "Hello a, Hi b");
+    }
+
+    #[test]
+    fn call_block_passes_caller() {
+        let tpl = "{% macro wrap(tag) %}<{{ tag }}>{{ caller() }}</{{ tag }}>{% endmacro %}{% call wrap('p') %}{{ text }}{% endcall %}";
+        assert_eq!(render(tpl, &vars(&[("text", "hi")])), "<p>hi</p>");
+    }
+
+    #[test]
+    fn import_macros() {
+        let dir = template_dir(
+            "import",
+            &[
+                ("macros.j2", "{% macro kv(k, v) %}{{ k }}={{ v }}{% endmacro %}"),
+                ("main.j2", "{% import 'macros.j2' as m %}{% from 'macros.j2' import kv as pair %}{{ m.kv('a', 1) }} {{ pair('b', 2) }}"),
+            ],
+        );
+        let env = Environment::new().search_path(&dir);
+        assert_eq!(env.render_file("main.j2", &vars(&[])).unwrap(), "a=1 b=2");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn imported_macros_lack_context_by_default() {
+        let dir = template_dir(
+            "import-context",
+            &[
+                ("macros.j2", "{% macro show() %}[{{ name }}]{% endmacro %}"),
+                ("plain.j2", "{% import 'macros.j2' as m %}{{ m.show() }}"),
+                ("ctx.j2", "{% import 'macros.j2' as m with context %}{{ m.show() }}"),
+            ],
+        );
+        let env = Environment::new().search_path(&dir);
+        let v = vars(&[("name", "web")]);
+        assert!(env.render_file("plain.j2", &v).is_err());
+        assert_eq!(env.render_file("ctx.j2", &v).unwrap(), "[web]");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extends_overrides_blocks() {
+        let dir = template_dir(
+            "extends",
+            &[
+                ("base.j2", "<{% block head %}base{% endblock %}|{% block body %}default{% endblock %}>"),
+                ("child.j2", "{% extends 'base.j2' %}ignored{% block body %}{{ super() }}+{{ name }}{% endblock %}"),
+            ],
+        );
+        let env = Environment::new().search_path(&dir);
+        assert_eq!(env.render_file("child.j2", &vars(&[("name", "x")])).unwrap(), "<base|default+x>");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn multi_level_inheritance() {
+        let dir = template_dir(
+            "extends-chain",
+            &[
+                ("base.j2", "{% block a %}1{% endblock %}"),
+                ("mid.j2", "{% extends 'base.j2' %}{% block a %}{{ super() }}2{% endblock %}"),
+                ("leaf.j2", "{% extends 'mid.j2' %}{% block a %}{{ super() }}3{% endblock %}"),
+            ],
+        );
+        let env = Environment::new().search_path(&dir);
+        assert_eq!(env.render_file("leaf.j2", &vars(&[])).unwrap(), "123");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_include_resolves_relative_to_template() {
+        let dir = template_dir(
+            "relative",
+            &[("main.j2", "{% include 'sub/a.j2' %}"), ("sub/a.j2", "{% include 'b.j2' %}"), ("sub/b.j2", "deep")],
+        );
+        let env = Environment::new().search_path(&dir);
+        assert_eq!(env.render_file("main.j2", &vars(&[])).unwrap(), "deep");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_filter_is_error() {
+        let err = Environment::new().render_str("{{ x | no_such_filter }}", &vars(&[("x", "1")])).unwrap_err();
+        assert!(err.msg.contains("no filter named 'no_such_filter'"), "{}", err);
+    }
+
+    #[test]
+    fn selectattr_map_chain() {
+        let tpl = "{{ users | selectattr('admin') | map(attribute='name') | sort | join(',') }}";
+        let v: HashMap<String, Value> = HashMap::from([(
+            "users".to_string(),
+            serde_json::json!([{"name": "b", "admin": true}, {"name": "c"}, {"name": "a", "admin": true}]),
+        )]);
+        assert_eq!(Environment::new().render_str(tpl, &v).unwrap(), "a,b");
+    }
+
+    #[test]
+    fn lookup_and_query() {
+        let v = vars(&[("name", "web")]);
+        // Set by cargo for the test run; setting a variable here would race
+        // with other tests reading the environment.
+        assert_eq!(render("{{ lookup('env', 'CARGO_PKG_NAME') }}", &v), env!("CARGO_PKG_NAME"));
+        assert_eq!(render("{{ lookup('pipe', 'echo hi') }}", &v), "hi");
+        assert_eq!(render("{{ lookup('sequence', 'end=3') }}", &v), "1,2,3");
+        assert_eq!(render("{{ query('sequence', 'end=2') | length }}", &v), "2");
+        assert_eq!(render("{{ lookup('vars', 'name') }}", &v), "web");
+        assert_eq!(render("{% for i in lookup('dict', {'a': 1}, wantlist=True) %}{{ i.key }}={{ i.value }}{% endfor %}", &v), "a=1");
+    }
+
+    #[test]
+    fn lookup_errors() {
+        let env = Environment::new();
+        let err = env.render_str("{{ lookup('nope', 'x') }}", &vars(&[])).unwrap_err();
+        assert!(err.msg.contains("lookup plugin 'nope' not found"), "{}", err);
+        assert!(env.render_str("{{ lookup('vars', 'missing') }}", &vars(&[])).is_err());
+        assert_eq!(env.render_str("{{ lookup('vars', 'missing', errors='ignore') }}", &vars(&[])).unwrap(), "");
+    }
+
+    #[test]
+    fn custom_lookup_plugin() {
+        let mut registry = LookupRegistry::empty();
+        registry.register("shout", |terms: &[Value], _: &[(String, Value)], _: &LookupContext| {
+            Ok(terms.iter().map(|t| Value::String(t.as_str().unwrap_or("").to_uppercase())).collect())
+        });
+        let env = Environment::new().lookups(Arc::new(registry));
+        assert_eq!(env.render_str("{{ lookup('shout', 'a', 'b') }}", &vars(&[])).unwrap(), "A,B");
+    }
+
+    fn json_vars(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn templated_vars_resolve_recursively() {
+        let v = json_vars(serde_json::json!({
+            "app_name": "shop",
+            "base": "/opt",
+            "app_dir": "{{ base }}/{{ app_name }}",
+            "log_dir": "{{ app_dir }}/logs",
+        }));
+        assert_eq!(Environment::new().render_str("{{ log_dir }}", &v).unwrap(), "/opt/shop/logs");
+    }
+
+    #[test]
+    fn templated_nested_structures() {
+        let v = json_vars(serde_json::json!({
+            "port": 8080,
+            "ports": "{{ [port, port + 1] }}",
+            "app": {"url": "http://localhost:{{ port }}", "tags": ["{{ port }}"]},
+        }));
+        let env = Environment::new();
+        assert_eq!(env.render_str("{{ ports | sum }}", &v).unwrap(), "16161");
+        assert_eq!(env.render_str("{{ app.url }} {{ app.tags[0] + 1 }}", &v).unwrap(), "http://localhost:8080 8081");
+    }
+
+    #[test]
+    fn recursive_var_loop_is_error() {
+        let v = json_vars(serde_json::json!({"a": "{{ b }}", "b": "x{{ a }}"}));
+        let err = Environment::new().render_str("{{ a }}", &v).unwrap_err();
+        assert!(err.msg.starts_with("recursive loop detected in template string"), "{}", err);
+    }
+
+    #[test]
+    fn unsafe_vars_are_never_rendered() {
+        let mut v = HostVars::from(json_vars(serde_json::json!({"dir": "/opt/{{ missing }}"})));
+        v.insert_unsafe("out".to_string(), serde_json::json!({"stdout": "{{ lookup('pipe', 'id') }} {{ x }}"}));
+        let env = Environment::new();
+        assert_eq!(env.render_str("{{ out.stdout }}", &v).unwrap(), "{{ lookup('pipe', 'id') }} {{ x }}");
+        assert!(env.render_str("{{ dir }}", &v).is_err());
+
+        // Setting the name again from the play makes it an ordinary variable.
+        v.insert("out".to_string(), Value::from("{{ 1 + 1 }}"));
+        assert_eq!(env.render_str("{{ out }}", &v).unwrap(), "2");
+    }
+
+    #[test]
+    fn undefined_inside_templated_var_names_variable() {
+        let v = json_vars(serde_json::json!({"app_dir": "/opt/{{ app_name }}"}));
+        let err = Environment::new().render_str("dir={{ app_dir }}", &v).unwrap_err();
+        assert_eq!(err.to_string(), "'app_name' is undefined (in variable 'app_dir') at line 1, column 8");
+    }
 }
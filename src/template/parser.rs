@@ -0,0 +1,927 @@
+use super::lexer::{self, Spanned, Token};
+use super::TemplateError;
+use serde_json::Value;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pos {
+    pub line: usize,
+    pub col: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    FloorDiv,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    NotIn,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Args {
+    pub positional: Vec<Expr>,
+    pub keyword: Vec<(String, Expr)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Const(Value),
+    Name(String, Pos),
+    List(Vec<Expr>),
+    Dict(Vec<(Expr, Expr)>),
+    Attr(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Slice(Box<Expr>, Option<Box<Expr>>, Option<Box<Expr>>),
+    Call(Box<Expr>, Args, Pos),
+    Filter(Box<Expr>, String, Args, Pos),
+    Test(Box<Expr>, String, Args, bool, Pos),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Option<Box<Expr>>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<(String, Option<Expr>)>,
+    pub body: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForLoop {
+    pub targets: Vec<String>,
+    pub iter: Expr,
+    pub filter: Option<Expr>,
+    pub body: Vec<Node>,
+    pub else_body: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Text(String),
+    Output(Expr),
+    If(Vec<(Expr, Vec<Node>)>, Vec<Node>),
+    For(Box<ForLoop>),
+    Set(Vec<String>, Expr),
+    SetBlock(String, Vec<Node>),
+    Include {
+        template: Expr,
+        ignore_missing: bool,
+        with_context: bool,
+        pos: Pos,
+    },
+    Import {
+        template: Expr,
+        alias: String,
+        with_context: bool,
+        pos: Pos,
+    },
+    FromImport {
+        template: Expr,
+        names: Vec<(String, String)>,
+        with_context: bool,
+        pos: Pos,
+    },
+    Macro(Rc<Macro>),
+    Call(Expr, Rc<Macro>),
+    Extends(Expr, Pos),
+    Block(String, Rc<Vec<Node>>),
+    FilterBlock(String, Args, Vec<Node>, Pos),
+}
+
+pub fn parse(source: &str) -> Result<Vec<Node>, TemplateError> {
+    let tokens = lexer::tokenize(source)?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let (nodes, end) = parser.parse_nodes(&[])?;
+    if let Some((tag, pos)) = end {
+        return Err(TemplateError::new(&format!("unexpected '{}'", tag)).at(pos.line, pos.col));
+    }
+    Ok(nodes)
+}
+
+/// Parses a bare expression, as used by `when` conditions.
+pub fn parse_expr(source: &str) -> Result<Expr, TemplateError> {
+    let mut parser = ExprParser::new(source.trim(), Pos { line: 1, col: 1 })?;
+    let expr = parser.parse_expr()?;
+    parser.expect_end()?;
+    Ok(expr)
+}
+
+/// The tag that ended a run of nodes, with its position.
+type EndTag = (String, Pos);
+
+struct Parser {
+    tokens: Vec<Spanned>,
+    pos: usize,
+}
+
+impl Parser {
+    /// Parses nodes until one of the `end` tags is reached. Returns the nodes
+    /// and the tag (with its arguments) that stopped parsing.
+    fn parse_nodes(&mut self, end: &[&str]) -> Result<(Vec<Node>, Option<EndTag>), TemplateError> {
+        let mut nodes = Vec::new();
+
+        while self.pos < self.tokens.len() {
+            let Spanned { token, line, col } = self.tokens[self.pos].clone();
+            let pos = Pos { line, col };
+            self.pos += 1;
+
+            match token {
+                Token::Text(text) => nodes.push(Node::Text(text)),
+                Token::Var(content) => {
+                    let mut parser = ExprParser::new(&content, pos)?;
+                    let expr = parser.parse_expr()?;
+                    parser.expect_end()?;
+                    nodes.push(Node::Output(expr));
+                }
+                Token::Block(content) => {
+                    let keyword = content.split_whitespace().next().unwrap_or("").to_string();
+                    if end.contains(&keyword.as_str()) {
+                        return Ok((nodes, Some((content, pos))));
+                    }
+                    nodes.push(self.parse_block(&keyword, &content, pos)?);
+                }
+            }
+        }
+
+        Ok((nodes, None))
+    }
+
+    fn parse_until(&mut self, end: &[&str], opened: Pos) -> Result<(Vec<Node>, String, Pos), TemplateError> {
+        match self.parse_nodes(end)? {
+            (nodes, Some((tag, pos))) => Ok((nodes, tag, pos)),
+            (_, None) => Err(TemplateError::new(&format!("missing '{}' tag", end[end.len() - 1]))
+                .at(opened.line, opened.col)),
+        }
+    }
+
+    fn parse_block(&mut self, keyword: &str, content: &str, pos: Pos) -> Result<Node, TemplateError> {
+        let rest = content[keyword.len()..].trim_start();
+        let rest_pos = Pos {
+            line: pos.line,
+            col: pos.col + (content.len() - rest.len()),
+        };
+        let mut p = ExprParser::new(rest, rest_pos)?;
+
+        match keyword {
+            "if" => {
+                let mut branches = Vec::new();
+                let mut cond = p.parse_expr()?;
+                p.expect_end()?;
+                loop {
+                    let (body, tag, tag_pos) = self.parse_until(&["elif", "else", "endif"], pos)?;
+                    branches.push((cond, body));
+                    if let Some(next) = tag.strip_prefix("elif") {
+                        let mut p = ExprParser::new(next.trim(), tag_pos)?;
+                        cond = p.parse_expr()?;
+                        p.expect_end()?;
+                    } else if tag == "else" {
+                        let (else_body, _, _) = self.parse_until(&["endif"], pos)?;
+                        return Ok(Node::If(branches, else_body));
+                    } else {
+                        return Ok(Node::If(branches, Vec::new()));
+                    }
+                }
+            }
+            "for" => {
+                let mut targets = vec![p.expect_name()?];
+                while p.eat_op(",") {
+                    targets.push(p.expect_name()?);
+                }
+                p.expect_keyword("in")?;
+                let iter = p.parse_or()?;
+                let filter = if p.eat_keyword("if") { Some(p.parse_or()?) } else { None };
+                p.eat_keyword("recursive");
+                p.expect_end()?;
+
+                let (body, tag, _) = self.parse_until(&["else", "endfor"], pos)?;
+                let else_body = if tag == "else" {
+                    self.parse_until(&["endfor"], pos)?.0
+                } else {
+                    Vec::new()
+                };
+
+                Ok(Node::For(Box::new(ForLoop {
+                    targets,
+                    iter,
+                    filter,
+                    body,
+                    else_body,
+                })))
+            }
+            "set" => {
+                let mut targets = vec![p.expect_name()?];
+                while p.eat_op(",") {
+                    targets.push(p.expect_name()?);
+                }
+                if p.eat_op("=") {
+                    let value = p.parse_expr()?;
+                    p.expect_end()?;
+                    Ok(Node::Set(targets, value))
+                } else {
+                    p.expect_end()?;
+                    let (body, _, _) = self.parse_until(&["endset"], pos)?;
+                    Ok(Node::SetBlock(targets.remove(0), body))
+                }
+            }
+            "include" => {
+                let template = p.parse_expr()?;
+                let mut ignore_missing = false;
+                if p.eat_keyword("ignore") {
+                    p.expect_keyword("missing")?;
+                    ignore_missing = true;
+                }
+                let with_context = p.parse_context_modifier(true)?;
+                p.expect_end()?;
+                Ok(Node::Include {
+                    template,
+                    ignore_missing,
+                    with_context,
+                    pos,
+                })
+            }
+            "import" => {
+                let template = p.parse_expr()?;
+                p.expect_keyword("as")?;
+                let alias = p.expect_name()?;
+                let with_context = p.parse_context_modifier(false)?;
+                p.expect_end()?;
+                Ok(Node::Import {
+                    template,
+                    alias,
+                    with_context,
+                    pos,
+                })
+            }
+            "from" => {
+                let template = p.parse_primary()?;
+                p.expect_keyword("import")?;
+                let mut names = Vec::new();
+                loop {
+                    let name = p.expect_name()?;
+                    let alias = if p.eat_keyword("as") { p.expect_name()? } else { name.clone() };
+                    names.push((name, alias));
+                    if !p.eat_op(",") {
+                        break;
+                    }
+                }
+                let with_context = p.parse_context_modifier(false)?;
+                p.expect_end()?;
+                Ok(Node::FromImport {
+                    template,
+                    names,
+                    with_context,
+                    pos,
+                })
+            }
+            "macro" => {
+                let name = p.expect_name()?;
+                let params = p.parse_params()?;
+                p.expect_end()?;
+                let (body, _, _) = self.parse_until(&["endmacro"], pos)?;
+                Ok(Node::Macro(Rc::new(Macro { name, params, body })))
+            }
+            "call" => {
+                let params = if p.peek_op("(") { p.parse_params()? } else { Vec::new() };
+                let call = p.parse_expr()?;
+                p.expect_end()?;
+                if !matches!(call, Expr::Call(..)) {
+                    return Err(TemplateError::new("expected a macro call after 'call'").at(pos.line, pos.col));
+                }
+                let (body, _, _) = self.parse_until(&["endcall"], pos)?;
+                let caller = Macro {
+                    name: "caller".to_string(),
+                    params,
+                    body,
+                };
+                Ok(Node::Call(call, Rc::new(caller)))
+            }
+            "extends" => {
+                let template = p.parse_expr()?;
+                p.expect_end()?;
+                Ok(Node::Extends(template, pos))
+            }
+            "block" => {
+                let name = p.expect_name()?;
+                p.eat_keyword("scoped");
+                p.expect_end()?;
+                let (body, _, _) = self.parse_until(&["endblock"], pos)?;
+                Ok(Node::Block(name, Rc::new(body)))
+            }
+            "filter" => {
+                let name = p.expect_name()?;
+                let args = if p.peek_op("(") { p.parse_args()? } else { Args::default() };
+                p.expect_end()?;
+                let (body, _, _) = self.parse_until(&["endfilter"], pos)?;
+                Ok(Node::FilterBlock(name, args, body, pos))
+            }
+            _ => Err(TemplateError::new(&format!("unknown tag '{}'", keyword)).at(pos.line, pos.col)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Name(String),
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 27] = [
+    "**", "//", "==", "!=", "<=", ">=", "+", "-", "*", "/", "%", "~", "<", ">", "=", "(", ")", "[", "]",
+    "{", "}", ",", ".", ":", "|", "?", ";",
+];
+
+struct ExprParser {
+    toks: Vec<(Tok, usize)>,
+    pos: usize,
+    origin: Pos,
+}
+
+impl ExprParser {
+    fn new(source: &str, origin: Pos) -> Result<Self, TemplateError> {
+        Ok(Self {
+            toks: tokenize_expr(source, origin)?,
+            pos: 0,
+            origin,
+        })
+    }
+
+    fn pos_of(&self, index: usize) -> Pos {
+        let offset = self.toks.get(index).map(|(_, o)| *o).unwrap_or_else(|| {
+            self.toks.last().map(|(_, o)| *o + 1).unwrap_or(0)
+        });
+        Pos {
+            line: self.origin.line,
+            col: self.origin.col + offset,
+        }
+    }
+
+    fn error(&self, msg: &str) -> TemplateError {
+        let pos = self.pos_of(self.pos);
+        TemplateError::new(msg).at(pos.line, pos.col)
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.pos).map(|(t, _)| t)
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        let tok = self.toks.get(self.pos).map(|(t, _)| t.clone());
+        self.pos += 1;
+        tok
+    }
+
+    fn peek_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Op(o)) if *o == op)
+    }
+
+    fn peek_keyword(&self, kw: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Name(n)) if n == kw)
+    }
+
+    fn eat_op(&mut self, op: &str) -> bool {
+        if self.peek_op(op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        if self.peek_keyword(kw) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), TemplateError> {
+        if self.eat_op(op) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", op)))
+        }
+    }
+
+    fn expect_keyword(&mut self, kw: &str) -> Result<(), TemplateError> {
+        if self.eat_keyword(kw) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", kw)))
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, TemplateError> {
+        match self.peek() {
+            Some(Tok::Name(n)) => {
+                let n = n.clone();
+                self.pos += 1;
+                Ok(n)
+            }
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), TemplateError> {
+        if self.pos < self.toks.len() {
+            Err(self.error("unexpected token"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn parse_context_modifier(&mut self, default: bool) -> Result<bool, TemplateError> {
+        if self.eat_keyword("with") {
+            self.expect_keyword("context")?;
+            Ok(true)
+        } else if self.eat_keyword("without") {
+            self.expect_keyword("context")?;
+            Ok(false)
+        } else {
+            Ok(default)
+        }
+    }
+
+    fn parse_params(&mut self) -> Result<Vec<(String, Option<Expr>)>, TemplateError> {
+        self.expect_op("(")?;
+        let mut params = Vec::new();
+        while !self.eat_op(")") {
+            let name = self.expect_name()?;
+            let default = if self.eat_op("=") { Some(self.parse_expr()?) } else { None };
+            params.push((name, default));
+            if !self.eat_op(",") {
+                self.expect_op(")")?;
+                break;
+            }
+        }
+        Ok(params)
+    }
+
+    fn parse_args(&mut self) -> Result<Args, TemplateError> {
+        self.expect_op("(")?;
+        let mut args = Args::default();
+        while !self.eat_op(")") {
+            let is_keyword = matches!(self.peek(), Some(Tok::Name(_)))
+                && matches!(self.toks.get(self.pos + 1), Some((Tok::Op("="), _)));
+            if is_keyword {
+                let name = self.expect_name()?;
+                self.pos += 1;
+                args.keyword.push((name, self.parse_expr()?));
+            } else {
+                args.positional.push(self.parse_expr()?);
+            }
+            if !self.eat_op(",") {
+                self.expect_op(")")?;
+                break;
+            }
+        }
+        Ok(args)
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, TemplateError> {
+        let expr = self.parse_or()?;
+        if self.eat_keyword("if") {
+            let cond = self.parse_or()?;
+            let otherwise = if self.eat_keyword("else") {
+                Some(Box::new(self.parse_expr()?))
+            } else {
+                None
+            };
+            return Ok(Expr::Cond(Box::new(expr), Box::new(cond), otherwise));
+        }
+        Ok(expr)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, TemplateError> {
+        let mut left = self.parse_and()?;
+        while self.eat_keyword("or") {
+            let right = self.parse_and()?;
+            left = Expr::Bin(BinOp::Or, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, TemplateError> {
+        let mut left = self.parse_not()?;
+        while self.eat_keyword("and") {
+            let right = self.parse_not()?;
+            left = Expr::Bin(BinOp::And, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, TemplateError> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_compare()
+    }
+
+    fn parse_compare(&mut self) -> Result<Expr, TemplateError> {
+        let mut left = self.parse_math1()?;
+        loop {
+            let op = match self.peek() {
+                Some(Tok::Op("==")) => BinOp::Eq,
+                Some(Tok::Op("!=")) => BinOp::Ne,
+                Some(Tok::Op("<")) => BinOp::Lt,
+                Some(Tok::Op("<=")) => BinOp::Le,
+                Some(Tok::Op(">")) => BinOp::Gt,
+                Some(Tok::Op(">=")) => BinOp::Ge,
+                Some(Tok::Name(n)) if n == "in" => BinOp::In,
+                Some(Tok::Name(n))
+                    if n == "not" && matches!(self.toks.get(self.pos + 1), Some((Tok::Name(m), _)) if m == "in") =>
+                {
+                    self.pos += 1;
+                    BinOp::NotIn
+                }
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.parse_math1()?;
+            left = Expr::Bin(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_math1(&mut self) -> Result<Expr, TemplateError> {
+        let mut left = self.parse_concat()?;
+        loop {
+            let op = if self.eat_op("+") {
+                BinOp::Add
+            } else if self.eat_op("-") {
+                BinOp::Sub
+            } else {
+                return Ok(left);
+            };
+            let right = self.parse_concat()?;
+            left = Expr::Bin(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_concat(&mut self) -> Result<Expr, TemplateError> {
+        let mut left = self.parse_math2()?;
+        while self.eat_op("~") {
+            let right = self.parse_math2()?;
+            left = Expr::Bin(BinOp::Concat, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_math2(&mut self) -> Result<Expr, TemplateError> {
+        let mut left = self.parse_pow()?;
+        loop {
+            let op = if self.eat_op("*") {
+                BinOp::Mul
+            } else if self.eat_op("//") {
+                BinOp::FloorDiv
+            } else if self.eat_op("/") {
+                BinOp::Div
+            } else if self.eat_op("%") {
+                BinOp::Mod
+            } else {
+                return Ok(left);
+            };
+            let right = self.parse_pow()?;
+            left = Expr::Bin(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn parse_pow(&mut self) -> Result<Expr, TemplateError> {
+        let mut left = self.parse_unary()?;
+        while self.eat_op("**") {
+            let right = self.parse_unary()?;
+            left = Expr::Bin(BinOp::Pow, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, TemplateError> {
+        if self.eat_op("-") {
+            let inner = self.parse_unary()?;
+            return self.parse_filters(Expr::Neg(Box::new(inner)));
+        }
+        if self.eat_op("+") {
+            return self.parse_unary();
+        }
+        let primary = self.parse_primary()?;
+        let postfix = self.parse_postfix(primary)?;
+        self.parse_filters(postfix)
+    }
+
+    fn parse_postfix(&mut self, mut expr: Expr) -> Result<Expr, TemplateError> {
+        loop {
+            if self.eat_op(".") {
+                let attr = match self.next() {
+                    Some(Tok::Name(n)) => n,
+                    Some(Tok::Int(i)) => i.to_string(),
+                    _ => return Err(self.error("expected attribute name")),
+                };
+                expr = Expr::Attr(Box::new(expr), attr);
+            } else if self.peek_op("[") {
+                self.pos += 1;
+                let start = if self.peek_op(":") { None } else { Some(Box::new(self.parse_expr()?)) };
+                if self.eat_op(":") {
+                    let end = if self.peek_op("]") { None } else { Some(Box::new(self.parse_expr()?)) };
+                    self.expect_op("]")?;
+                    expr = Expr::Slice(Box::new(expr), start, end);
+                } else {
+                    self.expect_op("]")?;
+                    let index = start.ok_or_else(|| self.error("expected subscript"))?;
+                    expr = Expr::Index(Box::new(expr), index);
+                }
+            } else if self.peek_op("(") {
+                let pos = self.pos_of(self.pos);
+                let args = self.parse_args()?;
+                expr = Expr::Call(Box::new(expr), args, pos);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_filters(&mut self, mut expr: Expr) -> Result<Expr, TemplateError> {
+        loop {
+            if self.eat_op("|") {
+                let pos = self.pos_of(self.pos);
+                let mut name = self.expect_name()?;
+                while self.eat_op(".") {
+                    name = format!("{}.{}", name, self.expect_name()?);
+                }
+                let args = if self.peek_op("(") { self.parse_args()? } else { Args::default() };
+                expr = Expr::Filter(Box::new(expr), name, args, pos);
+            } else if self.eat_keyword("is") {
+                let pos = self.pos_of(self.pos);
+                let negated = self.eat_keyword("not");
+                let mut name = self.expect_name()?;
+                while self.eat_op(".") {
+                    name = format!("{}.{}", name, self.expect_name()?);
+                }
+                let args = if self.peek_op("(") {
+                    self.parse_args()?
+                } else if self.starts_test_arg() {
+                    Args {
+                        positional: vec![self.parse_primary()?],
+                        keyword: Vec::new(),
+                    }
+                } else {
+                    Args::default()
+                };
+                expr = Expr::Test(Box::new(expr), name, args, negated, pos);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn starts_test_arg(&self) -> bool {
+        match self.peek() {
+            Some(Tok::Str(_)) | Some(Tok::Int(_)) | Some(Tok::Float(_)) => true,
+            Some(Tok::Op(o)) => *o == "[",
+            Some(Tok::Name(n)) => !matches!(
+                n.as_str(),
+                "and" | "or" | "not" | "if" | "else" | "in" | "is"
+            ),
+            None => false,
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, TemplateError> {
+        let pos = self.pos_of(self.pos);
+        match self.next() {
+            Some(Tok::Str(s)) => {
+                let mut s = s;
+                // Adjacent string literals are concatenated, as in Python.
+                while let Some(Tok::Str(next)) = self.peek() {
+                    s.push_str(next);
+                    self.pos += 1;
+                }
+                Ok(Expr::Const(Value::String(s)))
+            }
+            Some(Tok::Int(i)) => Ok(Expr::Const(Value::from(i))),
+            Some(Tok::Float(f)) => Ok(Expr::Const(Value::from(f))),
+            Some(Tok::Name(name)) => Ok(match name.as_str() {
+                "true" | "True" => Expr::Const(Value::Bool(true)),
+                "false" | "False" => Expr::Const(Value::Bool(false)),
+                "none" | "None" => Expr::Const(Value::Null),
+                _ => Expr::Name(name, pos),
+            }),
+            Some(Tok::Op("(")) => {
+                if self.eat_op(")") {
+                    return Ok(Expr::List(Vec::new()));
+                }
+                let first = self.parse_expr()?;
+                if self.peek_op(",") {
+                    let mut items = vec![first];
+                    while self.eat_op(",") {
+                        if self.peek_op(")") {
+                            break;
+                        }
+                        items.push(self.parse_expr()?);
+                    }
+                    self.expect_op(")")?;
+                    return Ok(Expr::List(items));
+                }
+                self.expect_op(")")?;
+                Ok(first)
+            }
+            Some(Tok::Op("[")) => {
+                let mut items = Vec::new();
+                while !self.eat_op("]") {
+                    items.push(self.parse_expr()?);
+                    if !self.eat_op(",") {
+                        self.expect_op("]")?;
+                        break;
+                    }
+                }
+                Ok(Expr::List(items))
+            }
+            Some(Tok::Op("{")) => {
+                let mut items = Vec::new();
+                while !self.eat_op("}") {
+                    let key = self.parse_expr()?;
+                    self.expect_op(":")?;
+                    let value = self.parse_expr()?;
+                    items.push((key, value));
+                    if !self.eat_op(",") {
+                        self.expect_op("}")?;
+                        break;
+                    }
+                }
+                Ok(Expr::Dict(items))
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.error("unexpected token in expression"))
+            }
+        }
+    }
+}
+
+fn tokenize_expr(source: &str, origin: Pos) -> Result<Vec<(Tok, usize)>, TemplateError> {
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut toks = Vec::new();
+    let mut i = 0;
+
+    let error = |offset: usize, msg: &str| TemplateError::new(msg).at(origin.line, origin.col + offset);
+
+    while i < chars.len() {
+        let (offset, c) = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c == '"' || c == '\'' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                let Some(&(_, ch)) = chars.get(i) else {
+                    return Err(error(offset, "unterminated string literal"));
+                };
+                i += 1;
+                if ch == c {
+                    break;
+                }
+                if ch == '\\' {
+                    let Some(&(_, esc)) = chars.get(i) else {
+                        return Err(error(offset, "unterminated string literal"));
+                    };
+                    i += 1;
+                    match esc {
+                        'n' => s.push('\n'),
+                        't' => s.push('\t'),
+                        'r' => s.push('\r'),
+                        '\\' => s.push('\\'),
+                        '\'' => s.push('\''),
+                        '"' => s.push('"'),
+                        other => {
+                            s.push('\\');
+                            s.push(other);
+                        }
+                    }
+                } else {
+                    s.push(ch);
+                }
+            }
+            toks.push((Tok::Str(s), offset));
+            continue;
+        }
+
+        if c.is_ascii_digit() {
+            let start = i;
+            let mut is_float = false;
+            while i < chars.len() {
+                let ch = chars[i].1;
+                if ch.is_ascii_digit() || ch == '_' {
+                    i += 1;
+                } else if ch == '.'
+                    && !is_float
+                    && chars.get(i + 1).map(|(_, n)| n.is_ascii_digit()).unwrap_or(false)
+                {
+                    is_float = true;
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            let text: String = chars[start..i].iter().map(|(_, ch)| *ch).filter(|ch| *ch != '_').collect();
+            let tok = if is_float {
+                Tok::Float(text.parse().map_err(|_| error(offset, "invalid number"))?)
+            } else {
+                Tok::Int(text.parse().map_err(|_| error(offset, "invalid number"))?)
+            };
+            toks.push((tok, offset));
+            continue;
+        }
+
+        if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].1.is_alphanumeric() || chars[i].1 == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().map(|(_, ch)| *ch).collect();
+            toks.push((Tok::Name(name), offset));
+            continue;
+        }
+
+        let rest = &source[offset..];
+        match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            Some(op) => {
+                toks.push((Tok::Op(op), offset));
+                i += op.chars().count();
+            }
+            None => return Err(error(offset, &format!("unexpected character '{}'", c))),
+        }
+    }
+
+    Ok(toks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filter_chain_with_args() {
+        let expr = parse_expr("name | default('x') | upper").unwrap();
+        match expr {
+            Expr::Filter(inner, name, _, _) => {
+                assert_eq!(name, "upper");
+                assert!(matches!(*inner, Expr::Filter(_, ref n, _, _) if n == "default"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_not_in() {
+        let expr = parse_expr("a not in b").unwrap();
+        assert!(matches!(expr, Expr::Bin(BinOp::NotIn, _, _)));
+    }
+
+    #[test]
+    fn parses_test_with_bare_argument() {
+        let expr = parse_expr("x is divisibleby 3").unwrap();
+        assert!(matches!(expr, Expr::Test(_, ref n, ref a, false, _) if n == "divisibleby" && a.positional.len() == 1));
+    }
+
+    #[test]
+    fn reports_unclosed_block() {
+        let err = parse("{% if a %}yes").unwrap_err();
+        assert!(err.msg.contains("endif"));
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn parses_macro_with_defaults() {
+        let nodes = parse("{% macro m(a, b='x') %}{{ a }}{% endmacro %}").unwrap();
+        match &nodes[0] {
+            Node::Macro(m) => {
+                assert_eq!(m.name, "m");
+                assert_eq!(m.params.len(), 2);
+                assert!(m.params[1].1.is_some());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,380 @@
+use super::value;
+use serde_json::Value;
+
+/// A small subset of JMESPath used by the `json_query` filter: field access,
+/// `[n]` indexes, `[*]` and `*` projections, `[]` flattening, `[?a == b]`
+/// filters (with `&&` and `||`) and `{k: expr}` / `[a, b]` multi-selects.
+#[derive(Debug, Clone)]
+enum Step {
+    Field(String),
+    Index(i64),
+    Wildcard,
+    Values,
+    Flatten,
+    Filter(Condition),
+    MultiHash(Vec<(String, Vec<Step>)>),
+    MultiList(Vec<Vec<Step>>),
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Compare(Operand, String, Operand),
+    Truthy(Operand),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Path(Vec<Step>),
+    Literal(Value),
+}
+
+pub fn query(data: &Value, expr: &str) -> Result<Value, String> {
+    let mut parser = QueryParser { chars: expr.chars().collect(), pos: 0 };
+    let steps = parser.path()?;
+    parser.skip_ws();
+    if parser.pos < parser.chars.len() {
+        return Err(format!("json_query: unexpected '{}' in '{}'", parser.chars[parser.pos], expr));
+    }
+    Ok(evaluate(&steps, data))
+}
+
+fn evaluate(steps: &[Step], data: &Value) -> Value {
+    let Some((step, rest)) = steps.split_first() else {
+        return data.clone();
+    };
+
+    match step {
+        Step::Field(name) => match data.get(name) {
+            Some(v) => evaluate(rest, v),
+            None => Value::Null,
+        },
+        Step::Index(i) => match data {
+            Value::Array(_) => value::get_item(data, &Value::from(*i))
+                .map(|v| evaluate(rest, &v))
+                .unwrap_or(Value::Null),
+            _ => Value::Null,
+        },
+        Step::Wildcard => match data {
+            Value::Array(items) => project(items.iter(), rest),
+            _ => Value::Null,
+        },
+        Step::Values => match data {
+            Value::Object(map) => project(map.values(), rest),
+            _ => Value::Null,
+        },
+        Step::Flatten => match data {
+            Value::Array(items) => {
+                let mut flat = Vec::new();
+                for item in items {
+                    match item {
+                        Value::Array(inner) => flat.extend(inner.iter().cloned()),
+                        other => flat.push(other.clone()),
+                    }
+                }
+                project(flat.iter(), rest)
+            }
+            _ => Value::Null,
+        },
+        Step::Filter(cond) => match data {
+            Value::Array(items) => project(items.iter().filter(|item| matches(cond, item)), rest),
+            _ => Value::Null,
+        },
+        Step::MultiHash(fields) => {
+            if data.is_null() {
+                return Value::Null;
+            }
+            let pairs = fields.iter().map(|(k, p)| (k.clone(), evaluate(p, data))).collect();
+            evaluate(rest, &value::object(pairs))
+        }
+        Step::MultiList(paths) => {
+            if data.is_null() {
+                return Value::Null;
+            }
+            evaluate(rest, &Value::Array(paths.iter().map(|p| evaluate(p, data)).collect()))
+        }
+    }
+}
+
+fn project<'a>(items: impl Iterator<Item = &'a Value>, rest: &[Step]) -> Value {
+    Value::Array(items.map(|item| evaluate(rest, item)).filter(|v| !v.is_null()).collect())
+}
+
+fn matches(cond: &Condition, item: &Value) -> bool {
+    let operand = |op: &Operand| match op {
+        Operand::Path(p) => evaluate(p, item),
+        Operand::Literal(v) => v.clone(),
+    };
+    match cond {
+        Condition::Compare(l, op, r) => {
+            let (l, r) = (operand(l), operand(r));
+            match op.as_str() {
+                "==" => l == r,
+                "!=" => l != r,
+                _ => match (l.as_f64(), r.as_f64()) {
+                    (Some(a), Some(b)) => match op.as_str() {
+                        "<" => a < b,
+                        "<=" => a <= b,
+                        ">" => a > b,
+                        _ => a >= b,
+                    },
+                    _ => false,
+                },
+            }
+        }
+        Condition::Truthy(op) => value::is_truthy(&operand(op)),
+        Condition::And(a, b) => matches(a, item) && matches(b, item),
+        Condition::Or(a, b) => matches(a, item) || matches(b, item),
+        Condition::Not(c) => !matches(c, item),
+    }
+}
+
+struct QueryParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl QueryParser {
+    fn skip_ws(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_ws();
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_ws();
+        let end = self.pos + s.chars().count();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().copied().eq(s.chars()) {
+            self.pos = end;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), String> {
+        if self.eat(s) {
+            Ok(())
+        } else {
+            Err(format!("json_query: expected '{}'", s))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        self.skip_ws();
+        if self.peek() == Some('"') {
+            return match self.literal()? {
+                Value::String(s) => Ok(s),
+                _ => Err("json_query: invalid quoted identifier".to_string()),
+            };
+        }
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(|c| c.is_alphanumeric() || *c == '_' || *c == '-') {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(format!("json_query: expected identifier at position {}", start));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn path(&mut self) -> Result<Vec<Step>, String> {
+        let mut steps = Vec::new();
+        match self.peek() {
+            Some('[') | Some('{') => {}
+            Some('*') => {
+                self.pos += 1;
+                steps.push(Step::Values);
+            }
+            Some('@') => self.pos += 1,
+            Some(_) => steps.push(Step::Field(self.ident()?)),
+            None => return Ok(steps),
+        }
+
+        loop {
+            match self.peek() {
+                Some('.') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('*') => {
+                            self.pos += 1;
+                            steps.push(Step::Values);
+                        }
+                        Some('{') => steps.push(self.multi_hash()?),
+                        Some('[') => steps.push(self.multi_list()?),
+                        _ => steps.push(Step::Field(self.ident()?)),
+                    }
+                }
+                Some('[') => steps.push(self.bracket()?),
+                Some('{') if steps.is_empty() => steps.push(self.multi_hash()?),
+                _ => return Ok(steps),
+            }
+        }
+    }
+
+    fn bracket(&mut self) -> Result<Step, String> {
+        self.expect("[")?;
+        if self.eat("]") {
+            return Ok(Step::Flatten);
+        }
+        if self.eat("*") {
+            self.expect("]")?;
+            return Ok(Step::Wildcard);
+        }
+        if self.eat("?") {
+            let cond = self.condition()?;
+            self.expect("]")?;
+            return Ok(Step::Filter(cond));
+        }
+        if self.peek().is_some_and(|c| c == '-' || c.is_ascii_digit()) {
+            let start = self.pos;
+            self.pos += 1;
+            while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+            let text: String = self.chars[start..self.pos].iter().collect();
+            let index = text.parse().map_err(|_| format!("json_query: invalid index '{}'", text))?;
+            self.expect("]")?;
+            return Ok(Step::Index(index));
+        }
+        self.pos -= 1;
+        self.multi_list()
+    }
+
+    fn multi_list(&mut self) -> Result<Step, String> {
+        self.expect("[")?;
+        let mut paths = vec![self.path()?];
+        while self.eat(",") {
+            paths.push(self.path()?);
+        }
+        self.expect("]")?;
+        Ok(Step::MultiList(paths))
+    }
+
+    fn multi_hash(&mut self) -> Result<Step, String> {
+        self.expect("{")?;
+        let mut fields = Vec::new();
+        loop {
+            let key = self.ident()?;
+            self.expect(":")?;
+            fields.push((key, self.path()?));
+            if !self.eat(",") {
+                break;
+            }
+        }
+        self.expect("}")?;
+        Ok(Step::MultiHash(fields))
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let mut left = self.and_condition()?;
+        while self.eat("||") {
+            left = Condition::Or(Box::new(left), Box::new(self.and_condition()?));
+        }
+        Ok(left)
+    }
+
+    fn and_condition(&mut self) -> Result<Condition, String> {
+        let mut left = self.unary_condition()?;
+        while self.eat("&&") {
+            left = Condition::And(Box::new(left), Box::new(self.unary_condition()?));
+        }
+        Ok(left)
+    }
+
+    fn unary_condition(&mut self) -> Result<Condition, String> {
+        if self.eat("!") {
+            return Ok(Condition::Not(Box::new(self.unary_condition()?)));
+        }
+        if self.eat("(") {
+            let cond = self.condition()?;
+            self.expect(")")?;
+            return Ok(cond);
+        }
+        let left = self.operand()?;
+        for op in ["==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat(op) {
+                return Ok(Condition::Compare(left, op.to_string(), self.operand()?));
+            }
+        }
+        Ok(Condition::Truthy(left))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.peek() {
+            Some('\'') | Some('`') => Ok(Operand::Literal(self.literal()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => Ok(Operand::Literal(self.literal()?)),
+            _ => Ok(Operand::Path(self.path()?)),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value, String> {
+        let Some(quote) = self.peek() else {
+            return Err("json_query: expected literal".to_string());
+        };
+        if quote == '-' || quote.is_ascii_digit() {
+            let start = self.pos;
+            self.pos += 1;
+            while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                self.pos += 1;
+            }
+            let text: String = self.chars[start..self.pos].iter().collect();
+            return serde_json::from_str(&text).map_err(|_| format!("json_query: invalid number '{}'", text));
+        }
+
+        self.pos += 1;
+        let mut text = String::new();
+        loop {
+            match self.chars.get(self.pos) {
+                None => return Err(format!("json_query: unterminated literal {}", quote)),
+                Some('\\') if quote != '"' && self.chars.get(self.pos + 1) == Some(&quote) => {
+                    text.push(quote);
+                    self.pos += 2;
+                }
+                Some(c) if *c == quote => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(c) => {
+                    text.push(*c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        match quote {
+            '\'' => Ok(Value::String(text)),
+            '"' => serde_json::from_str(&format!("\"{}\"", text)).map_err(|e| e.to_string()),
+            // Backtick literals are JSON; bare words are accepted as strings.
+            _ => Ok(serde_json::from_str(&text).unwrap_or(Value::String(text))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn projection_and_filter() {
+        let data = json!({"users": [{"name": "a", "uid": 1}, {"name": "b", "uid": 2}]});
+        assert_eq!(query(&data, "users[*].name").unwrap(), json!(["a", "b"]));
+        assert_eq!(query(&data, "users[?uid > `1`].name").unwrap(), json!(["b"]));
+        assert!(query(&data, "users[?name=='a'].uid | [0]").is_err());
+    }
+
+    #[test]
+    fn multi_select() {
+        let data = json!({"hosts": [{"n": "web", "ip": "10.0.0.1", "x": 1}]});
+        assert_eq!(query(&data, "hosts[].{host: n, addr: ip}").unwrap(), json!([{"host": "web", "addr": "10.0.0.1"}]));
+        assert_eq!(query(&data, "hosts[0].[n, ip]").unwrap(), json!(["web", "10.0.0.1"]));
+    }
+}
@@ -0,0 +1,213 @@
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+/// Converts a value to the text written into a rendered template.
+pub fn to_output(value: &Value) -> String {
+    match value {
+        Value::Null => "None".to_string(),
+        Value::Bool(true) => "True".to_string(),
+        Value::Bool(false) => "False".to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(to_output).collect::<Vec<_>>().join(", "),
+        Value::Object(_) => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
+
+pub fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64().map(|f| f != 0.0).unwrap_or(false),
+        Value::String(s) => !s.is_empty() && s != "false" && s != "0",
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+/// Returns the items a value yields when iterated. Strings are treated as
+/// comma-separated lists, which is how list variables arrive from INI
+/// inventories and `-e key=a,b` extra vars.
+pub fn iterate(value: &Value) -> Vec<Value> {
+    match value {
+        Value::Array(items) => items.clone(),
+        Value::Object(map) => map.keys().map(|k| Value::String(k.clone())).collect(),
+        Value::String(s) if s.is_empty() => Vec::new(),
+        Value::String(s) => s.split(',').map(|item| Value::String(item.trim().to_string())).collect(),
+        Value::Null => Vec::new(),
+        other => vec![other.clone()],
+    }
+}
+
+/// Numeric view of a value; numeric strings count as numbers since
+/// inventory variables are untyped.
+pub fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+pub fn as_int(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+        Value::Bool(b) => Some(*b as i64),
+        Value::String(s) => {
+            let s = s.trim();
+            s.parse().ok().or_else(|| s.parse::<f64>().ok().map(|f| f as i64))
+        }
+        _ => None,
+    }
+}
+
+pub fn number(f: f64) -> Value {
+    if f.fract() == 0.0 && f.abs() < i64::MAX as f64 {
+        Value::from(f as i64)
+    } else {
+        Value::from(f)
+    }
+}
+
+pub fn loose_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(x), Value::String(y)) => x == y,
+        (Value::Array(x), Value::Array(y)) => x.len() == y.len() && x.iter().zip(y).all(|(l, r)| loose_eq(l, r)),
+        (Value::Object(x), Value::Object(y)) => {
+            x.len() == y.len() && x.iter().all(|(k, v)| y.get(k).map(|w| loose_eq(v, w)).unwrap_or(false))
+        }
+        (Value::Null, Value::Null) => true,
+        (Value::Null, _) | (_, Value::Null) => false,
+        (Value::Bool(x), Value::Bool(y)) => x == y,
+        (Value::Bool(x), Value::String(s)) | (Value::String(s), Value::Bool(x)) => {
+            s.eq_ignore_ascii_case(if *x { "true" } else { "false" })
+        }
+        _ => match (as_number(a), as_number(b)) {
+            (Some(x), Some(y)) => x == y,
+            _ => false,
+        },
+    }
+}
+
+pub fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    if let (Value::String(x), Value::String(y)) = (a, b) {
+        if let (Ok(nx), Ok(ny)) = (x.trim().parse::<f64>(), y.trim().parse::<f64>()) {
+            return nx.partial_cmp(&ny);
+        }
+        return Some(x.cmp(y));
+    }
+    if let (Value::Array(x), Value::Array(y)) = (a, b) {
+        for (l, r) in x.iter().zip(y) {
+            match compare(l, r)? {
+                Ordering::Equal => continue,
+                other => return Some(other),
+            }
+        }
+        return Some(x.len().cmp(&y.len()));
+    }
+    match (as_number(a), as_number(b)) {
+        (Some(x), Some(y)) => x.partial_cmp(&y),
+        _ => None,
+    }
+}
+
+pub fn contains(container: &Value, item: &Value) -> bool {
+    match container {
+        Value::String(s) => s.contains(to_output(item).as_str()),
+        Value::Array(items) => items.iter().any(|v| loose_eq(v, item)),
+        Value::Object(map) => map.contains_key(&to_output(item)),
+        _ => false,
+    }
+}
+
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "NoneType",
+        Value::Bool(_) => "bool",
+        Value::Number(n) if n.is_f64() => "float",
+        Value::Number(_) => "int",
+        Value::String(_) => "str",
+        Value::Array(_) => "list",
+        Value::Object(_) => "dict",
+    }
+}
+
+/// Looks up `key` on a value the way Jinja resolves both `a.key` and `a[key]`.
+pub fn get_item(value: &Value, key: &Value) -> Option<Value> {
+    match value {
+        Value::Object(map) => map.get(&to_output(key)).cloned(),
+        Value::Array(items) => {
+            let index = as_int(key)?;
+            let index = if index < 0 { items.len() as i64 + index } else { index };
+            items.get(usize::try_from(index).ok()?).cloned()
+        }
+        Value::String(s) => {
+            let index = as_int(key)?;
+            let chars: Vec<char> = s.chars().collect();
+            let index = if index < 0 { chars.len() as i64 + index } else { index };
+            chars.get(usize::try_from(index).ok()?).map(|c| Value::String(c.to_string()))
+        }
+        _ => None,
+    }
+}
+
+pub fn slice(value: &Value, start: Option<i64>, end: Option<i64>) -> Value {
+    fn bounds(len: usize, start: Option<i64>, end: Option<i64>) -> (usize, usize) {
+        let clamp = |i: i64| -> usize {
+            let i = if i < 0 { len as i64 + i } else { i };
+            i.clamp(0, len as i64) as usize
+        };
+        let s = start.map(clamp).unwrap_or(0);
+        let e = end.map(clamp).unwrap_or(len);
+        (s, e.max(s))
+    }
+
+    match value {
+        Value::Array(items) => {
+            let (s, e) = bounds(items.len(), start, end);
+            Value::Array(items[s..e].to_vec())
+        }
+        Value::String(text) => {
+            let chars: Vec<char> = text.chars().collect();
+            let (s, e) = bounds(chars.len(), start, end);
+            Value::String(chars[s..e].iter().collect())
+        }
+        _ => Value::Null,
+    }
+}
+
+pub fn object(pairs: Vec<(String, Value)>) -> Value {
+    let mut map = Map::new();
+    for (k, v) in pairs {
+        map.insert(k, v);
+    }
+    Value::Object(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn output_formats() {
+        assert_eq!(to_output(&json!("a")), "a");
+        assert_eq!(to_output(&json!(true)), "True");
+        assert_eq!(to_output(&json!(["a", 1])), "a, 1");
+        assert_eq!(to_output(&json!({"k": 1})), "{\"k\":1}");
+    }
+
+    #[test]
+    fn loose_equality_between_strings_and_numbers() {
+        assert!(loose_eq(&json!("80"), &json!(80)));
+        assert!(!loose_eq(&json!("eighty"), &json!(80)));
+        assert!(loose_eq(&json!("true"), &json!(true)));
+    }
+
+    #[test]
+    fn negative_index_and_slice() {
+        assert_eq!(get_item(&json!([1, 2, 3]), &json!(-1)), Some(json!(3)));
+        assert_eq!(slice(&json!([1, 2, 3]), Some(1), None), json!([2, 3]));
+    }
+}